@@ -0,0 +1,180 @@
+//! An audit log of every file downloaded into or removed from a mirror.
+//!
+//! Each record is appended to a JSON lines file as soon as the action has
+//! finished, whether it succeeded or not, so that the contents of a mirror can
+//! be accounted for file by file.
+
+use serde::Serialize;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::logging;
+
+/// The audit log, once it has been opened.
+static LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// A file that audit records are appended to.
+#[derive(Debug)]
+struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open a file for appending records.
+    fn open(path: &Path) -> io::Result<AuditLog> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append a record as a single line.
+    fn append(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = self.file.lock().expect("Poisoned audit log");
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// Append a record of every download and deletion to a file.
+pub fn open(path: &Path) -> io::Result<()> {
+    let log = AuditLog::open(path)?;
+    LOG.set(log)
+        .map_err(|_| io::Error::other("The audit log is already open"))
+}
+
+/// Remove a file from a mirror, recording its removal.
+pub async fn remove_file(path: &Path) -> io::Result<()> {
+    let size = tokio::fs::metadata(path)
+        .await
+        .map(|metadata| metadata.len());
+    let removed = tokio::fs::remove_file(path).await;
+    let mut record = Record::new("remove", path);
+    if let Ok(size) = size {
+        record = record.bytes(size);
+    }
+    match &removed {
+        Ok(()) => record.write(),
+        Err(e) => record.error(e).write(),
+    }
+    removed
+}
+
+/// A file that was downloaded or removed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo: Option<String>,
+    action: &'static str,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    result: String,
+}
+
+impl Record {
+    /// Describe an action on a local file, which succeeded unless an error is given.
+    ///
+    /// The repository is taken from the logging context.
+    pub fn new(action: &'static str, path: &Path) -> Record {
+        Record {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            repo: logging::current_repo(),
+            action,
+            path: path.display().to_string(),
+            bytes: None,
+            checksum: None,
+            source: None,
+            result: "ok".to_owned(),
+        }
+    }
+
+    /// The size of the file.
+    pub fn bytes(mut self, bytes: u64) -> Record {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// The checksum the file is expected to have.
+    pub fn checksum<C: Display>(mut self, checksum: C) -> Record {
+        self.checksum = Some(checksum.to_string());
+        self
+    }
+
+    /// Where the file was downloaded from.
+    pub fn source<S: Display>(mut self, source: S) -> Record {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    /// The error that caused the action to fail.
+    pub fn error<E: Display>(mut self, error: E) -> Record {
+        self.result = error.to_string();
+        self
+    }
+
+    /// Append the record to the audit log, if there is one.
+    pub fn write(self) {
+        if let Some(log) = LOG.get() {
+            if let Err(e) = log.append(&self) {
+                warn!("Could not write to the audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn append_records() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit/yumclone.jsonl");
+        let log = AuditLog::open(&path).unwrap();
+
+        let download = logging::scope("fedora", None, async {
+            Record::new("download", Path::new("/srv/fedora/Packages/a.rpm"))
+                .bytes(10)
+                .checksum("sha256:abc")
+                .source("https://dl.example.com/fedora/Packages/a.rpm")
+        })
+        .await;
+        log.append(&download).unwrap();
+        let removal = Record::new("remove", Path::new("/srv/fedora/Packages/b.rpm"))
+            .error("Permission denied");
+        log.append(&removal).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["repo"], "fedora");
+        assert_eq!(lines[0]["action"], "download");
+        assert_eq!(lines[0]["path"], "/srv/fedora/Packages/a.rpm");
+        assert_eq!(lines[0]["bytes"], 10);
+        assert_eq!(lines[0]["checksum"], "sha256:abc");
+        assert_eq!(lines[0]["result"], "ok");
+        assert!(lines[1].get("repo").is_none());
+        assert_eq!(lines[1]["result"], "Permission denied");
+    }
+}
@@ -0,0 +1,180 @@
+//! Benchmarking the mirrors of a repository.
+//!
+//! The same sample of package files, spread across the sizes in the metadata,
+//! is downloaded from the upstream and each of its mirrors in turn, or only the
+//! first chunk of each file with ranged requests. Mirrors are measured one at a
+//! time so that they don't compete for bandwidth, and the files are discarded
+//! as they arrive. The latency and throughput of each help to choose the order
+//! of the mirrors and how many downloads to make at once.
+
+use reqwest::header::RANGE;
+use reqwest::{Client, Url};
+use std::time::{Duration, Instant};
+
+use crate::href;
+
+/// The measurements of the mirrors of a variant of a repository.
+#[derive(Debug, Clone)]
+pub struct Benchmark {
+    /// The label of the repository.
+    pub repo: String,
+    /// Where the variant is synchronised from.
+    pub src: String,
+    /// Each mirror, fastest first.
+    pub mirrors: Vec<Measurement>,
+}
+
+/// How quickly a mirror served the sample.
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    /// The base URL of the mirror.
+    pub mirror: String,
+    /// The number of files retrieved.
+    pub files: usize,
+    /// The bytes received.
+    pub bytes: u64,
+    /// The average time until the response to each request started.
+    pub latency: Option<Duration>,
+    /// The time spent retrieving files, including waiting for responses.
+    pub elapsed: Duration,
+    /// The files that couldn't be retrieved, and why.
+    pub failures: Vec<String>,
+}
+
+impl Measurement {
+    /// The bytes received per second, if anything was.
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.elapsed.as_secs_f64();
+        if self.bytes > 0 && secs > 0.0 {
+            Some(self.bytes as f64 / secs)
+        } else {
+            None
+        }
+    }
+}
+
+/// Choose up to `count` files spread evenly from the smallest to the largest.
+pub fn sample<'a>(files: impl IntoIterator<Item = (&'a str, u64)>, count: usize) -> Vec<&'a str> {
+    let mut files: Vec<(&str, u64)> = files.into_iter().collect();
+    files.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+    files.dedup();
+    if count == 0 || files.is_empty() {
+        return Vec::new();
+    }
+    if count >= files.len() {
+        return files.into_iter().map(|(file, _)| file).collect();
+    }
+    // The largest file is always included, as it shows the throughput best
+    if count == 1 {
+        return vec![files[files.len() - 1].0];
+    }
+    let last = files.len() - 1;
+    (0..count)
+        .map(|index| files[index * last / (count - 1)].0)
+        .collect()
+}
+
+/// Time retrieving each file of the sample from a mirror, or only the first `chunk` bytes of
+/// each if given.
+pub async fn measure(
+    client: &Client,
+    base: &Url,
+    files: &[&str],
+    chunk: Option<u64>,
+) -> Measurement {
+    let mut measurement = Measurement {
+        mirror: base.to_string(),
+        ..Measurement::default()
+    };
+    let mut waited = Duration::default();
+    for file in files {
+        let started = Instant::now();
+        let result = async {
+            let url = href::join(base, file)?;
+            let mut request = client.get(url);
+            if let Some(chunk) = chunk {
+                request = request.header(RANGE, format!("bytes=0-{}", chunk.max(1) - 1));
+            }
+            let mut response = request.send().await?.error_for_status()?;
+            let latency = started.elapsed();
+            let mut received = 0;
+            while let Some(data) = response.chunk().await? {
+                received += data.len() as u64;
+                // Servers that ignore the range still only need to send the chunk
+                if chunk.is_some_and(|chunk| received >= chunk) {
+                    break;
+                }
+            }
+            Ok::<_, failure::Error>((latency, received))
+        }
+        .await;
+        measurement.elapsed += started.elapsed();
+        match result {
+            Ok((latency, received)) => {
+                measurement.files += 1;
+                measurement.bytes += received;
+                waited += latency;
+            }
+            Err(e) => measurement.failures.push(format!("{}: {}", file, e)),
+        }
+    }
+    if measurement.files > 0 {
+        measurement.latency = Some(waited / measurement.files as u32);
+    }
+    measurement
+}
+
+/// Order measurements from the fastest to the slowest, with unreachable mirrors last.
+pub fn rank(measurements: &mut [Measurement]) {
+    measurements.sort_by(|a, b| {
+        let throughput = |m: &Measurement| m.throughput().unwrap_or(0.0);
+        throughput(b)
+            .partial_cmp(&throughput(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serve::serve_dir;
+    use tempdir::TempDir;
+
+    #[test]
+    fn sample_sizes() {
+        let files = vec![("e", 5), ("a", 1), ("c", 3), ("b", 2), ("d", 4)];
+        assert_eq!(sample(files.clone(), 2), vec!["a", "e"]);
+        assert_eq!(sample(files.clone(), 3), vec!["a", "c", "e"]);
+        assert_eq!(sample(files.clone(), 1), vec!["e"]);
+        assert_eq!(sample(files.clone(), 9).len(), 5);
+        assert!(sample(files, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn measure_mirror() {
+        let dir = TempDir::new("bench").unwrap();
+        std::fs::create_dir_all(dir.path().join("Packages")).unwrap();
+        std::fs::write(dir.path().join("Packages/a-1-1.rpm"), vec![0; 1000]).unwrap();
+        let base = serve_dir(dir.path());
+        let client = Client::new();
+        let files = ["Packages/a-1-1.rpm", "Packages/missing.rpm"];
+
+        let whole = measure(&client, &base, &files, None).await;
+        assert_eq!(whole.files, 1);
+        assert_eq!(whole.bytes, 1000);
+        assert!(whole.latency.is_some());
+        assert_eq!(whole.failures.len(), 1);
+
+        let chunked = measure(&client, &base, &files[..1], Some(100)).await;
+        assert_eq!(chunked.bytes, 100);
+        assert!(chunked.failures.is_empty());
+
+        let unreachable = Measurement {
+            mirror: "http://unreachable.example.com/".to_owned(),
+            ..Measurement::default()
+        };
+        let mut measurements = vec![unreachable, chunked, whole];
+        rank(&mut measurements);
+        assert_eq!(measurements[2].mirror, "http://unreachable.example.com/");
+    }
+}
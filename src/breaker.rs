@@ -0,0 +1,151 @@
+//! Circuit breakers for upstream hosts.
+//!
+//! Each host that files are downloaded from has a breaker, shared by every
+//! repository. After several consecutive failures the breaker opens and the
+//! host is avoided in favour of other mirrors, until a cooldown has passed
+//! and a single download is allowed through to test it again.
+
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The consecutive failures after which a host is avoided.
+const THRESHOLD: u32 = 5;
+/// How long a host is avoided before it is tried again.
+const COOLDOWN: Duration = Duration::from_secs(300);
+
+/// The failures of each upstream host.
+#[derive(Debug)]
+pub struct Breakers {
+    threshold: u32,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, Breaker>>,
+}
+
+/// The state of a single host.
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    opened: Option<Instant>,
+}
+
+/// The host and port that a URL connects to.
+fn host(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+impl Breakers {
+    /// Create breakers that open after a number of consecutive failures.
+    pub fn new(threshold: u32, cooldown: Duration) -> Breakers {
+        Breakers {
+            threshold,
+            cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether requests may be sent to the host of a URL at a time.
+    ///
+    /// Once the cooldown has passed, a single request is allowed through before the host is
+    /// avoided again for another cooldown.
+    pub fn allows(&self, url: &Url, now: Instant) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let breaker = match hosts.get_mut(&host(url)) {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+        match breaker.opened {
+            Some(opened) if now.duration_since(opened) < self.cooldown => false,
+            Some(_) => {
+                breaker.opened = Some(now);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Record a failed request to the host of a URL.
+    pub fn failed(&self, url: &Url, now: Instant) {
+        let host = host(url);
+        let mut hosts = self.hosts.lock().unwrap();
+        let breaker = hosts.entry(host.clone()).or_default();
+        breaker.failures += 1;
+        if breaker.failures == self.threshold {
+            warn!(
+                "Avoiding {} for {} after {} consecutive failures",
+                host,
+                humantime::format_duration(self.cooldown),
+                breaker.failures
+            );
+            breaker.opened = Some(now);
+        }
+    }
+
+    /// Record a successful request to the host of a URL.
+    pub fn succeeded(&self, url: &Url) {
+        let host = host(url);
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(breaker) = hosts.remove(&host) {
+            if breaker.opened.is_some() {
+                info!("Using {} again after it recovered", host);
+            }
+        }
+    }
+
+    /// Choose the first source, starting from an offset, that requests may be sent to.
+    ///
+    /// If every source is being avoided, the one at the offset is used anyway.
+    pub fn choose<'u>(&self, sources: &'u [Url], offset: usize) -> &'u Url {
+        let now = Instant::now();
+        (0..sources.len())
+            .map(|i| &sources[(offset + i) % sources.len()])
+            .find(|src| self.allows(src, now))
+            .unwrap_or(&sources[offset % sources.len()])
+    }
+}
+
+/// The breakers shared by every download.
+pub fn shared() -> &'static Breakers {
+    static BREAKERS: OnceLock<Breakers> = OnceLock::new();
+    BREAKERS.get_or_init(|| Breakers::new(THRESHOLD, COOLDOWN))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn open_and_close() {
+        let breakers = Breakers::new(2, Duration::from_secs(60));
+        let primary = Url::parse("https://primary.example.com/fedora/").unwrap();
+        let mirror = Url::parse("https://mirror.example.com/fedora/").unwrap();
+        let sources = [primary.clone(), mirror.clone()];
+        let start = Instant::now();
+
+        breakers.failed(&primary, start);
+        assert!(breakers.allows(&primary, start));
+        breakers.failed(&primary, start);
+        assert!(!breakers.allows(&primary, start));
+        assert_eq!(breakers.choose(&sources, 0), &mirror);
+
+        // A single request is let through after the cooldown
+        let later = start + Duration::from_secs(61);
+        assert!(breakers.allows(&primary, later));
+        assert!(!breakers.allows(&primary, later));
+
+        breakers.succeeded(&primary);
+        assert!(breakers.allows(&primary, later));
+        assert_eq!(breakers.choose(&sources, 0), &primary);
+
+        // With nowhere else to go, the avoided host is used anyway
+        breakers.failed(&mirror, start);
+        breakers.failed(&mirror, start);
+        assert_eq!(breakers.choose(&sources[1..], 0), &mirror);
+    }
+}
@@ -0,0 +1,90 @@
+//! Comparing the packages of two mirrored trees, such as a replica and its source.
+
+use std::collections::BTreeMap;
+
+use crate::list::Listed;
+
+/// The differences between the packages of two trees.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Packages only in the first tree, as `name-[epoch:]version-release.arch`.
+    pub only_left: Vec<String>,
+    /// Packages only in the second tree.
+    pub only_right: Vec<String>,
+    /// Packages in both trees whose files differ, by checksum or size.
+    pub differing: Vec<String>,
+}
+
+impl Comparison {
+    /// Compare the packages listed by the metadata of two trees.
+    pub fn new(left: &[Listed], right: &[Listed]) -> Comparison {
+        let by_nevra = |packages: &[Listed]| -> BTreeMap<String, (u64, String)> {
+            packages
+                .iter()
+                .map(|p| (p.nevra.clone(), (p.size, p.checksum.to_lowercase())))
+                .collect()
+        };
+        let (left, mut right) = (by_nevra(left), by_nevra(right));
+        let mut comparison = Comparison::default();
+        for (nevra, file) in left {
+            match right.remove(&nevra) {
+                None => comparison.only_left.push(nevra),
+                // Checksums of different algorithms can't be compared, only sizes
+                Some(other) if !same_file(&file, &other) => comparison.differing.push(nevra),
+                Some(_) => {}
+            }
+        }
+        comparison.only_right = right.into_keys().collect();
+        comparison
+    }
+
+    /// Whether the trees have the same packages.
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty() && self.only_right.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// Whether two packages of the same name and version have the same file.
+fn same_file(left: &(u64, String), right: &(u64, String)) -> bool {
+    let algorithm = |checksum: &str| checksum.split(':').next().unwrap_or_default().to_owned();
+    let comparable = algorithm(&left.1) == algorithm(&right.1);
+    left.0 == right.0 && (!comparable || left.1 == right.1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compare_trees() {
+        let package = |nevra: &str, size: u64, checksum: &str| Listed {
+            repo: String::new(),
+            dest: String::new(),
+            name: nevra.split('-').next().unwrap().to_owned(),
+            arch: "noarch".to_owned(),
+            nevra: nevra.to_owned(),
+            size,
+            checksum: checksum.to_owned(),
+            location: format!("Packages/{}.rpm", nevra),
+        };
+        let left = vec![
+            package("a-1-1.noarch", 1, "sha256:aa"),
+            package("b-1-1.noarch", 1, "sha256:bb"),
+            package("c-1-1.noarch", 1, "sha256:cc"),
+            package("d-1-1.noarch", 1, "sha256:dd"),
+        ];
+        let right = vec![
+            package("a-1-1.noarch", 1, "sha256:AA"),
+            package("b-1-1.noarch", 1, "sha256:b0"),
+            package("c-1-1.noarch", 1, "sha512:c0"),
+            package("e-1-1.noarch", 1, "sha256:ee"),
+        ];
+
+        let comparison = Comparison::new(&left, &right);
+        assert_eq!(comparison.only_left, vec!["d-1-1.noarch"]);
+        assert_eq!(comparison.only_right, vec!["e-1-1.noarch"]);
+        assert_eq!(comparison.differing, vec!["b-1-1.noarch"]);
+        assert!(!comparison.is_empty());
+        assert!(Comparison::new(&left, &left).is_empty());
+    }
+}
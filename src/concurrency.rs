@@ -0,0 +1,338 @@
+//! Adaptive limits on the number of concurrent downloads.
+//!
+//! The limit grows by one download after each round of responsive downloads,
+//! and is halved when a mirror shows signs of congestion: rate limiting
+//! responses, stalled transfers, or response times well above the average.
+//! This backs off from rate limited mirrors without any manual tuning.
+//!
+//! When a mirror says how long to wait with `Retry-After`, as it might when
+//! rate limiting or down for maintenance, every download from that mirror is
+//! paused for that long instead of being retried straight away.
+//!
+//! A global limit can also be set on the downloads of every repository being
+//! synchronised at once.
+
+use reqwest::{StatusCode, Url};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::delay_for;
+use tracing::{debug, info};
+
+/// The shortest time between two reductions, so one burst of errors only halves the limit once.
+const COOLDOWN: Duration = Duration::from_secs(5);
+/// How many times the average a response time must be to count as congestion.
+const SLOW_FACTOR: u32 = 4;
+/// Response times below this never count as congestion.
+const SLOW_MINIMUM: Duration = Duration::from_secs(1);
+/// The longest a mirror's downloads are paused for, however long it asks.
+const MAX_PAUSE: Duration = Duration::from_secs(10 * 60);
+
+/// The downloads allowed at once across every repository, if limited.
+static GLOBAL: OnceLock<Semaphore> = OnceLock::new();
+
+/// Allow at most `max` downloads at once across every repository.
+pub fn limit_globally(max: usize) {
+    let _ = GLOBAL.set(Semaphore::new(max.max(1)));
+}
+
+/// The number of downloads allowed at once.
+#[derive(Debug)]
+pub struct Concurrency {
+    semaphore: Semaphore,
+    max: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    /// Responsive downloads since the limit last changed
+    credit: usize,
+    /// Permits to take back as downloads finish
+    owed: usize,
+    /// The moving average of response times
+    latency: Option<Duration>,
+    decreased: Option<Instant>,
+    /// When downloads may resume from each paused mirror, by origin
+    paused: HashMap<String, Instant>,
+}
+
+impl Concurrency {
+    /// Allow up to `max` downloads at once, starting at the maximum.
+    pub fn new(max: usize) -> Concurrency {
+        Concurrency {
+            semaphore: Semaphore::new(max),
+            max,
+            state: Mutex::new(State {
+                limit: max,
+                credit: 0,
+                owed: 0,
+                latency: None,
+                decreased: None,
+                paused: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The number of downloads currently allowed at once.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Wait until another download is allowed, both by this limit and the global limit.
+    pub async fn acquire(&self) -> Permit<'_> {
+        let permit = Some(self.semaphore.acquire().await);
+        let global = match GLOBAL.get() {
+            Some(global) => Some(global.acquire().await),
+            None => None,
+        };
+        Permit {
+            concurrency: self,
+            permit,
+            _global: global,
+        }
+    }
+
+    /// Stop downloading from the mirror serving a URL for a while.
+    pub fn pause(&self, url: &Url, delay: Duration) {
+        let delay = delay.min(MAX_PAUSE);
+        let until = Instant::now() + delay;
+        let mut state = self.state.lock().unwrap();
+        let resume = state
+            .paused
+            .entry(url.origin().ascii_serialization())
+            .or_insert(until);
+        if *resume <= until {
+            *resume = until;
+            info!(
+                "Pausing downloads from {} for {}",
+                url.origin().ascii_serialization(),
+                humantime::format_duration(delay)
+            );
+        }
+    }
+
+    /// Wait until downloads from the mirror serving a URL are no longer paused.
+    pub async fn resumed(&self, url: &Url) {
+        let origin = url.origin().ascii_serialization();
+        loop {
+            let remaining = {
+                let mut state = self.state.lock().unwrap();
+                match state.paused.get(&origin) {
+                    Some(until) if *until > Instant::now() => *until - Instant::now(),
+                    Some(_) => {
+                        state.paused.remove(&origin);
+                        return;
+                    }
+                    None => return,
+                }
+            };
+            delay_for(remaining).await;
+        }
+    }
+
+    /// Record how long a server took to respond to a request.
+    pub fn responded(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let slow = state
+            .latency
+            .is_some_and(|average| latency > average * SLOW_FACTOR && latency > SLOW_MINIMUM);
+        state.latency = Some(match state.latency {
+            Some(average) => (average * 7 + latency) / 8,
+            None => latency,
+        });
+        if slow {
+            drop(state);
+            self.congested("slow responses");
+            return;
+        }
+
+        state.credit += 1;
+        if state.credit >= state.limit && state.limit < self.max {
+            state.limit += 1;
+            state.credit = 0;
+            if state.owed > 0 {
+                state.owed -= 1;
+            } else {
+                self.semaphore.add_permits(1);
+            }
+            debug!("Increased concurrent downloads to {}", state.limit);
+        }
+    }
+
+    /// Halve the limit in response to congestion.
+    pub fn congested(&self, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.decreased.is_some_and(|t| t.elapsed() < COOLDOWN) {
+            return;
+        }
+        let limit = (state.limit / 2).max(1);
+        let mut removed = state.limit - limit;
+        if removed == 0 {
+            return;
+        }
+        state.limit = limit;
+        state.credit = 0;
+        state.decreased = Some(Instant::now());
+
+        // Idle permits are taken back now, the rest as downloads finish
+        while removed > 0 {
+            match self.semaphore.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+            removed -= 1;
+        }
+        state.owed += removed;
+        info!("Reduced concurrent downloads to {} ({})", limit, reason);
+    }
+}
+
+/// Permission for a single download, returned when dropped.
+pub struct Permit<'c> {
+    concurrency: &'c Concurrency,
+    permit: Option<SemaphorePermit<'c>>,
+    _global: Option<SemaphorePermit<'static>>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.concurrency.state.lock().unwrap();
+        if state.owed > 0 {
+            state.owed -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// A server refused a request because it is overloaded or rate limited.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// The URL that was requested
+    pub url: Url,
+    /// The status the server responded with
+    pub status: StatusCode,
+    /// How long the server asked to wait before trying again, if it did
+    pub retry_after: Option<Duration>,
+}
+
+impl std::error::Error for RateLimited {}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} responded with {}", self.url, self.status)
+    }
+}
+
+/// Parse the value of a `Retry-After` header, either a number of seconds or an HTTP date.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date that has already passed means trying again straight away
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+tokio::task_local! {
+    /// The limit shared by the downloads of the current task.
+    static CURRENT: Arc<Concurrency>;
+}
+
+/// Run a future with its downloads feeding back into a limit.
+pub async fn scope<F: Future>(concurrency: Arc<Concurrency>, future: F) -> F::Output {
+    CURRENT.scope(concurrency, future).await
+}
+
+/// The limit of the downloads made by the current task, if any.
+pub fn current() -> Option<Arc<Concurrency>> {
+    CURRENT.try_with(|concurrency| concurrency.clone()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn increase_and_decrease() {
+        let concurrency = Concurrency::new(8);
+        let held = concurrency.acquire().await;
+
+        concurrency.congested("test");
+        assert_eq!(concurrency.limit(), 4);
+        assert_eq!(concurrency.semaphore.available_permits(), 3);
+        // Within the cooldown, the limit isn't reduced again
+        concurrency.congested("test");
+        assert_eq!(concurrency.limit(), 4);
+
+        // A round of responsive downloads raises the limit by one
+        for _ in 0..4 {
+            concurrency.responded(Duration::from_millis(10));
+        }
+        assert_eq!(concurrency.limit(), 5);
+        drop(held);
+        assert_eq!(concurrency.semaphore.available_permits(), 5);
+
+        // A response far slower than usual is congestion
+        concurrency.state.lock().unwrap().decreased = None;
+        concurrency.responded(Duration::from_secs(2));
+        assert_eq!(concurrency.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn owed_permits() {
+        let concurrency = Concurrency::new(4);
+        let permits = vec![
+            concurrency.acquire().await,
+            concurrency.acquire().await,
+            concurrency.acquire().await,
+            concurrency.acquire().await,
+        ];
+        concurrency.congested("test");
+        assert_eq!(concurrency.limit(), 2);
+        drop(permits);
+        assert_eq!(concurrency.semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn pause_mirror() {
+        let concurrency = Concurrency::new(4);
+        let paused = Url::parse("http://paused.example.com/Packages/a.rpm").unwrap();
+        let other = Url::parse("http://other.example.com/Packages/a.rpm").unwrap();
+        concurrency.pause(&paused, Duration::from_millis(200));
+        // A shorter pause doesn't cut a longer one short
+        concurrency.pause(&paused, Duration::from_millis(10));
+
+        let start = Instant::now();
+        concurrency.resumed(&other).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        concurrency.resumed(&paused).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(concurrency.state.lock().unwrap().paused.is_empty());
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
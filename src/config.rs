@@ -1,68 +1,2310 @@
 //! Configuration of the repo tool.
 
-use log::{debug, info, warn};
-use reqwest::Client;
+use futures::stream::{self, StreamExt};
+use glob::Pattern;
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Proxy, Url};
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::path::Path;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::Duration;
+use tempdir::TempDir;
+use tokio::time::delay_for;
+use tracing::{debug, info, warn};
 
-use crate::package::CheckType;
+use failure::{bail, format_err};
+
+use crate::bench::Benchmark;
+use crate::doctor::{self, Finding};
+use crate::filelists::Provider;
+use crate::hash::{self, MinimumDigest, OnWeakDigest};
+use crate::hooks::{self, Hooks};
+use crate::href;
+use crate::links::Checked;
+use crate::list::Listed;
+use crate::load;
+use crate::logging;
+use crate::manifest::Manifest;
+use crate::package::{self, Changelog, CheckType, DownloadOrder, Inconsistent, OnMissing, Vetting};
+use crate::plan::{self, Outdated, Removal};
+use crate::prefetch::Prefetch;
+use crate::rank;
 use crate::repo::*;
+use crate::repofile::{self, Clients, RepoFile};
+use crate::resolve::{IpFamily, Resolver};
+use crate::secret::{Secret, Source};
+use crate::serve::Upstream;
+use crate::shard::{self, Shard};
+use crate::sign::{self, Signing, Verification};
+use crate::stats::{LimitReached, Stats};
+use crate::throttle::Bandwidth;
+use crate::timeout::Timeouts;
+use crate::tls::{self, Pins};
+use crate::treeinfo;
+use crate::units;
 use crate::urlmux::*;
 
 type Result<T> = ::std::result::Result<T, ::failure::Error>;
 
+/// The complete configuration, merged from the main file and any included files.
+#[derive(Debug, Default, Deserialize)]
+pub struct Configs {
+    /// The repositories to clone.
+    #[serde(rename = "repo", default)]
+    pub repos: Vec<Config>,
+    /// Additional configuration files or directories to include.
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// The bandwidth available to downloads, which can only be set in the main file.
+    #[serde(default)]
+    pub bandwidth: Bandwidth,
+    /// The default time limits of every repository, which can only be set in the main file.
+    #[serde(default)]
+    timeouts: Timeouts,
+    /// How existing files of every repository are checked by default, which can only be set in
+    /// the main file.
+    #[serde(default)]
+    check_mode: Option<CheckType>,
+}
+
+impl Configs {
+    /// Load the configuration along with its includes and drop-in directory.
+    ///
+    /// Included paths are relative to the main configuration file. Directories (including the
+    /// `<name>.d` directory beside the main file) contribute every configuration file they
+    /// contain, in file name order.
+    pub fn load(name: &str) -> Result<Configs> {
+        let path = match load::find(name) {
+            Some(path) => path,
+            None => {
+                info!("No configuration found for '{}', using defaults", name);
+                return Ok(Configs::default());
+            }
+        };
+
+        let main: Configs = load::load_file(&path)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut includes: Vec<PathBuf> = main.include.iter().map(|p| base.join(p)).collect();
+        if let Some(stem) = path.file_stem() {
+            let drop_in = base.join(format!("{}.d", stem.to_string_lossy()));
+            if drop_in.is_dir() && !includes.contains(&drop_in) {
+                includes.push(drop_in);
+            }
+        }
+
+        let mut sources = vec![(path.clone(), main.repos)];
+        for file in expand_includes(&includes)? {
+            let included: Configs = load::load_file(&file)?;
+            if !included.include.is_empty() {
+                bail!("Nested includes are not supported (in {:?})", file);
+            }
+            if included.bandwidth.is_limited() {
+                bail!(
+                    "Bandwidth can only be set in the main configuration (in {:?})",
+                    file
+                );
+            }
+            if included.timeouts.is_set() {
+                bail!(
+                    "Default timeouts can only be set in the main configuration (in {:?})",
+                    file
+                );
+            }
+            if included.check_mode.is_some() {
+                bail!(
+                    "The default check mode can only be set in the main configuration (in {:?})",
+                    file
+                );
+            }
+            sources.push((file, included.repos));
+        }
+
+        let mut configs = Configs {
+            bandwidth: main.bandwidth,
+            ..Configs::default()
+        };
+        for (file, repos) in sources.iter_mut() {
+            for repo in repos.iter_mut() {
+                repo.interpolate_env()
+                    .and_then(|()| repo.check_secrets())
+                    .map_err(|e| format_err!("{} (in {:?})", e, file))?;
+                repo.timeouts = repo.timeouts.or(main.timeouts);
+                repo.check_mode = repo.check_mode.or(main.check_mode);
+            }
+        }
+
+        let mut origins: HashMap<String, PathBuf> = HashMap::new();
+        let mut names: HashMap<String, PathBuf> = HashMap::new();
+        for (file, repos) in sources {
+            for repo in repos {
+                if let Some(name) = &repo.name {
+                    if let Some(origin) = names.get(name) {
+                        bail!(
+                            "Repository name '{}' is used in both {:?} and {:?}",
+                            name,
+                            origin,
+                            file
+                        );
+                    }
+                    names.insert(name.clone(), file.clone());
+                }
+                for dest in &repo.dests {
+                    if let Some(origin) = origins.get(dest) {
+                        bail!(
+                            "Destination '{}' is configured in both {:?} and {:?}",
+                            dest,
+                            origin,
+                            file
+                        );
+                    }
+                    origins.insert(dest.clone(), file.clone());
+                }
+                configs.repos.push(repo);
+            }
+        }
+
+        Ok(configs)
+    }
+
+    /// Restrict tags to the given values for this run.
+    ///
+    /// Tags a repository doesn't define are ignored, and values that aren't configured for a
+    /// repository are dropped with a warning. Zipped tags are restricted a row at a time, so the
+    /// remaining values still pair up as they were configured.
+    pub fn restrict_tags(&mut self, overrides: &HashMap<String, Vec<String>>) {
+        for repo in &mut self.repos {
+            let label = repo.name.as_ref().unwrap_or(&repo.src);
+            for (tag, values) in overrides {
+                if let Some(configured) = repo.tags.get(tag) {
+                    for value in values {
+                        if !configured.contains(value) {
+                            warn!("Tag '${}' of '{}' has no value '{}'", tag, label, value);
+                        }
+                    }
+                }
+            }
+
+            let mut groups: Vec<Vec<String>> = repo.zip_tags.clone();
+            for tag in repo.tags.keys() {
+                if !groups.iter().any(|group| group.contains(tag)) {
+                    groups.push(vec![tag.clone()]);
+                }
+            }
+            for group in groups {
+                let rows = group
+                    .iter()
+                    .filter_map(|tag| repo.tags.get(tag))
+                    .map(Vec::len)
+                    .min()
+                    .unwrap_or(0);
+                let keep: Vec<bool> = (0..rows)
+                    .map(|row| {
+                        group
+                            .iter()
+                            .all(|tag| match (overrides.get(tag), repo.tags.get(tag)) {
+                                (Some(allowed), Some(values)) => allowed.contains(&values[row]),
+                                _ => true,
+                            })
+                    })
+                    .collect();
+                for tag in &group {
+                    if let Some(values) = repo.tags.get_mut(tag) {
+                        *values = values
+                            .iter()
+                            .zip(&keep)
+                            .filter(|(_, keep)| **keep)
+                            .map(|(value, _)| value.clone())
+                            .collect();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Order the repositories from highest to lowest priority.
+    ///
+    /// Repositories of the same priority keep the order they were configured in.
+    pub fn sort_by_priority(&mut self) {
+        self.repos.sort_by_key(|repo| Reverse(repo.priority()));
+    }
+
+    /// Keep only the repositories with names matching at least one of the glob patterns.
+    ///
+    /// If no patterns are given, every repository is kept.
+    pub fn select(&mut self, patterns: &[String]) -> Result<()> {
+        if patterns.is_empty() {
+            return Ok(());
+        }
+
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                Pattern::new(p)
+                    .map_err(|e| format_err!("Invalid repository pattern '{}': {}", p, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for pattern in &patterns {
+            let matched = self
+                .repos
+                .iter()
+                .filter_map(|r| r.name())
+                .any(|name| pattern.matches(name));
+            if !matched {
+                warn!("No repository matches '{}'", pattern);
+            }
+        }
+
+        self.repos.retain(|repo| match repo.name() {
+            Some(name) => patterns.iter().any(|p| p.matches(name)),
+            None => false,
+        });
+
+        Ok(())
+    }
+}
+
+/// Expand included paths into a list of files, reading directories in file name order.
+fn expand_includes(includes: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for include in includes {
+        if include.is_dir() {
+            let mut entries = Vec::new();
+            for entry in std::fs::read_dir(include)? {
+                let entry = entry?.path();
+                let extension = entry.extension().and_then(|e| e.to_str());
+                if entry.is_file() && matches!(extension, Some("toml" | "yaml" | "yml" | "json")) {
+                    entries.push(entry);
+                }
+            }
+            entries.sort();
+            files.append(&mut entries);
+        } else if include.is_file() {
+            files.push(include.clone());
+        } else {
+            bail!("Included configuration {:?} does not exist", include);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Configuration for a single repository to clone.
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    src: String,
-    dest: String,
+    /// Name used to select the repository on the command line.
     #[serde(default)]
+    name: Option<String>,
+    src: String,
+    /// Where to clone the repository.
+    ///
+    /// When several destinations are given, only the first is synchronised with the source and
+    /// the rest are replicated from it.
+    #[serde(rename = "dest", deserialize_with = "deserialize_dests")]
+    dests: Vec<String>,
+    /// Values for each tag, where `start..end` and `start..=end` expand to a range of integers.
+    #[serde(default, deserialize_with = "deserialize_tags")]
     tags: HashMap<String, Vec<String>>,
+    /// Combinations of tag values that should not be synchronised.
+    #[serde(default)]
+    exclude_combinations: Vec<TagRule>,
+    /// Groups of tags whose values advance together instead of being combined.
+    #[serde(default)]
+    zip_tags: Vec<Vec<String>>,
+    /// Values used in place of tag values in the destination.
+    #[serde(default)]
+    dest_values: ValueMap,
+    /// Transforms applied to tag values in the destination.
+    #[serde(default)]
+    dest_transforms: HashMap<String, Transform>,
+    /// Username for HTTP basic authentication with the source.
+    #[serde(default)]
+    username: Option<String>,
+    /// Password for HTTP basic authentication with the source.
+    #[serde(default)]
+    password: Option<Secret>,
+    /// A command that prints the password, such as a password manager.
+    #[serde(default)]
+    password_cmd: Option<String>,
+    /// A file containing the password.
+    #[serde(default)]
+    password_file: Option<String>,
+    /// The account that the password is stored under in the system keyring.
+    #[serde(default)]
+    password_keyring: Option<String>,
+    /// The password read from outside of the configuration, once it was first needed.
+    #[serde(skip)]
+    read_password: OnceLock<Secret>,
+    /// Proxy URL used for all requests to the source.
+    #[serde(default)]
+    proxy: Option<String>,
+    /// Directory to keep metadata in between runs, instead of a temporary directory.
+    ///
+    /// Each variant is cached in a subdirectory named after its destination.
+    #[serde(default)]
+    cache_dir: Option<String>,
+    /// Directory to stage metadata in before it is published.
+    ///
+    /// This should be on the same filesystem as the destination so that publishing is cheap. By
+    /// default, a directory within the destination is used.
+    #[serde(default)]
+    staging_dir: Option<String>,
+    /// The number of previous generations of metadata to keep when publishing new metadata.
+    ///
+    /// Clients that fetched an older index can still download the files it lists.
+    #[serde(default)]
+    retain_metadata: usize,
+    /// Glob patterns for paths in the destination that are never cleaned, along with everything
+    /// below them (such as `images` or `LiveOS` in installer trees).
+    #[serde(default)]
+    clean_exclude: Vec<String>,
+    /// Also mirror the installer files listed in the `.treeinfo` file of the source.
+    #[serde(default)]
+    treeinfo: bool,
+    /// Types of metadata to leave out of the mirror, such as `appstream` and `appstream-icons`.
+    #[serde(default)]
+    exclude_metadata: Vec<String>,
+    /// The local key used to sign the metadata index whenever it is rewritten.
+    #[serde(default)]
+    signing: Option<Signing>,
+    /// The manifest of every published file written to each destination.
+    #[serde(default)]
+    manifest: Manifest,
+    /// How clients reach the mirror, to write a `.repo` file for them to each destination.
+    #[serde(default)]
+    clients: Option<Clients>,
+    /// Only synchronise metadata (and any prefetched packages), leaving packages to be fetched by
+    /// `serve` when first requested.
+    #[serde(default)]
+    lazy: bool,
+    /// Whether packages are mirrored, or only the metadata.
+    #[serde(default = "default_true")]
+    packages: bool,
+    /// Packages to download first, named by access logs or package lists.
+    #[serde(default)]
+    prefetch: Option<Prefetch>,
+    /// A command run on each downloaded package before it is put in place, such as a virus
+    /// scan. Packages it rejects are quarantined.
+    #[serde(default)]
+    file_hook: Option<String>,
+    /// Directory to quarantine rejected files in, instead of a directory within the destination.
+    ///
+    /// Each variant is quarantined in a subdirectory named after its destination.
+    #[serde(default)]
+    quarantine_dir: Option<String>,
+    /// Fixed addresses for upstream hosts, as `host:port:address[,address...]`.
+    #[serde(default)]
+    resolve: Vec<String>,
+    /// The DNS server used to look up upstream hosts instead of the system resolver.
+    ///
+    /// It is only queried over UDP, so lookups whose answers are too large to fit fail rather
+    /// than being retried over TCP.
+    #[serde(default)]
+    nameserver: Option<String>,
+    /// Time limits on connecting and downloading, overriding the defaults of the main file.
+    #[serde(default)]
+    timeouts: Timeouts,
+    /// The IP version used to connect to upstream hosts.
+    #[serde(default)]
+    ip_family: IpFamily,
+    /// Hashes of the certificates or public keys that upstream servers may present.
+    #[serde(default)]
+    tls_pins: Vec<String>,
+    /// Whether the upstream and its mirrors are ranked by speed, preferring the fastest.
+    #[serde(default)]
+    rank_mirrors: bool,
+    /// How often mirrors are ranked again during a long synchronisation.
+    #[serde(default, deserialize_with = "units::deserialize_duration")]
+    rank_interval: Option<Duration>,
+    /// How long to wait before synchronising again when files referenced by the upstream
+    /// metadata are missing or don't match, as happens while the upstream is being updated.
+    #[serde(default, deserialize_with = "units::deserialize_duration")]
+    inconsistency_retry: Option<Duration>,
+    /// What to do about packages that are missing upstream.
+    #[serde(default)]
+    on_missing: OnMissing,
+    /// The order in which files are downloaded.
+    #[serde(default)]
+    download_order: DownloadOrder,
+    /// Glob patterns of the names of packages downloaded before any others, so that the most
+    /// important updates land first if a run is interrupted or limited.
+    #[serde(default)]
+    critical: Vec<String>,
+    /// How existing files are checked, unless given on the command line.
+    #[serde(default, alias = "check")]
+    check_mode: Option<CheckType>,
+    /// The weakest digest algorithm accepted for checksums in the upstream metadata.
+    #[serde(default, deserialize_with = "deserialize_digest")]
+    minimum_digest: Option<String>,
+    /// What to do about checksums weaker than `minimum_digest`.
+    #[serde(default)]
+    on_weak_digest: OnWeakDigest,
+    /// The oldest the upstream metadata may be, judged by the timestamps in its index.
+    #[serde(default, deserialize_with = "units::deserialize_duration")]
+    max_metadata_age: Option<Duration>,
+    /// What to do when the upstream metadata is older than `max_metadata_age`.
+    #[serde(default)]
+    on_stale: OnStale,
+    /// The URL or path of the key that must have signed the upstream metadata index.
+    #[serde(default)]
+    gpgkey: Option<String>,
+    /// The fingerprints that the key from `gpgkey` is pinned to, so that a different key can't
+    /// be substituted for it.
+    #[serde(default)]
+    gpgkey_fingerprints: Vec<String>,
+    /// Commands run before and after synchronising.
+    #[serde(flatten)]
+    hooks: Hooks,
+    /// Other sources of the same repository, using the same tags, that packages failing their
+    /// checks are downloaded from instead.
+    #[serde(default)]
+    mirrors: Vec<String>,
+    /// How many times a package that fails its checks is downloaded again before it is given up
+    /// on.
+    #[serde(default = "default_retries")]
+    retries: usize,
+    /// Whether the repository is synchronised at all.
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// Treat an unreachable source as a warning rather than an error.
+    ///
+    /// Metadata that can't be decoded is still an error, as the source is reachable but broken.
+    #[serde(default)]
+    skip_if_unavailable: bool,
+    /// The most bytes to download from the source each calendar month, metadata included.
+    #[serde(default, deserialize_with = "units::deserialize_size")]
+    monthly_quota: Option<u64>,
+    /// Repositories with a higher priority are synchronised first, and when watching, pause the
+    /// downloads of lower priorities.
+    #[serde(default)]
+    priority: i32,
+    /// The number of variants synchronised at once.
+    #[serde(default = "default_parallel_variants")]
+    parallel_variants: usize,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_retries() -> usize {
+    2
+}
+
+fn default_parallel_variants() -> usize {
+    1
+}
+
+/// The values given for a tag in the configuration.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TagValues {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Read the tags of a repository, expanding any numeric ranges.
+fn deserialize_tags<'de, D>(
+    deserializer: D,
+) -> ::std::result::Result<HashMap<String, Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let tags: HashMap<String, TagValues> = HashMap::deserialize(deserializer)?;
+    tags.into_iter()
+        .map(|(tag, values)| {
+            let values = match values {
+                TagValues::One(value) => vec![value],
+                TagValues::Many(values) => values,
+            };
+            let mut expanded = Vec::new();
+            for value in values {
+                match expand_range(&value) {
+                    Ok(Some(range)) => expanded.extend(range),
+                    Ok(None) => expanded.push(value),
+                    Err(e) => {
+                        return Err(serde::de::Error::custom(format!("Tag '{}': {}", tag, e)))
+                    }
+                }
+            }
+            Ok((tag, expanded))
+        })
+        .collect()
+}
+
+/// Read one or more destinations.
+fn deserialize_dests<'de, D>(deserializer: D) -> ::std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let dests = match TagValues::deserialize(deserializer)? {
+        TagValues::One(dest) => vec![dest],
+        TagValues::Many(dests) => dests,
+    };
+    if dests.is_empty() {
+        return Err(serde::de::Error::custom(
+            "At least one destination is required",
+        ));
+    }
+    Ok(dests)
+}
+
+/// Deserialize the name of a digest algorithm, which must be known so that its strength can be
+/// compared with others.
+fn deserialize_digest<'de, D>(deserializer: D) -> ::std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let algorithm = String::deserialize(deserializer)?;
+    if hash::strength(&algorithm).is_none() {
+        return Err(serde::de::Error::custom(format!(
+            "Unknown minimum digest '{}'",
+            algorithm
+        )));
+    }
+    Ok(Some(algorithm))
+}
+
+/// The outcome of synchronising a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every variant was synchronised.
+    Synced,
+    /// At least one variant was skipped because its source was unavailable.
+    Unavailable,
+}
+
+/// A variant of a repository along with its replicas, mirrors and key.
+struct Job {
+    variant: Variant,
+    /// Where the destination of the variant is replicated to.
+    replicas: Vec<String>,
+    /// Other locations of the upstream of the variant.
+    mirrors: Vec<String>,
+    /// The key that must have signed the upstream index.
+    verification: Option<Verification>,
+    /// The `.repo` file written for clients once the variant is published.
+    repo_file: Option<RepoFile>,
+}
+
+impl Job {
+    /// Replicate the destination of the variant to each of its replicas.
+    ///
+    /// The run of a shard leaves the replicas alone, as it only downloads part of the packages
+    /// and replicating it would remove the rest.
+    async fn replicate(&self, shard: Option<Shard>) -> Result<()> {
+        if let Some(shard) = shard {
+            debug!(
+                "Leaving the replicas of '{}' for a run without shards, as this is shard {}",
+                self.variant.dst, shard
+            );
+            return Ok(());
+        }
+        for replica in &self.replicas {
+            info!("Replicating '{}' to '{}'", self.variant.dst, replica);
+            replicate(Path::new(&self.variant.dst), Path::new(replica)).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the `.repo` file for clients of the variant, publishing the upstream key with it.
+    async fn write_repo_file(&self, client: &Client) -> Result<()> {
+        if let Some(repo_file) = &self.repo_file {
+            // The key was fetched to verify the metadata, unless the variant was up to date
+            let key = match &self.verification {
+                Some(verification) => match verification.fetch_key(client).await {
+                    Ok(key) => Some(key),
+                    Err(e) => {
+                        warn!(
+                            "Could not fetch the key of '{}' to publish it: {}",
+                            self.variant.dst, e
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            repo_file
+                .write(Path::new(&self.variant.dst), key.as_deref())
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 impl Config {
-    pub async fn sync(&self, check: CheckType) -> Result<()> {
-        let url_pairs = UrlMux::new(&self.src, &self.dest, &self.tags);
+    /// Synchronise every variant of the repository.
+    ///
+    /// The changes made are counted in `stats`. If the upstream was inconsistent and
+    /// `inconsistency_retry` is set, the repository is synchronised once more after that delay.
+    /// If `retry_missing`, variants are synchronised even if their upstream hasn't changed, so
+    /// that packages that were missing upstream before are downloaded.
+    pub async fn sync(
+        &self,
+        check: CheckType,
+        retry_missing: bool,
+        stats: &Stats,
+    ) -> Result<Outcome> {
+        package::with_order(
+            self.download_order,
+            self.critical()?,
+            self.sync_retrying(check, retry_missing, stats),
+        )
+        .await
+    }
+
+    /// Synchronise every variant of the repository, once more if the upstream was inconsistent.
+    async fn sync_retrying(
+        &self,
+        check: CheckType,
+        retry_missing: bool,
+        stats: &Stats,
+    ) -> Result<Outcome> {
+        let result = self.sync_variants(check, retry_missing, stats).await;
+        match (result, self.inconsistency_retry) {
+            (Err(err), Some(delay)) if err.downcast_ref::<Inconsistent>().is_some() => {
+                warn!(
+                    "{} in '{}', synchronising again in {}",
+                    err,
+                    self.label(),
+                    humantime::format_duration(delay)
+                );
+                delay_for(delay).await;
+                stats.clear_inconsistencies();
+                self.sync_variants(check, retry_missing, stats).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Pair each variant of the repository with its replicas, mirrors and key.
+    fn jobs(&self) -> Vec<Job> {
+        let url_pairs = self.url_pairs();
+        let mut replicas: Vec<_> = self.dests[1..]
+            .iter()
+            .map(|dest| self.url_pairs_to(dest))
+            .collect();
+        let mut mirrors: Vec<_> = self
+            .mirrors
+            .iter()
+            .map(|src| self.url_pairs_from(src, self.dest()))
+            .collect();
+        let mut keys = self
+            .gpgkey
+            .as_ref()
+            .map(|key| self.url_pairs_from(key, self.dest()));
+        let mut client_urls = self
+            .clients
+            .as_ref()
+            .map(|clients| self.url_pairs_from(&self.src, &clients.url));
+        let mut client_names = self
+            .clients
+            .as_ref()
+            .and_then(|clients| clients.name.as_ref())
+            .map(|name| self.url_pairs_from(&self.src, name));
 
+        let mut jobs = Vec::new();
+        for variant in url_pairs.variants() {
+            let replica_dests: Vec<String> = replicas
+                .iter_mut()
+                .filter_map(|pairs| pairs.next())
+                .map(|(_, replica)| replica)
+                .collect();
+            let mirror_srcs: Vec<String> = mirrors
+                .iter_mut()
+                .filter_map(|pairs| pairs.next())
+                .map(|(mirror, _)| mirror)
+                .collect();
+            let verification = keys
+                .as_mut()
+                .and_then(|keys| keys.next())
+                .map(|(key, _)| Verification::new(key, self.gpgkey_fingerprints.clone()));
+            let baseurl = client_urls
+                .as_mut()
+                .and_then(|urls| urls.next())
+                .map(|(_, url)| url);
+            let name = client_names
+                .as_mut()
+                .and_then(|names| names.next())
+                .map(|(_, name)| name);
+            let repo_file = self
+                .clients
+                .as_ref()
+                .zip(baseurl)
+                .map(|(clients, baseurl)| {
+                    // Tags are named in the order they appear in the destination
+                    let mut order: Vec<&str> = Vec::new();
+                    for tag in tag_names(&self.dests[0])
+                        .into_iter()
+                        .chain(variant.tags.keys().map(String::as_str))
+                    {
+                        if !order.contains(&tag) {
+                            order.push(tag);
+                        }
+                    }
+                    let values = order
+                        .into_iter()
+                        .filter_map(|tag| variant.tags.get(tag))
+                        .map(String::as_str);
+                    RepoFile {
+                        id: repofile::id(self.name().unwrap_or("mirror"), values),
+                        name: name.unwrap_or_else(|| {
+                            format!("{} {}", self.label(), variant)
+                                .trim_end()
+                                .to_owned()
+                        }),
+                        baseurl,
+                        gpgcheck: clients.gpgcheck,
+                        repo_gpgcheck: self.signing.is_some(),
+                        key: verification.is_some(),
+                    }
+                });
+            jobs.push(Job {
+                variant,
+                replicas: replica_dests,
+                mirrors: mirror_srcs,
+                verification,
+                repo_file,
+            });
+        }
+        jobs
+    }
+
+    /// Synchronise every variant of the repository once.
+    async fn sync_variants(
+        &self,
+        check: CheckType,
+        retry_missing: bool,
+        stats: &Stats,
+    ) -> Result<Outcome> {
         // Use a shared connection for each repo
-        let client = Client::builder()
-            .timeout(Duration::from_secs(600))
-            .gzip(false)
-            .build()?;
+        let client = self.client()?;
 
-        // Enumerate Variants
-        for (src, dest) in url_pairs {
-            info!("Syncing '{}' to '{}'", src, dest);
+        // Pair each variant with its replicas, mirrors and key before any are synchronised
+        let jobs = self.jobs();
+        let variants = jobs.len();
 
-            if let Err(err) = self.sync_pair(&client, (&src, &dest), check).await {
-                debug!("Error Backtrace:\n{:?}", err.backtrace());
-                warn!("Error: {}", err);
+        let client = &client;
+        let results = jobs.into_iter().map(|job| {
+            logging::scope(self.label(), Some(job.variant.to_string()), async move {
+                let (src, dest) = (&job.variant.src, &job.variant.dst);
+                info!("Syncing '{}' to '{}'", src, dest);
+                let mut result = self
+                    .sync_pair(client, &job, check, retry_missing, stats)
+                    .await;
+                if let (Ok(Outcome::Synced), None) = (&result, shard::current()) {
+                    result = job.write_repo_file(client).await.map(|()| Outcome::Synced);
+                }
+                if let Ok(Outcome::Synced) = result {
+                    result = job
+                        .replicate(shard::current())
+                        .await
+                        .map(|()| Outcome::Synced);
+                }
+                if let Err(err) = &result {
+                    debug!("Error Backtrace:\n{:?}", err.backtrace());
+                    warn!("Error: {}", err);
+                }
+                result
+            })
+        });
+        // Variants share the client and the limits on bandwidth and concurrent downloads
+        let mut results = stream::iter(results).buffer_unordered(self.parallel_variants.max(1));
+
+        let mut outcome = Outcome::Synced;
+        let mut failures = 0;
+        let mut inconsistent = 0;
+        let mut inconsistent_files = 0;
+        let mut missing_files = 0;
+        while let Some(result) = results.next().await {
+            match result {
+                Ok(Outcome::Synced) => {}
+                Ok(Outcome::Unavailable) => outcome = Outcome::Unavailable,
+                Err(err) if err.downcast_ref::<LimitReached>().is_some() => return Err(err),
+                Err(err) => {
+                    if let Some(err) = err.downcast_ref::<Inconsistent>() {
+                        inconsistent += 1;
+                        inconsistent_files += err.files;
+                        missing_files += err.missing;
+                    }
+                    failures += 1;
+                }
             }
         }
 
+        // Only an upstream that is inconsistent throughout is worth synchronising again
+        if failures > 0 && failures == inconsistent {
+            return Err(Inconsistent {
+                files: inconsistent_files,
+                missing: missing_files,
+            }
+            .into());
+        }
+        if failures > 0 {
+            bail!("{} of {} variants failed", failures, variants);
+        }
+
+        Ok(outcome)
+    }
+
+    /// Fingerprint the upstream metadata of every variant, by source URL.
+    ///
+    /// Only the metadata index of each variant is downloaded.
+    pub async fn upstream(&self) -> Result<BTreeMap<String, String>> {
+        let client = self.client()?;
+        let mut fingerprints = BTreeMap::new();
+        for (src, _) in self.url_pairs() {
+            let fingerprint = Mirror::fingerprint(&client, &src).await?;
+            fingerprints.insert(src, fingerprint);
+        }
+        Ok(fingerprints)
+    }
+
+    /// Find the files in every variant's destination that aren't referenced by its metadata.
+    ///
+    /// Nothing is removed. Destinations that haven't been synchronised yet are skipped.
+    pub async fn orphans(&self) -> Result<Vec<Orphan>> {
+        let exclude = self.clean_exclude()?;
+        let mut orphans = Vec::new();
+        for (_, dest) in self.url_pairs() {
+            if let Some(local) = Mirror::local(&dest).await? {
+                orphans.extend(
+                    local
+                        .orphans(&exclude)
+                        .await?
+                        .into_iter()
+                        .map(|orphan| Orphan {
+                            path: Path::new(&dest).join(orphan.path),
+                            ..orphan
+                        }),
+                );
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Describe the packages mirrored to every variant's destination.
+    ///
+    /// Destinations that haven't been synchronised yet are skipped.
+    pub async fn listed(&self) -> Result<Vec<Listed>> {
+        let mut listed = Vec::new();
+        for (_, dest) in self.url_pairs() {
+            if let Some(local) = Mirror::local(&dest).await? {
+                for mut package in local.listed(self.label()).await? {
+                    package.dest = dest.clone();
+                    listed.push(package);
+                }
+            }
+        }
+        Ok(listed)
+    }
+
+    /// Find the packages providing files that match a pattern in every variant's destination,
+    /// along with the destination.
+    ///
+    /// Destinations that haven't been synchronised yet are skipped.
+    pub async fn providers(&self, pattern: &Pattern) -> Result<Vec<(String, Provider)>> {
+        let mut providers = Vec::new();
+        for (_, dest) in self.url_pairs() {
+            if let Some(local) = Mirror::local(&dest).await? {
+                let found = local
+                    .providers(pattern)
+                    .await
+                    .map_err(|e| format_err!("Could not search '{}': {}", dest, e))?;
+                providers.extend(found.into_iter().map(|p| (dest.clone(), p)));
+            }
+        }
+        Ok(providers)
+    }
+
+    /// The variants of a lazily mirrored repository, from which missing packages are fetched on
+    /// demand.
+    pub fn upstreams(&self) -> Result<Vec<Upstream>> {
+        if !self.lazy || !self.enabled {
+            return Ok(Vec::new());
+        }
+        let client = self.client()?;
+        self.url_pairs()
+            .map(|(src, dest)| {
+                let vetting = self.vetting(&dest);
+                Ok(Upstream::new(
+                    href::base(&src)?,
+                    dest.into(),
+                    client.clone(),
+                    vetting,
+                ))
+            })
+            .collect()
+    }
+
+    /// The patterns of the names of packages downloaded before any others.
+    fn critical(&self) -> Result<Vec<Pattern>> {
+        self.critical
+            .iter()
+            .map(|pattern| {
+                Pattern::new(pattern).map_err(|e| {
+                    format_err!("Invalid critical package pattern '{}': {}", pattern, e)
+                })
+            })
+            .collect()
+    }
+
+    /// The patterns of paths excluded from cleaning.
+    fn clean_exclude(&self) -> Result<Vec<Pattern>> {
+        let mut exclude = self
+            .clean_exclude
+            .iter()
+            .map(|pattern| {
+                Pattern::new(pattern.trim_end_matches('/')).map_err(|e| {
+                    format_err!("Invalid clean exclusion pattern '{}': {}", pattern, e)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(name) = self.manifest.file_name() {
+            exclude.push(Pattern::new(name)?);
+        }
+        // The files written for clients aren't referenced by the metadata
+        for repo_file in self.jobs().into_iter().filter_map(|job| job.repo_file) {
+            for name in [repo_file.file_name(), repo_file.key_name()] {
+                exclude.push(Pattern::new(&Pattern::escape(&name))?);
+            }
+        }
+        Ok(exclude)
+    }
+
+    /// Expand the source and destination for every combination of tags.
+    fn url_pairs(&self) -> UrlMux<'_, '_, '_> {
+        self.url_pairs_to(self.dest())
+    }
+
+    /// Expand the source and a destination pattern for every combination of tags.
+    fn url_pairs_to<'c>(&'c self, dest: &'c str) -> UrlMux<'c, 'c, 'c> {
+        self.url_pairs_from(&self.src, dest)
+    }
+
+    /// Expand a source pattern and a destination pattern for every combination of tags.
+    fn url_pairs_from<'c>(&'c self, src: &'c str, dest: &'c str) -> UrlMux<'c, 'c, 'c> {
+        UrlMux::new(src, dest, &self.tags)
+            .zip(&self.zip_tags)
+            .exclude(&self.exclude_combinations)
+            .rewrite_dst(&self.dest_values, &self.dest_transforms)
+    }
+
+    /// Whether the repository should be synchronised.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether only the metadata is synchronised, leaving packages alone.
+    fn metadata_only(&self) -> bool {
+        !self.packages || (self.lazy && self.prefetch.is_none())
+    }
+
+    /// The priority of the repository, where higher priorities are synchronised first.
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// What to do about packages that are missing upstream.
+    pub fn on_missing(&self) -> OnMissing {
+        self.on_missing
+    }
+
+    /// How existing files are checked, if set for the repository.
+    pub fn check_mode(&self) -> Option<CheckType> {
+        self.check_mode
+    }
+
+    /// The time limits on transfers from the repository.
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// The commands run before and after synchronising.
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// The environment variables describing the repository to its hooks.
+    ///
+    /// `YUMCLONE_DESTINATIONS` lists the destination of every variant, one per line.
+    pub fn hook_env(&self) -> hooks::Environment {
+        let dests: Vec<String> = self.url_pairs().map(|(_, dest)| dest).collect();
+        vec![
+            ("YUMCLONE_REPO", self.label().to_owned()),
+            ("YUMCLONE_SRC", self.src.clone()),
+            ("YUMCLONE_DEST", self.dest().to_owned()),
+            ("YUMCLONE_DESTINATIONS", dests.join("\n")),
+        ]
+    }
+
+    /// The most bytes to download each month, if limited.
+    pub fn monthly_quota(&self) -> Option<u64> {
+        self.monthly_quota
+    }
+
+    /// The destination pattern synchronised with the source.
+    fn dest(&self) -> &str {
+        &self.dests[0]
+    }
+
+    /// The source URL pattern of the repository.
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// The name of the repository, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// A human readable label for the repository.
+    pub fn label(&self) -> &str {
+        self.name().unwrap_or(&self.src)
+    }
+
+    /// Describe every tag used in the source or destination that has no definition.
+    pub fn unresolved_tags(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let clients = self
+            .clients
+            .iter()
+            .flat_map(|clients| std::iter::once(&clients.url).chain(clients.name.as_ref()));
+        for url in std::iter::once(&self.src).chain(&self.dests).chain(clients) {
+            let mut seen = BTreeSet::new();
+            for tag in tag_names(url) {
+                if !self.tags.contains_key(tag) && seen.insert(tag) {
+                    problems.push(format!("Tag '${}' in '{}' is not defined", tag, url));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Describe every destination that more than one variant of the repository would write to.
+    pub fn dest_collisions(&self) -> Vec<String> {
+        collisions(self.url_pairs())
+            .into_iter()
+            .map(|(dest, srcs)| {
+                format!(
+                    "Destination '{}' is shared by {}",
+                    dest,
+                    srcs.iter()
+                        .map(|src| format!("'{}'", src))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+            .collect()
+    }
+
+    /// Describe problems with the selection of metadata to mirror.
+    fn metadata_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.exclude_metadata.iter().any(|t| t == "primary") {
+            problems.push("Primary metadata can't be excluded".to_owned());
+        }
+        if !self.gpgkey_fingerprints.is_empty() && self.gpgkey.is_none() {
+            problems.push("Key fingerprints are pinned without a gpgkey".to_owned());
+        }
+        for fingerprint in &self.gpgkey_fingerprints {
+            let normalised = sign::normalise(fingerprint);
+            let hex = normalised.chars().all(|c| c.is_ascii_hexdigit());
+            if !hex || !matches!(normalised.len(), 40 | 64) {
+                problems.push(format!("Invalid key fingerprint '{}'", fingerprint));
+            }
+        }
+        if let Err(err) = Pins::parse(&self.tls_pins) {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = Resolver::new(&self.resolve, self.nameserver.as_deref()) {
+            problems.push(err.to_string());
+        }
+        let resolving = !self.resolve.is_empty() || self.nameserver.is_some();
+        if resolving && self.proxy.is_some() {
+            problems.push("Hosts can't be resolved locally when using a proxy".to_owned());
+        }
+        if self.on_stale == OnStale::FallBack && self.mirrors.is_empty() {
+            problems.push("Stale metadata can't fall back without any mirrors".to_owned());
+        }
+        if !self.packages && self.prefetch.is_some() {
+            problems.push("Packages can't be prefetched when they aren't mirrored".to_owned());
+        }
+        problems
+    }
+
+    /// Check the repository for problems that would prevent it from being synchronised.
+    ///
+    /// This does not use the network, so it is run before anything is synchronised.
+    pub fn preflight(&self) -> Vec<String> {
+        let mut problems = self.unresolved_tags();
+        if !problems.is_empty() {
+            return problems;
+        }
+        problems.extend(self.dest_collisions());
+        problems.extend(self.metadata_problems());
+        problems
+    }
+
+    /// Expand `${VAR}` references to environment variables in the repository settings.
+    fn interpolate_env(&mut self) -> Result<()> {
+        self.src = interpolate_env(&self.src)?;
+        for dest in &mut self.dests {
+            *dest = interpolate_env(dest)?;
+        }
+        if let Some(key) = &mut self.gpgkey {
+            *key = interpolate_env(key)?;
+        }
+        for mirror in &mut self.mirrors {
+            *mirror = interpolate_env(mirror)?;
+        }
+        if let Some(password) = &mut self.password {
+            *password = password.map(interpolate_env)?;
+        }
+        for value in [
+            &mut self.username,
+            &mut self.password_file,
+            &mut self.proxy,
+            &mut self.cache_dir,
+            &mut self.staging_dir,
+            &mut self.quarantine_dir,
+        ]
+        .iter_mut()
+        .filter_map(|v| v.as_mut())
+        {
+            *value = interpolate_env(value)?;
+        }
         Ok(())
     }
 
-    async fn sync_pair(&self, client: &Client, pair: (&str, &str), check: CheckType) -> Result<()> {
-        let (src, dest) = pair;
-        let remote = Mirror::remote(&client, &src).await?;
+    /// Where the password is kept outside of the configuration, if anywhere.
+    fn password_source(&self) -> Result<Option<Source>> {
+        let source = Source::from_options(
+            "password",
+            self.password_cmd.as_deref(),
+            self.password_file.as_deref(),
+            self.password_keyring.as_deref(),
+        )?;
+        if let (Some(source), Some(_)) = (&source, &self.password) {
+            bail!("password can't be set along with {}", source);
+        }
+        Ok(source)
+    }
 
-        if let Some(local) = Mirror::local(&dest).await? {
-            if remote.same_version(&local) && check.remote_only() {
-                info!("Repository '{}' is up to date", dest);
-                return Ok(());
+    /// Check that the password is only kept in one place, without reading it.
+    fn check_secrets(&self) -> Result<()> {
+        self.password_source().map(|_| ())
+    }
+
+    /// The password, read from wherever it is kept the first time it is needed.
+    ///
+    /// Secrets are only read for the repositories that are actually used, so that a failing
+    /// password command doesn't stop every other repository from being used.
+    fn password(&self) -> Result<Option<&Secret>> {
+        let source = match self.password_source()? {
+            Some(source) => source,
+            None => return Ok(self.password.as_ref()),
+        };
+        if let Some(password) = self.read_password.get() {
+            return Ok(Some(password));
+        }
+        let password = source
+            .read()
+            .map_err(|e| format_err!("Could not read password of '{}': {}", self.label(), e))?;
+        Ok(Some(self.read_password.get_or_init(|| password)))
+    }
+
+    /// Create the HTTP client used to fetch the repository.
+    fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
+            .connect_timeout(self.timeouts.connect())
+            .gzip(false);
+        if let Some(file) = self.timeouts.file() {
+            builder = builder.timeout(file);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+
+        if !self.resolve.is_empty() || self.nameserver.is_some() {
+            let mut resolver = Resolver::new(&self.resolve, self.nameserver.as_deref())?;
+            // The proxy is reached over IPv4, so the family is restricted by the proxy instead.
+            resolver.restrict_to(self.ip_family);
+            builder = builder.proxy(Proxy::all(resolver.proxy()?.as_str())?);
+        } else if let Some(local) = self.ip_family.local_address() {
+            builder = builder.local_address(local);
+        }
+
+        if !self.tls_pins.is_empty() {
+            let pins = Pins::parse(&self.tls_pins)?;
+            builder = tls::pin(builder, pins);
+        }
+
+        if let Some(username) = &self.username {
+            let password = self.password()?.map_or("", Secret::expose);
+            let credentials = base64::encode(format!("{}:{}", username, password));
+            let mut authorization = HeaderValue::from_str(&format!("Basic {}", credentials))?;
+            authorization.set_sensitive(true);
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, authorization);
+            builder = builder.default_headers(headers);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// Check that the repository can be synchronised: that its configuration is valid, that
+    /// each upstream can be reached, and that each destination can be written to.
+    pub async fn diagnose(&self) -> Vec<Finding> {
+        let label = self.label();
+        let mut findings: Vec<Finding> = self
+            .validate()
+            .into_iter()
+            .map(|problem| Finding::error(label, format!("{}; fix the configuration", problem)))
+            .collect();
+        if !findings.is_empty() {
+            return findings;
+        }
+        let client = match self.client() {
+            Ok(client) => client,
+            Err(e) => {
+                findings.push(Finding::error(
+                    label,
+                    format!(
+                        "Could not set up the HTTP client: {}; check `proxy`, `resolve` and \
+                         `tls_pins`",
+                        e
+                    ),
+                ));
+                return findings;
+            }
+        };
+        if let Some(proxy) = &self.proxy {
+            findings.push(Finding::ok(
+                label,
+                format!("Upstreams are reached through {}", doctor::redact(proxy)),
+            ));
+        }
+        for job in self.jobs() {
+            findings.extend(doctor::upstream(&client, label, &job.variant.src).await);
+            for mirror in &job.mirrors {
+                findings.extend(doctor::upstream(&client, label, mirror).await);
+            }
+            for dest in std::iter::once(&job.variant.dst).chain(&job.replicas) {
+                findings.extend(doctor::destination(label, Path::new(dest)));
+            }
+        }
+        findings
+    }
+
+    /// Check the configuration for problems without performing any network I/O.
+    ///
+    /// Returns a description of each problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut expandable = true;
+
+        let referenced: BTreeSet<&str> = tag_names(&self.src)
+            .into_iter()
+            .chain(self.dests.iter().flat_map(|dest| tag_names(dest)))
+            .collect();
+        let unresolved = self.unresolved_tags();
+        if !unresolved.is_empty() {
+            problems.extend(unresolved);
+            expandable = false;
+        }
+        for (tag, values) in &self.tags {
+            if !referenced.contains(tag.as_str()) {
+                problems.push(format!("Tag '${}' is defined but never used", tag));
+            }
+            if values.is_empty() {
+                problems.push(format!("Tag '${}' has no values", tag));
+                expandable = false;
+            }
+        }
+
+        for rule in &self.exclude_combinations {
+            for tag in rule.keys() {
+                if !self.tags.contains_key(tag) {
+                    problems.push(format!(
+                        "Excluded combination uses undefined tag '${}'",
+                        tag
+                    ));
+                }
+            }
+        }
+
+        for (tag, values) in &self.dest_values {
+            match self.tags.get(tag) {
+                Some(defined) => {
+                    for value in values.keys() {
+                        if !defined.contains(value) {
+                            problems.push(format!(
+                                "Destination value given for '{}', which is not a value of '${}'",
+                                value, tag
+                            ));
+                        }
+                    }
+                }
+                None => problems.push(format!(
+                    "Destination values given for undefined tag '${}'",
+                    tag
+                )),
+            }
+        }
+        for tag in self.dest_transforms.keys() {
+            if !self.tags.contains_key(tag) {
+                problems.push(format!(
+                    "Destination transform given for undefined tag '${}'",
+                    tag
+                ));
+            }
+        }
+
+        if let Err(e) = self.clean_exclude() {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = self.critical() {
+            problems.push(e.to_string());
+        }
+        problems.extend(self.metadata_problems());
+
+        let mut zipped = BTreeSet::new();
+        for group in &self.zip_tags {
+            let mut lengths = BTreeSet::new();
+            for tag in group {
+                if !zipped.insert(tag.as_str()) {
+                    problems.push(format!("Tag '${}' is zipped more than once", tag));
+                }
+                match self.tags.get(tag) {
+                    Some(values) => {
+                        lengths.insert(values.len());
+                    }
+                    None => problems.push(format!("Zipped tag '${}' is not defined", tag)),
+                }
+            }
+            if lengths.len() > 1 {
+                problems.push(format!(
+                    "Zipped tags {} have different numbers of values",
+                    group
+                        .iter()
+                        .map(|tag| format!("'${}'", tag))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+
+        if !expandable {
+            return problems;
+        }
+
+        problems.extend(self.dest_collisions());
+        for (src, dest) in self.url_pairs() {
+            if let Err(err) = Url::parse(&src) {
+                problems.push(format!("Invalid source URL '{}': {}", src, err));
+            }
+            if !writable(Path::new(&dest)) {
+                problems.push(format!("Destination '{}' is not writable", dest));
+            }
+        }
+        for replica in &self.dests[1..] {
+            for (_, dest) in self.url_pairs_to(replica) {
+                if !writable(Path::new(&dest)) {
+                    problems.push(format!("Replica '{}' is not writable", dest));
+                }
+            }
+        }
+
+        problems
+    }
+
+    async fn sync_pair(
+        &self,
+        client: &Client,
+        job: &Job,
+        check: CheckType,
+        retry_missing: bool,
+        stats: &Stats,
+    ) -> Result<Outcome> {
+        let (src, dest) = (job.variant.src.as_str(), job.variant.dst.as_str());
+        let verification = job.verification.clone();
+        let remote = match self
+            .prepare(client, (src, dest), &job.mirrors, verification)
+            .await?
+        {
+            Some(remote) => remote,
+            None => return Ok(Outcome::Unavailable),
+        };
+
+        let local = Mirror::local(dest).await?;
+        if let Some(local) = &local {
+            if remote.same_version(local) && check.remote_only() {
+                if !retry_missing {
+                    info!("Repository '{}' is up to date", dest);
+                    return Ok(Outcome::Synced);
+                }
+                info!(
+                    "Repository '{}' is up to date, but has packages to retry that were missing \
+                     upstream",
+                    dest
+                );
             }
         }
 
         info!("Downloading repo from '{}'", src);
-        let remote = remote.into_cache(client).await?;
-        remote.clone(client, &Path::new(&dest), check).await?;
-        if let Some(local) = Mirror::local(&dest).await? {
+        let cache_dir = self.cache_dir(dest);
+        let remote = remote
+            .into_cache(client, cache_dir.as_deref(), &self.staging(dest), stats)
+            .await?;
+        if let Some(shard) = shard::current() {
+            if !self.metadata_only() {
+                remote
+                    .download(client, Path::new(&dest), check, stats)
+                    .await?;
+            }
+            info!(
+                "Downloaded shard {} of '{}', leaving the metadata for a run without shards",
+                shard, dest
+            );
+            return Ok(Outcome::Synced);
+        }
+        let changes = remote.changes_since(local.as_ref()).await?;
+        if self.metadata_only() {
+            remote
+                .replace_metadata(Path::new(&dest), self.retain_metadata)
+                .await?;
+        } else {
+            remote
+                .clone(client, Path::new(&dest), check, self.retain_metadata, stats)
+                .await?;
+        }
+        self.published(dest, changes, stats);
+        if self.treeinfo {
+            info!("Downloading installer tree from '{}'", src);
+            treeinfo::sync(client, src, Path::new(dest), &self.vetting(dest), stats).await?;
+        }
+        if let Some(local) = Mirror::local(dest).await? {
             info!("Cleaning repo in '{}'", dest);
-            local.clean().await?;
+            local.clean(&self.clean_exclude()?, stats).await?;
+        }
+        self.finish_publishing(dest, stats).await?;
+
+        Ok(Outcome::Synced)
+    }
+
+    /// Plan the changes synchronising every variant of the repository would make.
+    ///
+    /// Unavailable variants are left out of the plan if they may be skipped.
+    pub async fn plan(&self, check: CheckType) -> Result<Vec<plan::Variant>> {
+        let client = &self.client()?;
+        let exclude = self.clean_exclude()?;
+        let mut planned = Vec::new();
+        for job in self.jobs() {
+            let (src, dest) = (&job.variant.src, &job.variant.dst);
+            info!("Planning '{}' to '{}'", src, dest);
+            let fingerprint = Mirror::fingerprint(client, src).await?;
+            let remote = match self
+                .prepare(client, (src, dest), &job.mirrors, job.verification)
+                .await?
+            {
+                Some(remote) => remote,
+                None => continue,
+            };
+            let cache_dir = self.cache_dir(dest);
+            let remote = remote
+                .into_cache(
+                    client,
+                    cache_dir.as_deref(),
+                    &self.staging(dest),
+                    &Stats::default(),
+                )
+                .await?;
+            let (downloads, removals) = remote
+                .plan(Path::new(dest), check, self.metadata_only(), &exclude)
+                .await?;
+            planned.push(plan::Variant {
+                repo: self.label().to_owned(),
+                src: src.clone(),
+                dest: dest.clone(),
+                fingerprint,
+                check,
+                downloads,
+                removals: removals
+                    .into_iter()
+                    .map(|orphan| Removal {
+                        path: orphan.path.to_string_lossy().into_owned(),
+                        size: orphan.size,
+                    })
+                    .collect(),
+            });
+        }
+        Ok(planned)
+    }
+
+    /// Check that every file referenced by the upstream metadata of each variant can be
+    /// retrieved, without downloading any packages.
+    ///
+    /// Returns the broken files of each variant.
+    pub async fn check_links(&self) -> Result<Vec<Checked>> {
+        let client = &self.client()?;
+        let mut checked = Vec::new();
+        for job in self.jobs() {
+            let (src, dest) = (&job.variant.src, &job.variant.dst);
+            info!("Checking the links of '{}'", src);
+            let remote = match self
+                .prepare(client, (src, dest), &job.mirrors, job.verification)
+                .await?
+            {
+                Some(remote) => remote,
+                None => continue,
+            };
+            let cache_dir = self.cache_dir(dest);
+            let remote = remote
+                .into_cache(
+                    client,
+                    cache_dir.as_deref(),
+                    &self.staging(dest),
+                    &Stats::default(),
+                )
+                .await?;
+            checked.push(Checked {
+                repo: self.label().to_owned(),
+                src: src.clone(),
+                broken: remote.check_links(client).await?,
+            });
+        }
+        Ok(checked)
+    }
+
+    /// Measure how quickly the upstream and mirrors of each variant serve a sample of its
+    /// packages, or the first `chunk` bytes of each.
+    pub async fn bench(&self, files: usize, chunk: Option<u64>) -> Result<Vec<Benchmark>> {
+        let client = &self.client()?;
+        let mut benchmarks = Vec::new();
+        for job in self.jobs() {
+            let (src, dest) = (&job.variant.src, &job.variant.dst);
+            let remote = match self
+                .prepare(client, (src, dest), &job.mirrors, job.verification)
+                .await?
+            {
+                Some(remote) => remote,
+                None => continue,
+            };
+            let cache_dir = self.cache_dir(dest);
+            let remote = remote
+                .into_cache(
+                    client,
+                    cache_dir.as_deref(),
+                    &self.staging(dest),
+                    &Stats::default(),
+                )
+                .await?;
+            benchmarks.push(Benchmark {
+                repo: self.label().to_owned(),
+                src: src.clone(),
+                mirrors: remote.bench(client, files, chunk).await?,
+            });
         }
+        Ok(benchmarks)
+    }
+
+    /// Download the packages matching any of the patterns to every variant of the repository,
+    /// publishing the new metadata without a full synchronisation if nothing else it lists is
+    /// missing.
+    ///
+    /// Returns the number of matching packages.
+    pub async fn fetch(
+        &self,
+        patterns: &[Pattern],
+        check: CheckType,
+        stats: &Stats,
+    ) -> Result<usize> {
+        let client = &self.client()?;
+        let mut fetched = 0;
+        for job in self.jobs() {
+            let (src, dest) = (&job.variant.src, &job.variant.dst);
+            let remote = match self
+                .prepare(client, (src, dest), &job.mirrors, job.verification.clone())
+                .await?
+            {
+                Some(remote) => remote,
+                None => continue,
+            };
+            let local = Mirror::local(dest).await?;
+            let cache_dir = self.cache_dir(dest);
+            let remote = remote
+                .into_cache(client, cache_dir.as_deref(), &self.staging(dest), stats)
+                .await?;
+            let changes = remote.changes_since(local.as_ref()).await?;
+            let count = remote
+                .fetch(
+                    client,
+                    patterns,
+                    Path::new(dest),
+                    check,
+                    self.retain_metadata,
+                    stats,
+                )
+                .await?;
+            if count == 0 {
+                info!("No packages in '{}' match", src);
+                continue;
+            }
+            info!("Fetched {} packages from '{}'", count, src);
+            self.published(dest, changes, stats);
+            self.finish_publishing(dest, stats).await?;
+            job.replicate(shard::current()).await?;
+            fetched += count;
+        }
+        Ok(fetched)
+    }
+
+    /// Make the planned changes to a variant of the repository.
+    ///
+    /// Fails with [`Outdated`] if the upstream metadata has changed since the plan was made.
+    pub async fn apply(&self, planned: &plan::Variant, stats: &Stats) -> Result<Outcome> {
+        let job = self
+            .jobs()
+            .into_iter()
+            .find(|job| job.variant.src == planned.src && job.variant.dst == planned.dest)
+            .ok_or_else(|| {
+                format_err!(
+                    "'{}' is no longer synchronised to '{}'",
+                    planned.src,
+                    planned.dest
+                )
+            })?;
+        let (src, dest) = (&job.variant.src, &job.variant.dst);
+        let removal_paths = planned
+            .removals
+            .iter()
+            .map(|removal| href::local(Path::new(dest), &removal.path))
+            .collect::<Result<Vec<_>>>()?;
+        let client = &self.client()?;
+        if Mirror::fingerprint(client, src).await? != planned.fingerprint {
+            return Err(Outdated { src: src.clone() }.into());
+        }
+        let remote = match self
+            .prepare(client, (src, dest), &job.mirrors, job.verification.clone())
+            .await?
+        {
+            Some(remote) => remote,
+            None => return Ok(Outcome::Unavailable),
+        };
 
+        let local = Mirror::local(dest).await?;
+        let cache_dir = self.cache_dir(dest);
+        let remote = remote
+            .into_cache(client, cache_dir.as_deref(), &self.staging(dest), stats)
+            .await?;
+        let changes = remote.changes_since(local.as_ref()).await?;
+        remote
+            .apply(
+                client,
+                &planned.planned()?,
+                Path::new(dest),
+                planned.check,
+                self.retain_metadata,
+                stats,
+            )
+            .await?;
+        self.published(dest, changes, stats);
+
+        // Only remove files that are still orphaned, as they may have gone or be referenced again
+        let orphans = match Mirror::local(dest).await? {
+            Some(local) => local.orphans(&self.clean_exclude()?).await?,
+            None => Vec::new(),
+        };
+        let mut removals = Vec::new();
+        for (removal, path) in planned.removals.iter().zip(&removal_paths) {
+            match orphans
+                .iter()
+                .find(|orphan| Path::new(dest).join(&orphan.path) == *path)
+            {
+                Some(orphan) => removals.push(orphan.clone()),
+                None => debug!("Keeping '{}' as it is no longer orphaned", removal.path),
+            }
+        }
+        info!("Removing {} planned files from '{}'", removals.len(), dest);
+        remove_orphans(Path::new(dest), &removals, stats).await?;
+        prune_empty_dirs(Path::new(dest), &self.clean_exclude()?)?;
+        self.finish_publishing(dest, stats).await?;
+        job.write_repo_file(client).await?;
+        job.replicate(shard::current()).await?;
+        Ok(Outcome::Synced)
+    }
+
+    /// Log the changes published to a destination, counting them in `stats`.
+    fn published(&self, dest: &str, changes: Changelog, stats: &Stats) {
+        info!("Published '{}': {}", dest, changes);
+        for package in &changes.added {
+            debug!("Added {}", package);
+        }
+        for update in &changes.updated {
+            debug!("Updated {} to {}", update.from, update.to);
+        }
+        for package in &changes.removed {
+            debug!("Removed {}", package);
+        }
+        stats.changed(dest, changes);
+    }
+
+    /// Record what was published to a destination, writing its manifest if one is configured.
+    async fn finish_publishing(&self, dest: &str, stats: &Stats) -> Result<()> {
+        let local = match Mirror::local(dest).await? {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+        stats.published(dest, local.published().await?);
+        if let Some(name) = self.manifest.file_name() {
+            let entries = local.manifest(&[Pattern::new(name)?]).await?;
+            self.manifest.write(Path::new(dest), &entries).await?;
+        }
         Ok(())
     }
+
+    /// Where files are staged while a destination is synchronised.
+    fn staging(&self, dest: &str) -> PathBuf {
+        self.staging_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(dest).join(STAGING_DIR))
+    }
+
+    /// Fetch the upstream metadata of a variant and decide how it is synchronised.
+    ///
+    /// Returns `None` if the upstream is unavailable and may be skipped.
+    async fn prepare(
+        &self,
+        client: &Client,
+        pair: (&str, &str),
+        mirrors: &[String],
+        verification: Option<Verification>,
+    ) -> Result<Option<Mirror>> {
+        let (src, dest) = pair;
+        let remote = match Mirror::remote(client, src).await {
+            Ok(remote) => remote,
+            Err(err)
+                if self.skip_if_unavailable && err.downcast_ref::<XmlDecodeError>().is_none() =>
+            {
+                warn!("Skipping unavailable repository '{}': {}", src, err);
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        let (mut remote, fallbacks) = match self.max_metadata_age {
+            Some(max_age) => self.fresh(client, remote, src, mirrors, max_age).await?,
+            None => (remote, mirrors.to_vec()),
+        };
+        remote.exclude_metadata(&self.exclude_metadata);
+        remote.sign_with(self.signing.as_ref());
+        remote.vet_with(self.vetting(dest));
+        remote.tolerate_missing(self.on_missing);
+        remote.require_digest(self.minimum_digest.as_ref().map(|algorithm| MinimumDigest {
+            algorithm: algorithm.clone(),
+            on_weak: self.on_weak_digest,
+        }));
+        remote.fall_back_to(&fallbacks)?;
+        if self.rank_mirrors {
+            remote.rank_every(Some(self.rank_interval.unwrap_or(rank::INTERVAL)));
+        }
+        remote.verify_with(verification);
+        if let Some(prefetch) = &self.prefetch {
+            // Lazy repositories fetch everything else on demand
+            let wanted = prefetch.wanted().await?;
+            if wanted.is_empty() {
+                warn!("No packages to prefetch were found for '{}'", src);
+            }
+            remote.prefetch(wanted, prefetch.restrict || self.lazy);
+        }
+        Ok(Some(remote))
+    }
+
+    /// Check the age of the upstream metadata, switching to a fresher mirror if configured to.
+    ///
+    /// Returns the repository to synchronise from, along with the other locations to fall back to.
+    async fn fresh(
+        &self,
+        client: &Client,
+        remote: Mirror,
+        src: &str,
+        mirrors: &[String],
+        max_age: Duration,
+    ) -> Result<(Mirror, Vec<String>)> {
+        let stale = match remote.check_age(max_age) {
+            Ok(()) => return Ok((remote, mirrors.to_vec())),
+            Err(stale) => stale,
+        };
+        match self.on_stale {
+            OnStale::Warn => {
+                warn!("{}", stale);
+                Ok((remote, mirrors.to_vec()))
+            }
+            OnStale::Fail => Err(stale),
+            OnStale::FallBack => {
+                for (index, mirror) in mirrors.iter().enumerate() {
+                    let fresh = match Mirror::remote(client, mirror).await {
+                        Ok(fresh) => fresh.check_age(max_age).map(|()| fresh),
+                        Err(e) => Err(e),
+                    };
+                    match fresh {
+                        Ok(fresh) => {
+                            warn!("{}, synchronising from '{}' instead", stale, mirror);
+                            // The stale upstream is only used as a last resort
+                            let mut fallbacks: Vec<String> = mirrors
+                                .iter()
+                                .enumerate()
+                                .filter(|&(other, _)| other != index)
+                                .map(|(_, other)| other.clone())
+                                .collect();
+                            fallbacks.push(src.to_owned());
+                            return Ok((fresh, fallbacks));
+                        }
+                        Err(e) => debug!("Can't fall back to '{}': {}", mirror, e),
+                    }
+                }
+                bail!("{}, and no mirror is fresh enough", stale)
+            }
+        }
+    }
+
+    /// The persistent metadata cache for the variant synchronised to a destination, if any.
+    fn cache_dir(&self, dest: &str) -> Option<PathBuf> {
+        self.cache_dir
+            .as_ref()
+            .map(|dir| Path::new(dir).join(relative_dest(dest)))
+    }
+
+    /// The checks made on packages downloaded for the variant synchronised to a destination.
+    fn vetting(&self, dest: &str) -> Vetting {
+        let quarantine = match &self.quarantine_dir {
+            Some(dir) => Path::new(dir).join(relative_dest(dest)),
+            None => Path::new(dest).join(QUARANTINE_DIR),
+        };
+        Vetting {
+            command: self.file_hook.clone(),
+            quarantine,
+            retries: self.retries,
+        }
+    }
+}
+
+/// A destination as a relative path, for use within another directory.
+fn relative_dest(dest: &str) -> PathBuf {
+    Path::new(dest)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// Replace every `${VAR}` in a value with the contents of the environment variable.
+fn interpolate_env(value: &str) -> Result<String> {
+    let finder = Regex::new(r"\$\{(?P<var>[A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut result = String::with_capacity(value.len());
+    let mut last = 0;
+
+    for caps in finder.captures_iter(value) {
+        let whole = caps.get(0).unwrap();
+        let var = &caps["var"];
+        let contents =
+            env::var(var).map_err(|_| format_err!("Environment variable '{}' is not set", var))?;
+        result.push_str(&value[last..whole.start()]);
+        result.push_str(&contents);
+        last = whole.end();
+    }
+    result.push_str(&value[last..]);
+
+    Ok(result)
+}
+
+/// Check whether a destination directory (or the directory it would be created in) can be written.
+fn writable(path: &Path) -> bool {
+    let existing = path.ancestors().find(|p| p.exists());
+    let dir = match existing {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+
+    dir.is_dir() && TempDir::new_in(dir, env!("CARGO_PKG_NAME")).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir, write};
+
+    fn repo(dest: &str) -> String {
+        format!(
+            "[[repo]]\nsrc = \"https://example.com/{0}/\"\ndest = \"{0}\"\n",
+            dest
+        )
+    }
+
+    #[test]
+    fn interpolate() {
+        env::set_var("YUMCLONE_TEST_MIRROR", "/srv/mirror");
+        assert_eq!(
+            interpolate_env("${YUMCLONE_TEST_MIRROR}/$os/").unwrap(),
+            "/srv/mirror/$os/"
+        );
+        assert_eq!(interpolate_env("plain").unwrap(), "plain");
+        assert!(interpolate_env("${YUMCLONE_TEST_UNSET}").is_err());
+    }
+
+    #[test]
+    fn range_tags() {
+        let config: Config = toml::from_str(
+            "src = \"x/$releasever/$arch\"\ndest = \"y\"\n\
+             [tags]\nreleasever = \"38..=40\"\narch = [\"x86_64\", \"8..10\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.tags["releasever"], vec!["38", "39", "40"]);
+        assert_eq!(config.tags["arch"], vec!["x86_64", "8", "9"]);
+
+        for range in ["41..38", "38..4100000000"] {
+            let error = toml::from_str::<Config>(&format!(
+                "src = \"x/$releasever\"\ndest = \"y\"\n[tags]\nreleasever = \"{}\"\n",
+                range
+            ))
+            .unwrap_err();
+            assert!(error.to_string().contains(range), "{}", error);
+        }
+    }
+
+    #[test]
+    fn restrict_zipped_tags() {
+        let mut configs = Configs {
+            repos: vec![toml::from_str(
+                "src = \"x/$releasever/$codename/$arch\"\ndest = \"y\"\n\
+                 zip_tags = [[\"releasever\", \"codename\"]]\n\
+                 [tags]\nreleasever = [\"39\", \"40\"]\ncodename = [\"a\", \"b\"]\n\
+                 arch = [\"x86_64\", \"aarch64\"]\n",
+            )
+            .unwrap()],
+            ..Configs::default()
+        };
+        let overrides = vec![("releasever".to_owned(), vec!["40".to_owned()])]
+            .into_iter()
+            .collect();
+        configs.restrict_tags(&overrides);
+
+        let jobs = configs.repos[0].jobs();
+        let mut srcs: Vec<&str> = jobs.iter().map(|job| job.variant.src.as_str()).collect();
+        srcs.sort_unstable();
+        assert_eq!(srcs, vec!["x/40/b/aarch64", "x/40/b/x86_64"]);
+    }
+
+    #[test]
+    fn lazy_secrets() {
+        let config: Config = toml::from_str(
+            "src = \"x\"\ndest = \"y\"\nusername = \"me\"\npassword_cmd = \"echo hunter2\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.password().unwrap().unwrap().expose(), "hunter2");
+
+        // A failing command only fails the repository once its password is needed
+        let config: Config = toml::from_str(
+            "src = \"x\"\ndest = \"y\"\nusername = \"me\"\npassword_cmd = \"exit 1\"\n",
+        )
+        .unwrap();
+        assert!(config.check_secrets().is_ok());
+        assert!(config.password().is_err());
+    }
+
+    #[test]
+    fn unknown_minimum_digest() {
+        let error =
+            toml::from_str::<Config>("src = \"x\"\ndest = \"y\"\nminimum_digest = \"sha265\"\n")
+                .unwrap_err();
+        assert!(error.to_string().contains("sha265"), "{}", error);
+    }
+
+    #[test]
+    fn unresolved_tags() {
+        let config: Config = toml::from_str(
+            "src = \"x/$os/$arch/$arch\"\ndest = \"y/$os\"\n[tags]\nos = [\"fedora\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.unresolved_tags(),
+            vec!["Tag '$arch' in 'x/$os/$arch/$arch' is not defined"]
+        );
+    }
+
+    #[test]
+    fn client_repo_files() {
+        let config: Config = toml::from_str(
+            "name = \"fedora\"\nsrc = \"x/$v/\"\ndest = \"y/$v\"\ntags = { v = [\"39\"] }\n\
+             gpgkey = \"x/$v/key\"\n[clients]\nurl = \"https://mirror/$v/\"\n",
+        )
+        .unwrap();
+        let jobs = config.jobs();
+        let repo_file = jobs[0].repo_file.as_ref().unwrap();
+        assert_eq!(repo_file.id, "fedora-39");
+        assert_eq!(repo_file.name, "fedora v=39");
+        assert_eq!(repo_file.baseurl, "https://mirror/39/");
+        assert!(repo_file.gpgcheck && repo_file.key && !repo_file.repo_gpgcheck);
+        let exclude = config.clean_exclude().unwrap();
+        assert!(exclude.iter().any(|p| p.matches("fedora-39.repo")));
+        assert!(exclude.iter().any(|p| p.matches("RPM-GPG-KEY-fedora-39")));
+    }
+
+    #[test]
+    fn repo_file_id_order() {
+        let config: Config = toml::from_str(
+            "name = \"fedora\"\nsrc = \"x/$basearch/$releasever/$extra\"\n\
+             dest = \"y/$releasever/$basearch\"\n\
+             tags = { basearch = [\"x86_64\"], releasever = [\"39\"], extra = [\"e\"] }\n\
+             [clients]\nurl = \"https://mirror/$releasever/$basearch/\"\n",
+        )
+        .unwrap();
+        let jobs = config.jobs();
+        // Tags only in the source follow those of the destination
+        assert_eq!(jobs[0].repo_file.as_ref().unwrap().id, "fedora-39-x86_64-e");
+    }
+
+    #[test]
+    fn retry_missing_packages() {
+        // Syncing needs the stack of a main thread rather than a test thread in debug builds
+        let test = std::thread::Builder::new().stack_size(8 << 20).spawn(|| {
+            let mut runtime = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(sync_missing_packages())
+        });
+        test.unwrap().join().unwrap();
+    }
+
+    async fn sync_missing_packages() {
+        let upstream = TempDir::new("upstream").unwrap();
+        let mirror = TempDir::new("mirror").unwrap();
+        let write = |file: &str, contents: &[u8]| {
+            let path = upstream.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        let sha256 = |data: &[u8]| {
+            let mut hasher = crate::hash::Hasher::new("sha256").unwrap().unwrap();
+            hasher.update(data).unwrap();
+            hasher.finish().unwrap()
+        };
+        let mut primary = String::from("<?xml version=\"1.0\"?><metadata>");
+        for name in &["a", "b"] {
+            primary += &format!(
+                "<package><name>{0}</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">{1}</checksum>\
+                 <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"Packages/{0}-1-1.rpm\"/></package>",
+                name,
+                sha256(name.as_bytes())
+            );
+        }
+        primary += "</metadata>";
+        write("repodata/primary.xml", primary.as_bytes());
+        let repomd = format!(
+            "<?xml version=\"1.0\"?><repomd><data type=\"primary\">\
+             <checksum type=\"sha256\">{}</checksum>\
+             <location href=\"repodata/primary.xml\"/></data></repomd>",
+            sha256(primary.as_bytes())
+        );
+        write("repodata/repomd.xml", repomd.as_bytes());
+        write("Packages/a-1-1.rpm", b"a");
+        let config: Config = toml::from_str(&format!(
+            "src = \"{}\"\ndest = \"{}\"\non_missing = \"retry-later\"\n",
+            crate::serve::serve_dir(upstream.path()),
+            mirror.path().display()
+        ))
+        .unwrap();
+
+        let sync =
+            |retry_missing, stats| config.sync(CheckType::CheckRemoteSize, retry_missing, stats);
+        let stats = Stats::default();
+        assert_eq!(sync(false, &stats).await.unwrap(), Outcome::Synced);
+        let summary = stats.summary(Duration::default());
+        assert_eq!(summary.inconsistencies.len(), 1);
+        assert!(summary.inconsistencies[0].missing);
+        assert!(!mirror.path().join("Packages/b-1-1.rpm").exists());
+
+        // The upstream hasn't changed once the package appears, so only a retry downloads it
+        write("Packages/b-1-1.rpm", b"b");
+        let stats = Stats::default();
+        sync(false, &stats).await.unwrap();
+        assert!(!mirror.path().join("Packages/b-1-1.rpm").exists());
+        sync(true, &stats).await.unwrap();
+        assert!(mirror.path().join("Packages/b-1-1.rpm").exists());
+        let summary = stats.summary(Duration::default());
+        assert_eq!(summary.added_files, vec!["Packages/b-1-1.rpm"]);
+        assert!(summary.inconsistencies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn skip_only_unavailable() {
+        let upstream = TempDir::new("upstream").unwrap();
+        let mirror = TempDir::new("mirror").unwrap();
+        let config: Config = toml::from_str(&format!(
+            "src = \"{}\"\ndest = \"{}\"\nskip_if_unavailable = true\n",
+            crate::serve::serve_dir(upstream.path()),
+            mirror.path().display()
+        ))
+        .unwrap();
+        let stats = Stats::default();
+        let sync = || config.sync(CheckType::CheckRemoteSize, false, &stats);
+        assert_eq!(sync().await.unwrap(), Outcome::Unavailable);
+
+        // Corrupt metadata isn't hidden by skipping the repository
+        std::fs::create_dir_all(upstream.path().join("repodata")).unwrap();
+        write(upstream.path().join("repodata/repomd.xml"), "<repomd><data").unwrap();
+        assert!(sync().await.is_err());
+    }
+
+    #[test]
+    fn select_repos() {
+        let dir = TempDir::new("config").unwrap();
+        let main = dir.path().join("yumclone.toml");
+        let named = |name: &str| format!("{}name = \"{}\"\n", repo(name), name);
+        write(
+            &main,
+            format!(
+                "{}{}{}",
+                named("fedora-updates"),
+                named("epel"),
+                repo("other")
+            ),
+        )
+        .unwrap();
+
+        let mut configs = Configs::load(main.to_str().unwrap()).unwrap();
+        configs.select(&["fedora-*".to_owned()]).unwrap();
+        let names: Vec<_> = configs.repos.iter().map(|r| r.label()).collect();
+        assert_eq!(names, vec!["fedora-updates"]);
+    }
+
+    #[test]
+    fn parse_repo_settings() {
+        let configs: Configs = toml::from_str(
+            "[[repo]]\nsrc = \"https://example.com/$v/\"\n\
+             dest = [\"primary/$v\", \"replica/$v\"]\n\
+             tags = { v = [\"1\", \"2\"] }\nmonthly_quota = 100\npost_sync = \"true\"\n\
+             check_mode = \"hash\"\npackages = false\n",
+        )
+        .unwrap();
+        let repo = &configs.repos[0];
+        assert_eq!(repo.dest(), "primary/$v");
+        let replicas: Vec<String> = repo.url_pairs_to(&repo.dests[1]).map(|(_, d)| d).collect();
+        assert_eq!(replicas, vec!["replica/1", "replica/2"]);
+        assert!(repo.unresolved_tags().is_empty());
+        assert_eq!(repo.monthly_quota(), Some(100));
+        assert_eq!(repo.hooks().post_sync.as_deref(), Some("true"));
+        assert_eq!(repo.check_mode(), Some(CheckType::CheckHash));
+        assert!(repo.metadata_only());
+
+        let empty =
+            toml::from_str::<Configs>("[[repo]]\nsrc = \"https://example.com/\"\ndest = []\n");
+        assert!(empty.is_err());
+    }
+
+    #[tokio::test]
+    async fn shards_leave_replicas() {
+        let dir = TempDir::new("replicas").unwrap();
+        let (primary, replica) = (dir.path().join("primary"), dir.path().join("replica"));
+        for (root, file) in [
+            (&primary, "Packages/a-1-1.rpm"),
+            (&replica, "Packages/b-1-1.rpm"),
+        ] {
+            std::fs::create_dir_all(root.join("Packages")).unwrap();
+            write(root.join(file), "").unwrap();
+        }
+        let config: Config = toml::from_str(&format!(
+            "src = \"https://example.com/\"\ndest = [{:?}, {:?}]\n",
+            primary, replica
+        ))
+        .unwrap();
+        let jobs = config.jobs();
+
+        // A shard only downloaded part of the packages, so the replica keeps the rest
+        jobs[0]
+            .replicate(Some("1/2".parse().unwrap()))
+            .await
+            .unwrap();
+        assert!(replica.join("Packages/b-1-1.rpm").exists());
+        assert!(!replica.join("Packages/a-1-1.rpm").exists());
+
+        jobs[0].replicate(None).await.unwrap();
+        assert!(!replica.join("Packages/b-1-1.rpm").exists());
+        assert!(replica.join("Packages/a-1-1.rpm").exists());
+    }
+
+    #[test]
+    fn priority_order() {
+        let dir = TempDir::new("config").unwrap();
+        let main = dir.path().join("yumclone.toml");
+        let prioritised =
+            |dest: &str, priority: i32| format!("{}priority = {}\n", repo(dest), priority);
+        write(
+            &main,
+            format!(
+                "{}{}{}{}",
+                repo("base"),
+                prioritised("updates", 10),
+                prioritised("debug", -1),
+                repo("extras")
+            ),
+        )
+        .unwrap();
+
+        let mut configs = Configs::load(main.to_str().unwrap()).unwrap();
+        configs.sort_by_priority();
+        let dests: Vec<&str> = configs.repos.iter().map(|r| r.dest()).collect();
+        assert_eq!(dests, vec!["updates", "base", "extras", "debug"]);
+    }
+
+    #[test]
+    fn load_includes() {
+        let dir = TempDir::new("config").unwrap();
+        let main = dir.path().join("yumclone.toml");
+        write(
+            &main,
+            format!(
+                "include = [\"extra.toml\"]\ncheck_mode = \"size\"\n{}",
+                repo("main")
+            ),
+        )
+        .unwrap();
+        write(dir.path().join("extra.toml"), repo("extra")).unwrap();
+        create_dir(dir.path().join("yumclone.d")).unwrap();
+        write(dir.path().join("yumclone.d/b.toml"), repo("b")).unwrap();
+        write(
+            dir.path().join("yumclone.d/a.json"),
+            r#"{"repo": [{"src": "x", "dest": "a", "check": "hash"}]}"#,
+        )
+        .unwrap();
+
+        let configs = Configs::load(main.to_str().unwrap()).unwrap();
+        let dests: Vec<&str> = configs.repos.iter().map(|r| r.dest()).collect();
+        assert_eq!(dests, vec!["main", "extra", "a", "b"]);
+        let checks: Vec<_> = configs.repos.iter().map(|r| r.check_mode()).collect();
+        assert_eq!(
+            checks,
+            vec![
+                Some(CheckType::CheckSize),
+                Some(CheckType::CheckSize),
+                Some(CheckType::CheckHash),
+                Some(CheckType::CheckSize)
+            ]
+        );
+
+        write(dir.path().join("yumclone.d/c.toml"), repo("main")).unwrap();
+        assert!(Configs::load(main.to_str().unwrap()).is_err());
+    }
 }
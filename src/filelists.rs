@@ -0,0 +1,165 @@
+//! Searching the files of packages, from the `filelists` metadata of a repository.
+
+use flate2::read::GzDecoder;
+use glob::Pattern;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use failure::bail;
+
+use crate::package::{nevra, Version};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// A file provided by a package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provider {
+    /// The package, as `name-[epoch:]version-release.arch`.
+    pub package: String,
+    /// The path of the file.
+    pub path: String,
+}
+
+/// Find the files matching a pattern in a `filelists` file, which may be compressed with gzip.
+pub fn search_file(path: &Path, pattern: &Pattern) -> Result<Vec<Provider>> {
+    let file = BufReader::new(File::open(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => search(GzDecoder::new(file), pattern),
+        Some("xml") => search(file, pattern),
+        _ => bail!("Unsupported compression of filelists {:?}", path),
+    }
+}
+
+/// Find the files matching a pattern in `filelists` metadata.
+///
+/// The metadata is scanned as it is read, as it is often hundreds of megabytes once
+/// decompressed. Only elements and attributes are understood, which is all `filelists` uses.
+pub fn search(source: impl Read, pattern: &Pattern) -> Result<Vec<Provider>> {
+    let mut source = BufReader::new(source);
+    let mut providers = Vec::new();
+    let (mut name, mut arch) = (String::new(), String::new());
+    let mut package = String::new();
+    let mut in_file = false;
+    let (mut text, mut tag) = (Vec::new(), Vec::new());
+
+    loop {
+        text.clear();
+        source.read_until(b'<', &mut text)?;
+        if in_file {
+            let path = unescape(String::from_utf8_lossy(
+                text.strip_suffix(b"<").unwrap_or(&text),
+            ));
+            if pattern.matches(&path) {
+                providers.push(Provider {
+                    package: package.clone(),
+                    path,
+                });
+            }
+        }
+
+        tag.clear();
+        if source.read_until(b'>', &mut tag)? == 0 {
+            break;
+        }
+        let tag = String::from_utf8_lossy(&tag);
+        let tag = tag.trim_end_matches('>');
+        let element = tag.split_whitespace().next().unwrap_or_default();
+        let element = element.rsplit(':').next().unwrap_or(element);
+        in_file = false;
+        match element {
+            "package" => {
+                name = attribute(tag, "name");
+                arch = attribute(tag, "arch");
+            }
+            "version" => {
+                let version = Version::new(
+                    attribute(tag, "epoch"),
+                    attribute(tag, "ver"),
+                    attribute(tag, "rel"),
+                );
+                package = nevra(&name, &version, &arch);
+            }
+            "file" => in_file = !tag.ends_with('/'),
+            _ => {}
+        }
+    }
+    Ok(providers)
+}
+
+/// Get the value of an attribute of a tag.
+fn attribute(tag: &str, key: &str) -> String {
+    let mut rest = tag;
+    while let Some(index) = rest.find(key) {
+        let before = rest[..index].chars().next_back();
+        let after = rest[index + key.len()..].trim_start();
+        rest = &rest[index + key.len()..];
+        if !before.is_some_and(char::is_whitespace) || !after.starts_with('=') {
+            continue;
+        }
+        let value = after[1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => continue,
+        };
+        let value = &value[1..];
+        let end = value.find(quote).unwrap_or(value.len());
+        return unescape(value[..end].into());
+    }
+    String::new()
+}
+
+/// Replace the predefined entities of XML.
+fn unescape(text: Cow<'_, str>) -> String {
+    if !text.contains('&') {
+        return text.into_owned();
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn search_files() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<filelists xmlns="http://linux.duke.edu/metadata/filelists" packages="2">
+<package pkgid="00" name="bash" arch="x86_64">
+  <version epoch="0" ver="5.2.15" rel="1.fc39"/>
+  <file>/usr/bin/bash</file>
+  <file>/usr/bin/sh</file>
+  <file>/usr/share/doc/bash/R&amp;D</file>
+  <file type="dir">/usr/share/doc/bash</file>
+</package>
+<package pkgid="01" name="vim-enhanced" arch="x86_64">
+  <version epoch="2" ver="9.0.2120" rel="1.fc39"/>
+  <file>/usr/bin/vim</file>
+</package>
+</filelists>"#;
+        let search =
+            |pattern: &str| search(xml.as_bytes(), &Pattern::new(pattern).unwrap()).unwrap();
+
+        assert_eq!(
+            search("/usr/bin/vim"),
+            vec![Provider {
+                package: "vim-enhanced-2:9.0.2120-1.fc39.x86_64".to_owned(),
+                path: "/usr/bin/vim".to_owned(),
+            }]
+        );
+        let found: Vec<String> = search("/usr/bin/*").into_iter().map(|p| p.path).collect();
+        assert_eq!(found, vec!["/usr/bin/bash", "/usr/bin/sh", "/usr/bin/vim"]);
+        assert!(search("/usr/bin/emacs").is_empty());
+        assert_eq!(search("*&*")[0].path, "/usr/share/doc/bash/R&D");
+        assert_eq!(
+            attribute("version epoch=\"0\" ver='1' rel=\"2\"/", "ver"),
+            "1"
+        );
+        assert_eq!(attribute("version epoch=\"0\"", "rel"), "");
+    }
+}
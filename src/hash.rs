@@ -0,0 +1,286 @@
+//! Message digest backends used to verify files.
+//!
+//! The digest implementation is selected at build time. The `openssl`
+//! feature (the default) uses the system OpenSSL library, while the
+//! `rustcrypto` feature uses pure-Rust implementations so that yumclone can
+//! be built statically. If both are enabled, OpenSSL is used.
+
+#[cfg(not(any(feature = "openssl", feature = "rustcrypto")))]
+compile_error!("Either the `openssl` or `rustcrypto` feature must be enabled");
+
+use failure::format_err;
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use tracing::warn;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The digest backend yumclone was built with.
+pub const BACKEND: &str = if cfg!(feature = "openssl") {
+    "openssl"
+} else {
+    "rustcrypto"
+};
+
+/// An incremental hasher for a single checksum algorithm.
+pub struct Hasher {
+    inner: backend::Hasher,
+}
+
+impl Hasher {
+    /// Create a hasher for an algorithm named in repository metadata.
+    ///
+    /// Returns `None` if the algorithm is not supported by the backend, or an error if the
+    /// backend supports it but couldn't set it up, such as when OpenSSL is in FIPS mode.
+    pub fn new(algorithm: &str) -> Result<Option<Hasher>> {
+        let inner = backend::Hasher::new(&algorithm.to_lowercase())?;
+        Ok(inner.map(|inner| Hasher { inner }))
+    }
+
+    /// Feed data into the hasher.
+    pub fn update(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.update(data)
+    }
+
+    /// Finish hashing and return the hex encoded digest.
+    pub fn finish(self) -> Result<String> {
+        Ok(hex::encode(self.inner.finish()?))
+    }
+}
+
+/// The strength of a digest algorithm, in bits of collision resistance.
+///
+/// Algorithms with practical collision attacks, such as md5 and sha1, have no strength at all.
+/// Returns `None` for unknown algorithms.
+pub fn strength(algorithm: &str) -> Option<u32> {
+    let strength = match algorithm.to_lowercase().as_str() {
+        "md5" | "sha" | "sha1" => 0,
+        "ripemd160" => 80,
+        "sha224" | "sha3-224" | "sha3_224" => 112,
+        "sha256" | "sha3-256" | "sha3_256" | "blake2s" | "blake2s-256" | "blake2s256" => 128,
+        "sha384" | "sha3-384" | "sha3_384" => 192,
+        "sha512" | "sha3-512" | "sha3_512" | "blake2b" | "blake2b-512" | "blake2b512" => 256,
+        _ => return None,
+    };
+    Some(strength)
+}
+
+/// What to do about checksums weaker than the minimum digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnWeakDigest {
+    /// Refuse to synchronise the repository.
+    #[default]
+    Fail,
+    /// Synchronise the repository anyway, warning about the weak checksums.
+    Warn,
+}
+
+/// The weakest digest algorithm that checksums may use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinimumDigest {
+    /// The weakest algorithm accepted.
+    pub algorithm: String,
+    /// What to do about weaker checksums.
+    pub on_weak: OnWeakDigest,
+}
+
+impl MinimumDigest {
+    /// Check the algorithms of the checksums of some files.
+    ///
+    /// Unknown algorithms are left for verification to reject, but an unknown minimum is an
+    /// error rather than accepting everything.
+    pub fn check<'a>(
+        &self,
+        what: &str,
+        algorithms: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let minimum = strength(&self.algorithm)
+            .ok_or_else(|| format_err!("Unknown minimum digest '{}'", self.algorithm))?;
+        let mut weak = WeakDigest {
+            what: what.to_owned(),
+            minimum: self.algorithm.clone(),
+            algorithms: Vec::new(),
+            files: 0,
+        };
+        for algorithm in algorithms {
+            if strength(algorithm).is_some_and(|strength| strength < minimum) {
+                weak.files += 1;
+                if !weak.algorithms.iter().any(|a| a == algorithm) {
+                    weak.algorithms.push(algorithm.to_owned());
+                }
+            }
+        }
+        match (weak.files, self.on_weak) {
+            (0, _) => Ok(()),
+            (_, OnWeakDigest::Warn) => {
+                warn!("{}", weak);
+                Ok(())
+            }
+            (_, OnWeakDigest::Fail) => Err(weak.into()),
+        }
+    }
+}
+
+/// Checksums that are weaker than the minimum digest.
+#[derive(Debug)]
+pub struct WeakDigest {
+    /// The kind of files with weak checksums.
+    pub what: String,
+    /// The weakest algorithm accepted.
+    pub minimum: String,
+    /// The weaker algorithms used.
+    pub algorithms: Vec<String>,
+    /// The number of files with weak checksums.
+    pub files: usize,
+}
+
+impl std::error::Error for WeakDigest {}
+
+impl Display for WeakDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} have {} checksums, weaker than {}",
+            self.files,
+            self.what,
+            self.algorithms.join(", "),
+            self.minimum
+        )
+    }
+}
+
+#[cfg(feature = "openssl")]
+mod backend {
+    use super::Result;
+    use openssl::hash::MessageDigest;
+
+    pub struct Hasher(openssl::hash::Hasher);
+
+    impl Hasher {
+        pub fn new(algorithm: &str) -> Result<Option<Hasher>> {
+            let digest = match algorithm {
+                "md5" => MessageDigest::md5(),
+                "sha" | "sha1" => MessageDigest::sha1(),
+                "sha224" => MessageDigest::sha224(),
+                "sha256" => MessageDigest::sha256(),
+                "sha384" => MessageDigest::sha384(),
+                "sha512" => MessageDigest::sha512(),
+                "sha3-224" | "sha3_224" => MessageDigest::sha3_224(),
+                "sha3-256" | "sha3_256" => MessageDigest::sha3_256(),
+                "sha3-384" | "sha3_384" => MessageDigest::sha3_384(),
+                "sha3-512" | "sha3_512" => MessageDigest::sha3_512(),
+                "blake2b" | "blake2b-512" | "blake2b512" => {
+                    match MessageDigest::from_name("BLAKE2b512") {
+                        Some(digest) => digest,
+                        None => return Ok(None),
+                    }
+                }
+                "blake2s" | "blake2s-256" | "blake2s256" => {
+                    match MessageDigest::from_name("BLAKE2s256") {
+                        Some(digest) => digest,
+                        None => return Ok(None),
+                    }
+                }
+                "ripemd160" => MessageDigest::ripemd160(),
+                _ => return Ok(None),
+            };
+            Ok(Some(Hasher(openssl::hash::Hasher::new(digest)?)))
+        }
+
+        pub fn update(&mut self, data: &[u8]) -> Result<()> {
+            Ok(self.0.update(data)?)
+        }
+
+        pub fn finish(mut self) -> Result<Vec<u8>> {
+            Ok(self.0.finish()?.to_vec())
+        }
+    }
+}
+
+#[cfg(all(feature = "rustcrypto", not(feature = "openssl")))]
+mod backend {
+    use super::Result;
+    use digest::DynDigest;
+
+    pub struct Hasher(Box<dyn DynDigest + Send>);
+
+    impl Hasher {
+        pub fn new(algorithm: &str) -> Result<Option<Hasher>> {
+            let digest: Box<dyn DynDigest + Send> = match algorithm {
+                "md5" => Box::new(md5::Md5::default()),
+                "sha" | "sha1" => Box::new(sha1::Sha1::default()),
+                "sha224" => Box::new(sha2::Sha224::default()),
+                "sha256" => Box::new(sha2::Sha256::default()),
+                "sha384" => Box::new(sha2::Sha384::default()),
+                "sha512" => Box::new(sha2::Sha512::default()),
+                "sha3-224" | "sha3_224" => Box::new(sha3::Sha3_224::default()),
+                "sha3-256" | "sha3_256" => Box::new(sha3::Sha3_256::default()),
+                "sha3-384" | "sha3_384" => Box::new(sha3::Sha3_384::default()),
+                "sha3-512" | "sha3_512" => Box::new(sha3::Sha3_512::default()),
+                "blake2b" | "blake2b-512" | "blake2b512" => Box::new(blake2::Blake2b512::default()),
+                "blake2s" | "blake2s-256" | "blake2s256" => Box::new(blake2::Blake2s256::default()),
+                "ripemd160" => Box::new(ripemd::Ripemd160::default()),
+                _ => return Ok(None),
+            };
+            Ok(Some(Hasher(digest)))
+        }
+
+        pub fn update(&mut self, data: &[u8]) -> Result<()> {
+            self.0.update(data);
+            Ok(())
+        }
+
+        pub fn finish(self) -> Result<Vec<u8>> {
+            Ok(self.0.finalize().into_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unsupported_algorithms() {
+        let mut hasher = Hasher::new("SHA256").unwrap().unwrap();
+        hasher.update(b"").unwrap();
+        assert_eq!(
+            hasher.finish().unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(Hasher::new("crc32").unwrap().is_none());
+    }
+
+    #[test]
+    fn weak_digests() {
+        assert_eq!(strength("SHA1"), Some(0));
+        assert!(strength("sha3-256") > strength("ripemd160"));
+        assert_eq!(strength("crc32"), None);
+
+        let minimum = MinimumDigest {
+            algorithm: "sha256".to_owned(),
+            on_weak: OnWeakDigest::Fail,
+        };
+        assert!(minimum.check("packages", vec!["sha256", "sha512"]).is_ok());
+        let err = minimum
+            .check("packages", vec!["sha256", "md5", "sha1", "md5"])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "3 packages have md5, sha1 checksums, weaker than sha256"
+        );
+
+        let warn = MinimumDigest {
+            on_weak: OnWeakDigest::Warn,
+            ..minimum
+        };
+        assert!(warn.check("packages", vec!["md5"]).is_ok());
+
+        let unknown = MinimumDigest {
+            algorithm: "sha265".to_owned(),
+            ..warn
+        };
+        assert!(unknown.check("packages", vec!["sha256"]).is_err());
+    }
+}
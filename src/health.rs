@@ -0,0 +1,226 @@
+//! Health and status of the daemon, served over HTTP.
+//!
+//! While watching for upstream changes, `/healthz` tells load balancers
+//! whether the mirror is being kept up to date, and `/status` describes the
+//! last synchronisation of each repository and which are in progress.
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use tracing::info;
+
+use crate::report::Status;
+use crate::state::State;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The status of the daemon, recorded as repositories are synchronised.
+static DAEMON: OnceLock<Mutex<Daemon>> = OnceLock::new();
+
+/// The status of the daemon.
+#[derive(Debug, Clone, Default, Serialize)]
+struct Daemon {
+    /// When the daemon started.
+    started: String,
+    /// When the last poll for upstream changes finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_poll: Option<String>,
+    /// When the next poll is due.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_poll: Option<String>,
+    repos: BTreeMap<String, Repo>,
+}
+
+/// The status of a repository.
+#[derive(Debug, Clone, Default, Serialize)]
+struct Repo {
+    /// When the synchronisation in progress started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_progress: Option<String>,
+    /// When the repository was last synchronised successfully.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_sync: Option<String>,
+    /// When the last synchronisation finished, whether or not it succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_run: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The number of runs that have failed since the last that didn't.
+    failures: u64,
+}
+
+/// The current time in RFC 3339 format.
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+/// Update the status of the daemon, if it is being recorded.
+fn update<F: FnOnce(&mut Daemon)>(update: F) {
+    if let Some(daemon) = DAEMON.get() {
+        update(&mut daemon.lock().unwrap());
+    }
+}
+
+/// Start recording the status of the daemon, starting from the runs kept in the state.
+pub fn record(state: &State) {
+    let mut daemon = Daemon {
+        started: now(),
+        ..Daemon::default()
+    };
+    for (label, repo_state) in &state.repos {
+        let repo = daemon.repos.entry(label.clone()).or_default();
+        for run in &repo_state.history {
+            repo.finished(run.finished.clone(), run.status, None);
+        }
+    }
+    let _ = DAEMON.set(Mutex::new(daemon));
+}
+
+/// Record that a repository has started synchronising.
+pub fn started(repo: &str) {
+    update(|daemon| daemon.repos.entry(repo.to_owned()).or_default().in_progress = Some(now()));
+}
+
+/// Record that a repository has finished synchronising.
+pub fn finished(repo: &str, status: Status, error: Option<&str>) {
+    update(|daemon| {
+        daemon.repos.entry(repo.to_owned()).or_default().finished(
+            now(),
+            status,
+            error.map(str::to_owned),
+        )
+    });
+}
+
+/// Record that a poll for upstream changes has finished, and when the next is due.
+pub fn polled(next: SystemTime) {
+    update(|daemon| {
+        daemon.last_poll = Some(now());
+        daemon.next_poll = Some(humantime::format_rfc3339_seconds(next).to_string());
+    });
+}
+
+impl Repo {
+    /// Record the outcome of a run.
+    fn finished(&mut self, time: String, status: Status, error: Option<String>) {
+        self.in_progress = None;
+        match status {
+            Status::Synced => {
+                self.last_sync = Some(time.clone());
+                self.failures = 0;
+            }
+            Status::Failed | Status::Unavailable => self.failures += 1,
+            Status::Disabled | Status::OverQuota | Status::OverBudget => {}
+        }
+        self.last_run = Some(time);
+        self.status = Some(status);
+        self.error = error;
+    }
+}
+
+impl Daemon {
+    /// Why the daemon is unhealthy, if it is.
+    ///
+    /// The daemon is unhealthy when no repository is being kept up to date: every repository
+    /// that has been run has failed in its last run, and none is in progress.
+    fn unhealthy(&self) -> Option<String> {
+        let ran: Vec<&Repo> = self
+            .repos
+            .values()
+            .filter(|repo| repo.status.is_some())
+            .collect();
+        if ran.is_empty()
+            || self.repos.values().any(|repo| repo.in_progress.is_some())
+            || ran.iter().any(|repo| repo.failures == 0)
+        {
+            return None;
+        }
+        Some(format!("All {} repositories are failing", ran.len()))
+    }
+}
+
+/// Serve `/healthz` and `/status` until the process exits.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request| async move {
+            Ok::<_, Infallible>(respond(&request))
+        }))
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!(
+        "Serving health and status on http://{}",
+        server.local_addr()
+    );
+    server.await?;
+    Ok(())
+}
+
+/// Respond to a request for the health or status of the daemon.
+fn respond(request: &Request<Body>) -> Response<Body> {
+    let daemon = DAEMON
+        .get()
+        .map(|daemon| daemon.lock().unwrap().clone())
+        .unwrap_or_default();
+    let (code, body, content_type) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => match daemon.unhealthy() {
+            None => (StatusCode::OK, "ok\n".to_owned(), "text/plain"),
+            Some(reason) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("{}\n", reason),
+                "text/plain",
+            ),
+        },
+        (&Method::GET, "/status") => (
+            StatusCode::OK,
+            serde_json::to_string_pretty(&daemon).unwrap_or_default(),
+            "application/json",
+        ),
+        (&Method::GET, _) => (StatusCode::NOT_FOUND, String::new(), "text/plain"),
+        _ => (StatusCode::METHOD_NOT_ALLOWED, String::new(), "text/plain"),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = code;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn healthy_while_any_repo_succeeds() {
+        let mut daemon = Daemon::default();
+        assert_eq!(daemon.unhealthy(), None);
+
+        let fedora = daemon.repos.entry("fedora".to_owned()).or_default();
+        fedora.finished(now(), Status::Synced, None);
+        let epel = daemon.repos.entry("epel".to_owned()).or_default();
+        epel.finished(now(), Status::Failed, Some("timed out".to_owned()));
+        assert_eq!(daemon.unhealthy(), None);
+
+        let fedora = daemon.repos.get_mut("fedora").unwrap();
+        fedora.finished(now(), Status::Unavailable, None);
+        assert!(fedora.last_sync.is_some());
+        assert_eq!(fedora.failures, 1);
+        assert!(daemon.unhealthy().is_some());
+
+        daemon.repos.get_mut("epel").unwrap().in_progress = Some(now());
+        assert_eq!(daemon.unhealthy(), None);
+
+        let json = serde_json::to_value(&daemon).unwrap();
+        assert_eq!(json["repos"]["epel"]["error"], "timed out");
+        assert_eq!(json["repos"]["epel"]["failures"], 1);
+        assert_eq!(json["repos"]["fedora"]["status"], "unavailable");
+    }
+}
@@ -0,0 +1,140 @@
+//! Commands run before and after a repository is synchronised.
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+use failure::{bail, format_err};
+
+use crate::report::{RepoReport, Status};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// Shell commands run around the synchronisation of a repository.
+///
+/// Each command is run with `sh -c` and is told about the repository with `YUMCLONE_*`
+/// environment variables.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Hooks {
+    /// Run before synchronising, which is abandoned if the command fails.
+    #[serde(default)]
+    pub pre_sync: Option<String>,
+    /// Run after the repository was synchronised (or skipped for a reason other than failure).
+    #[serde(default)]
+    pub post_sync: Option<String>,
+    /// Run after synchronising failed.
+    #[serde(default)]
+    pub on_failure: Option<String>,
+}
+
+/// Environment variables describing a repository to its hooks.
+pub type Environment = Vec<(&'static str, String)>;
+
+impl Hooks {
+    /// Run the command for before the repository is synchronised, if there is one.
+    pub async fn pre_sync(&self, env: &Environment) -> Result<()> {
+        match &self.pre_sync {
+            Some(command) => {
+                info!("Running pre_sync hook");
+                run("pre_sync", command, env).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Run the command for the outcome of synchronising the repository, if there is one.
+    ///
+    /// The outcome is described by `YUMCLONE_STATUS`, `YUMCLONE_ERROR` and variables for each
+    /// of the statistics of the run.
+    pub async fn finish(&self, env: &Environment, report: &RepoReport) -> Result<()> {
+        let (name, command) = match report.status {
+            Status::Failed => ("on_failure", &self.on_failure),
+            _ => ("post_sync", &self.post_sync),
+        };
+        let command = match command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+
+        let mut env = env.clone();
+        let status = serde_json::to_value(report.status)?;
+        env.push((
+            "YUMCLONE_STATUS",
+            status.as_str().unwrap_or_default().to_owned(),
+        ));
+        if let Some(error) = &report.error {
+            env.push(("YUMCLONE_ERROR", error.clone()));
+        }
+        let stats = &report.stats;
+        env.push(("YUMCLONE_ADDED", stats.added.to_string()));
+        env.push(("YUMCLONE_REMOVED", stats.removed.to_string()));
+        env.push((
+            "YUMCLONE_BYTES_DOWNLOADED",
+            stats.bytes_downloaded.to_string(),
+        ));
+        env.push(("YUMCLONE_BYTES_DELETED", stats.bytes_deleted.to_string()));
+        env.push(("YUMCLONE_DURATION", format!("{:.1}", stats.duration)));
+        info!("Running {} hook", name);
+        run(name, command, &env).await
+    }
+}
+
+/// Run a hook command, failing if it exits unsuccessfully.
+pub(crate) async fn run(name: &str, command: &str, env: &Environment) -> Result<()> {
+    debug!("Running {} hook '{}'", name, command);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .status()
+        .await
+        .map_err(|e| format_err!("Could not run {} hook: {}", name, e))?;
+    if !status.success() {
+        bail!("The {} hook failed ({})", name, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::Stats;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn run_hooks() {
+        let dir = TempDir::new("hooks").unwrap();
+        let output = dir.path().join("output");
+        let hooks = Hooks {
+            pre_sync: Some("exit 3".to_owned()),
+            post_sync: Some(format!(
+                "echo \"$YUMCLONE_REPO $YUMCLONE_STATUS $YUMCLONE_ADDED\" > {:?}",
+                output
+            )),
+            on_failure: None,
+        };
+        let env = vec![("YUMCLONE_REPO", "fedora".to_owned())];
+        assert!(hooks.pre_sync(&env).await.is_err());
+
+        let stats = Stats::default();
+        stats.added("Packages/a/a-1-1.rpm");
+        let mut report = RepoReport {
+            repo: "fedora".to_owned(),
+            status: Status::Synced,
+            error: None,
+            stats: stats.summary(Duration::default()),
+        };
+        hooks.finish(&env, &report).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "fedora synced 1\n"
+        );
+
+        // Failures only run the failure hook, of which there is none
+        std::fs::remove_file(&output).unwrap();
+        report.status = Status::Failed;
+        hooks.finish(&env, &report).await.unwrap();
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,104 @@
+//! Resolution of the locations of files within repositories.
+//!
+//! Repository URLs are treated as directories whether or not they end with a
+//! slash, so that joining a location to them never drops their last segment.
+//! Locations given by metadata must stay within the repository, so that a
+//! malicious or broken upstream can't write files outside of the mirror.
+
+use reqwest::Url;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// A location that refers to a file outside of the repository.
+#[derive(Debug)]
+pub struct Escapes {
+    href: String,
+}
+
+impl std::error::Error for Escapes {}
+
+impl Display for Escapes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Location '{}' is outside of the repository", self.href)
+    }
+}
+
+/// Parse the URL of a repository, treating it as a directory.
+pub fn base(url: &str) -> Result<Url> {
+    let mut url = Url::parse(url)?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// Check that a location is relative and stays within the repository.
+pub fn check(href: &str) -> Result<&str> {
+    let escapes = || Escapes {
+        href: href.to_owned(),
+    };
+    if href.starts_with('/') || href.contains('\\') || href.contains("://") {
+        return Err(escapes().into());
+    }
+    let mut depth: usize = 0;
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => depth = depth.checked_sub(1).ok_or_else(escapes)?,
+            _ => depth += 1,
+        }
+    }
+    Ok(href)
+}
+
+/// Resolve a location within a repository.
+pub fn join(base: &Url, href: &str) -> Result<Url> {
+    Ok(base.join(check(href)?)?)
+}
+
+/// The local path of a location within a mirror.
+pub fn local(dest: &Path, href: &str) -> Result<PathBuf> {
+    Ok(dest.join(check(href)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn join_locations() {
+        let src = base("https://dl.example.com/fedora/39/os").unwrap();
+        assert_eq!(src.as_str(), "https://dl.example.com/fedora/39/os/");
+        assert_eq!(
+            join(&src, "Packages/a/a-1-1.rpm").unwrap().as_str(),
+            "https://dl.example.com/fedora/39/os/Packages/a/a-1-1.rpm"
+        );
+        assert_eq!(
+            join(&src, "Packages/../repodata/repomd.xml")
+                .unwrap()
+                .as_str(),
+            "https://dl.example.com/fedora/39/os/repodata/repomd.xml"
+        );
+        let query = base("https://dl.example.com/os?token=1").unwrap();
+        assert_eq!(query.as_str(), "https://dl.example.com/os/?token=1");
+
+        for escaping in &[
+            "../other/a.rpm",
+            "Packages/../../a.rpm",
+            "/etc/passwd",
+            "https://evil.example.com/a.rpm",
+            "..\\a.rpm",
+        ] {
+            let err = join(&src, escaping).unwrap_err();
+            assert!(err.downcast_ref::<Escapes>().is_some(), "{}", escaping);
+            assert!(local(Path::new("/srv/mirror"), escaping).is_err());
+        }
+        assert_eq!(
+            local(Path::new("/srv/mirror"), "./Packages/a.rpm").unwrap(),
+            Path::new("/srv/mirror/./Packages/a.rpm")
+        );
+    }
+}
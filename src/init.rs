@@ -0,0 +1,354 @@
+//! Generation of example configuration.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::urlmux::tag_names;
+
+/// An annotated example configuration.
+pub const EXAMPLE: &str = r#"# Example yumclone configuration.
+#
+# Each [[repo]] section describes a repository to clone. Run
+# `yumclone config validate` to check this file without syncing anything.
+#
+# By default only the sizes of downloaded files are checked. Pass
+# `--check-mode size` to also check the sizes of existing local files, or
+# `--check-mode hash` to verify the checksums of every file. The default
+# for every repository can be set here, and each repository can set its own.
+# check_mode = "size"
+
+# Repositories can also be kept in other files. Every file in the
+# `yumclone.d` directory beside this file is included automatically.
+# include = ["more-repos.toml", "repos/"]
+
+# Downloads can be limited to a rate, with different rates for periods of
+# the day (in local time). Unset rates are unlimited. Sizes and rates are
+# written with their units, like "1.5TiB" or "5MiB/s", and durations like
+# "10m" or "1h 30m".
+# [bandwidth]
+# default = "2MiB/s"
+# [[bandwidth.schedule]]
+# from = "22:00"
+# to = "06:00"
+
+# Connecting and waiting for data time out after 30s and 60s by default,
+# while downloading a whole file is only limited if `file` is set. Each
+# repository can override these with its own `timeouts` table.
+# [timeouts]
+# connect = "10s"
+# read = "2m"
+# file = "1h"
+# Transfers averaging less than low_speed_limit over low_speed_time (60s by
+# default) are restarted.
+# low_speed_limit = "10KB/s"
+# low_speed_time = "1m"
+
+[[repo]]
+# URL of the upstream repository (the directory containing repodata/).
+src = "https://dl.fedoraproject.org/pub/fedora/linux/releases/$releasever/Everything/$basearch/os/"
+# Local directory to clone the repository into.
+dest = "mirror/fedora/$releasever/$basearch"
+# Several destinations can be given, in which case the first is downloaded
+# and the rest are replicated from it.
+# dest = ["mirror/fedora/$releasever/$basearch", "/srv/www/fedora/$releasever/$basearch"]
+# Metadata can be kept between runs, so that files which haven't changed
+# upstream aren't downloaded again.
+# cache_dir = "/var/cache/yumclone"
+# Files that aren't listed in the metadata are removed, except below these
+# paths. Installer trees keep boot images outside of the metadata.
+# clean_exclude = ["images", "EFI", "LiveOS"]
+# The kernel, initrd and install images listed in .treeinfo can be mirrored
+# too, so that the clone can be used for network installs.
+# treeinfo = true
+# Types of metadata can be left out, in which case repomd.xml is rewritten
+# without them.
+# exclude_metadata = ["appstream", "appstream-icons"]
+# Only the metadata of a lazy repository is synchronised. Packages are
+# fetched from upstream and kept by `yumclone serve` when first requested.
+# lazy = true
+# Only the repodata directory is mirrored when packages are turned off, such
+# as to inspect the metadata or stage it ahead of a large package download.
+# packages = false
+# Packages that clients actually install can be downloaded first, along with
+# everything they depend on. Access logs of a web server serving the mirror,
+# or lists of package names, can be given. With `restrict`, nothing else is
+# downloaded.
+# prefetch = { from = ["/var/log/httpd/access_log"], restrict = true }
+# Commands can be run before and after synchronising, with YUMCLONE_REPO,
+# YUMCLONE_STATUS and other variables describing the repository. A failing
+# pre_sync command skips the repository.
+# post_sync = "systemctl reload httpd"
+# on_failure = "mail -s \"yumclone: $YUMCLONE_REPO failed\" root < /dev/null"
+# Each downloaded package can be checked by a command before it is put in
+# place. Packages it rejects are moved to quarantine_dir (by default
+# .yumclone/quarantine within the destination).
+# file_hook = "clamscan --no-summary \"$YUMCLONE_FILE\""
+# Packages that fail their checksums are also quarantined, then downloaded
+# again from each of the mirrors in turn up to `retries` times (2 by default).
+# mirrors = ["https://mirror.example.net/fedora/releases/$releasever/Everything/$basearch/os/"]
+# retries = 3
+# Upstreams requiring HTTP basic authentication can be given a username and
+# password. Rather than writing the password here, it can be printed by a
+# command, read from a file, or looked up in the system keyring (under the
+# service "yumclone", with secret-tool or the macOS keychain).
+# username = "mirror"
+# password_cmd = "pass show mirror"
+# password_file = "/run/secrets/mirror-password"
+# password_keyring = "mirror"
+# Variants (each combination of tag values) are synchronised one at a time
+# by default. Several can be synchronised at once, sharing the connection
+# and the limits on bandwidth and concurrent downloads.
+# parallel_variants = 3
+# Downloads from the source, metadata included, can be capped each calendar
+# month, leaving the rest for the next month.
+# monthly_quota = "500GiB"
+# Connections can be restricted to "v4" or "v6" for hosts with broken routes
+# over the other ("auto" by default).
+# ip_family = "v4"
+# timeouts = { read = "5m" }
+# Upstream hosts can be given fixed addresses, like curl --resolve, or be
+# looked up from a particular DNS server.
+# resolve = ["dl.fedoraproject.org:443:10.0.0.5"]
+# nameserver = "10.0.0.53"
+# Upstream servers can be required to present a particular certificate or
+# public key, given as the base64 SHA-256 hash used by curl --pinnedpubkey.
+# tls_pins = ["sha256//NuArpS7NcGvdCJoHNUEIyELK/qoeJV+8lFCjD2dva3A="]
+# With several mirrors, each can be probed before downloading packages so
+# that the fastest is preferred, and probed again every rank_interval (30m
+# by default).
+# rank_mirrors = true
+# rank_interval = "1h"
+# Packages that are missing upstream or don't match their checksums, as
+# happens while the upstream is being updated, are listed separately in the
+# report. The repository can be synchronised once more after a delay.
+# inconsistency_retry = "15m"
+# Packages missing upstream fail the repository by default. With "skip" the
+# rest is synchronised anyway, and with "retry-later" `yumclone watch` keeps
+# synchronising until they appear.
+# on_missing = "retry-later"
+# Files are downloaded in order of their paths by default, or can be
+# downloaded "smallest-first", "largest-first", or in the order the
+# "metadata" lists them.
+# download_order = "largest-first"
+# Packages whose names match any of these patterns are downloaded before
+# any others, so the most important updates land first if a run is
+# interrupted or stops at its download limit.
+# critical = ["kernel*", "glibc*", "dnf*"]
+# Existing files of this repository can always be checked by "size" or
+# "hash", unless a check mode is given on the command line.
+# check = "hash"
+# Checksums in the upstream metadata weaker than minimum_digest (such as md5
+# or sha1) fail the repository, or only "warn" with on_weak_digest.
+# minimum_digest = "sha256"
+# on_weak_digest = "warn"
+# Upstream metadata older than max_metadata_age is reported as stale, which
+# can "warn" (the default), "fail", or "fall-back" to the first of the
+# mirrors whose metadata is fresh enough.
+# max_metadata_age = "3days"
+# on_stale = "fall-back"
+# The upstream repomd.xml can be required to be signed by the repository key,
+# pinned to its fingerprint so that a different key can't be substituted.
+# gpgkey = "https://dl.fedoraproject.org/pub/fedora/linux/releases/$releasever/Everything/$basearch/os/RPM-GPG-KEY-fedora-$releasever-primary"
+# gpgkey_fingerprints = ["E8F2 3996 F232 1864 0CB4  4CBE 75CF 5AC4 1863 5B65"]
+# A rewritten repomd.xml no longer matches the upstream signature, so it can
+# be signed with a local gpg key instead for clients using repo_gpgcheck.
+# [repo.signing]
+# key = "mirror@example.com"
+# A manifest of every published file and its SHA-256 digest can be written
+# to each destination, as MANIFEST.sha256 for `sha256sum -c`, or as "json".
+# manifest = "sha256"
+# Once published, each destination gets a .repo file for clients, named
+# after the repository and its tags in the order they appear in `dest` (such
+# as fedora-39-x86_64.repo), which points at the URL the mirror is served at. The upstream key is published
+# next to it as RPM-GPG-KEY-<id> for gpgcheck.
+# [repo.clients]
+# url = "https://mirror.example.com/fedora/$releasever/$basearch/"
+# name = "Fedora $releasever - $basearch (mirror)"
+# gpgcheck = true
+
+# Tags are replaced in both `src` and `dest`. Every combination of values
+# is cloned, so this repo produces four (src, dest) pairs. A numeric range
+# such as "39..=40" expands to every number in it ("39..41" excludes 41).
+[repo.tags]
+releasever = ["39", "40"]
+basearch = ["x86_64", "aarch64"]
+
+[[repo]]
+# Repositories without tags are cloned exactly as written.
+src = "https://dl.fedoraproject.org/pub/epel/9/Everything/x86_64/"
+dest = "mirror/epel/9/x86_64"
+"#;
+
+/// Convert the repositories in a dnf `.repo` file into configuration.
+///
+/// Each repository with a `baseurl` is mirrored below `mirror/<id>`, with a
+/// tag for each dnf variable used in the URL.
+pub fn from_dnf_repo(source: &str) -> String {
+    let mut config = String::from("# Generated from a dnf .repo file by yumclone.\n");
+
+    for (id, section) in parse_ini(source) {
+        let baseurl = section.get("baseurl").and_then(|urls| {
+            urls.split(|c: char| c == ',' || c.is_whitespace())
+                .find(|u| !u.is_empty())
+        });
+
+        let _ = writeln!(config);
+        if let Some(name) = section.get("name") {
+            let _ = writeln!(config, "# {}", name);
+        }
+
+        let src = match baseurl {
+            Some(src) => src,
+            None => {
+                let _ = writeln!(
+                    config,
+                    "# Skipped '{}': only repositories with a baseurl can be cloned",
+                    id
+                );
+                continue;
+            }
+        };
+
+        let mut tags: Vec<&str> = Vec::new();
+        for tag in tag_names(src) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        let mut dest = format!("mirror/{}", id);
+        for tag in &tags {
+            dest.push_str("/$");
+            dest.push_str(tag);
+        }
+
+        let _ = writeln!(config, "[[repo]]");
+        let _ = writeln!(config, "src = {}", quote(src));
+        let _ = writeln!(config, "dest = {}", quote(&dest));
+        let gpgkey = section
+            .get("gpgkey")
+            .and_then(|keys| keys.split_whitespace().next());
+        if let Some(gpgkey) = gpgkey {
+            if section.get("repo_gpgcheck").map(|c| c.trim()) == Some("1") {
+                let _ = writeln!(config, "gpgkey = {}", quote(gpgkey));
+            } else {
+                let _ = writeln!(config, "# gpgkey = {}", quote(gpgkey));
+            }
+        }
+        if section.get("enabled").map(|e| e.trim()) == Some("0") {
+            let _ = writeln!(config, "# This repository is disabled in the .repo file");
+        }
+
+        if !tags.is_empty() {
+            let _ = writeln!(config, "\n[repo.tags]");
+            for tag in tags {
+                match tag {
+                    "basearch" | "arch" => {
+                        let _ = writeln!(config, "{} = [\"x86_64\"]", tag);
+                    }
+                    _ => {
+                        let _ = writeln!(config, "# Fill in the values for ${}", tag);
+                        let _ = writeln!(config, "{} = []", tag);
+                    }
+                }
+            }
+        }
+    }
+
+    config
+}
+
+/// Quote a string for use as a TOML value.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Parse the sections of an INI style file.
+///
+/// Indented lines continue the value of the previous key.
+pub(crate) fn parse_ini(source: &str) -> Vec<(String, BTreeMap<String, String>)> {
+    let mut sections: Vec<(String, BTreeMap<String, String>)> = Vec::new();
+    let mut last_key: Option<String> = None;
+
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            let id = trimmed[1..trimmed.len() - 1].trim().to_owned();
+            sections.push((id, BTreeMap::new()));
+            last_key = None;
+            continue;
+        }
+
+        let section = match sections.last_mut() {
+            Some((_, section)) => section,
+            None => continue,
+        };
+
+        let continuation = line.starts_with(|c: char| c.is_whitespace());
+        match (continuation, &last_key, trimmed.find('=')) {
+            (true, Some(key), _) => {
+                if let Some(value) = section.get_mut(key) {
+                    value.push('\n');
+                    value.push_str(trimmed);
+                }
+            }
+            (_, _, Some(split)) => {
+                let key = trimmed[..split].trim().to_owned();
+                let value = trimmed[split + 1..].trim().to_owned();
+                section.insert(key.clone(), value);
+                last_key = Some(key);
+            }
+            _ => {}
+        }
+    }
+
+    sections
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FEDORA_REPO: &str = "
+[fedora]
+name=Fedora $releasever - $basearch
+#baseurl=http://download.example/pub/fedora/linux/releases/$releasever/Everything/$basearch/os/
+metalink=https://mirrors.fedoraproject.org/metalink?repo=fedora-$releasever&arch=$basearch
+enabled=1
+
+[local]
+name=Local mirror
+baseurl=http://mirror.example/fedora/$releasever/$basearch/
+        http://backup.example/fedora/$releasever/$basearch/
+enabled=0
+repo_gpgcheck=1
+gpgkey=file:///etc/pki/rpm-gpg/RPM-GPG-KEY-local-$releasever
+";
+
+    #[test]
+    fn parse_repo_file() {
+        let sections = parse_ini(FEDORA_REPO);
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].0, "fedora");
+        assert!(!sections[0].1.contains_key("baseurl"));
+        assert_eq!(
+            sections[1].1["baseurl"],
+            "http://mirror.example/fedora/$releasever/$basearch/\nhttp://backup.example/fedora/$releasever/$basearch/"
+        );
+    }
+
+    #[test]
+    fn convert_repo_file() {
+        let config = from_dnf_repo(FEDORA_REPO);
+        assert!(config.contains("# Skipped 'fedora'"));
+        assert!(config.contains("src = \"http://mirror.example/fedora/$releasever/$basearch/\"\n"));
+        assert!(config.contains("dest = \"mirror/local/$releasever/$basearch\"\n"));
+        assert!(config.contains("basearch = [\"x86_64\"]\n"));
+        assert!(
+            config.contains("gpgkey = \"file:///etc/pki/rpm-gpg/RPM-GPG-KEY-local-$releasever\"\n")
+        );
+    }
+}
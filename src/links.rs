@@ -0,0 +1,159 @@
+//! Checking that the files referenced by upstream metadata can be retrieved.
+//!
+//! Each file is probed with a `HEAD` request, or a request for its first byte
+//! if the server doesn't answer `HEAD`, so nothing is downloaded. Files that
+//! are missing or whose size doesn't match the metadata are reported as
+//! broken, before committing to a long synchronisation.
+
+use futures::stream::{self, StreamExt};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::Serialize;
+use std::fmt::{self, Display};
+
+use crate::href;
+use crate::package::Fetch;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The number of files probed at once.
+const WORKERS: usize = 16;
+
+/// The links checked in a variant of a repository.
+#[derive(Debug, Clone, Serialize)]
+pub struct Checked {
+    /// The label of the repository.
+    pub repo: String,
+    /// Where the variant is synchronised from.
+    pub src: String,
+    /// The files that couldn't be retrieved.
+    pub broken: Vec<Broken>,
+}
+
+/// A file referenced by the metadata that couldn't be retrieved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Broken {
+    /// The path of the file, relative to the repository.
+    pub path: String,
+    /// Where the file was probed.
+    pub url: String,
+    /// Why the file couldn't be retrieved.
+    pub reason: String,
+}
+
+impl Display for Broken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.url, self.reason)
+    }
+}
+
+/// Probe every file of the metadata, returning those that are broken.
+pub async fn check(client: &Client, fetch: &impl Fetch, base: &Url) -> Result<Vec<Broken>> {
+    let remote_files = fetch.remote_files();
+    let mut probes = Vec::new();
+    for (path, size, _) in fetch.files() {
+        let url = match remote_files.get(path) {
+            Some(url) => url.clone(),
+            None => href::join(base, path)?,
+        };
+        probes.push(async move {
+            probe(client, &url, size).await.map(|reason| Broken {
+                path: path.to_owned(),
+                url: url.to_string(),
+                reason,
+            })
+        });
+    }
+    let mut broken: Vec<Broken> = stream::iter(probes)
+        .buffer_unordered(WORKERS)
+        .filter_map(|broken| async { broken })
+        .collect()
+        .await;
+    broken.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(broken)
+}
+
+/// Probe a file, returning why it is broken, if it is.
+async fn probe(client: &Client, url: &Url, size: u64) -> Option<String> {
+    let response = match client.head(url.clone()).send().await {
+        Ok(response)
+            if response.status() == StatusCode::METHOD_NOT_ALLOWED
+                || response.status() == StatusCode::NOT_IMPLEMENTED =>
+        {
+            client
+                .get(url.clone())
+                .header(RANGE, "bytes=0-0")
+                .send()
+                .await
+        }
+        response => response,
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => return Some(e.to_string()),
+    };
+    if !response.status().is_success() {
+        return Some(response.status().to_string());
+    }
+    match length(&response) {
+        Some(length) if length != size => Some(format!(
+            "{} bytes, where the metadata lists {} bytes",
+            length, size
+        )),
+        _ => None,
+    }
+}
+
+/// The length of the whole file a response is for, if the server gave it.
+fn length(response: &Response) -> Option<u64> {
+    if response.status() == StatusCode::PARTIAL_CONTENT {
+        let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+        return range.rsplit('/').next()?.parse().ok();
+    }
+    response
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::package::Metadata;
+    use crate::serve::serve_dir;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn broken_links() {
+        let dir = TempDir::new("links").unwrap();
+        std::fs::create_dir_all(dir.path().join("Packages")).unwrap();
+        std::fs::write(dir.path().join("Packages/a-1-1.rpm"), "a").unwrap();
+        std::fs::write(dir.path().join("Packages/b-1-1.rpm"), "bb").unwrap();
+        let package = |name: &str| {
+            format!(
+                "<package><name>{0}</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">00</checksum>\
+                 <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"Packages/{0}-1-1.rpm\"/></package>",
+                name
+            )
+        };
+        let xml = format!(
+            "<?xml version=\"1.0\"?><metadata>{}{}{}</metadata>",
+            package("a"),
+            package("b"),
+            package("c")
+        );
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let base = serve_dir(dir.path());
+
+        let broken = check(&Client::new(), &metadata, &base).await.unwrap();
+        let paths: Vec<&str> = broken.iter().map(|b| b.path.as_str()).collect();
+        assert_eq!(paths, vec!["Packages/b-1-1.rpm", "Packages/c-1-1.rpm"]);
+        assert!(broken[0].reason.contains("2 bytes"));
+        assert!(broken[1].reason.contains("404"));
+    }
+}
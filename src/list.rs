@@ -0,0 +1,175 @@
+//! Listing the packages of mirrored repositories for inventory.
+
+use glob::Pattern;
+use serde::Serialize;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// A package in the metadata of a mirror.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Listed {
+    /// The repository the package is mirrored by.
+    pub repo: String,
+    /// The destination the package is mirrored to.
+    pub dest: String,
+    /// The name of the package.
+    pub name: String,
+    /// The architecture of the package.
+    pub arch: String,
+    /// The package, as `name-[epoch:]version-release.arch`.
+    pub nevra: String,
+    /// The size of the package file in bytes.
+    pub size: u64,
+    /// The checksum of the package file, as `algorithm:digest`.
+    pub checksum: String,
+    /// The path of the package file within the destination.
+    pub location: String,
+}
+
+/// How packages are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// One package per line, separated by tabs
+    Text,
+    /// A JSON array of packages
+    Json,
+    /// Comma separated values with a header
+    Csv,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<ListFormat, String> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            "csv" => Ok(ListFormat::Csv),
+            _ => Err(format!(
+                "Unknown list format '{}' (expected text, json or csv)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ListFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListFormat::Text => write!(f, "text"),
+            ListFormat::Json => write!(f, "json"),
+            ListFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// Which packages are listed.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only packages with names matching this pattern.
+    pub name: Option<Pattern>,
+    /// Only packages built for this architecture.
+    pub arch: Option<String>,
+}
+
+impl Filter {
+    /// Whether a package is listed.
+    pub fn matches(&self, package: &Listed) -> bool {
+        self.name.as_ref().is_none_or(|p| p.matches(&package.name))
+            && self.arch.as_ref().is_none_or(|arch| *arch == package.arch)
+    }
+}
+
+/// Render packages in a format.
+pub fn render(packages: &[Listed], format: ListFormat) -> Result<String> {
+    let mut output = String::new();
+    match format {
+        ListFormat::Text => {
+            for package in packages {
+                output += &format!(
+                    "{}\t{}\t{}\t{}\n",
+                    package.nevra, package.size, package.checksum, package.dest
+                );
+            }
+        }
+        ListFormat::Json => output = serde_json::to_string_pretty(packages)? + "\n",
+        ListFormat::Csv => {
+            output += "repo,dest,name,arch,nevra,size,checksum,location\n";
+            for package in packages {
+                let size = package.size.to_string();
+                let fields = [
+                    &package.repo,
+                    &package.dest,
+                    &package.name,
+                    &package.arch,
+                    &package.nevra,
+                    &size,
+                    &package.checksum,
+                    &package.location,
+                ];
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                output += &fields.join(",");
+                output += "\n";
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Quote a CSV field if it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_packages() {
+        let package = |name: &str, arch: &str| Listed {
+            repo: "fedora, updates".to_owned(),
+            dest: "mirror/fedora".to_owned(),
+            name: name.to_owned(),
+            arch: arch.to_owned(),
+            nevra: format!("{}-1-1.{}", name, arch),
+            size: 42,
+            checksum: "sha256:00".to_owned(),
+            location: format!("Packages/{}-1-1.{}.rpm", name, arch),
+        };
+        let packages = vec![package("bash", "x86_64"), package("vim", "aarch64")];
+        let filter = Filter {
+            name: Some(Pattern::new("ba*").unwrap()),
+            arch: None,
+        };
+        assert!(filter.matches(&packages[0]));
+        assert!(!filter.matches(&packages[1]));
+        let filter = Filter {
+            name: None,
+            arch: Some("aarch64".to_owned()),
+        };
+        assert!(!filter.matches(&packages[0]));
+
+        assert_eq!(
+            render(&packages[..1], ListFormat::Text).unwrap(),
+            "bash-1-1.x86_64\t42\tsha256:00\tmirror/fedora\n"
+        );
+        assert_eq!(
+            render(&packages[..1], ListFormat::Csv).unwrap(),
+            "repo,dest,name,arch,nevra,size,checksum,location\n\
+             \"fedora, updates\",mirror/fedora,bash,x86_64,bash-1-1.x86_64,42,sha256:00,\
+             Packages/bash-1-1.x86_64.rpm\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&packages, ListFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[1]["nevra"], "vim-1-1.aarch64");
+        assert_eq!("csv".parse::<ListFormat>().unwrap(), ListFormat::Csv);
+        assert!("yaml".parse::<ListFormat>().is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Loading of configuration files in TOML, YAML, or JSON.
+
+use serde::de::DeserializeOwned;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// The format of a configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// TOML, the default for files without a recognised extension
+    Toml,
+    /// YAML (`.yaml` or `.yml`)
+    Yaml,
+    /// JSON (`.json`)
+    Json,
+}
+
+impl Format {
+    /// Determine the format of a file from its extension.
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    /// Parse a configuration in this format.
+    fn parse<C: DeserializeOwned>(self, text: &str) -> Result<C, (String, Option<(usize, usize)>)> {
+        match self {
+            Format::Toml => toml::from_str(text).map_err(|e| {
+                let location = e.line_col().map(|(line, col)| (line + 1, col + 1));
+                (e.to_string(), location)
+            }),
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| {
+                let location = e.location().map(|l| (l.line(), l.column()));
+                (e.to_string(), location)
+            }),
+            Format::Json => serde_json::from_str(text).map_err(|e| {
+                let location = Some((e.line(), e.column()));
+                (e.to_string(), location)
+            }),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Toml => write!(f, "TOML"),
+            Format::Yaml => write!(f, "YAML"),
+            Format::Json => write!(f, "JSON"),
+        }
+    }
+}
+
+/// An error loading a configuration file.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file could not be read.
+    Read {
+        /// The file that was being read.
+        path: PathBuf,
+        /// The underlying error.
+        error: io::Error,
+    },
+    /// The file could not be parsed.
+    Parse {
+        /// The file that was being parsed.
+        path: PathBuf,
+        /// The format the file was parsed as.
+        format: Format,
+        /// The line and column of the error, if known.
+        location: Option<(usize, usize)>,
+        /// A description of the error.
+        message: String,
+    },
+}
+
+impl std::error::Error for LoadError {}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Read { path, error } => write!(f, "Could not read {:?}: {}", path, error),
+            LoadError::Parse {
+                path,
+                format,
+                location: Some((line, column)),
+                message,
+            } => write!(
+                f,
+                "Could not parse {:?} as {} at line {}, column {}: {}",
+                path, format, line, column, message
+            ),
+            LoadError::Parse {
+                path,
+                format,
+                location: None,
+                message,
+            } => write!(f, "Could not parse {:?} as {}: {}", path, format, message),
+        }
+    }
+}
+
+/// Load a configuration by name or path.
+///
+/// An existing path is loaded directly, otherwise the standard configuration locations are
+/// searched for the name with each supported extension. If no file is found, the default
+/// configuration is returned.
+pub fn load<C: DeserializeOwned + Default>(name: &str) -> Result<C, LoadError> {
+    match find(name) {
+        Some(path) => load_file(&path),
+        None => {
+            info!("No configuration found for '{}', using defaults", name);
+            Ok(C::default())
+        }
+    }
+}
+
+/// Find the configuration file for a name or path.
+pub fn find(name: &str) -> Option<PathBuf> {
+    search_paths(name).into_iter().find(|p| p.is_file())
+}
+
+/// Load a configuration from a specific file.
+pub fn load_file<C: DeserializeOwned>(path: &Path) -> Result<C, LoadError> {
+    let format = Format::of(path);
+    info!("Loading {} configuration from {:?}", format, path);
+
+    let text = fs::read_to_string(path).map_err(|error| LoadError::Read {
+        path: path.to_owned(),
+        error,
+    })?;
+
+    format
+        .parse(&text)
+        .map_err(|(message, location)| LoadError::Parse {
+            path: path.to_owned(),
+            format,
+            location,
+            message,
+        })
+}
+
+/// List the locations searched for a configuration, in order of preference.
+fn search_paths(name: &str) -> Vec<PathBuf> {
+    const EXTENSIONS: &[&str] = &["", ".toml", ".yaml", ".yml", ".json"];
+
+    let mut bases = vec![name.to_owned(), format!(".{}", name)];
+    if let Some(home) = env::var_os("HOME") {
+        let home = Path::new(&home);
+        bases.push(
+            home.join(format!(".{}", name))
+                .to_string_lossy()
+                .into_owned(),
+        );
+        let config_dir = home.join(".config");
+        bases.push(config_dir.join(name).to_string_lossy().into_owned());
+        bases.push(
+            config_dir
+                .join(name)
+                .join("config")
+                .to_string_lossy()
+                .into_owned(),
+        );
+    }
+    bases.push(format!("/etc/{}", name));
+    bases.push(format!("/etc/{}/config", name));
+
+    let paths = bases
+        .iter()
+        .flat_map(|base| {
+            EXTENSIONS
+                .iter()
+                .map(move |ext| PathBuf::from(format!("{}{}", base, ext)))
+        })
+        .collect();
+    debug!("Configuration search paths: {:?}", paths);
+    paths
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use tempdir::TempDir;
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    struct Example {
+        src: String,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    fn load_text(name: &str, text: &str) -> Result<Example, LoadError> {
+        let dir = TempDir::new("load").unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, text).unwrap();
+        load_file(&path)
+    }
+
+    #[test]
+    fn load_formats() {
+        let expected = Example {
+            src: "https://example.com/".to_owned(),
+            tags: vec!["a".to_owned()],
+        };
+
+        let toml = load_text("c.toml", "src = \"https://example.com/\"\ntags = [\"a\"]\n");
+        let yaml = load_text("c.yaml", "src: https://example.com/\ntags: [a]\n");
+        let json = load_text(
+            "c.json",
+            r#"{"src": "https://example.com/", "tags": ["a"]}"#,
+        );
+
+        assert_eq!(toml.unwrap(), expected);
+        assert_eq!(yaml.unwrap(), expected);
+        assert_eq!(json.unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let err = load_text("c.json", "{\n  \"src\": 1\n}").unwrap_err();
+        match err {
+            LoadError::Parse {
+                format, location, ..
+            } => {
+                assert_eq!(format, Format::Json);
+                assert_eq!(location.map(|(line, _)| line), Some(2));
+            }
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+}
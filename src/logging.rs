@@ -0,0 +1,716 @@
+//! Logging to standard error and, optionally, a rotated log file.
+//!
+//! Messages are `tracing` events. The spans they are logged within carry the
+//! repository, variant and file being worked on, and can also be written out
+//! as folded stacks of the time spent in each, for drawing flamegraphs.
+
+use serde::Serialize;
+use std::cell::RefCell;
+use std::env;
+use std::fmt::{self, Debug, Display};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event as TracingEvent, Instrument, Level, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context as LayerContext, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+use tracing_subscriber::EnvFilter;
+
+use crate::telemetry::Telemetry;
+
+/// The format of each logged line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unknown log format '{}' (expected text or json)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Structured details of an action, attached to the message that describes it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Event {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Event {
+    /// Describe an action.
+    pub fn new(action: &'static str) -> Event {
+        Event {
+            action,
+            ..Event::default()
+        }
+    }
+
+    /// The repository the action applies to.
+    pub fn repo(mut self, repo: &str) -> Event {
+        self.repo = Some(repo.to_owned());
+        self
+    }
+
+    /// The file the action applies to.
+    pub fn file<F: Display>(mut self, file: F) -> Event {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    /// The number of bytes transferred.
+    pub fn bytes(mut self, bytes: u64) -> Event {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// How long the action took.
+    pub fn duration(mut self, duration: Duration) -> Event {
+        self.duration = Some(duration.as_secs_f64());
+        self
+    }
+
+    /// The error that caused the action to fail.
+    pub fn error<E: Display>(mut self, error: E) -> Event {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// Attach the event to every message logged by `log`.
+    ///
+    /// Only JSON logs include the details of the event.
+    pub fn log<F: FnOnce()>(self, log: F) {
+        EVENT.with(|event| *event.borrow_mut() = Some(self));
+        log();
+        EVENT.with(|event| *event.borrow_mut() = None);
+    }
+}
+
+thread_local! {
+    /// The event being logged on this thread.
+    static EVENT: RefCell<Option<Event>> = const { RefCell::new(None) };
+}
+
+/// A single log line in JSON.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    level: String,
+    target: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
+    #[serde(flatten)]
+    event: Option<Event>,
+}
+
+/// The repository, variant and file that log messages are about, from the spans they are
+/// logged within.
+#[derive(Debug, Clone, Default)]
+struct Context {
+    repo: Option<String>,
+    variant: Option<String>,
+    file: Option<String>,
+}
+
+impl Context {
+    /// Fill in the fields a span sets.
+    fn update(&mut self, fields: &Context) {
+        for (field, value) in &mut [
+            (&mut self.repo, &fields.repo),
+            (&mut self.variant, &fields.variant),
+            (&mut self.file, &fields.file),
+        ] {
+            if value.is_some() {
+                **field = (*value).clone();
+            }
+        }
+    }
+}
+
+impl Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.repo, &self.variant) {
+            (Some(repo), Some(variant)) if !variant.is_empty() => {
+                write!(f, "{} {}", repo, variant)
+            }
+            (Some(repo), _) => write!(f, "{}", repo),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+impl Visit for Context {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "repo" => self.repo = Some(value.to_owned()),
+            "variant" => self.variant = Some(value.to_owned()),
+            "file" => self.file = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+}
+
+/// The message of an event, followed by any other fields it has.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.0.insert_str(0, &format!("{:?}", value)),
+            // Added to messages forwarded from the `log` crate
+            name if name.starts_with("log.") => {}
+            name => self.0 += &format!(" {}={:?}", name, value),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The repository of the task doing the logging.
+    static REPO: String;
+}
+
+/// Run a future within a span for a repository and variant, which labels every message it logs.
+pub async fn scope<F: Future>(repo: &str, variant: Option<String>, future: F) -> F::Output {
+    let span = match &variant {
+        Some(variant) => tracing::info_span!("variant", repo, variant = variant.as_str()),
+        None => tracing::info_span!("repo", repo),
+    };
+    REPO.scope(repo.to_owned(), future.instrument(span)).await
+}
+
+/// The repository that the current task is working on, if any.
+pub fn current_repo() -> Option<String> {
+    REPO.try_with(|repo| repo.clone()).ok()
+}
+
+/// Where and how a log file is written.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    /// The file that log lines are appended to.
+    pub path: PathBuf,
+    /// Rotate the file once it grows beyond this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate the file once it has been open for this long.
+    pub max_age: Option<Duration>,
+    /// The number of rotated files to keep.
+    pub keep: usize,
+}
+
+/// Install the subscriber for log messages and spans.
+///
+/// Log lines go to standard error and are also appended to the log file if one is given. A
+/// non-zero verbosity selects the level of messages to log, otherwise `RUST_LOG` is used if it
+/// is set. If a timing file is given, the time spent in each span is appended to it as folded
+/// stacks, and spans are also collected for export if telemetry is given. The verbosity only
+/// applies to messages, so spans are timed and exported however quiet the log is. Messages from
+/// dependencies that use the `log` crate are included.
+pub fn init(
+    format: LogFormat,
+    verbosity: i32,
+    file: Option<&LogFile>,
+    timing: Option<&Path>,
+    telemetry: Option<Telemetry>,
+) -> io::Result<()> {
+    let file = match file {
+        Some(options) => Some(Mutex::new(RotatingFile::open(options.clone())?)),
+        None => None,
+    };
+    let timing = match timing {
+        Some(path) => Some(Timing::open(path)?),
+        None => None,
+    };
+
+    let spans = Targets::new().with_target(env!("CARGO_PKG_NAME"), Level::INFO);
+    let subscriber = Registry::default()
+        .with(
+            Output {
+                format,
+                stderr: true,
+                file,
+            }
+            .with_filter(filter(verbosity, env::var("RUST_LOG").ok().as_deref())),
+        )
+        .with(timing.with_filter(spans.clone()))
+        .with(telemetry.with_filter(spans));
+    tracing::subscriber::set_global_default(subscriber).map_err(io::Error::other)?;
+    tracing_log::LogTracer::init().map_err(io::Error::other)
+}
+
+/// Build the filter for a verbosity, falling back to a `RUST_LOG` style filter.
+///
+/// By default progress is logged for yumclone and only warnings for its dependencies. Each step
+/// of verbosity logs more detail, and each step below zero logs less.
+fn filter(verbosity: i32, rust_log: Option<&str>) -> EnvFilter {
+    let crate_name = env!("CARGO_PKG_NAME");
+    let directives = match (verbosity, rust_log) {
+        (0, Some(filters)) => filters.to_owned(),
+        (v, _) if v <= -2 => "error".to_owned(),
+        (-1, _) => "warn".to_owned(),
+        (0, None) => format!("warn,{}=info", crate_name),
+        (1, _) => format!("warn,{}=debug", crate_name),
+        (2, _) => format!("warn,{}=trace", crate_name),
+        _ => "trace".to_owned(),
+    };
+    EnvFilter::new(directives)
+}
+
+/// Writes log lines to standard error and a log file.
+struct Output {
+    format: LogFormat,
+    stderr: bool,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Output {
+    /// Format a message as a single line.
+    fn line(&self, level: &Level, target: &str, message: &str, context: &Context) -> String {
+        let time = humantime::format_rfc3339_seconds(SystemTime::now());
+        match (self.format, &context.repo) {
+            (LogFormat::Text, Some(_)) => format!(
+                "{} {:<5} {}: [{}] {}\n",
+                time, level, target, context, message
+            ),
+            (LogFormat::Text, None) => {
+                format!("{} {:<5} {}: {}\n", time, level, target, message)
+            }
+            (LogFormat::Json, _) => {
+                let mut event = EVENT.with(|event| event.borrow().clone());
+                let repo = event
+                    .as_mut()
+                    .and_then(|event| event.repo.take())
+                    .or_else(|| context.repo.clone());
+                if let Some(file) = &context.file {
+                    let event = event.get_or_insert_with(|| Event::new("log"));
+                    event.file.get_or_insert_with(|| file.clone());
+                }
+                let line = JsonLine {
+                    time: time.to_string(),
+                    level: level.to_string(),
+                    target,
+                    message,
+                    repo,
+                    variant: context.variant.clone(),
+                    event,
+                };
+                let mut json = serde_json::to_string(&line).unwrap_or_default();
+                json.push('\n');
+                json
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for Output
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let mut fields = Context::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_event(&self, event: &TracingEvent<'_>, ctx: LayerContext<'_, S>) {
+        let mut context = Context::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<Context>() {
+                    context.update(fields);
+                }
+            }
+        }
+        let mut message = Message::default();
+        event.record(&mut message);
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let line = self.line(metadata.level(), metadata.target(), &message.0, &context);
+        if self.stderr {
+            eprint!("{}", line);
+        }
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                if let Err(e) = file.write_line(&line) {
+                    eprintln!("Could not write to log file {:?}: {}", file.options.path, e);
+                }
+            }
+        }
+    }
+}
+
+/// Appends the time spent within each span to a file as folded stacks, such as
+/// `repo:fedora;variant:arch=x86_64;file 1500`, which flamegraph tools can draw.
+///
+/// Times are in microseconds spent polling the span, excluding the time spent in the spans
+/// within it.
+struct Timing {
+    file: Mutex<File>,
+}
+
+/// The time spent within a span so far.
+#[derive(Default)]
+struct Busy {
+    entered: Option<Instant>,
+    busy: Duration,
+    children: Duration,
+}
+
+impl Timing {
+    /// Open a file for appending folded stacks.
+    fn open(path: &Path) -> io::Result<Timing> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Timing {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl<S> Layer<S> for Timing
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Busy::default());
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(busy) = span.extensions_mut().get_mut::<Busy>() {
+                busy.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(busy) = span.extensions_mut().get_mut::<Busy>() {
+                if let Some(entered) = busy.entered.take() {
+                    busy.busy += entered.elapsed();
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let (busy, own) = match span.extensions().get::<Busy>() {
+            Some(busy) => (busy.busy, busy.busy.saturating_sub(busy.children)),
+            None => return,
+        };
+        if let Some(parent) = span.parent() {
+            if let Some(parent) = parent.extensions_mut().get_mut::<Busy>() {
+                parent.children += busy;
+            }
+        }
+
+        // Repositories and variants are named in their frames, but files are merged together
+        let frames: Vec<String> = span
+            .scope()
+            .from_root()
+            .map(|span| {
+                let extensions = span.extensions();
+                let fields = extensions.get::<Context>();
+                match (span.name(), fields) {
+                    (
+                        "repo",
+                        Some(Context {
+                            repo: Some(repo), ..
+                        }),
+                    ) => format!("repo:{}", repo),
+                    (
+                        "variant",
+                        Some(Context {
+                            variant: Some(variant),
+                            ..
+                        }),
+                    ) => {
+                        format!("variant:{}", variant)
+                    }
+                    (name, _) => name.to_owned(),
+                }
+            })
+            .collect();
+        let line = format!("{} {}\n", frames.join(";"), own.as_micros());
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// A log file that is rotated when it grows too large or too old.
+struct RotatingFile {
+    options: LogFile,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl RotatingFile {
+    /// Open the log file for appending.
+    fn open(options: LogFile) -> io::Result<RotatingFile> {
+        if let Some(parent) = options.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.path)?;
+        let metadata = file.metadata()?;
+        let opened = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+
+        Ok(RotatingFile {
+            size: metadata.len(),
+            options,
+            file,
+            opened,
+        })
+    }
+
+    /// Append a line, rotating the file first if needed.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Check whether the file has reached its size or age limit.
+    fn needs_rotation(&self) -> bool {
+        let too_large = self.options.max_size.is_some_and(|max| self.size >= max);
+        let too_old = self
+            .options
+            .max_age
+            .is_some_and(|max| self.opened.elapsed().is_ok_and(|age| age >= max));
+        too_large || too_old
+    }
+
+    /// Shift each rotated file up by one, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let path = &self.options.path;
+        if self.options.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            let _ = fs::remove_file(rotated(path, self.options.keep));
+            for n in (1..self.options.keep).rev() {
+                let from = rotated(path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated(path, 1))?;
+        }
+
+        let options = self.options.clone();
+        *self = RotatingFile::open(options)?;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
+
+/// The path of the n-th rotated log file.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use tracing::{info, info_span};
+
+    #[test]
+    fn json_line() {
+        let output = Output {
+            format: LogFormat::Json,
+            stderr: false,
+            file: None,
+        };
+        let context = Context {
+            repo: Some("fedora".to_owned()),
+            variant: Some("arch=x86_64".to_owned()),
+            file: None,
+        };
+        let mut line = String::new();
+        Event::new("download")
+            .file("Packages/a.rpm")
+            .bytes(10)
+            .log(|| line = output.line(&Level::INFO, "yumclone::package", "Downloaded", &context));
+
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["message"], "Downloaded");
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["action"], "download");
+        assert_eq!(json["file"], "Packages/a.rpm");
+        assert_eq!(json["bytes"], 10);
+        assert_eq!(json["repo"], "fedora");
+        assert_eq!(json["variant"], "arch=x86_64");
+        assert!(json.get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn span_context() {
+        let dir = TempDir::new("logging").unwrap();
+        let path = dir.path().join("yumclone.log");
+        let timing_path = dir.path().join("timing.folded");
+        let file = RotatingFile::open(LogFile {
+            path: path.clone(),
+            max_size: None,
+            max_age: None,
+            keep: 0,
+        })
+        .unwrap();
+        let subscriber = Registry::default()
+            .with(Output {
+                format: LogFormat::Json,
+                stderr: false,
+                file: Some(Mutex::new(file)),
+            })
+            .with(Timing::open(&timing_path).unwrap());
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        scope("fedora", None, async {
+            scope("fedora", Some("arch=x86_64".to_owned()), async {
+                let _file = info_span!("file", file = "Packages/a.rpm").entered();
+                info!(bytes = 10, "Downloaded");
+            })
+            .await
+        })
+        .await;
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["message"], "Downloaded bytes=10");
+        assert_eq!(json["repo"], "fedora");
+        assert_eq!(json["variant"], "arch=x86_64");
+        assert_eq!(json["file"], "Packages/a.rpm");
+
+        let timing = fs::read_to_string(&timing_path).unwrap();
+        let stacks: Vec<&str> = timing
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                "repo:fedora;variant:arch=x86_64;file",
+                "repo:fedora;variant:arch=x86_64",
+                "repo:fedora"
+            ]
+        );
+    }
+
+    #[test]
+    fn verbosity_filter() {
+        let enabled = |filter: EnvFilter, check: fn() -> bool| {
+            tracing::subscriber::with_default(Registry::default().with(filter), check)
+        };
+        let info = || tracing::enabled!(target: "yumclone::package", Level::INFO);
+        let debug = || tracing::enabled!(target: "yumclone::package", Level::DEBUG);
+        let dependency = || tracing::enabled!(target: "reqwest::connect", Level::INFO);
+
+        assert!(enabled(filter(0, None), info));
+        assert!(!enabled(filter(0, None), debug));
+        assert!(!enabled(filter(0, None), dependency));
+
+        assert!(enabled(filter(1, None), debug));
+        assert!(!enabled(filter(-1, None), info));
+        assert!(enabled(filter(0, Some("debug")), dependency));
+        assert!(!enabled(filter(-1, Some("debug")), dependency));
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = TempDir::new("logging").unwrap();
+        let path = dir.path().join("yumclone.log");
+        let mut file = RotatingFile::open(LogFile {
+            path: path.clone(),
+            max_size: Some(10),
+            max_age: None,
+            keep: 2,
+        })
+        .unwrap();
+
+        for line in &[
+            "first line\n",
+            "second line\n",
+            "third line\n",
+            "fourth line\n",
+        ] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 1)).unwrap(),
+            "third line\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 2)).unwrap(),
+            "second line\n"
+        );
+        assert!(!rotated(&path, 3).exists());
+    }
+}
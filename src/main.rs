@@ -13,70 +13,1539 @@
 
 #![warn(missing_docs)]
 
-use loadconf::Load;
-use log::{debug, error};
-use serde::Deserialize;
+use failure::format_err;
+use futures::stream::{self, StreamExt};
+use glob::Pattern;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
 
+pub mod audit;
+pub mod bench;
+pub mod breaker;
+pub mod compare;
+pub mod concurrency;
 pub mod config;
+pub mod doctor;
+pub mod filelists;
+pub mod hash;
+pub mod health;
+pub mod hooks;
+pub mod href;
+pub mod init;
+pub mod links;
+pub mod list;
+pub mod load;
+pub mod logging;
+pub mod manifest;
+pub mod other;
 pub mod package;
+pub mod plan;
+pub mod prefetch;
+pub mod progress;
+pub mod rank;
 mod repo;
+pub mod repofile;
+pub mod report;
+pub mod resolve;
+pub mod secret;
+pub mod serve;
+pub mod shard;
+pub mod sign;
+pub mod snapshot;
+pub mod state;
+pub mod stats;
+pub mod telemetry;
+pub mod throttle;
+pub mod timeout;
+pub mod tls;
+pub mod treeinfo;
+pub mod units;
 pub mod urlmux;
+pub mod usage;
 
-use crate::config::Config;
-use crate::package::CheckType::*;
+use crate::config::{Config, Configs, Outcome};
+use crate::logging::Event;
+use crate::package::CheckType::{self, *};
+use crate::package::OnMissing;
+use crate::progress::format_bytes;
 pub use crate::repo::Repo;
-
-#[derive(Debug, Deserialize)]
-struct Configs {
-    repo: Vec<Config>,
-}
-
-impl Default for Configs {
-    fn default() -> Configs {
-        Configs {
-            repo: Vec::default(),
-        }
-    }
-}
+use crate::report::{RepoReport, Report, Status};
+use crate::state::{Run, State};
+use crate::stats::{LimitReached, Stats};
 
 #[derive(StructOpt)]
 #[structopt(about = "Synchronise a remote rpm repository.")]
 struct Args {
-    /// Ensure that local files match their checksums (implies size check)
+    /// Ensure that local files match their checksums (same as `--check-mode hash`)
     #[structopt(short = "c", long = "check")]
     check: bool,
-    /// Ensure that local files match their sizes
+    /// Ensure that local files match their sizes (same as `--check-mode size`)
     #[structopt(short = "s", long = "size")]
     size: bool,
+    /// How existing local files are checked (none, size or hash) [default: none]
+    #[structopt(long = "check-mode", raw(conflicts_with_all = r#"&["check", "size"]"#))]
+    check_mode: Option<CheckType>,
+    /// Only download this share of the packages, as i/N, leaving the metadata alone
+    #[structopt(long = "shard")]
+    shard: Option<shard::Shard>,
     /// Configuration file
     #[structopt(short = "C", long = "config")]
     config: Option<String>,
+    /// Only use repositories with names matching this glob (may be repeated)
+    #[structopt(short = "r", long = "repo", number_of_values = 1, raw(global = "true"))]
+    repos: Vec<String>,
+    /// Only use the given value for a tag, as TAG=VALUE (may be repeated)
+    #[structopt(
+        short = "t",
+        long = "tag",
+        number_of_values = 1,
+        parse(try_from_str = "parse_tag")
+    )]
+    tags: Vec<(String, String)>,
+    /// Read this many bytes at a time when verifying checksums (e.g. "1MiB")
+    #[structopt(long = "hash-block-size", parse(try_from_str = "units::parse_size"))]
+    hash_block_size: Option<u64>,
+    /// Map files into memory when verifying checksums, instead of reading them
+    #[structopt(long = "mmap")]
+    mmap: bool,
+    /// Number of received chunks to buffer for each download while it is written to disk
+    #[structopt(long = "buffer-chunks")]
+    buffer_chunks: Option<usize>,
+    /// Allocate the space for each download before writing it
+    #[structopt(long = "preallocate")]
+    preallocate: bool,
+    /// Stop downloading after this many bytes (e.g. "50GiB"), continuing on the next run
+    #[structopt(long = "max-bytes", parse(try_from_str = "units::parse_size"))]
+    max_bytes: Option<u64>,
+    /// Synchronise this many repositories at once
+    #[structopt(long = "parallel-repos", default_value = "1")]
+    parallel_repos: usize,
+    /// Allow at most this many downloads at once across every repository
+    #[structopt(long = "max-downloads")]
+    max_downloads: Option<usize>,
+    /// File to keep state between runs in
+    #[structopt(long = "state", parse(from_os_str))]
+    state: Option<PathBuf>,
+    /// Write a JSON report of the run to this file
+    #[structopt(long = "report", parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// Write an HTML report of the run, with recent history, to this file
+    #[structopt(long = "html-report", parse(from_os_str))]
+    html_report: Option<PathBuf>,
+    /// Log more detail (may be repeated)
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: i32,
+    /// Log less detail (may be repeated)
+    #[structopt(short = "q", long = "quiet", parse(from_occurrences))]
+    quiet: i32,
+    /// Format of log messages (text or json)
+    #[structopt(long = "log-format", default_value = "text")]
+    log_format: logging::LogFormat,
+    /// Also append log messages to this file
+    #[structopt(long = "log-file", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+    /// Rotate the log file once it reaches this size (e.g. "100MiB")
+    #[structopt(long = "log-max-size", parse(try_from_str = "units::parse_size"))]
+    log_max_size: Option<u64>,
+    /// Rotate the log file once it is this old (e.g. "1day")
+    #[structopt(long = "log-max-age", parse(try_from_str = "units::parse_duration"))]
+    log_max_age: Option<Duration>,
+    /// Number of rotated log files to keep
+    #[structopt(long = "log-keep", default_value = "5")]
+    log_keep: usize,
+    /// Append the time spent in each span to this file, as folded stacks for flamegraphs
+    #[structopt(long = "trace-timing", parse(from_os_str))]
+    trace_timing: Option<PathBuf>,
+    /// Append a JSON record of every file downloaded or removed to this file
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+    /// Export spans and metrics to this OTLP/HTTP collector (e.g. "http://localhost:4318")
+    #[structopt(
+        long = "otlp-endpoint",
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT",
+        parse(try_from_str = "href::base")
+    )]
+    otlp_endpoint: Option<Url>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Manage the configuration file
+    #[structopt(name = "config")]
+    Config(ConfigCommand),
+    /// Report files that aren't referenced by any current metadata, without removing them
+    #[structopt(name = "orphans")]
+    Orphans {
+        /// Write the path of every orphaned file to this file
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Verify local files and write the changes synchronising would make to a plan
+    #[structopt(name = "plan")]
+    Plan {
+        /// Write the plan to this file
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Compare the packages in the metadata of two mirrored trees
+    #[structopt(name = "compare")]
+    Compare {
+        /// The first tree
+        left: String,
+        /// The second tree
+        right: String,
+        /// Also check that every package file in each tree matches its checksum
+        #[structopt(long = "hash")]
+        hash: bool,
+    },
+    /// Export the files added or changed between two snapshots of a destination, to update an
+    /// offline mirror
+    #[structopt(name = "delta")]
+    Delta {
+        /// The older snapshot
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        /// The newer snapshot
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+        /// Empty directory to export the changed files and DELTA.json to
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Check that every file the upstream metadata references can be retrieved, without
+    /// downloading them
+    #[structopt(name = "links")]
+    Links {
+        /// Write a JSON report of the broken links to this file
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Measure the latency and throughput of the upstream and mirrors of each repository
+    #[structopt(name = "bench")]
+    Bench {
+        /// The number of packages to download from each, spread across their sizes
+        #[structopt(long = "files", default_value = "5")]
+        files: usize,
+        /// Only download the first part of each package (e.g. "1MiB")
+        #[structopt(long = "chunk", parse(try_from_str = "units::parse_size"))]
+        chunk: Option<u64>,
+    },
+    /// Download only the matching packages, publishing the new metadata if nothing else it
+    /// lists is missing
+    #[structopt(name = "fetch")]
+    Fetch {
+        /// Packages to download, as globs of their names, name-version-release.arch or file names
+        #[structopt(required = true)]
+        packages: Vec<String>,
+    },
+    /// Make the changes in a plan, unless the upstream has changed since it was made
+    #[structopt(name = "apply")]
+    Apply {
+        /// The plan to apply
+        #[structopt(parse(from_os_str))]
+        plan: PathBuf,
+    },
+    /// Check the configuration, upstreams, destinations and clock for common problems
+    #[structopt(name = "doctor")]
+    Doctor,
+    /// Show the last synchronisation of each repository, as recorded in the state
+    #[structopt(name = "status")]
+    Status,
+    /// Summarise the mirrored packages by architecture and repository, and their growth since
+    /// the previous sync
+    #[structopt(name = "stats")]
+    Stats {
+        /// The number of the largest packages to show
+        #[structopt(long = "top", default_value = "10")]
+        top: usize,
+    },
+    /// Poll upstream metadata and only synchronise repositories that have changed
+    #[structopt(name = "watch")]
+    Watch {
+        /// How long to wait between polls (e.g. "15m")
+        #[structopt(
+            long = "interval",
+            default_value = "15m",
+            parse(try_from_str = "units::parse_duration")
+        )]
+        interval: Duration,
+        /// Serve `/healthz` and `/status` on this address
+        #[structopt(long = "listen")]
+        listen: Option<SocketAddr>,
+    },
+    /// Serve mirrored repositories over HTTP
+    #[structopt(name = "serve")]
+    Serve {
+        /// Address to listen on
+        #[structopt(long = "listen", default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        /// Directory to serve
+        #[structopt(long = "root", default_value = ".", parse(from_os_str))]
+        root: PathBuf,
+        /// List the contents of directories
+        #[structopt(long = "listing")]
+        listing: bool,
+    },
+    /// List the packages in the metadata of mirrored repositories
+    #[structopt(name = "list")]
+    List {
+        /// Only list packages with names matching this glob
+        #[structopt(long = "name")]
+        name: Option<String>,
+        /// Only list packages built for this architecture
+        #[structopt(long = "arch")]
+        arch: Option<String>,
+        /// Output format (text, json or csv)
+        #[structopt(long = "format", default_value = "text")]
+        format: list::ListFormat,
+    },
+    /// Search the metadata of mirrored repositories
+    #[structopt(name = "query")]
+    Query {
+        /// Find the packages providing a file (may be a glob pattern)
+        #[structopt(long = "file")]
+        file: String,
+    },
+}
+
+#[derive(StructOpt)]
+enum ConfigCommand {
+    /// Check the configuration for problems without synchronising
+    #[structopt(name = "validate")]
+    Validate,
+    /// Write an example configuration
+    #[structopt(name = "init")]
+    Init {
+        /// Convert the repositories in a dnf .repo file instead
+        #[structopt(long = "from-repo", parse(from_os_str))]
+        from_repo: Option<PathBuf>,
+        /// Write the configuration to a file instead of standard output
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Overwrite the output file if it exists
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-
-    let args = Args::from_args();
-    let config_file = args
-        .config
-        .as_ref()
-        .map(|s| s.as_str())
-        .unwrap_or(env!("CARGO_PKG_NAME"));
-    let configs: Configs = Load::try_load(config_file).expect("Could not load configuration");
-
-    let check = match (args.check, args.size) {
-        (true, _) => CheckHash,
-        (false, true) => CheckSize,
-        (false, false) => CheckRemoteSize,
-    };
-
-    for repo in configs.repo {
-        debug!("Loaded repo: {:?}", repo);
-        if let Err(e) = repo.sync(check).await {
-            error!("Error synchronising: {}'", e);
+    let version = format!(
+        "{}\nTLS: {}\nDigests: {}",
+        env!("CARGO_PKG_VERSION"),
+        tls::BACKENDS.join(", "),
+        hash::BACKEND
+    );
+    let args = Args::from_clap(&Args::clap().long_version(version.as_str()).get_matches());
+    let log_file = args.log_file.as_ref().map(|path| logging::LogFile {
+        path: path.clone(),
+        max_size: args.log_max_size,
+        max_age: args.log_max_age,
+        keep: args.log_keep,
+    });
+    if let Err(e) = logging::init(
+        args.log_format,
+        args.verbose - args.quiet,
+        log_file.as_ref(),
+        args.trace_timing.as_deref(),
+        args.otlp_endpoint.clone().map(telemetry::start),
+    ) {
+        eprintln!("Could not set up logging: {}", e);
+        std::process::exit(1);
+    }
+    if let Some(path) = &args.audit_log {
+        if let Err(e) = audit::open(path) {
+            error!("Could not open audit log {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(Command::Config(ConfigCommand::Init {
+        from_repo,
+        output,
+        force,
+    })) = &args.command
+    {
+        if let Err(e) = init(from_repo.as_deref(), output.as_deref(), *force) {
+            error!("Error writing configuration: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Compare { left, right, hash }) = &args.command {
+        match compare(left, right, *hash).await {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("Error comparing '{}' and '{}': {}", left, right, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(Command::Delta { old, new, output }) = &args.command {
+        if let Err(e) = delta(old, new, output).await {
+            error!(
+                "Error exporting changes between {:?} and {:?}: {}",
+                old, new, e
+            );
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config_file = args.config.as_deref().unwrap_or(env!("CARGO_PKG_NAME"));
+    if let Some(Command::Doctor) = &args.command {
+        if !doctor(config_file, &args.repos).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut configs = match Configs::load(config_file) {
+        Ok(configs) => configs,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = configs.select(&args.repos) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if !args.tags.is_empty() {
+        let mut overrides: HashMap<String, Vec<String>> = HashMap::new();
+        for (tag, value) in &args.tags {
+            let values = match urlmux::expand_range(value) {
+                Ok(values) => values.unwrap_or_else(|| vec![value.clone()]),
+                Err(e) => {
+                    error!("Tag '{}': {}", tag, e);
+                    std::process::exit(1);
+                }
+            };
+            overrides.entry(tag.clone()).or_default().extend(values);
+        }
+        configs.restrict_tags(&overrides);
+    }
+
+    let check = match (args.check_mode, args.check, args.size) {
+        (Some(mode), _, _) => Some(mode),
+        (None, true, _) => Some(CheckHash),
+        (None, false, true) => Some(CheckSize),
+        (None, false, false) => None,
+    };
+
+    match &args.command {
+        Some(Command::Config(ConfigCommand::Validate)) => {
+            if !validate(&configs) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Serve {
+            listen,
+            root,
+            listing,
+        }) => {
+            if let Err(e) = serve(&configs, *listen, root, *listing).await {
+                error!("Error serving {:?}: {}", root, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::List { name, arch, format }) => {
+            if let Err(e) = list(&configs, name.as_deref(), arch.as_deref(), *format).await {
+                error!("Error listing packages: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Query { file }) => {
+            match query(&configs, file).await {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    error!("Error searching metadata: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Links { output }) => {
+            match links(&configs, output.as_deref()).await {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    error!("Error checking links: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Bench { files, chunk }) => {
+            if let Err(e) = bench(&configs, *files, *chunk).await {
+                error!("Error measuring mirrors: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Orphans { output }) => {
+            if let Err(e) = orphans(&configs, output.as_deref()).await {
+                error!("Error finding orphaned files: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    configs.sort_by_priority();
+    let daemon = matches!(args.command, Some(Command::Watch { .. }));
+    throttle::install(configs.bandwidth.clone(), daemon);
+    let mut hashing = package::Hashing {
+        mmap: args.mmap,
+        ..package::Hashing::default()
+    };
+    if let Some(block_size) = args.hash_block_size {
+        hashing.block_size = block_size as usize;
+    }
+    package::configure_hashing(hashing);
+    package::configure_preallocation(args.preallocate);
+    if let Some(depth) = args.buffer_chunks {
+        package::configure_buffer(depth);
+    }
+    let state_path = args.state.clone().unwrap_or_else(state::default_path);
+    let options = SyncOptions {
+        check,
+        state_path: &state_path,
+        report_path: args.report.as_deref(),
+        html_report_path: args.html_report.as_deref(),
+        max_bytes: args.max_bytes,
+        parallel_repos: args.parallel_repos,
+    };
+    if let Some(max) = args.max_downloads {
+        concurrency::limit_globally(max);
+    }
+    if let Some(shard) = args.shard {
+        // Fetch and apply publish metadata that would refer to the packages of the other shards,
+        // and a plan would list them for download
+        if let Some(Command::Plan { .. } | Command::Fetch { .. } | Command::Apply { .. }) =
+            args.command
+        {
+            error!("--shard can only be used to synchronise, not with plan, fetch or apply");
+            std::process::exit(1);
+        }
+        shard::install(shard);
+    }
+
+    match args.command {
+        None => {
+            let repos: Vec<&Config> = configs.repos.iter().collect();
+            let succeeded = run(&repos, &options).await;
+            telemetry::flush().await;
+            if !succeeded {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Watch { interval, listen }) => {
+            watch(&configs, &options, interval, listen).await
+        }
+        Some(Command::Plan { output }) => {
+            if let Err(e) = plan(&configs, &options, &output).await {
+                error!("Error planning changes: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Apply { plan }) => {
+            if !apply(&configs, &plan).await {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Status) => match State::load(&state_path) {
+            Ok(state) => status(&configs, &state),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Command::Stats { top }) => {
+            let result = match State::load(&state_path) {
+                Ok(state) => summarise(&configs, &state, top).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("Error summarising packages: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Fetch { packages }) => {
+            if !fetch(&configs, &options, &packages).await {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Config(_))
+        | Some(Command::Orphans { .. })
+        | Some(Command::Links { .. })
+        | Some(Command::Bench { .. })
+        | Some(Command::Delta { .. })
+        | Some(Command::Compare { .. })
+        | Some(Command::Doctor)
+        | Some(Command::Serve { .. })
+        | Some(Command::Query { .. })
+        | Some(Command::List { .. }) => unreachable!(),
+    }
+}
+
+/// Parse a TAG=VALUE pair from the command line.
+fn parse_tag(arg: &str) -> Result<(String, String), String> {
+    let mut parts = arg.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(tag), Some(value)) if !tag.is_empty() => {
+            Ok((tag.trim_start_matches('$').to_owned(), value.to_owned()))
+        }
+        _ => Err(format!("Expected TAG=VALUE but found '{}'", arg)),
+    }
+}
+
+/// Options that apply to a whole synchronisation run.
+struct SyncOptions<'a> {
+    /// How existing files are checked, overriding the configuration of each repository.
+    check: Option<CheckType>,
+    /// Where state is kept between runs.
+    state_path: &'a Path,
+    /// Where to write a JSON report of the run.
+    report_path: Option<&'a Path>,
+    /// Where to write an HTML report of the run.
+    html_report_path: Option<&'a Path>,
+    /// The most bytes to download during the run.
+    max_bytes: Option<u64>,
+    /// The number of repositories synchronised at once.
+    parallel_repos: usize,
+}
+
+/// Synchronise repositories and write the report.
+///
+/// Returns whether every enabled repository was synchronised without errors.
+async fn run(repos: &[&Config], options: &SyncOptions<'_>) -> bool {
+    match sync(repos, options).await {
+        Some(report) => finish(&report, options),
+        None => false,
+    }
+}
+
+/// Plan the changes to every enabled repository, writing the plan to a file.
+async fn plan(
+    configs: &Configs,
+    options: &SyncOptions<'_>,
+    output: &Path,
+) -> Result<(), failure::Error> {
+    let mut plan = plan::Plan::new();
+    for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+        let check = options
+            .check
+            .or_else(|| repo.check_mode())
+            .unwrap_or(CheckRemoteSize);
+        let variants = logging::scope(
+            repo.label(),
+            None,
+            timeout::scope(repo.timeouts(), repo.plan(check)),
+        )
+        .await?;
+        for variant in &variants {
+            println!(
+                "{}: download {} files ({}), remove {} files ({})",
+                variant.dest,
+                variant.downloads.len(),
+                format_bytes(variant.download_size()),
+                variant.removals.len(),
+                format_bytes(variant.removal_size())
+            );
+        }
+        plan.variants.extend(variants);
+    }
+    plan.save(output)?;
+    info!("Wrote plan to {:?}", output);
+    Ok(())
+}
+
+/// Make the changes in a plan, returning whether every variant was changed.
+async fn apply(configs: &Configs, path: &Path) -> bool {
+    let plan = match plan::Plan::load(path) {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let started = Instant::now();
+    let stats = Stats::default();
+    let mut succeeded = true;
+    for variant in &plan.variants {
+        let repo = match configs
+            .repos
+            .iter()
+            .find(|repo| repo.label() == variant.repo)
+        {
+            Some(repo) => repo,
+            None => {
+                error!("Repository '{}' is no longer configured", variant.repo);
+                succeeded = false;
+                continue;
+            }
+        };
+        // Boxed, as applying the plan is too large for the stack in debug builds
+        let result = Box::pin(logging::scope(
+            repo.label(),
+            None,
+            timeout::scope(repo.timeouts(), repo.apply(variant, &stats)),
+        ))
+        .await;
+        match result {
+            Ok(Outcome::Synced) => {}
+            Ok(Outcome::Unavailable) => succeeded = false,
+            Err(e) => {
+                error!("Error applying the plan to '{}': {}", variant.dest, e);
+                succeeded = false;
+            }
+        }
+    }
+    let summary = stats.summary(started.elapsed());
+    info!(
+        "Applied plan made {}: {} downloaded ({}), {} removed ({})",
+        plan.created,
+        summary.added,
+        format_bytes(summary.bytes_downloaded),
+        summary.removed,
+        format_bytes(summary.bytes_deleted)
+    );
+    succeeded
+}
+
+/// Download the packages matching any of the patterns from every enabled repository, returning
+/// whether any were found and every repository succeeded.
+async fn fetch(configs: &Configs, options: &SyncOptions<'_>, packages: &[String]) -> bool {
+    let patterns = match packages
+        .iter()
+        .map(|package| Pattern::new(package))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            error!("Invalid package pattern: {}", e);
+            return false;
+        }
+    };
+    let started = Instant::now();
+    let stats = Stats::default();
+    let mut succeeded = true;
+    let mut fetched = 0;
+    for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+        let check = options
+            .check
+            .or_else(|| repo.check_mode())
+            .unwrap_or(CheckRemoteSize);
+        // Boxed, as fetching packages is too large for the stack in debug builds
+        let result = Box::pin(logging::scope(
+            repo.label(),
+            None,
+            timeout::scope(repo.timeouts(), repo.fetch(&patterns, check, &stats)),
+        ))
+        .await;
+        match result {
+            Ok(count) => fetched += count,
+            Err(e) => {
+                error!("Error fetching packages from '{}': {}", repo.label(), e);
+                succeeded = false;
+            }
+        }
+    }
+    if succeeded && fetched == 0 {
+        error!("No packages match {}", packages.join(", "));
+        return false;
+    }
+    let summary = stats.summary(started.elapsed());
+    info!(
+        "Fetched {} packages: {} downloaded ({})",
+        fetched,
+        summary.added,
+        format_bytes(summary.bytes_downloaded)
+    );
+    succeeded
+}
+
+/// Poll the upstream metadata of every enabled repository, synchronising those that changed.
+///
+/// The fingerprint of the upstream metadata is kept in the state after each successful
+/// synchronisation, so a restart doesn't synchronise everything again. If an address is given,
+/// the health and status of the daemon are served on it.
+async fn watch(
+    configs: &Configs,
+    options: &SyncOptions<'_>,
+    interval: Duration,
+    listen: Option<SocketAddr>,
+) {
+    if let Some(addr) = listen {
+        match State::load(options.state_path) {
+            Ok(state) => health::record(&state),
+            Err(e) => warn!("Could not load past runs: {}", e),
+        }
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(addr).await {
+                error!("Error serving health on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    info!(
+        "Watching for upstream changes every {}",
+        progress::format_duration(interval)
+    );
+    loop {
+        poll(configs, options).await;
+        health::polled(SystemTime::now() + interval);
+        telemetry::flush().await;
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+/// Check every enabled repository for upstream changes once, synchronising those that changed.
+async fn poll(configs: &Configs, options: &SyncOptions<'_>) {
+    let mut state = match State::load(options.state_path) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    let mut changed = Vec::new();
+    let mut fingerprints = Vec::new();
+    for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+        // Packages that were missing upstream are retried even if nothing else changed
+        let missing = state.repo(repo.label()).missing.len();
+        match repo.upstream().await {
+            Ok(upstream) if upstream == state.repo(repo.label()).upstream && missing == 0 => {
+                debug!("'{}' is unchanged upstream", repo.label());
+            }
+            Ok(upstream) => {
+                if missing > 0 {
+                    info!(
+                        "'{}' has {} packages to retry that were missing upstream",
+                        repo.label(),
+                        missing
+                    );
+                } else {
+                    info!("'{}' has changed upstream", repo.label());
+                }
+                changed.push(repo);
+                fingerprints.push((repo.label(), upstream));
+            }
+            Err(e) => warn!("Could not check '{}' for changes: {}", repo.label(), e),
+        }
+    }
+    if changed.is_empty() {
+        return;
+    }
+
+    let report = match sync(&changed, options).await {
+        Some(report) => report,
+        None => return,
+    };
+    finish(&report, options);
+
+    // Synchronising saves the state, so load it again before recording what was synchronised.
+    let mut state = match State::load(options.state_path) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    for (label, upstream) in fingerprints {
+        if report.status(label) == Some(Status::Synced) {
+            state.repo(label).upstream = upstream;
+        }
+    }
+    if let Err(e) = state.save(options.state_path) {
+        warn!("Could not save state: {}", e);
+    }
+}
+
+/// Write the report of a run if requested, returning whether the run succeeded.
+fn finish(report: &Report, options: &SyncOptions<'_>) -> bool {
+    if let Some(path) = options.report_path {
+        if let Err(e) = report.write(path) {
+            error!("Could not write report to {:?}: {}", path, e);
+            return false;
+        }
+    }
+    if let Some(path) = options.html_report_path {
+        let written =
+            State::load(options.state_path).and_then(|state| Ok(report.write_html(path, &state)?));
+        if let Err(e) = written {
+            error!("Could not write HTML report to {:?}: {}", path, e);
+            return false;
+        }
+    }
+
+    report.succeeded()
+}
+
+/// Synchronise repositories, returning a report of the run.
+///
+/// Up to `parallel_repos` repositories are synchronised at once, in order of priority. With a
+/// download budget, the repositories of each priority only start once those of higher priorities
+/// are done, so that they are the first to spend it. Returns `None` if nothing could be
+/// synchronised.
+async fn sync(repos: &[&Config], options: &SyncOptions<'_>) -> Option<Report> {
+    let month = state::current_month();
+    let state = match State::load(options.state_path) {
+        Ok(state) => Mutex::new(state),
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
+
+    let mut invalid = false;
+    for repo in repos.iter().filter(|repo| repo.enabled()) {
+        for problem in repo.preflight() {
+            error!("Invalid repository '{}': {}", repo.label(), problem);
+            invalid = true;
+        }
+    }
+    if invalid {
+        return None;
+    }
+
+    let run = Running {
+        options,
+        month: &month,
+        state: &state,
+        budget: options.max_bytes.map(Stats::with_limit),
+    };
+    let parallel = options.parallel_repos.max(1);
+    let mut report = Report { repos: Vec::new() };
+    let groups: Vec<&[&Config]> = if options.max_bytes.is_some() {
+        repos
+            .chunk_by(|a, b| a.priority() == b.priority())
+            .collect()
+    } else {
+        vec![repos]
+    };
+    for group in groups {
+        let reports: Vec<RepoReport> =
+            stream::iter(group.iter().map(|&repo| sync_repo(repo, &run)))
+                .buffered(parallel)
+                .collect()
+                .await;
+        report.repos.extend(reports);
+    }
+
+    let count = |status| report.repos.iter().filter(|r| r.status == status).count();
+    let unavailable = count(Status::Unavailable);
+    info!(
+        "Synchronised {} repositories ({} failed, {} unavailable, {} disabled, {} over quota, \
+         {} over budget)",
+        count(Status::Synced),
+        count(Status::Failed),
+        unavailable,
+        count(Status::Disabled),
+        count(Status::OverQuota),
+        count(Status::OverBudget)
+    );
+    if unavailable > 0 {
+        warn!("{} repositories were unavailable and skipped", unavailable);
+    }
+
+    Some(report)
+}
+
+/// What the repositories being synchronised in a run share.
+struct Running<'a> {
+    options: &'a SyncOptions<'a>,
+    month: &'a str,
+    state: &'a Mutex<State>,
+    /// The download budget shared by every repository, if limited.
+    budget: Option<Stats>,
+}
+
+/// Synchronise a single repository as part of a run, recording the outcome in the state.
+///
+/// The download budget of the run is shared with the repositories synchronised at the same
+/// time, with each download setting aside its bytes before it starts.
+async fn sync_repo(repo: &Config, run: &Running<'_>) -> RepoReport {
+    let options = run.options;
+    debug!("Loaded repo: {:?}", repo);
+    let (used, pending) = {
+        let mut state = run.state.lock().unwrap();
+        let repo_state = state.repo(repo.label());
+        (
+            repo_state.month_to_date(run.month),
+            repo_state.missing.clone(),
+        )
+    };
+    let quota_left = repo.monthly_quota().map(|quota| quota.saturating_sub(used));
+    let stats = match (quota_left, &run.budget) {
+        (quota, Some(budget)) => Stats::within(quota, budget),
+        (Some(quota), None) => Stats::with_limit(quota),
+        (None, None) => Stats::default(),
+    };
+    let started = Instant::now();
+    let skipped = |status| RepoReport {
+        repo: repo.label().to_owned(),
+        status,
+        error: None,
+        stats: stats.summary(started.elapsed()),
+    };
+    if !repo.enabled() {
+        info!("Skipping disabled repository '{}'", repo.label());
+        return skipped(Status::Disabled);
+    }
+
+    let limit_reached = stats.check_limit().err();
+    if limit_reached.as_ref().is_some_and(|limit| limit.shared) {
+        info!(
+            "Skipping '{}': download budget for this run is spent",
+            repo.label()
+        );
+        return skipped(Status::OverBudget);
+    } else if limit_reached.is_some() {
+        warn!(
+            "Skipping '{}': monthly quota reached ({} used)",
+            repo.label(),
+            format_bytes(used)
+        );
+        return skipped(Status::OverQuota);
+    }
+
+    health::started(repo.label());
+    let check = options
+        .check
+        .or_else(|| repo.check_mode())
+        .unwrap_or(CheckRemoteSize);
+    let env = repo.hook_env();
+    let result = match repo.hooks().pre_sync(&env).await {
+        Ok(()) => {
+            // Boxed, as the whole sync is too large for the stack in debug builds
+            Box::pin(logging::scope(
+                repo.label(),
+                None,
+                timeout::scope(
+                    repo.timeouts(),
+                    throttle::with_priority(
+                        repo.priority(),
+                        repo.sync(check, !pending.is_empty(), &stats),
+                    ),
+                ),
+            ))
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    let (status, error) = match result {
+        Ok(Outcome::Synced) => (Status::Synced, None),
+        Ok(Outcome::Unavailable) => (Status::Unavailable, None),
+        Err(e)
+            if e.downcast_ref::<LimitReached>()
+                .is_some_and(|limit| limit.shared) =>
+        {
+            info!(
+                "Stopped synchronising '{}': download budget for this run is spent, \
+                 the rest will be downloaded on the next run",
+                repo.label()
+            );
+            (Status::OverBudget, Some(e.to_string()))
+        }
+        Err(e) if e.downcast_ref::<LimitReached>().is_some() => {
+            warn!(
+                "Stopped synchronising '{}': monthly quota reached ({} used)",
+                repo.label(),
+                format_bytes(used + stats.summary(started.elapsed()).bytes_downloaded)
+            );
+            (Status::OverQuota, Some(e.to_string()))
+        }
+        Err(e) => {
+            Event::new("sync").repo(repo.label()).error(&e).log(|| {
+                error!("Error synchronising '{}': {}", repo.label(), e);
+            });
             debug!("Error backtrace:\n{:?}", e.backtrace());
+            (Status::Failed, Some(e.to_string()))
+        }
+    };
+
+    health::finished(repo.label(), status, error.as_deref());
+    let summary = stats.summary(started.elapsed());
+    info!("Finished '{}': {}", repo.label(), summary);
+    {
+        let mut state = run.state.lock().unwrap();
+        let repo_state = state.repo(repo.label());
+        repo_state.record_transfer(run.month, summary.bytes_downloaded);
+        repo_state.published.extend(summary.published.clone());
+        repo_state.record_run(Run::finished_now(status, &summary, &repo_state.published));
+        match repo.on_missing() {
+            OnMissing::RetryLater => repo_state.record_missing(&summary, status == Status::Synced),
+            _ => repo_state.missing.clear(),
+        }
+        if let Err(e) = state.save(options.state_path) {
+            warn!("Could not save state: {}", e);
+        }
+    }
+    let repo_report = RepoReport {
+        repo: repo.label().to_owned(),
+        status,
+        error,
+        stats: summary,
+    };
+    if let Err(e) = repo.hooks().finish(&env, &repo_report).await {
+        error!("Error running hook for '{}': {}", repo.label(), e);
+    }
+    repo_report
+}
+
+/// Report all problems with the configuration, returning whether it is valid.
+fn validate(configs: &Configs) -> bool {
+    let mut valid = true;
+
+    for (index, repo) in configs.repos.iter().enumerate() {
+        for problem in repo.validate() {
+            println!("repo {} ({}): {}", index + 1, repo.label(), problem);
+            valid = false;
+        }
+    }
+
+    if valid {
+        println!("Configuration is valid");
+    }
+
+    valid
+}
+
+/// Check for the usual causes of failing synchronisations, printing what was found.
+///
+/// Returns whether nothing was found that would make synchronising fail.
+async fn doctor(config_file: &str, repos: &[String]) -> bool {
+    let mut findings = doctor::environment();
+    let loaded = Configs::load(config_file).and_then(|mut configs| {
+        configs.select(repos)?;
+        Ok(configs)
+    });
+    match loaded {
+        Ok(configs) if configs.repos.is_empty() => findings.push(doctor::Finding::warning(
+            "configuration",
+            format!("No repositories are configured in '{}'", config_file),
+        )),
+        Ok(configs) => {
+            findings.push(doctor::Finding::ok(
+                "configuration",
+                format!("Loaded {} repositories", configs.repos.len()),
+            ));
+            for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+                findings.extend(repo.diagnose().await);
+            }
+        }
+        Err(e) => findings.push(doctor::Finding::error(
+            "configuration",
+            format!("{}; fix the configuration file", e),
+        )),
+    }
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    let count = |severity| findings.iter().filter(|f| f.severity == severity).count();
+    let (errors, warnings) = (
+        count(doctor::Severity::Error),
+        count(doctor::Severity::Warning),
+    );
+    println!("{} errors, {} warnings", errors, warnings);
+    errors == 0
+}
+
+/// Measure the mirrors of every enabled repository, printing them fastest first.
+async fn bench(configs: &Configs, files: usize, chunk: Option<u64>) -> Result<(), failure::Error> {
+    for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+        for benchmark in repo.bench(files, chunk).await? {
+            println!("{} ({}):", benchmark.repo, benchmark.src);
+            for measurement in &benchmark.mirrors {
+                let speed = match (measurement.throughput(), measurement.latency) {
+                    (Some(throughput), Some(latency)) => format!(
+                        "{}/s, {}ms latency, {} in {} files",
+                        format_bytes(throughput as u64),
+                        latency.as_millis(),
+                        format_bytes(measurement.bytes),
+                        measurement.files
+                    ),
+                    _ => "unreachable".to_owned(),
+                };
+                println!("  {}: {}", measurement.mirror, speed);
+                for failure in &measurement.failures {
+                    println!("    failed: {}", failure);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Summarise the orphaned files of every enabled repository, optionally listing them in a file.
+async fn orphans(configs: &Configs, output: Option<&Path>) -> Result<(), failure::Error> {
+    let mut listing = String::new();
+    let (mut count, mut bytes) = (0, 0);
+
+    for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+        let orphans = repo.orphans().await?;
+        let size: u64 = orphans.iter().map(|orphan| orphan.size).sum();
+        println!(
+            "{}: {} orphaned files ({})",
+            repo.label(),
+            orphans.len(),
+            format_bytes(size)
+        );
+        for orphan in &orphans {
+            listing += &format!("{}\n", orphan.path.display());
         }
+        count += orphans.len();
+        bytes += size;
+    }
+    println!("Total: {} orphaned files ({})", count, format_bytes(bytes));
+
+    if let Some(path) = output {
+        fs::write(path, listing)?;
+    }
+    Ok(())
+}
+
+/// Print the last synchronisation of each repository, as recorded in the state.
+fn status(configs: &Configs, state: &State) {
+    for repo in &configs.repos {
+        let label = repo.label();
+        let repo_state = match state.repos.get(label) {
+            Some(repo_state) => repo_state,
+            None => {
+                println!("{}: never synchronised", label);
+                continue;
+            }
+        };
+        let last_synced = match &repo_state.last_synced {
+            Some(time) => {
+                let ago = humantime::parse_rfc3339(time)
+                    .ok()
+                    .and_then(|time| SystemTime::now().duration_since(time).ok())
+                    .map(|ago| format!(" ({} ago)", progress::format_duration(ago)))
+                    .unwrap_or_default();
+                format!("last synchronised {}{}", time, ago)
+            }
+            None => "never synchronised successfully".to_owned(),
+        };
+        let disabled = if repo.enabled() { "" } else { " [disabled]" };
+        println!("{}{}: {}", label, disabled, last_synced);
+        for (dest, published) in &repo_state.published {
+            let revision = published
+                .revision
+                .map(|revision| format!("revision {}, ", revision))
+                .unwrap_or_default();
+            println!(
+                "  {}: {}{} packages ({})",
+                dest,
+                revision,
+                published.packages,
+                format_bytes(published.bytes)
+            );
+        }
+        let failures = repo_state.failures();
+        if failures > 0 {
+            println!("  {} failed runs since the last success", failures);
+        }
+        if !repo_state.missing.is_empty() {
+            println!(
+                "  {} packages missing upstream, to be downloaded later",
+                repo_state.missing.len()
+            );
+        }
+    }
+}
+
+/// Print a summary of the mirrored packages, and their growth since the previous sync.
+async fn summarise(configs: &Configs, state: &State, top: usize) -> Result<(), failure::Error> {
+    let mut packages = Vec::new();
+    for repo in &configs.repos {
+        packages.extend(repo.listed().await?);
+    }
+    let usage = usage::Usage::new(&packages, top);
+    let total = |total: &usage::Total| {
+        format!(
+            "{} packages ({})",
+            total.packages,
+            format_bytes(total.bytes)
+        )
+    };
+    println!("Total: {}", total(&usage.total));
+    println!("By architecture:");
+    for (arch, arch_total) in &usage.by_arch {
+        println!("  {}: {}", arch, total(arch_total));
+    }
+    println!("By repository:");
+    for (label, repo_total) in &usage.by_repo {
+        let growth = state
+            .repos
+            .get(label)
+            .and_then(|repo_state| repo_state.previous_sync())
+            .and_then(|run| Some((&run.finished, run.published.as_ref()?)))
+            .map(|(finished, previous)| {
+                let sign = |now: u64, then: u64| if now >= then { '+' } else { '-' };
+                format!(
+                    ", {}{} packages ({}{}) since {}",
+                    sign(repo_total.packages, previous.packages),
+                    repo_total.packages.abs_diff(previous.packages),
+                    sign(repo_total.bytes, previous.bytes),
+                    format_bytes(repo_total.bytes.abs_diff(previous.bytes)),
+                    finished
+                )
+            })
+            .unwrap_or_default();
+        println!("  {}: {}{}", label, total(repo_total), growth);
+    }
+    if !usage.largest.is_empty() {
+        println!("Largest packages:");
+        for package in &usage.largest {
+            println!(
+                "  {} ({}) in {}",
+                package.nevra,
+                format_bytes(package.size),
+                package.repo
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Compare the packages of two mirrored trees, returning whether they are the same.
+async fn compare(left: &str, right: &str, hash: bool) -> Result<bool, failure::Error> {
+    let left_mirror = local_mirror(left).await?;
+    let right_mirror = local_mirror(right).await?;
+    let comparison = compare::Comparison::new(
+        &left_mirror.listed("").await?,
+        &right_mirror.listed("").await?,
+    );
+    for nevra in &comparison.only_left {
+        println!("< {}", nevra);
+    }
+    for nevra in &comparison.only_right {
+        println!("> {}", nevra);
+    }
+    for nevra in &comparison.differing {
+        println!("! {}", nevra);
+    }
+    println!(
+        "{} only in '{}', {} only in '{}', {} differ",
+        comparison.only_left.len(),
+        left,
+        comparison.only_right.len(),
+        right,
+        comparison.differing.len()
+    );
+
+    let mut same = comparison.is_empty();
+    if hash {
+        for (dir, mirror) in [(left, &left_mirror), (right, &right_mirror)] {
+            let failed = mirror.verify(CheckHash).await?;
+            for file in &failed {
+                println!("{}: {} is missing or doesn't match its checksum", dir, file);
+            }
+            same &= failed.is_empty();
+        }
+    }
+    Ok(same)
+}
+
+/// Load the metadata of a mirrored tree.
+async fn local_mirror(dir: &str) -> Result<repo::Mirror, failure::Error> {
+    repo::Mirror::local(dir)
+        .await?
+        .ok_or_else(|| format_err!("No repository metadata found in '{}'", dir))
+}
+
+/// Export the changes between two snapshots to a directory.
+async fn delta(old: &Path, new: &Path, output: &Path) -> Result<(), failure::Error> {
+    let delta = snapshot::Delta::between(old, new).await?;
+    delta.export(new, output).await?;
+    println!(
+        "Exported {} added and {} changed files ({}), {} removed",
+        delta.added.len(),
+        delta.changed.len(),
+        format_bytes(delta.size()),
+        delta.removed.len()
+    );
+    Ok(())
+}
+
+/// Check the links of every enabled repository, returning whether none were broken.
+async fn links(configs: &Configs, output: Option<&Path>) -> Result<bool, failure::Error> {
+    let mut checked = Vec::new();
+    for repo in configs.repos.iter().filter(|repo| repo.enabled()) {
+        let variants = logging::scope(repo.label(), None, repo.check_links()).await?;
+        for variant in &variants {
+            println!("{}: {} broken links", variant.src, variant.broken.len());
+            for broken in &variant.broken {
+                println!("  {}", broken);
+            }
+        }
+        checked.extend(variants);
+    }
+    let count: usize = checked.iter().map(|variant| variant.broken.len()).sum();
+    println!("Total: {} broken links", count);
+
+    if let Some(path) = output {
+        fs::write(path, serde_json::to_string_pretty(&checked)? + "\n")?;
+    }
+    Ok(count == 0)
+}
+
+/// Print the packages of the mirrored repositories that match the filters.
+async fn list(
+    configs: &Configs,
+    name: Option<&str>,
+    arch: Option<&str>,
+    format: list::ListFormat,
+) -> Result<(), failure::Error> {
+    let filter = list::Filter {
+        name: name
+            .map(Pattern::new)
+            .transpose()
+            .map_err(|e| format_err!("Invalid name pattern: {}", e))?,
+        arch: arch.map(str::to_owned),
+    };
+    let mut packages = Vec::new();
+    for repo in &configs.repos {
+        packages.extend(
+            repo.listed()
+                .await?
+                .into_iter()
+                .filter(|package| filter.matches(package)),
+        );
+    }
+    print!("{}", list::render(&packages, format)?);
+    Ok(())
+}
+
+/// Print the package, path and destination of every file matching a pattern in the mirrored
+/// repositories, returning whether any were found.
+async fn query(configs: &Configs, file: &str) -> Result<bool, failure::Error> {
+    let pattern =
+        Pattern::new(file).map_err(|e| format_err!("Invalid file pattern '{}': {}", file, e))?;
+    let mut found = false;
+    for repo in &configs.repos {
+        for (dest, provider) in repo.providers(&pattern).await? {
+            println!("{}\t{}\t{}", provider.package, provider.path, dest);
+            found = true;
+        }
+    }
+    Ok(found)
+}
+
+/// Serve a directory over HTTP, fetching the packages of lazily mirrored repositories on demand.
+async fn serve(
+    configs: &Configs,
+    listen: SocketAddr,
+    root: &Path,
+    listing: bool,
+) -> Result<(), failure::Error> {
+    let mut upstreams = Vec::new();
+    for repo in &configs.repos {
+        upstreams.extend(repo.upstreams()?);
+    }
+    let options = serve::Options {
+        root: root.to_owned(),
+        listing,
+        upstreams,
+    };
+    serve::serve(listen, options).await
+}
+
+/// Write an example configuration, optionally converted from a dnf .repo file.
+fn init(from_repo: Option<&Path>, output: Option<&Path>, force: bool) -> io::Result<()> {
+    let config = match from_repo {
+        Some(path) => init::from_dnf_repo(&fs::read_to_string(path)?),
+        None => init::EXAMPLE.to_owned(),
+    };
+
+    match output {
+        Some(path) if path.exists() && !force => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists (use --force to overwrite)", path),
+        )),
+        Some(path) => fs::write(path, config),
+        None => io::stdout().write_all(config.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repo::MD_PATH;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn plan_with_timeouts() {
+        // A server that serves the index, but never responds to requests for anything else
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            use std::io::{BufRead, BufReader};
+            let repomd = "<?xml version=\"1.0\"?><repomd><data type=\"primary\">\
+                          <location href=\"repodata/primary.xml\"/></data></repomd>";
+            let mut stalled = Vec::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                if request.contains(MD_PATH) {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        repomd.len(),
+                        repomd
+                    )
+                    .unwrap();
+                } else {
+                    stalled.push(stream);
+                }
+            }
+        });
+        let dir = TempDir::new("plan").unwrap();
+        let configs: Configs = toml::from_str(&format!(
+            "[[repo]]\nsrc = \"http://{}/\"\ndest = {:?}\n[repo.timeouts]\nread = \"200ms\"\n",
+            addr,
+            dir.path().join("mirror")
+        ))
+        .unwrap();
+        let options = SyncOptions {
+            check: None,
+            state_path: &dir.path().join("state.json"),
+            report_path: None,
+            html_report_path: None,
+            max_bytes: None,
+            parallel_repos: 1,
+        };
+
+        let output = dir.path().join("plan.json");
+        let planned = plan(&configs, &options, &output);
+        let err = tokio::time::timeout(Duration::from_secs(10), planned)
+            .await
+            .expect("the read timeout of the repository wasn't applied")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("No data received from"), "{}", err);
     }
 }
@@ -0,0 +1,114 @@
+//! Manifests of every file in a published destination.
+//!
+//! A manifest lists the SHA-256 digest of each file, either in the format of
+//! `sha256sum` (so `sha256sum -c MANIFEST.sha256` verifies a copy) or as JSON.
+//! It is written after the metadata is published and the destination cleaned,
+//! and replaced in a single rename so readers never see it half written.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs::{create_dir_all, rename, write};
+use tracing::info;
+
+use crate::repo::STAGING_DIR;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The format of the manifest written to a destination, if any.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Manifest {
+    /// No manifest is written.
+    #[default]
+    None,
+    /// `MANIFEST.sha256`, in the format of `sha256sum`.
+    Sha256,
+    /// `MANIFEST.json`, listing the path, size and digest of each file.
+    Json,
+}
+
+/// A file listed in a manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The path of the file, relative to the destination.
+    pub path: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The SHA-256 digest of the file, hex encoded.
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// The name of the manifest file in the destination.
+    pub fn file_name(self) -> Option<&'static str> {
+        match self {
+            Manifest::None => None,
+            Manifest::Sha256 => Some("MANIFEST.sha256"),
+            Manifest::Json => Some("MANIFEST.json"),
+        }
+    }
+
+    /// Format the entries of a manifest, sorted by path.
+    pub fn render(self, entries: &[Entry]) -> Result<String> {
+        let mut entries = entries.to_vec();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(match self {
+            Manifest::None => String::new(),
+            Manifest::Sha256 => entries
+                .iter()
+                .map(|entry| format!("{}  {}\n", entry.sha256, entry.path))
+                .collect(),
+            Manifest::Json => serde_json::to_string_pretty(&entries)? + "\n",
+        })
+    }
+
+    /// Replace the manifest in a destination.
+    pub async fn write(self, dest: &Path, entries: &[Entry]) -> Result<()> {
+        let name = match self.file_name() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        // Staged within the destination, so the rename can't cross filesystems
+        let staging = dest.join(STAGING_DIR);
+        create_dir_all(&staging).await?;
+        let staged = staging.join(name);
+        write(&staged, self.render(entries)?).await?;
+        rename(&staged, dest.join(name)).await?;
+        info!(
+            "Wrote manifest of {} files to {:?}",
+            entries.len(),
+            dest.join(name)
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_manifests() {
+        let entries = vec![
+            Entry {
+                path: "repodata/repomd.xml".to_owned(),
+                size: 3,
+                sha256: "bb".to_owned(),
+            },
+            Entry {
+                path: "Packages/a-1-1.rpm".to_owned(),
+                size: 1,
+                sha256: "aa".to_owned(),
+            },
+        ];
+        assert_eq!(
+            Manifest::Sha256.render(&entries).unwrap(),
+            "aa  Packages/a-1-1.rpm\nbb  repodata/repomd.xml\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&Manifest::Json.render(&entries).unwrap()).unwrap();
+        assert_eq!(json[0]["path"], "Packages/a-1-1.rpm");
+        assert_eq!(json[1]["size"], 3);
+        assert_eq!(Manifest::None.file_name(), None);
+    }
+}
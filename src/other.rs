@@ -0,0 +1,141 @@
+//! Changelogs of packages, from the `other` metadata of a repository.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::fs::read;
+
+use crate::package::{decode_xml, nevra, Changelog, Version};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The `other` metadata of a repository.
+#[derive(Debug, Default, Deserialize)]
+pub struct Other {
+    #[serde(rename = "package", default)]
+    packages: Vec<OtherPackage>,
+}
+
+/// The changelog of a single package.
+#[derive(Debug, Deserialize)]
+struct OtherPackage {
+    name: String,
+    arch: String,
+    version: Version,
+    #[serde(rename = "changelog", default)]
+    changelog: Vec<Entry>,
+}
+
+/// A single entry in the changelog of a package.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The author of the change, usually followed by the version it was made in.
+    pub author: String,
+    /// When the change was made, in seconds since the Unix epoch.
+    pub date: u64,
+    /// The description of the change.
+    #[serde(rename = "$value", default)]
+    pub text: String,
+}
+
+impl Entry {
+    /// The day the change was made, as `YYYY-MM-DD`.
+    pub fn day(&self) -> String {
+        let time = UNIX_EPOCH + Duration::from_secs(self.date);
+        humantime::format_rfc3339_seconds(time).to_string()[..10].to_owned()
+    }
+}
+
+impl Other {
+    /// Load the `other` metadata from a file, which may be compressed with gzip.
+    pub async fn load(path: &Path) -> Result<Other> {
+        decode_xml(&read(path).await?)
+    }
+
+    /// The changelog of each package, by NEVRA, oldest entry first.
+    fn changelogs(&self) -> HashMap<String, &[Entry]> {
+        self.packages
+            .iter()
+            .map(|package| {
+                let nevra = nevra(&package.name, &package.version, &package.arch);
+                (nevra, &package.changelog[..])
+            })
+            .collect()
+    }
+}
+
+impl Changelog {
+    /// Add the upstream changelog entries of each updated package, newest first.
+    ///
+    /// Only the entries made since the newest entry of the previous package are included. If the
+    /// previous changelog isn't known, only the newest entry is included.
+    pub fn annotate(&mut self, new: &Other, old: Option<&Other>) {
+        let new = new.changelogs();
+        let old = old.map(Other::changelogs).unwrap_or_default();
+        for update in &mut self.updated {
+            let entries = new.get(&update.to).copied().unwrap_or_default();
+            let since = old
+                .get(&update.from)
+                .and_then(|entries| entries.iter().map(|entry| entry.date).max());
+            update.changelog = match since {
+                Some(since) => entries
+                    .iter()
+                    .filter(|entry| entry.date > since)
+                    .rev()
+                    .cloned()
+                    .collect(),
+                None => entries
+                    .iter()
+                    .max_by_key(|e| e.date)
+                    .cloned()
+                    .into_iter()
+                    .collect(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::package::Update;
+
+    const LOCAL_OTHER: &str = "src/test-data/local/repodata/ad4b82bdb7098f324d9d1b4813916433af03df2a6786cad07159cca7c95fb945-other.xml.gz";
+    const REMOTE_OTHER: &str = "src/test-data/remote/repodata/f1ff910143fad9fdfe8f968937fb57ab805162628df99307d8a6371618a9c334-other.xml.gz";
+
+    #[tokio::test]
+    async fn changelog_entries() {
+        let local = Other::load(Path::new(LOCAL_OTHER)).await.unwrap();
+        let remote = Other::load(Path::new(REMOTE_OTHER)).await.unwrap();
+        let bash = &remote.changelogs()["bash-4.4.12-13.fc27.x86_64"];
+        assert!(bash[0]
+            .author
+            .starts_with("Siteshwar Vashisht <svashisht@redhat.com>"));
+        assert_eq!(bash[0].day(), "2017-04-26");
+        assert!(bash[0]
+            .text
+            .starts_with("- Explicitly unset nonblocking mode"));
+
+        let mut changelog = Changelog {
+            updated: vec![Update {
+                from: "vim-enhanced-2:8.0.1451-1.fc27.x86_64".to_owned(),
+                to: "vim-enhanced-2:8.0.1473-1.fc27.x86_64".to_owned(),
+                changelog: Vec::new(),
+            }],
+            ..Changelog::default()
+        };
+        changelog.annotate(&remote, Some(&local));
+        let entries = &changelog.updated[0].changelog;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].author,
+            "Karsten Hopp <karsten@redhat.com> 8.0.1473-1"
+        );
+        assert_eq!(entries[0].text, "- patchlevel 1473");
+
+        // The new package isn't in the older metadata
+        changelog.annotate(&local, None);
+        assert!(changelog.updated[0].changelog.is_empty());
+    }
+}
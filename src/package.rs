@@ -1,46 +1,91 @@
 //! Representation of package metadata from a YUM repository.
 
 use flate2::read::GzDecoder;
-use hex;
-use log::{debug, info};
-use openssl::hash::{Hasher, MessageDigest};
-use reqwest::{Client, Url};
-use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use memmap2::{Advice, Mmap};
+use reqwest::header::{CONTENT_LENGTH, RETRY_AFTER};
+use reqwest::{Client, StatusCode, Url};
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
 use serde_xml_rs as xml;
-use std::collections::BTreeSet;
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{self, Debug, Display};
+use std::future::Future;
+use std::io::Read;
 use std::marker::Unpin;
-use std::path::Path;
-use std::sync::Arc;
-use tokio::fs::{create_dir_all, metadata, rename, File, OpenOptions};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
-use tokio::sync::mpsc::unbounded_channel;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs::{copy, create_dir_all, metadata, remove_file, rename, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::channel;
 use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
 use tokio::try_join;
+use tracing::{debug, field, info, info_span, instrument, warn, Instrument, Span};
 use tree_magic as magic;
 
 use failure::{bail, format_err};
+use glob::Pattern;
 type Result<T> = ::std::result::Result<T, ::failure::Error>;
 
+use crate::audit;
+use crate::breaker;
+use crate::concurrency::{self, Concurrency, RateLimited};
+use crate::hash::Hasher;
+use crate::hooks;
+use crate::href;
+use crate::list::Listed;
+use crate::logging::Event;
+use crate::other;
+use crate::prefetch::Wanted;
+use crate::progress::{Progress, Tracker, REPORT_INTERVAL};
+use crate::rank::Sources;
 use crate::repo::XmlDecodeError;
+use crate::shard;
+use crate::stats::{LimitReached, Stats};
+use crate::throttle;
+use crate::timeout::{self, TooSlow};
 
 /// A set of files that can be loaded from XML and fetched.
 pub trait Fetch: DeserializeOwned {
     /// Generate a sorted list of packages for the repository.
     fn files(&self) -> BTreeSet<(&str, u64, &Checksum)>;
 
+    /// List the files in the order the metadata lists them.
+    fn listed_files(&self) -> Vec<(&str, u64, &Checksum)> {
+        self.files().into_iter().collect()
+    }
+
+    /// The URLs of files that aren't downloaded relative to the repository.
+    fn remote_files(&self) -> HashMap<&str, Url> {
+        HashMap::new()
+    }
+
+    /// The files of the packages whose names match any of the patterns.
+    fn named(&self, _patterns: &[Pattern]) -> HashSet<&str> {
+        HashSet::new()
+    }
+
     /// Decode a raw slice of data
     fn decode_raw(source: &[u8]) -> Result<Self> {
-        if magic::match_u8("application/gzip", source) {
-            debug!("Metadata is gzip encoded");
-            Ok(xml::from_reader(GzDecoder::new(source)).map_err(XmlDecodeError::from)?)
-        } else if magic::match_u8("application/xml", source) {
-            debug!("Metadata is raw xml");
-            Ok(xml::from_reader(source).map_err(XmlDecodeError::from)?)
-        } else {
-            Err(format_err!("Primary metadata in incompatible filetype"))
-        }
+        decode_xml(source)
+    }
+}
+
+/// Decode metadata that may be compressed with gzip.
+pub fn decode_xml<T: DeserializeOwned>(source: &[u8]) -> Result<T> {
+    if magic::match_u8("application/gzip", source) {
+        debug!("Metadata is gzip encoded");
+        Ok(xml::from_reader(GzDecoder::new(source)).map_err(XmlDecodeError::from)?)
+    } else if magic::match_u8("application/xml", source) {
+        debug!("Metadata is raw xml");
+        Ok(xml::from_reader(source).map_err(XmlDecodeError::from)?)
+    } else {
+        Err(format_err!("Primary metadata in incompatible filetype"))
     }
 }
 
@@ -55,46 +100,366 @@ where
     F::decode_raw(bytes.as_slice())
 }
 
+/// The most files downloaded at once.
+const WORKERS: usize = 8;
+
+/// The number of times a file is requested again from a rate limited server.
+const RATE_LIMIT_RETRIES: u32 = 4;
+
 /// Download all files to destination.
+///
+/// Files that fail their checks are quarantined and downloaded again from each source in turn,
+/// up to the number of retries allowed by `vetting`. The rest of the files are still downloaded
+/// if one can't be, but the download as a whole then fails. Files that can't be downloaded from
+/// one source are tried from the others, and sources that keep failing are avoided for a while.
+/// Fewer files are downloaded at once while the sources show signs of congestion.
+///
+/// Files that are missing from every source, or that never match their checksums, are recorded
+/// in `stats` as inconsistencies of the upstream, and the download fails with [`Inconsistent`].
+///
+/// Files are queued by priority, with the packages matching the critical patterns of the current
+/// task before the rest. Files of the same priority are queued in the [`DownloadOrder`] of the
+/// current task, unless the download is limited, in which case the smallest are downloaded first
+/// to fit as many as possible; size only breaks ties, so the critical packages still come first.
+/// If the work is shared between hosts, only the files of this host's shard are downloaded.
+#[instrument(name = "packages", skip_all)]
 pub async fn sync_all(
     client: &Client,
     fetch: &impl Fetch,
-    src: &Url,
+    sources: &Sources,
     dest: &Path,
     check: CheckType,
+    stats: &Stats,
+    vetting: &Vetting,
 ) -> Result<()> {
-    let queue = Arc::new(Mutex::new(fetch.files().into_iter()));
+    let order = ORDER.try_with(|order| *order).unwrap_or_default();
+    let mut files = order.queue(fetch);
+    if let Some(shard) = shard::current() {
+        files.retain(|(file, _, _)| shard.contains(file));
+    }
+    let critical = CRITICAL
+        .try_with(|critical| fetch.named(critical))
+        .unwrap_or_default();
+    if stats.is_limited() {
+        // Fit as many of the files of the highest priority as possible within the limit
+        files.sort_by_key(|(file, size, _)| (!critical.contains(file), *size));
+    } else if !critical.is_empty() {
+        // Stable, so the files of each priority keep their order among themselves
+        files.sort_by_key(|(file, _, _)| !critical.contains(file));
+    }
+    let total = files.iter().map(|(_, size, _)| size).sum();
+    let remote_files = &fetch.remote_files();
+    // The largest file gives the best measure of each mirror's speed
+    let sample = files
+        .iter()
+        .max_by_key(|(_, size, _)| *size)
+        .map(|(file, _, _)| file.to_string());
+    if let Some(sample) = &sample {
+        sources.rank(client, sample).await;
+    }
+    let progress = Progress::new(total, WORKERS, stats.clone());
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+    let skipped = &AtomicBool::new(false);
+    let rejected = &AtomicU64::new(0);
+    let inconsistent = &AtomicU64::new(0);
+    let missing = &AtomicU64::new(0);
+    let concurrency = Arc::new(Concurrency::new(WORKERS));
 
-    let worker = move || {
+    let worker = |index| {
         let queue = queue.clone();
+        let tracker = progress.tracker(index);
+        let concurrency = concurrency.clone();
         async move {
             while let Some((file, size, checksum)) = queue.lock().await.next() {
-                let check = match check {
-                    CheckRemoteSize => Check::RemoteSize(size),
-                    CheckSize => Check::Size(size),
-                    CheckHash => Check::Hash(size, checksum),
-                };
-                sync_file(client, file, src, dest, check).await?
+                if !stats.reserve(size) {
+                    skipped.store(true, Ordering::Relaxed);
+                    continue;
+                }
+                let check = check.check(size, checksum);
+                let breakers = breaker::shared();
+                let mut attempt = 0;
+                let mut failures = 0;
+                let mut limited = 0;
+                loop {
+                    let urls = sources.get();
+                    let src = match remote_files.get(file) {
+                        Some(remote) => remote,
+                        None => breakers.choose(&urls, attempt + failures),
+                    };
+                    let remote_path = match remote_files.get(file) {
+                        Some(remote) => remote.clone(),
+                        None => href::join(src, file)?,
+                    };
+                    if attempt + failures > 0 {
+                        info!("Downloading '{}' again from '{}'", file, src);
+                    }
+                    concurrency.resumed(&remote_path).await;
+                    let permit = concurrency.acquire().await;
+                    let result = concurrency::scope(
+                        concurrency.clone(),
+                        sync_remote_file(
+                            client,
+                            file,
+                            &remote_path,
+                            dest,
+                            check,
+                            Some(&tracker),
+                            Some(vetting),
+                        ),
+                    )
+                    .await;
+                    drop(permit);
+                    let e = match result {
+                        Ok(()) => {
+                            breakers.succeeded(src);
+                            break;
+                        }
+                        Err(e) if e.downcast_ref::<LimitReached>().is_some() => return Err(e),
+                        Err(e)
+                            if limited < RATE_LIMIT_RETRIES
+                                && e.downcast_ref::<RateLimited>().is_some() =>
+                        {
+                            limited += 1;
+                            let rate_limited = e.downcast_ref::<RateLimited>().unwrap();
+                            match rate_limited.retry_after {
+                                // Every download from the mirror waits, not just this one
+                                Some(delay) => concurrency.pause(&rate_limited.url, delay),
+                                None => {
+                                    let delay = Duration::from_secs(1 << limited);
+                                    info!("{}, waiting {}", e, humantime::format_duration(delay));
+                                    delay_for(delay).await;
+                                }
+                            }
+                            continue;
+                        }
+                        Err(e) => e,
+                    };
+                    // Only the host's own failures count, not local ones such as a full disk
+                    if host_failed(&e) {
+                        breakers.failed(src, Instant::now());
+                    }
+                    if let Some(quarantined) = e.downcast_ref::<Quarantined>() {
+                        if attempt >= vetting.retries {
+                            if quarantined.mismatched {
+                                stats.inconsistent(file, &quarantined.reason, false);
+                                inconsistent.fetch_add(1, Ordering::Relaxed);
+                            } else {
+                                rejected.fetch_add(1, Ordering::Relaxed);
+                            }
+                            break;
+                        }
+                        attempt += 1;
+                    } else if failures + 1 < urls.len() {
+                        warn!("Failed to download '{}' from '{}': {}", file, src, e);
+                        failures += 1;
+                    } else if e.downcast_ref::<Missing>().is_some() {
+                        stats.inconsistent(file, &e.to_string(), true);
+                        inconsistent.fetch_add(1, Ordering::Relaxed);
+                        missing.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    } else {
+                        return Err(e);
+                    }
+                }
+                let downloaded = tracker.finish(size);
+                stats.release(size.saturating_sub(downloaded));
             }
-            Ok(())
+            Ok::<(), failure::Error>(())
         }
     };
 
-    try_join!(
-        worker(),
-        worker(),
-        worker(),
-        worker(),
-        worker(),
-        worker(),
-        worker(),
-        worker()
-    )
-    .map(|_| ())
+    let workers = async {
+        try_join!(
+            worker(0),
+            worker(1),
+            worker(2),
+            worker(3),
+            worker(4),
+            worker(5),
+            worker(6),
+            worker(7)
+        )
+    };
+
+    tokio::select! {
+        result = workers => result?,
+        _ = progress.report(REPORT_INTERVAL) => unreachable!("Progress reports never finish"),
+        _ = sources.keep_ranked(client, sample.as_deref()) => unreachable!("Ranking never finishes"),
+    };
+
+    if skipped.load(Ordering::Relaxed) {
+        return Err(stats.limit_reached().into());
+    }
+    let rejected = rejected.load(Ordering::Relaxed);
+    if rejected > 0 {
+        bail!(
+            "{} files were quarantined after failing their checks",
+            rejected
+        );
+    }
+    let files = inconsistent.load(Ordering::Relaxed);
+    if files > 0 {
+        let missing = missing.load(Ordering::Relaxed);
+        return Err(Inconsistent { files, missing }.into());
+    }
+    Ok(())
+}
+
+/// Files referenced by the upstream metadata were missing or didn't match their checksums.
+#[derive(Debug)]
+pub struct Inconsistent {
+    /// The number of files affected.
+    pub files: u64,
+    /// The number of those files that were missing.
+    pub missing: u64,
+}
+
+impl std::error::Error for Inconsistent {}
+
+impl Display for Inconsistent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files referenced by the upstream metadata are missing or don't match",
+            self.files
+        )
+    }
+}
+
+/// The order in which files are downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloadOrder {
+    /// In order of their paths.
+    #[default]
+    Path,
+    /// Smallest files first, so that many files land quickly.
+    SmallestFirst,
+    /// Largest files first, to use the whole bandwidth early.
+    LargestFirst,
+    /// In the order the metadata lists them.
+    Metadata,
+}
+
+impl DownloadOrder {
+    /// Queue the files to download.
+    fn queue(self, fetch: &impl Fetch) -> Vec<(&str, u64, &Checksum)> {
+        let mut files = match self {
+            DownloadOrder::Metadata => return fetch.listed_files(),
+            _ => fetch.files().into_iter().collect::<Vec<_>>(),
+        };
+        match self {
+            DownloadOrder::SmallestFirst => files.sort_by_key(|(_, size, _)| *size),
+            DownloadOrder::LargestFirst => files.sort_by_key(|(_, size, _)| Reverse(*size)),
+            DownloadOrder::Path | DownloadOrder::Metadata => {}
+        }
+        files
+    }
+}
+
+tokio::task_local! {
+    /// The order of downloads of the repository being synchronised by the current task.
+    static ORDER: DownloadOrder;
+    /// Patterns of the names of packages downloaded before any others by the current task.
+    static CRITICAL: Vec<Pattern>;
+}
+
+/// Run a future with files downloaded in an order, after the packages whose names match any of
+/// the critical patterns.
+pub async fn with_order<F: Future>(
+    order: DownloadOrder,
+    critical: Vec<Pattern>,
+    future: F,
+) -> F::Output {
+    ORDER.scope(order, CRITICAL.scope(critical, future)).await
+}
+
+/// What to do about packages that are missing upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnMissing {
+    /// Fail the synchronisation.
+    #[default]
+    Fail,
+    /// Synchronise everything else, reporting the missing packages.
+    Skip,
+    /// Synchronise everything else, and synchronise again when polling for changes until the
+    /// missing packages are downloaded.
+    RetryLater,
+}
+
+impl OnMissing {
+    /// Apply the policy to the result of downloading files.
+    pub fn tolerate(self, result: Result<()>) -> Result<()> {
+        let e = match result {
+            Err(e) if self != OnMissing::Fail => e,
+            result => return result,
+        };
+        match e.downcast_ref::<Inconsistent>() {
+            Some(inconsistent) if inconsistent.missing == inconsistent.files => {
+                warn!("Skipped {} packages missing upstream", inconsistent.missing);
+                Ok(())
+            }
+            _ => Err(e),
+        }
+    }
+}
+
+/// A file doesn't exist upstream.
+#[derive(Debug)]
+pub struct Missing {
+    /// The URL that was requested
+    pub url: Url,
+    /// The status the server responded with
+    pub status: StatusCode,
+}
+
+impl std::error::Error for Missing {}
+
+impl Display for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} responded with {}", self.url, self.status)
+    }
+}
+
+/// A host failed to serve a file, by responding with an error or not sending any data in time.
+#[derive(Debug)]
+pub struct Unresponsive {
+    /// The URL that was requested
+    pub url: Url,
+    /// The status the server responded with, if it responded with an error
+    pub status: Option<StatusCode>,
+    /// How long the request waited for data
+    pub read: Duration,
+}
+
+impl std::error::Error for Unresponsive {}
+
+impl Display for Unresponsive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} responded with {}", self.url, status),
+            None => write!(
+                f,
+                "No data received from {} for {}",
+                self.url,
+                humantime::format_duration(self.read)
+            ),
+        }
+    }
+}
+
+/// Check whether a download failed because of the host it was made from, rather than locally,
+/// such as when the disk is full or a file can't be renamed into place.
+fn host_failed(e: &failure::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>().is_some()
+        || e.downcast_ref::<Unresponsive>().is_some()
+        || e.downcast_ref::<Missing>().is_some()
+        || e.downcast_ref::<TooSlow>().is_some()
 }
 
 /// A collection of package metadata.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Metadata {
     #[serde(rename = "package", default)]
     packages: Vec<Package>,
@@ -107,6 +472,40 @@ impl Fetch for Metadata {
             .map(|p| (p.location(), p.size.package, &p.checksum))
             .collect()
     }
+
+    fn listed_files(&self) -> Vec<(&str, u64, &Checksum)> {
+        let mut seen = HashSet::new();
+        self.packages
+            .iter()
+            .map(|p| (p.location(), p.size.package, &p.checksum))
+            .filter(|(file, _, _)| seen.insert(*file))
+            .collect()
+    }
+
+    fn remote_files(&self) -> HashMap<&str, Url> {
+        let mut remote = HashMap::new();
+        for package in &self.packages {
+            match package.location.remote() {
+                Some(Ok(url)) => {
+                    remote.insert(package.location(), url);
+                }
+                Some(Err(e)) => warn!(
+                    "Downloading '{}' relative to the repository: {}",
+                    package.location.href, e
+                ),
+                None => {}
+            }
+        }
+        remote
+    }
+
+    fn named(&self, patterns: &[Pattern]) -> HashSet<&str> {
+        self.packages
+            .iter()
+            .filter(|package| patterns.iter().any(|p| p.matches(&package.name)))
+            .map(|package| package.location())
+            .collect()
+    }
 }
 
 impl Metadata {
@@ -117,6 +516,178 @@ impl Metadata {
         packages.sort_unstable();
         packages
     }
+
+    /// Describe every package for an inventory of a mirror.
+    pub fn listed(&self, repo: &str, dest: &str) -> Vec<Listed> {
+        self.packages()
+            .into_iter()
+            .map(|package| Listed {
+                repo: repo.to_owned(),
+                dest: dest.to_owned(),
+                name: package.name.clone(),
+                arch: package.arch.clone(),
+                nevra: package.nevra(),
+                size: package.size.package,
+                checksum: package.checksum.to_string(),
+                location: package.location().to_owned(),
+            })
+            .collect()
+    }
+
+    /// Split the packages into those that are wanted, along with everything they depend on, and
+    /// the rest.
+    ///
+    /// Every package providing a requirement is included, so the selection may include
+    /// alternatives that a client wouldn't install.
+    pub fn partition(self, wanted: &Wanted) -> (Metadata, Metadata) {
+        let mut providers: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (index, package) in self.packages.iter().enumerate() {
+            for capability in package.format.provided() {
+                providers.entry(capability).or_default().push(index);
+            }
+        }
+
+        let mut selected = HashSet::new();
+        let mut queue: Vec<usize> = (0..self.packages.len())
+            .filter(|&index| {
+                let package = &self.packages[index];
+                wanted.matches(&package.name, package.location())
+            })
+            .collect();
+        while let Some(index) = queue.pop() {
+            if !selected.insert(index) {
+                continue;
+            }
+            for requirement in self.packages[index].format.required() {
+                if let Some(indexes) = providers.get(requirement) {
+                    queue.extend(indexes.iter().filter(|i| !selected.contains(*i)));
+                }
+            }
+        }
+
+        let (mut chosen, mut rest) = (Vec::new(), Vec::new());
+        for (index, package) in self.packages.into_iter().enumerate() {
+            if selected.contains(&index) {
+                chosen.push(package);
+            } else {
+                rest.push(package);
+            }
+        }
+        (Metadata { packages: chosen }, Metadata { packages: rest })
+    }
+
+    /// Keep only the packages matching any of the patterns, by name, by
+    /// `name-version-release.arch`, or by file name.
+    pub fn matching(self, patterns: &[Pattern]) -> Metadata {
+        let packages = self
+            .packages
+            .into_iter()
+            .filter(|package| {
+                let file = package.location().rsplit('/').next().unwrap_or_default();
+                let nevra = package.nevra();
+                patterns.iter().any(|pattern| {
+                    pattern.matches(&package.name)
+                        || pattern.matches(&nevra)
+                        || pattern.matches(file)
+                })
+            })
+            .collect();
+        Metadata { packages }
+    }
+
+    /// Find the packages added, updated and removed since an older version of the metadata.
+    ///
+    /// Packages are matched by name and architecture. When several versions of a package change
+    /// at once, the newest of each side (by their version strings) are paired as an update and
+    /// the rest are reported as added or removed.
+    pub fn changes_since(&self, old: &Metadata) -> Changelog {
+        type Versions<'a> = BTreeMap<(&'a str, &'a str), BTreeMap<&'a Version, &'a Package>>;
+        fn group(metadata: &Metadata) -> Versions<'_> {
+            let mut versions = Versions::new();
+            for package in &metadata.packages {
+                versions
+                    .entry((package.name.as_str(), package.arch.as_str()))
+                    .or_default()
+                    .insert(&package.version, package);
+            }
+            versions
+        }
+        /// The packages of the first versions that aren't in the second, oldest first.
+        fn only<'a>(
+            a: &BTreeMap<&Version, &'a Package>,
+            b: &BTreeMap<&Version, &Package>,
+        ) -> Vec<&'a Package> {
+            a.iter()
+                .filter(|(version, _)| !b.contains_key(*version))
+                .map(|(_, package)| *package)
+                .collect()
+        }
+        let (old, new) = (group(old), group(self));
+
+        let mut changelog = Changelog::default();
+        let keys: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+        let empty = BTreeMap::new();
+        for key in keys {
+            let before = old.get(key).unwrap_or(&empty);
+            let after = new.get(key).unwrap_or(&empty);
+            let mut removed = only(before, after);
+            let mut added = only(after, before);
+            if !before.is_empty() && !after.is_empty() {
+                if let (Some(from), Some(to)) = (removed.pop(), added.pop()) {
+                    changelog.updated.push(Update {
+                        from: from.nevra(),
+                        to: to.nevra(),
+                        changelog: Vec::new(),
+                    });
+                }
+            }
+            changelog.added.extend(added.iter().map(|p| p.nevra()));
+            changelog.removed.extend(removed.iter().map(|p| p.nevra()));
+        }
+        changelog
+    }
+}
+
+/// The packages that differ between two versions of a repository, by NEVRA.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Changelog {
+    /// Packages that are new to the repository.
+    pub added: Vec<String>,
+    /// Packages replaced by another version.
+    pub updated: Vec<Update>,
+    /// Packages no longer in the repository.
+    pub removed: Vec<String>,
+}
+
+/// A package replaced by another version.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Update {
+    /// The previous package.
+    pub from: String,
+    /// The package that replaced it.
+    pub to: String,
+    /// The upstream changelog entries made since the previous package, newest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changelog: Vec<other::Entry>,
+}
+
+impl Changelog {
+    /// Whether no packages changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Display for Changelog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packages added, {} updated, {} removed",
+            self.added.len(),
+            self.updated.len(),
+            self.removed.len()
+        )
+    }
 }
 
 /// Metadata for a single package.
@@ -127,22 +698,188 @@ pub struct Package {
     name: String,
     checksum: Checksum,
     size: Size,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    arch: String,
 }
 
 impl Package {
     fn location(&self) -> &str {
-        self.location.href.as_ref()
+        self.location.path()
+    }
+
+    /// The name, epoch, version, release and architecture of the package.
+    fn nevra(&self) -> String {
+        nevra(&self.name, &self.version, &self.arch)
+    }
+}
+
+/// Identify a package as `name-[epoch:]version-release.arch`.
+pub fn nevra(name: &str, version: &Version, arch: &str) -> String {
+    let epoch = match version.epoch.as_str() {
+        "" | "0" => String::new(),
+        epoch => format!("{}:", epoch),
+    };
+    format!("{}-{}{}-{}.{}", name, epoch, version.ver, version.rel, arch)
+}
+
+/// The capabilities a package provides and requires.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+struct Format {
+    #[serde(default)]
+    provides: Entries,
+    #[serde(default)]
+    requires: Entries,
+    /// The files of the package listed in the primary metadata, which may be required by path.
+    #[serde(rename = "file", default)]
+    files: Vec<FileEntry>,
+}
+
+impl Format {
+    /// Every capability provided, including files.
+    fn provided(&self) -> impl Iterator<Item = &str> {
+        let names = self.provides.entries.iter().map(|e| e.name.as_str());
+        names.chain(self.files.iter().map(|f| f.path.as_str()))
+    }
+
+    /// Every capability required, other than those of rpm itself.
+    fn required(&self) -> impl Iterator<Item = &str> {
+        self.requires
+            .entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .filter(|name| !name.starts_with("rpmlib("))
     }
 }
 
+/// A list of capabilities.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+struct Entries {
+    #[serde(rename = "entry", default)]
+    entries: Vec<Entry>,
+}
+
+/// A single capability, ignoring any version constraint.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+struct Entry {
+    name: String,
+}
+
+/// A file listed in the primary metadata.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+struct FileEntry {
+    #[serde(rename = "$value")]
+    path: String,
+}
+
 /// Version metadata for a single package.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+///
+/// Versions are ordered as rpm orders them, by epoch, then version, then release.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
 pub struct Version {
     epoch: String,
     ver: String,
     rel: String,
 }
 
+impl Version {
+    /// Describe the version of a package.
+    pub fn new(epoch: String, ver: String, rel: String) -> Version {
+        Version { epoch, ver, rel }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> CmpOrdering {
+        let epoch = |version: &Version| version.epoch.parse::<u64>().unwrap_or(0);
+        epoch(self)
+            .cmp(&epoch(other))
+            .then_with(|| rpmvercmp(&self.ver, &other.ver))
+            .then_with(|| rpmvercmp(&self.rel, &other.rel))
+            // Versions rpm considers the same, such as 1.01 and 1.1, are still distinct
+            .then_with(|| {
+                (&self.epoch, &self.ver, &self.rel).cmp(&(&other.epoch, &other.ver, &other.rel))
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compare two versions or releases as rpm does.
+///
+/// Each is split into runs of digits and runs of letters, which are compared in turn: digits as
+/// numbers, letters as strings, and digits above letters. A `~` sorts before anything, even the
+/// end of the other, and a `^` sorts after the end of the other but before anything else.
+fn rpmvercmp(a: &str, b: &str) -> CmpOrdering {
+    use CmpOrdering::*;
+
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    let separator = |c: &u8| !c.is_ascii_alphanumeric() && *c != b'~' && *c != b'^';
+    loop {
+        let skip = |s: &[u8]| s.iter().take_while(|c| separator(c)).count();
+        a = &a[skip(a)..];
+        b = &b[skip(b)..];
+
+        match (a.first(), b.first()) {
+            (Some(b'~'), Some(b'~')) | (Some(b'^'), Some(b'^')) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (Some(b'~'), _) => return Less,
+            (_, Some(b'~')) => return Greater,
+            (Some(b'^'), None) => return Greater,
+            (Some(b'^'), _) => return Less,
+            (None, Some(b'^')) => return Less,
+            (_, Some(b'^')) => return Greater,
+            (None, None) => return Equal,
+            (None, _) => return Less,
+            (_, None) => return Greater,
+            _ => {}
+        }
+
+        let numeric = a[0].is_ascii_digit();
+        let segment = |s: &[u8]| {
+            s.iter()
+                .take_while(|c| {
+                    if numeric {
+                        c.is_ascii_digit()
+                    } else {
+                        c.is_ascii_alphabetic()
+                    }
+                })
+                .count()
+        };
+        let (length_a, length_b) = (segment(a), segment(b));
+        if length_b == 0 {
+            // Numeric segments are newer than alphabetic ones
+            return if numeric { Greater } else { Less };
+        }
+        let (segment_a, segment_b) = (&a[..length_a], &b[..length_b]);
+        let ordering = if numeric {
+            let zeros = |s: &[u8]| s.iter().take_while(|c| **c == b'0').count();
+            let segment_a = &segment_a[zeros(segment_a)..];
+            let segment_b = &segment_b[zeros(segment_b)..];
+            segment_a
+                .len()
+                .cmp(&segment_b.len())
+                .then_with(|| segment_a.cmp(segment_b))
+        } else {
+            segment_a.cmp(segment_b)
+        };
+        if ordering != Equal {
+            return ordering;
+        }
+        a = &a[length_a..];
+        b = &b[length_b..];
+    }
+}
+
 impl Debug for Version {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> ::std::result::Result<(), fmt::Error> {
         write!(f, "ver({}, {}, {})", self.epoch, self.ver, self.rel)
@@ -173,9 +910,29 @@ impl Fetch for PrestoDelta {
                 })
             })
     }
+
+    fn listed_files(&self) -> Vec<(&str, u64, &Checksum)> {
+        let mut seen = HashSet::new();
+        self.new_packages
+            .iter()
+            .flat_map(|new_package| &new_package.deltas)
+            .map(|delta| (delta.filename.as_ref(), delta.size, &delta.checksum))
+            .filter(|(file, _, _)| seen.insert(*file))
+            .collect()
+    }
+
+    fn named(&self, patterns: &[Pattern]) -> HashSet<&str> {
+        self.new_packages
+            .iter()
+            .filter(|new_package| patterns.iter().any(|p| p.matches(&new_package.name)))
+            .flat_map(|new_package| &new_package.deltas)
+            .map(|delta| delta.filename.as_str())
+            .collect()
+    }
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 struct NewPackage {
     name: String,
     version: String,
@@ -200,10 +957,58 @@ struct Size {
 /// Location information for a package.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 struct Location {
-    /// Location of the package relative to the root.
+    /// Location of the package relative to the root, or an absolute URL.
+    #[serde(deserialize_with = "deserialize_href")]
     href: String,
+    /// The URL that `href` is relative to instead of the root, from `xml:base`.
+    #[serde(default)]
+    base: Option<String>,
+}
+
+impl Location {
+    /// The path of the package within the mirror.
+    ///
+    /// Packages at absolute URLs are kept at the path of the URL.
+    fn path(&self) -> &str {
+        match self.href.split_once("://") {
+            Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+            None => &self.href,
+        }
+    }
+
+    /// The URL of the package, if it isn't relative to the repository.
+    fn remote(&self) -> Option<Result<Url>> {
+        if self.href.contains("://") {
+            return Some(Url::parse(&self.href).map_err(|e| e.into()));
+        }
+        let base = self.base.as_ref()?;
+        Some(
+            href::base(base)
+                .and_then(|base| href::join(&base, &self.href))
+                .map_err(|e| format_err!("Invalid xml:base '{}': {}", base, e)),
+        )
+    }
+}
+
+/// Parse the location of a package, which must leave it a path within the mirror.
+fn deserialize_href<'de, D>(deserializer: D) -> ::std::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let location = Location {
+        href: String::deserialize(deserializer)?,
+        base: None,
+    };
+    if location.path().is_empty() {
+        return Err(de::Error::custom(format!(
+            "Package location '{}' has no path",
+            location.href
+        )));
+    }
+    Ok(location.href)
 }
 
+/// A checksum of a file in the repository.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 pub struct Checksum {
     #[serde(rename = "type")]
@@ -212,119 +1017,521 @@ pub struct Checksum {
     sum: String,
 }
 
+impl Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.sum)
+    }
+}
+
 impl Checksum {
-    async fn check(&self, path: impl AsRef<Path>) -> Result<bool> {
-        let digest = match self.algorithm.as_str() {
-            "md5" => MessageDigest::md5(),
-            "sha1" => MessageDigest::sha1(),
-            "sha224" => MessageDigest::sha224(),
-            "sha256" => MessageDigest::sha256(),
-            "sha384" => MessageDigest::sha384(),
-            "sha512" => MessageDigest::sha512(),
-            "ripemd160" => MessageDigest::ripemd160(),
-            unknown => bail!("Unknown checksum alogorithm: {}", unknown),
+    /// Create a checksum from an algorithm and a hex encoded digest.
+    pub(crate) fn new(algorithm: &str, sum: &str) -> Checksum {
+        Checksum {
+            algorithm: algorithm.to_owned(),
+            sum: sum.to_lowercase(),
+        }
+    }
+
+    /// The algorithm of the checksum, as named in the metadata.
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// The hex encoded digest.
+    pub fn digest(&self) -> &str {
+        &self.sum
+    }
+
+    #[instrument(name = "verify", skip_all)]
+    pub(crate) async fn check(&self, path: impl AsRef<Path>) -> Result<bool> {
+        let path = path.as_ref().to_owned();
+        let hasher = self.hasher(&path)?;
+
+        // Hashing large packages is CPU and disk bound, so keep it off the runtime threads
+        let (_, sum) = spawn_blocking(move || hash_file(hasher, &path, hashing())).await??;
+
+        Ok(self.matches(&sum))
+    }
+
+    /// Verify the decompressed contents of a metadata file against this checksum.
+    ///
+    /// Returns `None` if the file is compressed in a format that can't be decoded.
+    pub(crate) async fn check_open(
+        &self,
+        path: impl AsRef<Path>,
+        size: Option<u64>,
+    ) -> Result<Option<bool>> {
+        let path = path.as_ref().to_owned();
+        let hasher = self.hasher(&path)?;
+
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        let gzip = match extension {
+            "gz" => true,
+            "bz2" | "xz" | "zst" | "zck" => return Ok(None),
+            _ => false,
         };
 
-        let mut hasher = Hasher::new(digest)?;
+        let (open_size, sum) = spawn_blocking(move || {
+            let block_size = hashing().block_size;
+            let file = std::fs::File::open(&path)?;
+            if gzip {
+                hash_reader(hasher, GzDecoder::new(file), block_size)
+            } else {
+                hash_reader(hasher, file, block_size)
+            }
+        })
+        .await??;
+
+        let size_matches = size.map(|size| size == open_size).unwrap_or(true);
+        Ok(Some(size_matches && self.matches(&sum)))
+    }
+
+    /// Create a hasher for the algorithm used by this checksum.
+    fn hasher(&self, path: &Path) -> Result<Hasher> {
+        Hasher::new(&self.algorithm)?.ok_or_else(|| {
+            ChecksumError::UnknownAlgorithm {
+                path: path.to_owned(),
+                algorithm: self.algorithm.clone(),
+            }
+            .into()
+        })
+    }
+
+    /// Compare a hex encoded digest with the expected sum.
+    fn matches(&self, sum: &str) -> bool {
+        sum == self.sum
+    }
+}
+
+/// How existing files are read to verify their checksums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hashing {
+    /// The number of bytes read at a time.
+    pub block_size: usize,
+    /// Map files into memory rather than reading them, falling back to reading if that fails.
+    pub mmap: bool,
+}
+
+impl Default for Hashing {
+    fn default() -> Hashing {
+        Hashing {
+            block_size: 8 * 1024 * 1024,
+            mmap: false,
+        }
+    }
+}
+
+/// The number of chunks buffered between the network and the disk by default.
+const BUFFER_DEPTH: usize = 16;
+
+/// The number of chunks buffered between the network and the disk for each download.
+static BUFFER: OnceLock<usize> = OnceLock::new();
+
+/// Buffer at most `depth` chunks between the network and the disk, so that a slow disk holds
+/// up the network rather than filling memory.
+pub fn configure_buffer(depth: usize) {
+    let _ = BUFFER.set(depth.max(1));
+}
+
+/// The number of chunks buffered for each download.
+fn buffer_depth() -> usize {
+    BUFFER.get().copied().unwrap_or(BUFFER_DEPTH)
+}
+
+/// The number of bytes written to disk at a time.
+const WRITE_BUFFER: usize = 1024 * 1024;
+
+/// The number of times a download that was too slow is restarted.
+const SLOW_RETRIES: usize = 2;
+
+/// Whether space for downloads is allocated before they are written.
+static PREALLOCATE: AtomicBool = AtomicBool::new(false);
 
-        let mut file = File::open(path).await?;
-        let mut block = vec![0; 1024 * 1024 * 8];
+/// Allocate the space for each download before writing it, to reduce fragmentation.
+pub fn configure_preallocation(enabled: bool) {
+    PREALLOCATE.store(enabled, Ordering::Relaxed);
+}
+
+/// Allocate space for a file of the given size without changing its length.
+///
+/// This is only a hint, so failures are ignored.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &tokio::fs::File, size: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: The descriptor stays open for as long as the file is borrowed.
+    let result =
+        unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, size as i64) };
+    if result != 0 {
+        debug!(
+            "Could not preallocate {} bytes: {}",
+            size,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Allocate space for a file of the given size without changing its length.
+///
+/// Preallocation is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &tokio::fs::File, _size: u64) {}
 
-        loop {
-            let bytes_read = file.read(&mut block).await?;
-            if bytes_read == 0 {
-                break;
+/// The hashing options used for every file.
+static HASHING: OnceLock<Hashing> = OnceLock::new();
+
+/// Read files with the given options when verifying checksums.
+pub fn configure_hashing(hashing: Hashing) {
+    let _ = HASHING.set(hashing);
+}
+
+/// The hashing options in use.
+fn hashing() -> Hashing {
+    HASHING.get().copied().unwrap_or_default()
+}
+
+/// Hash the entire contents of a file, returning the size and digest.
+pub async fn digest_file(path: &Path, algorithm: &str) -> Result<(u64, String)> {
+    let hasher = Hasher::new(algorithm)?
+        .ok_or_else(|| format_err!("Unknown checksum algorithm '{}'", algorithm))?;
+    let path = path.to_owned();
+    spawn_blocking(move || hash_file(hasher, &path, hashing())).await?
+}
+
+/// Hash the entire contents of a file on the current thread, returning the size and digest.
+fn hash_file(mut hasher: Hasher, path: &Path, hashing: Hashing) -> Result<(u64, String)> {
+    let file = std::fs::File::open(path)?;
+    if hashing.mmap {
+        // SAFETY: The mapping is only read. If another process changes the file while it is
+        // mapped, the digest will be wrong and the file downloaded again.
+        match unsafe { Mmap::map(&file) } {
+            Ok(map) => {
+                let _ = map.advise(Advice::Sequential);
+                hasher.update(&map)?;
+                return Ok((map.len() as u64, hasher.finish()?));
             }
+            Err(e) => debug!("Could not map {:?}, reading it instead: {}", path, e),
+        }
+    }
+    hash_reader(hasher, file, hashing.block_size)
+}
+
+/// Hash the entire contents of a reader on the current thread, returning the size and digest.
+fn hash_reader(
+    mut hasher: Hasher,
+    mut reader: impl Read,
+    block_size: usize,
+) -> Result<(u64, String)> {
+    let mut block = vec![0; block_size.max(1)];
+    let mut size = 0;
 
-            hasher.update(&block[0..bytes_read])?;
+    loop {
+        let bytes_read = reader.read(&mut block)?;
+        if bytes_read == 0 {
+            break;
         }
 
-        let sum_bytes = hasher.finish()?;
-        let sum = hex::encode(&sum_bytes);
+        size += bytes_read as u64;
+        hasher.update(&block[0..bytes_read])?;
+    }
+
+    Ok((size, hasher.finish()?))
+}
+
+/// An error verifying the checksum of a single file.
+#[derive(Debug)]
+pub enum ChecksumError {
+    /// The metadata specified an algorithm that can't be computed.
+    UnknownAlgorithm {
+        /// The file that was being verified.
+        path: PathBuf,
+        /// The algorithm named in the metadata.
+        algorithm: String,
+    },
+}
+
+impl std::error::Error for ChecksumError {}
 
-        Ok(sum == self.sum)
+impl Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumError::UnknownAlgorithm { path, algorithm } => write!(
+                f,
+                "Unknown checksum algorithm '{}' for {:?}",
+                algorithm, path
+            ),
+        }
+    }
+}
+
+/// Checks made on downloaded files before they are put in place.
+#[derive(Debug, Clone, Default)]
+pub struct Vetting {
+    /// A shell command run on each downloaded file, which is rejected if the command fails.
+    ///
+    /// The command is given the path of the download in `YUMCLONE_FILE` and its path within the
+    /// repository in `YUMCLONE_PATH`.
+    pub command: Option<String>,
+    /// Where rejected files are moved, keeping their paths within the repository.
+    pub quarantine: PathBuf,
+    /// How many more times a rejected file is downloaded.
+    pub retries: usize,
+}
+
+impl Vetting {
+    /// Run the command on a downloaded file, quarantining the file if it is rejected.
+    async fn vet(&self, relative: &str, path: &Path, stats: Option<&Stats>) -> Result<()> {
+        let command = match &self.command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        let env = vec![
+            ("YUMCLONE_FILE", path.to_string_lossy().into_owned()),
+            ("YUMCLONE_PATH", relative.to_owned()),
+        ];
+        match hooks::run("file", command, &env).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.quarantine(relative, path, &e, stats).await?.into()),
+        }
+    }
+
+    /// Move a rejected file into quarantine.
+    async fn quarantine(
+        &self,
+        relative: &str,
+        path: &Path,
+        reason: impl Display,
+        stats: Option<&Stats>,
+    ) -> Result<Quarantined> {
+        let target = self.quarantine.join(relative);
+        create_dir_all(target.parent().expect("Invalid repository structure")).await?;
+        if rename(path, &target).await.is_err() {
+            // The quarantine is on another filesystem
+            copy(path, &target).await?;
+            remove_file(path).await?;
+        }
+        if let Some(stats) = stats {
+            stats.quarantined();
+        }
+
+        let reason = reason.to_string();
+        Event::new("quarantine")
+            .file(relative)
+            .error(&reason)
+            .log(|| warn!("Quarantined '{}' in {:?}: {}", relative, target, reason));
+        Ok(Quarantined {
+            path: relative.to_owned(),
+            reason,
+            mismatched: false,
+        })
+    }
+}
+
+/// A downloaded file was rejected and moved into quarantine.
+#[derive(Debug)]
+pub struct Quarantined {
+    /// The path of the file within the repository.
+    pub path: String,
+    /// Why the file was rejected.
+    pub reason: String,
+    /// Whether the file didn't match the size or checksum in the metadata, rather than being
+    /// rejected by the vetting command.
+    pub mismatched: bool,
+}
+
+impl std::error::Error for Quarantined {}
+
+impl Display for Quarantined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Quarantined '{}': {}", self.path, self.reason)
     }
 }
 
 /// Synchronise a remote file to a local location.
+///
+/// If `vetting` is given, the downloaded file is vetted before it is put in place.
 pub async fn sync_file<'c>(
     client: &Client,
     relative: &str,
     src: &Url,
     dest: &Path,
     check: Check<'c>,
+    tracker: Option<&Tracker>,
+    vetting: Option<&Vetting>,
 ) -> Result<()> {
-    let remote_path = src.join(&relative)?;
-    let local_path = dest.join(&relative);
+    let remote_path = href::join(src, relative)?;
+    sync_remote_file(
+        client,
+        relative,
+        &remote_path,
+        dest,
+        check,
+        tracker,
+        vetting,
+    )
+    .await
+}
+
+/// Synchronise a file from a URL to a path relative to a local location.
+#[instrument(name = "file", skip_all, fields(file = relative))]
+pub async fn sync_remote_file<'c>(
+    client: &Client,
+    relative: &str,
+    remote_path: &Url,
+    dest: &Path,
+    check: Check<'c>,
+    tracker: Option<&Tracker>,
+    vetting: Option<&Vetting>,
+) -> Result<()> {
+    let remote_path = remote_path.clone();
+    let local_path = href::local(dest, relative)?;
     let temp_path = local_path.with_extension("sync.tmp");
+    let failed = || {
+        if let Some(tracker) = tracker {
+            tracker.stats().checksum_failed();
+        }
+    };
 
-    if local_path.exists() {
-        let local_size = metadata(&local_path).await?.len();
-        if let Check::Hash(size, checksum) = check {
-            debug!("Verifying size and checksum of {:?}", local_path);
-            if local_size != size {
-                debug!("Local file incorrect size {:?}", local_path);
-            } else if checksum.check(&local_path).await? {
-                debug!(
-                    "Skipping (already exists with valid checksum) {:?}",
-                    remote_path
-                );
-                return Ok(());
-            } else {
-                debug!("Local file failed checksum {:?}", local_path);
-            }
-        } else if let Check::Size(size) = check {
-            debug!("Verifying size of {:?}", local_path);
-            if local_size != size {
-                debug!("Local file incorrect size {:?}", local_path);
-            } else {
-                debug!(
-                    "Skipping (already exists with valid size) {:?}",
-                    remote_path
-                );
-                return Ok(());
-            }
-        } else {
+    match local_matches(&local_path, check).await? {
+        Some(true) => {
             debug!("Skipping (already exists) {:?}", remote_path);
             return Ok(());
         }
+        Some(false) => failed(),
+        None => {}
     }
 
-    info!("Downloading \"{}\" to {:?}", remote_path, local_path);
-
-    create_dir_all(local_path.parent().expect("Invalid repository structure")).await?;
-    let download_size = download(client, &remote_path, &temp_path).await?;
-    match check {
-        Check::RemoteSize(size) | Check::Size(size) => {
-            info!("Verifying size of {:?}", remote_path);
-            if download_size != size {
-                bail!("Remote file failed size {:?}", temp_path);
+    let result: Result<u64> = async {
+        if let Check::RemoteSize(size) = check {
+            if let Some(remote_size) = remote_size(client, &remote_path).await {
+                if remote_size != size {
+                    bail!(
+                        "Remote file has incorrect size {:?} (expected {}, found {})",
+                        remote_path,
+                        size,
+                        remote_size
+                    );
+                }
             }
         }
-        Check::Hash(size, checksum) => {
-            info!("Verifying size and checksum of {:?}", remote_path);
-            if download_size != size {
-                bail!("Remote file failed size {:?}", temp_path);
-            } else if !checksum.check(&temp_path).await? {
-                bail!("Remote file failed checksum {:?}", temp_path);
+
+        info!("Downloading \"{}\" to {:?}", remote_path, local_path);
+
+        create_dir_all(local_path.parent().expect("Invalid repository structure")).await?;
+        let started = Instant::now();
+        let mut attempts = 0;
+        let (download_size, download_sum) = loop {
+            let hasher = match check {
+                Check::Hash(_, checksum) => Some(checksum.hasher(&local_path)?),
+                _ => None,
+            };
+            let result = download(
+                client,
+                &remote_path,
+                &temp_path,
+                check.size(),
+                hasher,
+                tracker.cloned(),
+            )
+            .await;
+            match result {
+                Err(e) if attempts < SLOW_RETRIES && e.downcast_ref::<TooSlow>().is_some() => {
+                    attempts += 1;
+                    warn!("{}, retrying", e);
+                    // The retry starts from the beginning, so its bytes would be counted twice
+                    if let Some(tracker) = tracker {
+                        tracker.restart();
+                    }
+                }
+                result => break result?,
             }
+        };
+        let elapsed = started.elapsed();
+        let rejected = match check {
+            Check::RemoteSize(size) | Check::Size(size) => {
+                info!("Verifying size of {:?}", remote_path);
+                Some("failed size").filter(|_| download_size != size)
+            }
+            Check::Hash(size, checksum) => {
+                info!("Verifying size and checksum of {:?}", remote_path);
+                if download_size != size {
+                    Some("failed size")
+                } else {
+                    // The file was hashed as it was written
+                    let matched = download_sum.is_some_and(|sum| checksum.matches(&sum));
+                    Some("failed checksum").filter(|_| !matched)
+                }
+            }
+            Check::Metadata => {
+                // Don't know size of repomd.xml ahead of time
+                None
+            }
+        };
+        if let Some(reason) = rejected {
+            failed();
+            match vetting {
+                Some(vetting) => {
+                    let stats = tracker.map(Tracker::stats);
+                    let mut quarantined = vetting
+                        .quarantine(relative, &temp_path, reason, stats)
+                        .await?;
+                    quarantined.mismatched = true;
+                    return Err(quarantined.into());
+                }
+                None => bail!("Remote file {} {:?}", reason, temp_path),
+            }
+        }
+        if let Some(vetting) = vetting {
+            vetting
+                .vet(relative, &temp_path, tracker.map(Tracker::stats))
+                .await?;
         }
-        Check::Metadata => {
-            // Don't know size of metadata ahead of time
+        rename(&temp_path, &local_path).await?;
+        if let Some(tracker) = tracker {
+            tracker.added(relative);
         }
+
+        Event::new("download")
+            .file(relative)
+            .bytes(download_size)
+            .duration(elapsed)
+            .log(|| {
+                debug!(
+                    "Downloaded {:?} ({} bytes in {:.1}s)",
+                    local_path,
+                    download_size,
+                    elapsed.as_secs_f64()
+                )
+            });
+        Ok(download_size)
     }
-    rename(&temp_path, &local_path).await?;
-    Ok(())
+    .await;
+
+    let mut record = audit::Record::new("download", &local_path).source(&remote_path);
+    if let Check::Hash(_, checksum) = check {
+        record = record.checksum(checksum);
+    }
+    match &result {
+        Ok(bytes) => record.bytes(*bytes).write(),
+        Err(e) => record.error(e).write(),
+    }
+    result.map(|_| ())
 }
 
 /// The kind of check to be made on a package
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CheckType {
     /// Only check the size of the downloadeded package
+    #[serde(rename = "none")]
     CheckRemoteSize,
     /// Check the size of the package
+    #[serde(rename = "size")]
     CheckSize,
     /// Check the hash of the file
+    #[serde(rename = "hash")]
     CheckHash,
 }
 pub use CheckType::*;
@@ -332,17 +1539,74 @@ pub use CheckType::*;
 impl CheckType {
     /// Check if the type is only for remote files
     pub fn remote_only(self) -> bool {
+        matches!(self, CheckType::CheckRemoteSize)
+    }
+
+    /// The check to make on a file of a given size and checksum.
+    pub fn check(self, size: u64, checksum: &Checksum) -> Check<'_> {
         match self {
-            CheckType::CheckRemoteSize => true,
-            _ => false,
+            CheckRemoteSize => Check::RemoteSize(size),
+            CheckSize => Check::Size(size),
+            CheckHash => Check::Hash(size, checksum),
         }
     }
 }
 
+impl FromStr for CheckType {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<CheckType, String> {
+        match s {
+            "none" => Ok(CheckRemoteSize),
+            "size" => Ok(CheckSize),
+            "hash" => Ok(CheckHash),
+            _ => Err(format!(
+                "Unknown check mode '{}' (expected none, size or hash)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for CheckType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckRemoteSize => write!(f, "none"),
+            CheckSize => write!(f, "size"),
+            CheckHash => write!(f, "hash"),
+        }
+    }
+}
+
+/// Check whether a local file passes a check, so needn't be downloaded again.
+///
+/// Returns `None` if there is no local file.
+pub(crate) async fn local_matches(local_path: &Path, check: Check<'_>) -> Result<Option<bool>> {
+    if !local_path.exists() {
+        return Ok(None);
+    }
+    let local_size = metadata(local_path).await?.len();
+    let matches = match check {
+        Check::Hash(size, checksum) => {
+            debug!("Verifying size and checksum of {:?}", local_path);
+            local_size == size && checksum.check(local_path).await?
+        }
+        Check::Size(size) => {
+            debug!("Verifying size of {:?}", local_path);
+            local_size == size
+        }
+        Check::Metadata | Check::RemoteSize(_) => true,
+    };
+    if !matches {
+        debug!("Local file doesn't match {:?}", local_path);
+    }
+    Ok(Some(matches))
+}
+
 /// Check data to use when checking a package
 #[derive(Debug, Clone, Copy)]
 pub enum Check<'c> {
-    /// Don't have check for metadata
+    /// Don't have check for the repository metadata index
     Metadata,
     /// Only check remote size
     RemoteSize(u64),
@@ -352,49 +1616,256 @@ pub enum Check<'c> {
     Hash(u64, &'c Checksum),
 }
 
+impl Check<'_> {
+    /// The expected size of the file, if known.
+    fn size(&self) -> Option<u64> {
+        match *self {
+            Check::Metadata => None,
+            Check::RemoteSize(size) | Check::Size(size) | Check::Hash(size, _) => Some(size),
+        }
+    }
+}
+
+/// Find the size of a remote file without downloading it.
+///
+/// Returns `None` if the server doesn't report the size.
+async fn remote_size(client: &Client, src: &Url) -> Option<u64> {
+    let read = timeout::current().read();
+    let response = match tokio::time::timeout(read, client.head(src.clone()).send()).await {
+        Ok(response) => response,
+        Err(_) => {
+            debug!("HEAD {} timed out", src);
+            return None;
+        }
+    };
+    match response {
+        // The length of the response, rather than of its empty body
+        Ok(response) if response.status().is_success() => response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok()),
+        Ok(response) => {
+            debug!("HEAD {} returned {}", src, response.status());
+            None
+        }
+        Err(err) => {
+            debug!("HEAD {} failed: {}", src, err);
+            None
+        }
+    }
+}
+
 /// Download a network file to a local file
-async fn download(client: &Client, src: &Url, dest: &Path) -> Result<u64> {
+///
+/// If a hasher is given, the file is hashed as it is written and the hex encoded digest returned
+/// alongside the size.
+async fn download(
+    client: &Client,
+    src: &Url,
+    dest: &Path,
+    size: Option<u64>,
+    mut hasher: Option<Hasher>,
+    tracker: Option<Tracker>,
+) -> Result<(u64, Option<String>)> {
     let src = src.to_owned();
-    let request = client.get(src);
+    let request = client.get(src.clone());
     let dest = dest.to_owned();
-    let (tx, mut rx) = unbounded_channel();
-
-    let network: tokio::task::JoinHandle<Result<()>> = tokio::spawn(async move {
-        let mut src = request.send().await?;
+    let (mut tx, mut rx) = channel(buffer_depth());
 
-        while let Some(chunk) = src.chunk().await? {
-            tx.send(chunk)?;
+    let priority = throttle::current_priority();
+    let concurrency = concurrency::current();
+    let congested = {
+        let concurrency = concurrency.clone();
+        move |reason| {
+            if let Some(concurrency) = &concurrency {
+                concurrency.congested(reason);
+            }
+        }
+    };
+    let timeouts = timeout::current();
+    let read = timeouts.read();
+    let mut low_speed = timeouts.low_speed();
+    // Wake up often enough to notice a transfer that has slowed to nothing
+    let wait = low_speed.as_ref().map_or(read, |l| read.min(l.time()));
+    let stalled = {
+        let src = src.clone();
+        move || -> failure::Error {
+            Unresponsive {
+                url: src.clone(),
+                status: None,
+                read,
+            }
+            .into()
         }
+    };
 
-        Ok(())
-    });
+    let request_span = info_span!(
+        "request",
+        mirror = %src.origin().ascii_serialization(),
+        status = field::Empty,
+        latency_ms = field::Empty,
+        bytes = field::Empty,
+        error = field::Empty,
+    );
+    let network: tokio::task::JoinHandle<Result<()>> = tokio::spawn(
+        async move {
+            let span = Span::current();
+            let result: Result<()> = async {
+                let _transfer = throttle::start(priority);
+                let requested = Instant::now();
+                let response = tokio::time::timeout(read, request.send()).await;
+                let mut response = match response {
+                    Ok(response) => response?,
+                    Err(_) => {
+                        congested("timeouts");
+                        return Err(stalled());
+                    }
+                };
+                span.record("latency_ms", requested.elapsed().as_secs_f64() * 1000.0);
+                let status = response.status();
+                span.record("status", status.as_u16());
+                if status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::SERVICE_UNAVAILABLE
+                {
+                    congested("rate limited");
+                    let retry_after = response
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| concurrency::parse_retry_after(value, SystemTime::now()));
+                    return Err(RateLimited {
+                        url: src,
+                        status,
+                        retry_after,
+                    }
+                    .into());
+                }
+                if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+                    return Err(Missing { url: src, status }.into());
+                }
+                if !status.is_success() {
+                    return Err(Unresponsive {
+                        url: src,
+                        status: Some(status),
+                        read,
+                    }
+                    .into());
+                }
+                if let Some(concurrency) = &concurrency {
+                    concurrency.responded(requested.elapsed());
+                }
 
-    let disk: tokio::task::JoinHandle<Result<u64>> = tokio::spawn(async move {
-        let mut local = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(dest)
-            .await?;
-        let mut size = 0;
+                let mut last_data = Instant::now();
+                let mut received_bytes = 0;
+                loop {
+                    // None if nothing was received in time
+                    let next = match tokio::time::timeout(wait, response.chunk()).await {
+                        Ok(chunk) => Some(chunk?),
+                        Err(_) => None,
+                    };
+                    let now = Instant::now();
+                    let received = match &next {
+                        Some(Some(chunk)) => chunk.len() as u64,
+                        Some(None) => break,
+                        None => 0,
+                    };
+                    if let Some(low_speed) = low_speed.as_mut() {
+                        if let Some(speed) = low_speed.record(received, now) {
+                            congested("slow transfers");
+                            return Err(low_speed.too_slow(&src, speed).into());
+                        }
+                    }
+                    let chunk = match next {
+                        Some(Some(chunk)) => chunk,
+                        _ if now.duration_since(last_data) >= read => {
+                            congested("timeouts");
+                            return Err(stalled());
+                        }
+                        _ => continue,
+                    };
+                    last_data = now;
 
-        while let Some(chunk) = rx.recv().await {
-            size += chunk.len() as u64;
-            local.write_all(&chunk[..]).await?;
+                    received_bytes += chunk.len() as u64;
+                    span.record("bytes", received_bytes);
+                    throttle::acquire(chunk.len() as u64, priority).await;
+                    tx.send(chunk).await?;
+                    if let Some(low_speed) = low_speed.as_mut() {
+                        low_speed.pause(now.elapsed());
+                    }
+                }
+
+                Ok(())
+            }
+            .await;
+            if let Err(e) = &result {
+                span.record("error", field::display(e));
+            }
+            result
         }
+        .instrument(request_span),
+    );
+
+    let disk: tokio::task::JoinHandle<Result<(u64, Option<String>)>> = tokio::spawn(
+        async move {
+            let local = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(dest)
+                .await?;
+            if let Some(size) = size.filter(|_| PREALLOCATE.load(Ordering::Relaxed)) {
+                preallocate(&local, size);
+            }
+            let mut local = BufWriter::with_capacity(WRITE_BUFFER, local);
+            let mut size = 0;
 
-        Ok(size)
-    });
+            while let Some(chunk) = rx.recv().await {
+                size += chunk.len() as u64;
+                local.write_all(&chunk[..]).await?;
+                if let Some(tracker) = &tracker {
+                    tracker.transferred(chunk.len() as u64);
+                }
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(&chunk[..])?;
+                }
+            }
+
+            local.flush().await?;
+
+            let sum = match hasher {
+                Some(hasher) => Some(hasher.finish()?),
+                None => None,
+            };
+
+            Ok((size, sum))
+        }
+        .in_current_span(),
+    );
 
-    let size = disk.await??;
+    let result = disk.await??;
     network.await??;
 
-    Ok(size)
+    Ok(result)
 }
 
 #[cfg(test)]
 mod test {
-    use super::{decode, Metadata};
+    use super::{
+        decode, download, hash_file, host_failed, preallocate, remote_size, rpmvercmp, sync_all,
+        with_order, CheckHash, Checksum, ChecksumError, CmpOrdering, DownloadOrder, Fetch, Hashing,
+        Inconsistent, Metadata, Missing, OnMissing, Quarantined, Update, Version, Vetting,
+    };
+    use crate::hash::Hasher;
+    use crate::prefetch::Wanted;
+    use crate::rank::Sources;
+    use crate::serve::serve_dir;
+    use crate::stats::{LimitReached, Stats};
+    use crate::timeout::{self, Timeouts};
+    use failure::format_err;
+    use glob::Pattern;
+    use reqwest::{Client, StatusCode, Url};
+    use std::collections::BTreeMap;
+    use tempdir::TempDir;
 
     const LOCAL_XML: &[u8] = include_bytes!(
         "test-data/local/repodata/84fe7bb9cf340186df02863647f41a4be32c86a21b80eaaeddaa97e99a24b7a6-primary.xml.gz"
@@ -403,10 +1874,661 @@ mod test {
         "test-data/remote/repodata/328a9f961ff596aedac41d051634325110b8fb30b87c00f678c257644337d1d6-primary.xml.gz"
     );
 
+    #[tokio::test]
+    async fn quarantine_rejected() {
+        let dir = TempDir::new("vetting").unwrap();
+        let download = dir.path().join("a-1-1.rpm.sync.tmp");
+        std::fs::write(&download, "a").unwrap();
+        let stats = Stats::default();
+        let mut vetting = Vetting {
+            command: Some("test \"$YUMCLONE_PATH\" = Packages/a/a-1-1.rpm".to_owned()),
+            quarantine: dir.path().join("quarantine"),
+            retries: 0,
+        };
+
+        let relative = "Packages/a/a-1-1.rpm";
+        vetting
+            .vet(relative, &download, Some(&stats))
+            .await
+            .unwrap();
+        assert!(download.exists());
+
+        vetting.command = Some("grep -q virus \"$YUMCLONE_FILE\"".to_owned());
+        let error = vetting
+            .vet(relative, &download, Some(&stats))
+            .await
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<Quarantined>().unwrap().path, relative);
+        assert!(!download.exists());
+        assert!(dir.path().join("quarantine").join(relative).exists());
+        assert_eq!(stats.summary(Default::default()).quarantined, 1);
+    }
+
+    #[test]
+    fn empty_locations() {
+        let package = |href: &str| {
+            format!(
+                "<?xml version=\"1.0\"?><metadata>\
+                 <package><name>a</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">00</checksum>\
+                 <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"{}\"/></package></metadata>",
+                href
+            )
+        };
+        assert!(Metadata::decode_raw(package("a-1-1.rpm").as_bytes()).is_ok());
+        for href in [
+            "",
+            "https://other.example.com",
+            "https://other.example.com/",
+        ] {
+            assert!(
+                Metadata::decode_raw(package(href).as_bytes()).is_err(),
+                "'{}' was accepted",
+                href
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn xml_base_locations() {
+        let dir = TempDir::new("xml-base").unwrap();
+        let packages = dir.path().join("packages");
+        std::fs::create_dir_all(packages.join("b")).unwrap();
+        std::fs::write(packages.join("b/b-1-1.rpm"), "b").unwrap();
+        let base = serve_dir(&packages);
+        let xml = format!(
+            "<?xml version=\"1.0\"?><metadata>\
+             <package><name>a</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+             <checksum type=\"sha256\">\
+             ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb</checksum>\
+             <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+             <location href=\"https://other.example.com/pub/a/a-1-1.rpm\"/></package>\
+             <package><name>b</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+             <checksum type=\"sha256\">\
+             3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d</checksum>\
+             <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+             <location xml:base=\"{}\" href=\"b/b-1-1.rpm\"/></package></metadata>",
+            base
+        );
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let files: Vec<_> = metadata.files().into_iter().map(|(f, _, _)| f).collect();
+        assert_eq!(files, vec!["b/b-1-1.rpm", "pub/a/a-1-1.rpm"]);
+        let remote = metadata.remote_files();
+        assert_eq!(
+            remote["pub/a/a-1-1.rpm"].as_str(),
+            "https://other.example.com/pub/a/a-1-1.rpm"
+        );
+        assert_eq!(remote["b/b-1-1.rpm"], base.join("b/b-1-1.rpm").unwrap());
+
+        // The package at the xml:base is downloaded from there rather than the repository
+        let only_b = Metadata {
+            packages: metadata
+                .packages
+                .iter()
+                .filter(|p| p.location() == "b/b-1-1.rpm")
+                .cloned()
+                .collect(),
+        };
+        let empty = dir.path().join("empty");
+        std::fs::create_dir_all(&empty).unwrap();
+        let sources = Sources::from(vec![serve_dir(&empty)]);
+        let dest = dir.path().join("mirror");
+        let vetting = Vetting {
+            command: None,
+            quarantine: dir.path().join("quarantine"),
+            retries: 0,
+        };
+        let stats = Stats::default();
+        sync_all(
+            &Client::new(),
+            &only_b,
+            &sources,
+            &dest,
+            CheckHash,
+            &stats,
+            &vetting,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(dest.join("b/b-1-1.rpm")).unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn upstream_inconsistencies() {
+        let dir = TempDir::new("inconsistent").unwrap();
+        let upstream = dir.path().join("upstream");
+        std::fs::create_dir_all(&upstream).unwrap();
+        std::fs::write(upstream.join("a-1-1.rpm"), "a").unwrap();
+        // Replaced upstream after the metadata was generated
+        std::fs::write(upstream.join("b-1-1.rpm"), "x").unwrap();
+        let package = |name, checksum| {
+            format!(
+                "<package><name>{0}</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">{1}</checksum>\
+                 <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"{0}-1-1.rpm\"/></package>",
+                name, checksum
+            )
+        };
+        let xml = format!(
+            "<?xml version=\"1.0\"?><metadata>{}{}{}</metadata>",
+            package(
+                "a",
+                "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"
+            ),
+            package(
+                "b",
+                "3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d"
+            ),
+            package(
+                "c",
+                "2e7d2c03a9507ae265ecf5b5356885a53393a2029d241394997265a1a25aefc6"
+            ),
+        );
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let sources = Sources::from(vec![serve_dir(&upstream)]);
+        let dest = dir.path().join("mirror");
+        let vetting = Vetting {
+            command: None,
+            quarantine: dir.path().join("quarantine"),
+            retries: 0,
+        };
+        let stats = Stats::default();
+        let error = sync_all(
+            &Client::new(),
+            &metadata,
+            &sources,
+            &dest,
+            CheckHash,
+            &stats,
+            &vetting,
+        )
+        .await
+        .unwrap_err();
+
+        // Every other file is still downloaded
+        assert_eq!(error.downcast_ref::<Inconsistent>().unwrap().files, 2);
+        assert!(dest.join("a-1-1.rpm").exists());
+        let inconsistencies = stats.summary(Default::default()).inconsistencies;
+        assert_eq!(inconsistencies.len(), 2);
+        assert_eq!(inconsistencies[0].path, "b-1-1.rpm");
+        assert_eq!(inconsistencies[0].problem, "failed checksum");
+        assert_eq!(inconsistencies[1].path, "c-1-1.rpm");
+        assert!(inconsistencies[1].problem.contains("404"));
+    }
+
+    #[test]
+    fn tolerate_missing() {
+        let missing = || {
+            Err(Inconsistent {
+                files: 2,
+                missing: 2,
+            }
+            .into())
+        };
+        let mismatched = || {
+            Err(Inconsistent {
+                files: 2,
+                missing: 1,
+            }
+            .into())
+        };
+        assert!(OnMissing::Fail.tolerate(missing()).is_err());
+        assert!(OnMissing::Skip.tolerate(missing()).is_ok());
+        assert!(OnMissing::RetryLater.tolerate(missing()).is_ok());
+        assert!(OnMissing::Skip.tolerate(mismatched()).is_err());
+        assert!(OnMissing::Skip
+            .tolerate(Err(format_err!("Connection refused")))
+            .is_err());
+
+        let on_missing: BTreeMap<String, OnMissing> =
+            toml::from_str("on_missing = \"retry-later\"").unwrap();
+        assert_eq!(on_missing["on_missing"], OnMissing::RetryLater);
+    }
+
+    #[test]
+    fn download_order() {
+        let package = |name, size| {
+            format!(
+                "<package><name>{0}</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">{0}</checksum>\
+                 <size package=\"{1}\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"{0}-1-1.rpm\"/></package>",
+                name, size
+            )
+        };
+        let xml = format!(
+            "<?xml version=\"1.0\"?><metadata>{}{}{}</metadata>",
+            package("b", 3),
+            package("c", 1),
+            package("a", 2),
+        );
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let queued = |order: DownloadOrder| -> Vec<&str> {
+            order
+                .queue(&metadata)
+                .into_iter()
+                .map(|(file, _, _)| file)
+                .collect()
+        };
+
+        assert_eq!(
+            queued(DownloadOrder::Path),
+            vec!["a-1-1.rpm", "b-1-1.rpm", "c-1-1.rpm"]
+        );
+        assert_eq!(
+            queued(DownloadOrder::SmallestFirst),
+            vec!["c-1-1.rpm", "a-1-1.rpm", "b-1-1.rpm"]
+        );
+        assert_eq!(
+            queued(DownloadOrder::LargestFirst),
+            vec!["b-1-1.rpm", "a-1-1.rpm", "c-1-1.rpm"]
+        );
+        assert_eq!(
+            queued(DownloadOrder::Metadata),
+            vec!["b-1-1.rpm", "c-1-1.rpm", "a-1-1.rpm"]
+        );
+    }
+
+    #[tokio::test]
+    async fn critical_first() {
+        let dir = TempDir::new("critical").unwrap();
+        let (upstream, dest) = (dir.path().join("upstream"), dir.path().join("dest"));
+        std::fs::create_dir_all(&upstream).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        let mut packages = String::new();
+        for name in ["bash", "glibc", "kernel-core", "vim"] {
+            let file = upstream.join(format!("{}-1-1.rpm", name));
+            std::fs::write(&file, name).unwrap();
+            let (_, digest) = hash_file(
+                Hasher::new("sha256").unwrap().unwrap(),
+                &file,
+                Hashing::default(),
+            )
+            .unwrap();
+            packages += &format!(
+                "<package><name>{0}</name><version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">{1}</checksum>\
+                 <size package=\"{2}\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"{0}-1-1.rpm\"/></package>",
+                name,
+                digest,
+                name.len()
+            );
+        }
+        let xml = format!("<?xml version=\"1.0\"?><metadata>{}</metadata>", packages);
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let critical = vec![
+            Pattern::new("kernel*").unwrap(),
+            Pattern::new("glibc").unwrap(),
+        ];
+        assert_eq!(
+            metadata.named(&critical),
+            vec!["glibc-1-1.rpm", "kernel-core-1-1.rpm"]
+                .into_iter()
+                .collect()
+        );
+
+        // With room for only two files, the critical packages are downloaded rather than the
+        // smallest
+        let stats = Stats::with_limit(5 + 11);
+        let sources = Sources::from(vec![serve_dir(&upstream)]);
+        let client = Client::new();
+        let vetting = Vetting {
+            command: None,
+            quarantine: dir.path().join("quarantine"),
+            retries: 0,
+        };
+        let sync = sync_all(
+            &client, &metadata, &sources, &dest, CheckHash, &stats, &vetting,
+        );
+        // Boxed, as the download is too large for the stack in debug builds
+        let result = Box::pin(with_order(DownloadOrder::Path, critical, sync)).await;
+        assert!(result.unwrap_err().downcast_ref::<LimitReached>().is_some());
+        assert!(dest.join("glibc-1-1.rpm").exists());
+        assert!(dest.join("kernel-core-1-1.rpm").exists());
+        assert!(!dest.join("vim-1-1.rpm").exists());
+    }
+
+    #[tokio::test]
+    async fn stalled_download() {
+        // A server that accepts connections but never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let src = Url::parse(&format!("http://{}/a.rpm", listener.local_addr().unwrap())).unwrap();
+        let dir = TempDir::new("stalled").unwrap();
+        let timeouts: Timeouts = toml::from_str("read = \"200ms\"").unwrap();
+
+        let result = timeout::scope(
+            timeouts,
+            download(
+                &Client::new(),
+                &src,
+                &dir.path().join("a.rpm"),
+                None,
+                None,
+                None,
+            ),
+        )
+        .await;
+        let err = result.unwrap_err();
+        assert!(host_failed(&err));
+        let err = err.to_string();
+        assert!(err.starts_with("No data received from"), "{}", err);
+        drop(listener);
+    }
+
+    #[test]
+    fn local_failures() {
+        let url = Url::parse("http://mirror.example/a.rpm").unwrap();
+        let missing = Missing {
+            url,
+            status: StatusCode::NOT_FOUND,
+        };
+        assert!(host_failed(&missing.into()));
+
+        // Running out of space is no reason to avoid the host
+        let disk_full = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        assert!(!host_failed(&disk_full.into()));
+        assert!(!host_failed(&format_err!("Remote file failed checksum")));
+    }
+
+    #[tokio::test]
+    async fn head_remote_size() {
+        let dir = TempDir::new("size").unwrap();
+        std::fs::write(dir.path().join("a-1-1.rpm"), "abc").unwrap();
+        let base = serve_dir(dir.path());
+        let client = Client::new();
+        let present = base.join("a-1-1.rpm").unwrap();
+        assert_eq!(remote_size(&client, &present).await, Some(3));
+        let missing = base.join("missing.rpm").unwrap();
+        assert_eq!(remote_size(&client, &missing).await, None);
+    }
+
+    #[tokio::test]
+    async fn retry_from_mirror() {
+        let dir = TempDir::new("retry").unwrap();
+        let relative = "Packages/a/a-1-1.rpm";
+        let write = |root: &str, contents: &str| {
+            let path = dir.path().join(root).join(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("corrupt", "b");
+        write("good", "a");
+        let sources = Sources::from(vec![
+            serve_dir(&dir.path().join("corrupt")),
+            serve_dir(&dir.path().join("good")),
+        ]);
+        let xml = format!(
+            "<?xml version=\"1.0\"?><metadata><package><name>a</name>\
+             <version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+             <checksum type=\"sha256\">\
+             ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb</checksum>\
+             <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+             <location href=\"{}\"/></package></metadata>",
+            relative
+        );
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let client = Client::new();
+        let dest = dir.path().join("mirror");
+        let stats = Stats::default();
+        let mut vetting = Vetting {
+            command: None,
+            quarantine: dir.path().join("quarantine"),
+            retries: 0,
+        };
+
+        // Without retries, the corrupt file is quarantined and the download fails
+        let result = sync_all(
+            &client, &metadata, &sources, &dest, CheckHash, &stats, &vetting,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!dest.join(relative).exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("quarantine").join(relative)).unwrap(),
+            "b"
+        );
+
+        vetting.retries = 1;
+        sync_all(
+            &client, &metadata, &sources, &dest, CheckHash, &stats, &vetting,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(dest.join(relative)).unwrap(), "a");
+        assert_eq!(stats.summary(Default::default()).quarantined, 2);
+    }
+
+    #[test]
+    fn changelog_between_versions() {
+        let metadata = |packages: &[(&str, &str, &str)]| {
+            let packages: String = packages
+                .iter()
+                .map(|(name, epoch, ver)| {
+                    format!(
+                        "<package type=\"rpm\"><name>{0}</name><arch>x86_64</arch>\
+                         <version epoch=\"{1}\" ver=\"{2}\" rel=\"1\"/>\
+                         <checksum type=\"sha256\">00</checksum>\
+                         <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+                         <location href=\"Packages/{0}-{2}-1.x86_64.rpm\"/></package>",
+                        name, epoch, ver
+                    )
+                })
+                .collect();
+            let xml = format!("<?xml version=\"1.0\"?><metadata>{}</metadata>", packages);
+            Metadata::decode_raw(xml.as_bytes()).unwrap()
+        };
+        let old = metadata(&[
+            ("bash", "0", "5.1"),
+            ("vim", "2", "9.0"),
+            ("kernel", "0", "6.1"),
+            ("kernel", "0", "6.2"),
+        ]);
+        let new = metadata(&[
+            ("bash", "0", "5.2"),
+            ("emacs", "1", "29.1"),
+            ("kernel", "0", "6.2"),
+            ("kernel", "0", "6.3"),
+        ]);
+
+        let changelog = new.changes_since(&old);
+        assert_eq!(changelog.added, vec!["emacs-1:29.1-1.x86_64"]);
+        assert_eq!(
+            changelog.updated,
+            vec![
+                Update {
+                    from: "bash-5.1-1.x86_64".to_owned(),
+                    to: "bash-5.2-1.x86_64".to_owned(),
+                    changelog: Vec::new(),
+                },
+                Update {
+                    from: "kernel-6.1-1.x86_64".to_owned(),
+                    to: "kernel-6.3-1.x86_64".to_owned(),
+                    changelog: Vec::new(),
+                },
+            ]
+        );
+        assert_eq!(changelog.removed, vec!["vim-2:9.0-1.x86_64"]);
+        assert!(new.changes_since(&new).is_empty());
+        assert_eq!(
+            new.changes_since(&Metadata::default()).to_string(),
+            "4 packages added, 0 updated, 0 removed"
+        );
+
+        // The newest version is found by comparing each segment as a number
+        let old = metadata(&[("kernel", "0", "6.9")]);
+        let new = metadata(&[("kernel", "0", "6.9.1"), ("kernel", "0", "6.10")]);
+        let changelog = new.changes_since(&old);
+        assert_eq!(changelog.updated[0].to, "kernel-6.10-1.x86_64");
+        assert_eq!(changelog.added, vec!["kernel-6.9.1-1.x86_64"]);
+    }
+
+    #[test]
+    fn rpm_version_order() {
+        let version = |epoch: &str, ver: &str, rel: &str| {
+            Version::new(epoch.to_owned(), ver.to_owned(), rel.to_owned())
+        };
+        assert!(version("0", "6.10", "1") > version("0", "6.9", "1"));
+        assert!(version("0", "1.0", "10") > version("0", "1.0", "9"));
+        assert!(version("1", "1.0", "1") > version("0", "2.0", "1"));
+        assert!(version("", "1.0", "1") < version("1", "1.0", "1"));
+        assert!(version("0", "1.0", "1.fc39") < version("0", "1.0", "1.fc40"));
+
+        let ordered = [
+            "1.0~rc1", "1.0", "1.0^git1", "1.0.a", "1.0.1", "1.0.10", "1.1", "1.9", "1.10", "2",
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(rpmvercmp(pair[0], pair[1]), CmpOrdering::Less, "{:?}", pair);
+            assert_eq!(
+                rpmvercmp(pair[1], pair[0]),
+                CmpOrdering::Greater,
+                "{:?}",
+                pair
+            );
+        }
+        assert_eq!(rpmvercmp("1.01", "1.1"), CmpOrdering::Equal);
+        assert_eq!(rpmvercmp("1_0", "1.0"), CmpOrdering::Equal);
+        // Still distinct versions, so they can't replace each other in a map
+        assert_ne!(
+            version("0", "1.01", "1").cmp(&version("0", "1.1", "1")),
+            CmpOrdering::Equal
+        );
+    }
+
+    #[test]
+    fn dependency_closure() {
+        let package = |name: &str, provides: &str, requires: &str| {
+            let entries = |names: &str| -> String {
+                names
+                    .split_whitespace()
+                    .map(|n| format!("<rpm:entry name=\"{}\"/>", n))
+                    .collect()
+            };
+            format!(
+                "<package type=\"rpm\"><name>{0}</name>\
+                 <version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+                 <checksum type=\"sha256\">00</checksum>\
+                 <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+                 <location href=\"Packages/{0}-1-1.rpm\"/>\
+                 <format><rpm:provides>{1}</rpm:provides><rpm:requires>{2}</rpm:requires>\
+                 <file>/usr/bin/{0}</file></format></package>",
+                name,
+                entries(provides),
+                entries(requires)
+            )
+        };
+        let xml =
+            format!(
+            "<?xml version=\"1.0\"?><metadata xmlns:rpm=\"http://linux.duke.edu/metadata/rpm\">\
+             {}{}{}{}</metadata>",
+            package("vim", "vim", "libc.so.6 /usr/bin/sh rpmlib(CompressedFileNames)"),
+            package("glibc", "libc.so.6", ""),
+            package("sh", "sh", ""),
+            package("emacs", "emacs", "libc.so.6")
+        );
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+
+        let mut wanted = Wanted::default();
+        wanted.extend("vim\n");
+        let (chosen, rest) = metadata.partition(&wanted);
+        let names =
+            |m: &Metadata| -> Vec<String> { m.packages().iter().map(|p| p.name.clone()).collect() };
+        assert_eq!(names(&chosen), vec!["glibc", "sh", "vim"]);
+        assert_eq!(names(&rest), vec!["emacs"]);
+
+        let metadata = Metadata::decode_raw(xml.as_bytes()).unwrap();
+        let patterns = [Pattern::new("vim-1*").unwrap(), Pattern::new("sh").unwrap()];
+        assert_eq!(names(&metadata.matching(&patterns)), vec!["sh", "vim"]);
+    }
+
     #[tokio::test]
     async fn read_packages() {
-        let local: Metadata = decode(&mut LOCAL_XML).await.unwrap();
+        let local: Metadata = decode(&mut &LOCAL_XML[..]).await.unwrap();
+        let remote: Metadata = decode(&mut &REMOTE_XML[..]).await.unwrap();
 
         assert_eq!(local.packages.len(), 11331);
+        assert_eq!(remote.packages.len(), 11348);
+    }
+
+    fn checksum(algorithm: &str, sum: &str) -> Checksum {
+        Checksum {
+            algorithm: algorithm.to_owned(),
+            sum: sum.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn modern_checksums() {
+        let dir = TempDir::new("checksum").unwrap();
+        let path = dir.path().join("empty");
+        std::fs::write(&path, b"").unwrap();
+
+        let sha3 = checksum(
+            "sha3-256",
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a",
+        );
+        assert!(sha3.check(&path).await.unwrap());
+
+        let blake2 = checksum(
+            "blake2b",
+            "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+             d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce",
+        );
+        assert!(blake2.check(&path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unknown_checksum() {
+        let dir = TempDir::new("checksum").unwrap();
+        let path = dir.path().join("empty");
+        std::fs::write(&path, b"").unwrap();
+
+        let err = checksum("crc32", "0").check(&path).await.unwrap_err();
+        match err.downcast_ref::<ChecksumError>() {
+            Some(ChecksumError::UnknownAlgorithm { algorithm, .. }) => {
+                assert_eq!(algorithm, "crc32")
+            }
+            None => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn hash_mapped_and_read() {
+        let dir = TempDir::new("checksum").unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, vec![7; 100_000]).unwrap();
+        let empty = dir.path().join("empty");
+        std::fs::write(&empty, b"").unwrap();
+
+        let hash =
+            |path, hashing| hash_file(Hasher::new("sha256").unwrap().unwrap(), path, hashing);
+        let mapped = Hashing {
+            block_size: 1,
+            mmap: true,
+        };
+        let small_blocks = Hashing {
+            block_size: 4096,
+            mmap: false,
+        };
+        assert_eq!(
+            hash(&path, mapped).unwrap(),
+            hash(&path, small_blocks).unwrap()
+        );
+        assert_eq!(hash(&path, mapped).unwrap().0, 100_000);
+        assert_eq!(
+            hash(&empty, mapped).unwrap(),
+            hash(&empty, Hashing::default()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn preallocate_keeps_length() {
+        let dir = TempDir::new("preallocate").unwrap();
+        let path = dir.path().join("package.rpm");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        preallocate(&file, 1 << 20);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
     }
 }
@@ -0,0 +1,206 @@
+//! Plans of the changes synchronising would make, to be reviewed and applied later.
+//!
+//! `yumclone plan` verifies the local files of each repository against the
+//! upstream metadata and writes the files to download and remove to a plan.
+//! `yumclone apply` then makes exactly those changes, provided the upstream
+//! metadata hasn't changed since the plan was made.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use failure::format_err;
+
+use crate::package::{CheckType, Checksum, Fetch};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The changes to make to every repository.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    /// When the plan was made, in RFC 3339 format.
+    pub created: String,
+    /// The changes to make to each variant of each repository.
+    pub variants: Vec<Variant>,
+}
+
+/// The changes to make to a single variant of a repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Variant {
+    /// The label of the repository.
+    pub repo: String,
+    /// Where the variant is synchronised from.
+    pub src: String,
+    /// Where the variant is synchronised to.
+    pub dest: String,
+    /// The fingerprint of the upstream metadata the plan was made from.
+    pub fingerprint: String,
+    /// How the local files were checked, and are checked again as they are downloaded.
+    pub check: CheckType,
+    /// Files to download.
+    pub downloads: Vec<Download>,
+    /// Files to remove, relative to the destination.
+    pub removals: Vec<Removal>,
+}
+
+/// A file to download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Download {
+    /// The path of the file, relative to the repository.
+    pub path: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The checksum of the file, as `algorithm:digest`.
+    pub checksum: String,
+}
+
+/// A file to remove.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Removal {
+    /// The path of the file, relative to the destination.
+    pub path: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+}
+
+impl Plan {
+    /// Start a plan made now.
+    pub fn new() -> Plan {
+        Plan {
+            created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            variants: Vec::new(),
+        }
+    }
+
+    /// Load a plan from a file.
+    pub fn load(path: &Path) -> Result<Plan> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("Could not read plan {:?}: {}", path, e))?;
+        serde_json::from_str(&text)
+            .map_err(|e| format_err!("Could not parse plan {:?}: {}", path, e))
+    }
+
+    /// Save the plan to a file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+}
+
+impl Variant {
+    /// The total size of the files to download.
+    pub fn download_size(&self) -> u64 {
+        self.downloads.iter().map(|download| download.size).sum()
+    }
+
+    /// The total size of the files to remove.
+    pub fn removal_size(&self) -> u64 {
+        self.removals.iter().map(|removal| removal.size).sum()
+    }
+
+    /// The files to download, in a form that can be fetched.
+    pub fn planned(&self) -> Result<Planned> {
+        let files = self
+            .downloads
+            .iter()
+            .map(|download| {
+                let (algorithm, sum) = download.checksum.split_once(':').ok_or_else(|| {
+                    format_err!(
+                        "Invalid checksum '{}' for '{}'",
+                        download.checksum,
+                        download.path
+                    )
+                })?;
+                Ok((
+                    download.path.clone(),
+                    download.size,
+                    Checksum::new(algorithm, sum),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Planned { files })
+    }
+}
+
+/// An upstream that changed after a plan was made.
+#[derive(Debug)]
+pub struct Outdated {
+    /// Where the variant is synchronised from.
+    pub src: String,
+}
+
+impl std::error::Error for Outdated {}
+
+impl Display for Outdated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The metadata of '{}' has changed since the plan was made",
+            self.src
+        )
+    }
+}
+
+/// The files a plan downloads.
+#[derive(Debug, Default, Deserialize)]
+pub struct Planned {
+    files: Vec<(String, u64, Checksum)>,
+}
+
+impl Fetch for Planned {
+    fn files(&self) -> BTreeSet<(&str, u64, &Checksum)> {
+        self.files
+            .iter()
+            .map(|(path, size, checksum)| (path.as_str(), *size, checksum))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new("plan").unwrap();
+        let path = dir.path().join("plan.json");
+        let mut plan = Plan::new();
+        plan.variants.push(Variant {
+            repo: "fedora".to_owned(),
+            src: "https://example.com/fedora/".to_owned(),
+            dest: "fedora".to_owned(),
+            fingerprint: "abc".to_owned(),
+            check: CheckType::CheckHash,
+            downloads: vec![Download {
+                path: "Packages/a-1-1.rpm".to_owned(),
+                size: 10,
+                checksum: "sha256:AA".to_owned(),
+            }],
+            removals: vec![Removal {
+                path: "Packages/a-0-1.rpm".to_owned(),
+                size: 9,
+            }],
+        });
+        plan.save(&path).unwrap();
+
+        let loaded = Plan::load(&path).unwrap();
+        assert_eq!(loaded.variants, plan.variants);
+        let variant = &loaded.variants[0];
+        assert_eq!(variant.download_size(), 10);
+        assert_eq!(variant.removal_size(), 9);
+        let planned = variant.planned().unwrap();
+        let files: Vec<_> = planned.files().into_iter().collect();
+        assert_eq!(
+            files,
+            vec![("Packages/a-1-1.rpm", 10, &Checksum::new("sha256", "aa"))]
+        );
+
+        let mut invalid = variant.clone();
+        invalid.downloads[0].checksum = "AA".to_owned();
+        assert!(invalid.planned().is_err());
+    }
+}
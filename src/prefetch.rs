@@ -0,0 +1,115 @@
+//! Selection of the packages clients actually install, from access logs or package lists.
+
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tokio::fs::read_to_string;
+
+use failure::format_err;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// Packages to download ahead of the rest of a repository.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Prefetch {
+    /// Access logs or package lists naming the packages clients install.
+    pub from: Vec<PathBuf>,
+    /// Only download the named packages and their dependencies, rather than downloading them
+    /// first.
+    #[serde(default)]
+    pub restrict: bool,
+}
+
+impl Prefetch {
+    /// Read the packages named by every input.
+    pub async fn wanted(&self) -> Result<Wanted> {
+        let mut wanted = Wanted::default();
+        for path in &self.from {
+            let source = read_to_string(path)
+                .await
+                .map_err(|e| format_err!("Could not read {:?}: {}", path, e))?;
+            wanted.extend(&source);
+        }
+        Ok(wanted)
+    }
+}
+
+/// Packages named by file or by package name.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Wanted {
+    /// The file names of packages, such as `bash-5.2.15-1.fc39.x86_64.rpm`.
+    files: HashSet<String>,
+    /// The names of packages, such as `bash`.
+    names: HashSet<String>,
+}
+
+impl Wanted {
+    /// Add the packages named in an access log or package list.
+    ///
+    /// Any word of a line ending in `.rpm` is taken as the path of a package, so that the logs
+    /// of most web servers can be used as they are. A line holding a single other word is taken
+    /// as the name of a package. Blank lines and those starting with `#` are ignored.
+    pub fn extend(&mut self, source: &str) {
+        for line in source.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut found = false;
+            for word in line.split_whitespace() {
+                let path = word.trim_matches(|c| c == '"' || c == '\'');
+                let path = path.split(['?', '#']).next().unwrap_or_default();
+                if path.ends_with(".rpm") {
+                    let file = path.rsplit('/').next().unwrap_or(path);
+                    self.files.insert(file.to_owned());
+                    found = true;
+                }
+            }
+            if !found && !line.contains(char::is_whitespace) && !line.contains('/') {
+                self.names.insert(line.to_owned());
+            }
+        }
+    }
+
+    /// Whether a package is named, by its name or location.
+    pub fn matches(&self, name: &str, location: &str) -> bool {
+        let file = location.rsplit('/').next().unwrap_or(location);
+        self.names.contains(name) || self.files.contains(file)
+    }
+
+    /// Whether no packages are named.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_inputs() {
+        let mut wanted = Wanted::default();
+        wanted.extend(
+            r#"10.0.0.5 - - [16/Oct/2026:10:00:00 +0000] "GET /fedora/39/x86_64/Packages/b/bash-5.2.15-1.fc39.x86_64.rpm HTTP/1.1" 200 1843200
+10.0.0.5 - - [16/Oct/2026:10:00:01 +0000] "GET /fedora/39/x86_64/repodata/repomd.xml HTTP/1.1" 200 4000
+# Installed by hand
+vim-enhanced
+kernel-core-6.5.6-300.fc39.x86_64.rpm
+"#,
+        );
+
+        assert!(wanted.matches("bash", "Packages/b/bash-5.2.15-1.fc39.x86_64.rpm"));
+        assert!(wanted.matches(
+            "vim-enhanced",
+            "Packages/v/vim-enhanced-9.0-1.fc39.x86_64.rpm"
+        ));
+        assert!(wanted.matches(
+            "kernel-core",
+            "Packages/k/kernel-core-6.5.6-300.fc39.x86_64.rpm"
+        ));
+        assert!(!wanted.matches("bash", "Packages/b/bash-5.2.26-1.fc39.x86_64.rpm"));
+        assert!(!wanted.matches("repomd.xml", "repodata/repomd.xml"));
+        assert!(!wanted.is_empty());
+    }
+}
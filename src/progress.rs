@@ -0,0 +1,297 @@
+//! Tracking of download throughput and the time remaining.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::stats::Stats;
+
+/// How often progress is logged.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Progress through a planned set of downloads.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Bytes in the whole plan.
+    total: u64,
+    /// Bytes that have been downloaded or found to be up to date.
+    done: AtomicU64,
+    /// Bytes that have been downloaded.
+    downloaded: AtomicU64,
+    started: Instant,
+    workers: Vec<Worker>,
+    stats: Stats,
+}
+
+#[derive(Debug, Default)]
+struct Worker {
+    /// Bytes downloaded by the worker.
+    downloaded: AtomicU64,
+    /// Bytes downloaded for the file the worker is on.
+    current: AtomicU64,
+}
+
+/// Records the progress of a single worker.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    shared: Arc<Shared>,
+    worker: usize,
+    /// Whether the files downloaded are packages, rather than metadata.
+    packages: bool,
+}
+
+impl Progress {
+    /// Start tracking a plan of `total` bytes shared between a number of workers.
+    pub fn new(total: u64, workers: usize, stats: Stats) -> Progress {
+        Progress {
+            shared: Arc::new(Shared {
+                total,
+                done: AtomicU64::new(0),
+                downloaded: AtomicU64::new(0),
+                started: Instant::now(),
+                workers: (0..workers).map(|_| Worker::default()).collect(),
+                stats,
+            }),
+        }
+    }
+
+    /// Get the tracker for a worker.
+    pub fn tracker(&self, worker: usize) -> Tracker {
+        Tracker {
+            shared: self.shared.clone(),
+            worker,
+            packages: true,
+        }
+    }
+
+    /// Take a snapshot of the progress so far.
+    pub fn sample(&self) -> Sample {
+        let shared = &self.shared;
+        Sample {
+            at: Instant::now(),
+            done: shared.done.load(Ordering::Relaxed),
+            downloaded: shared.downloaded.load(Ordering::Relaxed),
+            workers: shared
+                .workers
+                .iter()
+                .map(|w| w.downloaded.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+
+    /// Log the progress periodically until the future is dropped.
+    pub async fn report(&self, interval: Duration) {
+        let mut last = self.sample();
+        loop {
+            tokio::time::delay_for(interval).await;
+            let now = self.sample();
+            self.log(&last, &now);
+            last = now;
+        }
+    }
+
+    /// Log the progress since the previous sample.
+    fn log(&self, last: &Sample, now: &Sample) {
+        let elapsed = now.at.duration_since(self.shared.started);
+        let average = rate(now.downloaded, elapsed);
+        let current = rate(
+            now.downloaded - last.downloaded,
+            now.at.duration_since(last.at),
+        );
+        let remaining = self.shared.total.saturating_sub(now.done);
+        let eta = if average > 0.0 {
+            format_duration(Duration::from_secs_f64(remaining as f64 / average))
+        } else {
+            "unknown".to_owned()
+        };
+
+        info!(
+            "{} of {} remaining, {}/s now, {}/s average, ETA {}",
+            format_bytes(remaining),
+            format_bytes(self.shared.total),
+            format_bytes(current as u64),
+            format_bytes(average as u64),
+            eta
+        );
+        for (worker, (before, after)) in last.workers.iter().zip(&now.workers).enumerate() {
+            debug!(
+                "Worker {}: {}/s now, {}/s average",
+                worker,
+                format_bytes(rate(after - before, now.at.duration_since(last.at)) as u64),
+                format_bytes(rate(*after, elapsed) as u64)
+            );
+        }
+    }
+}
+
+impl Tracker {
+    /// Track downloads of metadata, which count towards the limits of `stats` like packages do
+    /// but aren't recorded as added packages.
+    pub fn metadata(stats: &Stats) -> Tracker {
+        Tracker {
+            shared: Progress::new(0, 1, stats.clone()).shared,
+            worker: 0,
+            packages: false,
+        }
+    }
+
+    /// The statistics of the repository being downloaded.
+    pub fn stats(&self) -> &Stats {
+        &self.shared.stats
+    }
+
+    /// Record bytes downloaded for the current file.
+    pub fn transferred(&self, bytes: u64) {
+        let shared = &self.shared;
+        shared.stats.downloaded(bytes);
+        shared.done.fetch_add(bytes, Ordering::Relaxed);
+        shared.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(worker) = shared.workers.get(self.worker) {
+            worker.downloaded.fetch_add(bytes, Ordering::Relaxed);
+            worker.current.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a file that was downloaded in full.
+    pub fn added(&self, relative: &str) {
+        if self.packages {
+            self.shared.stats.added(relative);
+        }
+    }
+
+    /// Forget the bytes downloaded for the current file, as it is being downloaded again from
+    /// the start.
+    pub fn restart(&self) {
+        let shared = &self.shared;
+        let current = match shared.workers.get(self.worker) {
+            Some(worker) => {
+                let current = worker.current.swap(0, Ordering::Relaxed);
+                worker.downloaded.fetch_sub(current, Ordering::Relaxed);
+                current
+            }
+            None => return,
+        };
+        shared.stats.discarded(current);
+        shared.done.fetch_sub(current, Ordering::Relaxed);
+        shared.downloaded.fetch_sub(current, Ordering::Relaxed);
+    }
+
+    /// Record that the current file of the given size is finished, whether or not it was
+    /// downloaded.
+    ///
+    /// Returns the number of bytes downloaded for the file.
+    pub fn finish(&self, size: u64) -> u64 {
+        let current = match self.shared.workers.get(self.worker) {
+            Some(worker) => worker.current.swap(0, Ordering::Relaxed),
+            None => 0,
+        };
+        self.shared
+            .done
+            .fetch_add(size.saturating_sub(current), Ordering::Relaxed);
+        current
+    }
+}
+
+/// The state of progress at a point in time.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    at: Instant,
+    done: u64,
+    downloaded: u64,
+    workers: Vec<u64>,
+}
+
+/// The rate of transfer in bytes per second.
+fn rate(bytes: u64, duration: Duration) -> f64 {
+    let secs = duration.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Format a number of bytes with a binary unit.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format a duration to the nearest second.
+pub fn format_duration(duration: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn track_progress() {
+        let stats = Stats::default();
+        let progress = Progress::new(1000, 2, stats.clone());
+        let first = progress.tracker(0);
+        let second = progress.tracker(1);
+
+        first.transferred(100);
+        first.transferred(50);
+        first.finish(200);
+        second.finish(300);
+
+        let sample = progress.sample();
+        assert_eq!(sample.done, 500);
+        assert_eq!(sample.downloaded, 150);
+        assert_eq!(sample.workers, vec![150, 0]);
+        assert_eq!(stats.summary(Duration::default()).bytes_downloaded, 150);
+
+        // A download that starts again only counts the bytes of the last attempt
+        second.transferred(80);
+        second.restart();
+        second.transferred(100);
+        assert_eq!(second.finish(100), 100);
+        let sample = progress.sample();
+        assert_eq!(sample.done, 600);
+        assert_eq!(sample.downloaded, 250);
+        assert_eq!(sample.workers, vec![150, 100]);
+        assert_eq!(stats.summary(Duration::default()).bytes_downloaded, 250);
+    }
+
+    #[test]
+    fn track_metadata() {
+        let stats = Stats::default();
+        let tracker = Tracker::metadata(&stats);
+        tracker.transferred(300);
+        tracker.added("repodata/repomd.xml");
+        tracker.finish(0);
+
+        // Metadata counts towards the bytes downloaded, but isn't a package
+        let summary = stats.summary(Duration::default());
+        assert_eq!(summary.bytes_downloaded, 300);
+        assert_eq!(summary.added, 0);
+        assert!(summary.added_files.is_empty());
+    }
+
+    #[test]
+    fn human_units() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(format_duration(Duration::from_millis(65_500)), "1m 5s");
+    }
+}
@@ -0,0 +1,184 @@
+//! Ranking of the mirrors that packages are downloaded from.
+//!
+//! Each mirror is probed with a small ranged request for the same file, and
+//! the mirrors are ordered by how long the request took, so that the fastest
+//! is preferred and unreachable mirrors are only used as a last resort. The
+//! ranking is repeated periodically while a long synchronisation runs.
+
+use reqwest::header::RANGE;
+use reqwest::{Client, Url};
+use std::future::pending;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, info};
+
+/// How often mirrors are ranked again, unless configured.
+pub const INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// The number of bytes requested from each mirror.
+const PROBE_BYTES: u64 = 256 * 1024;
+/// How long a probe may take before the mirror is considered unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The sources that files can be downloaded from, in order of preference.
+#[derive(Debug)]
+pub struct Sources {
+    urls: RwLock<Vec<Url>>,
+    ranking: Option<Ranking>,
+}
+
+/// When the sources were last ranked and how often they are ranked again.
+#[derive(Debug)]
+struct Ranking {
+    interval: Duration,
+    ranked: Mutex<Option<Instant>>,
+}
+
+impl From<Vec<Url>> for Sources {
+    fn from(urls: Vec<Url>) -> Sources {
+        Sources {
+            urls: RwLock::new(urls),
+            ranking: None,
+        }
+    }
+}
+
+impl Sources {
+    /// Sources that are ranked by speed, and ranked again after each interval.
+    pub fn ranked(urls: Vec<Url>, interval: Duration) -> Sources {
+        Sources {
+            urls: RwLock::new(urls),
+            ranking: Some(Ranking {
+                interval,
+                ranked: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// The sources, from the most to least preferred.
+    pub fn get(&self) -> Vec<Url> {
+        self.urls.read().unwrap().clone()
+    }
+
+    /// Rank the sources by how quickly they serve part of a file, unless they were ranked
+    /// within the interval.
+    pub async fn rank(&self, client: &Client, sample: &str) {
+        let ranking = match &self.ranking {
+            Some(ranking) => ranking,
+            None => return,
+        };
+        let urls = self.get();
+        if urls.len() < 2 {
+            return;
+        }
+        {
+            let mut ranked = ranking.ranked.lock().unwrap();
+            if ranked.is_some_and(|ranked| ranked.elapsed() < ranking.interval) {
+                return;
+            }
+            *ranked = Some(Instant::now());
+        }
+
+        // Probes are made one at a time so that they don't compete for bandwidth
+        let mut probed = Vec::with_capacity(urls.len());
+        for url in urls {
+            let time = probe(client, &url, sample).await;
+            probed.push((time, url));
+        }
+        probed.sort_by_key(|(time, _)| time.unwrap_or(Duration::MAX));
+
+        let described: Vec<String> = probed
+            .iter()
+            .map(|(time, url)| match time {
+                Some(time) => format!("{} ({}ms)", url, time.as_millis()),
+                None => format!("{} (unreachable)", url),
+            })
+            .collect();
+        info!("Ranked mirrors: {}", described.join(", "));
+        *self.urls.write().unwrap() = probed.into_iter().map(|(_, url)| url).collect();
+    }
+
+    /// Rank the sources again after each interval, forever.
+    pub async fn keep_ranked(&self, client: &Client, sample: Option<&str>) {
+        match (&self.ranking, sample) {
+            (Some(ranking), Some(sample)) => loop {
+                delay_for(ranking.interval).await;
+                self.rank(client, sample).await;
+            },
+            _ => pending().await,
+        }
+    }
+}
+
+/// Time a ranged request for the start of a file from a mirror.
+///
+/// Returns `None` if the mirror couldn't serve the file in time.
+async fn probe(client: &Client, base: &Url, sample: &str) -> Option<Duration> {
+    let url = base.join(sample).ok()?;
+    let started = Instant::now();
+    let request = async {
+        let mut response = client
+            .get(url.clone())
+            .header(RANGE, format!("bytes=0-{}", PROBE_BYTES - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        // Servers that ignore the range still only need to send the start of the file
+        let mut received = 0;
+        while let Some(chunk) = response.chunk().await? {
+            received += chunk.len() as u64;
+            if received >= PROBE_BYTES {
+                break;
+            }
+        }
+        Ok::<_, reqwest::Error>(received)
+    };
+    match timeout(PROBE_TIMEOUT, request).await {
+        Ok(Ok(received)) => {
+            let elapsed = started.elapsed();
+            debug!("Probed {}: {} bytes in {:?}", url, received, elapsed);
+            Some(elapsed)
+        }
+        Ok(Err(e)) => {
+            debug!("Probing {} failed: {}", url, e);
+            None
+        }
+        Err(_) => {
+            debug!("Probing {} timed out", url);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serve::serve_dir;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn unreachable_last() {
+        let dir = TempDir::new("rank").unwrap();
+        std::fs::write(dir.path().join("a.rpm"), vec![0; 1024]).unwrap();
+        let served = serve_dir(dir.path());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+        let missing = served.join("missing/").unwrap();
+
+        let client = Client::new();
+        let urls = vec![closed.clone(), missing.clone(), served.clone()];
+        let fixed = Sources::from(urls.clone());
+        fixed.rank(&client, "a.rpm").await;
+        assert_eq!(fixed.get(), urls);
+
+        let sources = Sources::ranked(urls, Duration::from_secs(60));
+        sources.rank(&client, "a.rpm").await;
+        assert_eq!(sources.get()[0], served);
+
+        // Ranked again only after the interval
+        *sources.urls.write().unwrap() = vec![closed.clone(), served.clone()];
+        sources.rank(&client, "a.rpm").await;
+        assert_eq!(sources.get(), vec![closed, served]);
+    }
+}
@@ -1,60 +1,239 @@
 //! Represetnation of repository metadata.
 
 use std::cmp::PartialEq;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env::current_dir;
+use std::fmt;
 use std::marker::Unpin;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use tokio::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{
+    create_dir_all, hard_link, metadata, read_dir, read_to_string, remove_file, write, File,
+    OpenOptions,
+};
 use tokio::io::{copy, AsyncRead, AsyncReadExt};
+use tokio::task::spawn_blocking;
 
-use failure::format_err;
-use log::{debug, info};
-use reqwest::{Client, Url};
+use failure::{bail, format_err};
+use glob::Pattern;
+use regex::{Captures, Regex};
+use reqwest::{Client, StatusCode, Url};
 use serde::*;
 use serde_xml_rs as xml;
 use tempdir::TempDir;
+use tracing::{debug, info, instrument, warn};
 use walkdir::WalkDir;
 
-use crate::package::{decode, sync_all, sync_file, Check, CheckType, Fetch, Metadata, PrestoDelta};
+use crate::audit;
+use crate::bench::{self, Measurement};
+use crate::filelists::{self, Provider};
+use crate::hash::{Hasher, MinimumDigest};
+use crate::href;
+use crate::links::{self, Broken};
+use crate::list::Listed;
+use crate::logging::Event;
+use crate::manifest;
+use crate::other::Other;
+use crate::package::{
+    decode, digest_file, local_matches, sync_all, sync_file, Changelog, Check, CheckType, Checksum,
+    Fetch, Metadata, OnMissing, PrestoDelta, Vetting,
+};
+use crate::plan::{Download, Planned};
+use crate::prefetch::Wanted;
+use crate::progress::Tracker;
+use crate::rank::Sources;
+use crate::sign::{Signing, Verification};
+use crate::state::Published;
+use crate::stats::Stats;
+use crate::treeinfo::TreeInfo;
 
-pub const MD_DIR: &'static str = "repodata";
-pub const MD_PATH: &'static str = "repodata/repomd.xml";
+pub const MD_DIR: &str = "repodata";
+pub const MD_PATH: &str = "repodata/repomd.xml";
+/// Files published alongside the metadata by some repositories, such as signatures of the index
+/// and the product description of SUSE repositories.
+pub const EXTRA_FILES: &[&str] = &[
+    "repodata/repomd.xml.asc",
+    "repodata/repomd.xml.key",
+    "content",
+    "content.asc",
+    "content.key",
+    "media.1/media",
+    "media.1/products",
+];
+/// The directory within a destination used for staging when no other is configured.
+pub const STAGING_DIR: &str = ".yumclone";
+/// The directory within a destination where rejected downloads are kept when no other is
+/// configured.
+pub const QUARANTINE_DIR: &str = ".yumclone/quarantine";
+/// The directory within a destination where indexes of previous metadata generations are kept.
+const GENERATIONS_DIR: &str = ".yumclone/generations";
+
+/// Revisions at least this large are taken to be times (in 2001 or later).
+const EPOCH_REVISION: u64 = 1_000_000_000;
 
 type Result<T> = ::std::result::Result<T, ::failure::Error>;
 
+/// What to do when the upstream metadata is older than allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnStale {
+    /// Warn and synchronise anyway.
+    #[default]
+    Warn,
+    /// Fail the synchronisation.
+    Fail,
+    /// Synchronise from the first mirror with fresh enough metadata instead.
+    FallBack,
+}
+
+/// The upstream metadata is older than allowed.
+#[derive(Debug)]
+pub struct Stale {
+    /// The location of the repository
+    pub url: Url,
+    /// How long ago the metadata was generated
+    pub age: Duration,
+}
+
+impl std::error::Error for Stale {}
+
+impl fmt::Display for Stale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Metadata of '{}' is stale (generated {} ago)",
+            self.url,
+            humantime::format_duration(Duration::from_secs(self.age.as_secs()))
+        )
+    }
+}
+
 /// A mirror of a repository at a particular locaiton.
 pub struct Mirror {
     repo: Repo,
     location: Url,
+    /// Types of metadata left out of the mirror.
+    excluded: Vec<String>,
+    /// The key used to sign the index when it is rewritten.
+    signing: Option<Signing>,
+    /// Packages downloaded before the rest.
+    wanted: Option<Wanted>,
+    /// Whether only the wanted packages are downloaded.
+    restrict: bool,
+    /// Checks made on downloaded packages.
+    vetting: Vetting,
+    /// Other locations of the same repository, to download packages from when they fail their
+    /// checks.
+    fallbacks: Vec<Url>,
+    /// The key that must have signed the upstream index.
+    verification: Option<Verification>,
+    /// How often the upstream and its fallbacks are ranked by speed, if they are.
+    ranking: Option<Duration>,
+    /// What to do about packages that are missing upstream.
+    on_missing: OnMissing,
+    /// The weakest checksums accepted from the upstream metadata.
+    minimum_digest: Option<MinimumDigest>,
 }
 
 impl Mirror {
     fn new(repo: Repo, location: Url) -> Mirror {
         Mirror {
-            repo: repo,
-            location: location,
+            repo,
+            location,
+            excluded: Vec::new(),
+            signing: None,
+            wanted: None,
+            restrict: false,
+            vetting: Vetting::default(),
+            fallbacks: Vec::new(),
+            verification: None,
+            ranking: None,
+            on_missing: OnMissing::default(),
+            minimum_digest: None,
         }
     }
 
+    /// Sign the index with a local key whenever it is rewritten.
+    pub fn sign_with(&mut self, signing: Option<&Signing>) {
+        self.signing = signing.cloned();
+    }
+
+    /// Prefer whichever of the upstream and its fallbacks is fastest, ranking them again after
+    /// each interval.
+    pub fn rank_every(&mut self, interval: Option<Duration>) {
+        self.ranking = interval;
+    }
+
+    /// Check that the upstream index was signed by a key before anything is downloaded.
+    pub fn verify_with(&mut self, verification: Option<Verification>) {
+        self.verification = verification;
+    }
+
+    /// Download the wanted packages, along with everything they depend on, before the rest.
+    ///
+    /// If `restrict` is set, the rest of the packages (and any deltas) aren't downloaded at all.
+    pub fn prefetch(&mut self, wanted: Wanted, restrict: bool) {
+        self.wanted = Some(wanted);
+        self.restrict = restrict;
+    }
+
+    /// Decide whether packages that are missing upstream fail the synchronisation.
+    pub fn tolerate_missing(&mut self, on_missing: OnMissing) {
+        self.on_missing = on_missing;
+    }
+
+    /// Refuse, or warn about, checksums in the upstream metadata weaker than a minimum.
+    pub fn require_digest(&mut self, minimum: Option<MinimumDigest>) {
+        self.minimum_digest = minimum;
+    }
+
+    /// Vet downloaded packages before they are put in place.
+    pub fn vet_with(&mut self, vetting: Vetting) {
+        self.vetting = vetting;
+    }
+
+    /// Download packages again from other locations of the repository when they fail their
+    /// checks.
+    pub fn fall_back_to(&mut self, urls: &[String]) -> Result<()> {
+        self.fallbacks = urls
+            .iter()
+            .map(|url| href::base(url).map_err(|e| format_err!("Invalid mirror '{}': {}", url, e)))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Leave types of metadata, such as `appstream`, out of the mirror.
+    ///
+    /// The index is rewritten without them when it is cached.
+    pub fn exclude_metadata(&mut self, types: &[String]) {
+        self.repo.data.retain(|datum| !types.contains(&datum.datum));
+        self.excluded = types.to_vec();
+    }
+
     /// Download a mirror metadata from a remote location.
     pub async fn remote(client: &Client, url: &str) -> Result<Mirror> {
-        let md_url = Url::parse(url)?.join(MD_PATH)?;
-        debug!("Loading remote metadata from '{}'", md_url);
-        let raw = client.get(md_url).send().await?.text().await?;
+        let raw = fetch_repomd(client, url).await?;
         let repo = Repo::decode(&mut raw.as_bytes()).await?;
 
-        Ok(Mirror::new(repo, Url::parse(url)?))
+        Ok(Mirror::new(repo, href::base(url)?))
+    }
+
+    /// Get a fingerprint of the remote metadata index that changes whenever the repository does.
+    ///
+    /// Only the index is downloaded, so this is cheap enough to poll.
+    pub async fn fingerprint(client: &Client, url: &str) -> Result<String> {
+        let raw = fetch_repomd(client, url).await?;
+        let mut hasher = Hasher::new("sha256")?.ok_or(format_err!("sha256 is not supported"))?;
+        hasher.update(raw.as_bytes())?;
+        hasher.finish()
     }
 
     /// Load a mirror from a local location.
     pub async fn local(path: &str) -> Result<Option<Mirror>> {
         let local_path = current_dir()?.join(path);
-        let local_path_str = local_path
-            .to_str()
-            .ok_or(format_err!("Couldn't decode directory: {}", path))?;
-        let url = Url::parse("file:///")?.join(local_path_str)?;
+        let url = Url::from_directory_path(&local_path)
+            .map_err(|_| format_err!("Couldn't decode directory: {}", path))?;
 
         let md_path = Path::new(path).join(MD_PATH);
         debug!("Loading local metadata from {:?}", md_path);
@@ -69,20 +248,67 @@ impl Mirror {
         Ok(Some(Mirror::new(repo, url)))
     }
 
+    /// When the metadata was generated, from the newest timestamp in the index.
+    ///
+    /// Indexes without timestamps are dated by their revision, if it is a time.
+    pub fn generated(&self) -> Option<SystemTime> {
+        let timestamp = self
+            .repo
+            .data
+            .iter()
+            .filter_map(|datum| datum.timestamp)
+            .max()
+            .or(self
+                .repo
+                .revision
+                .filter(|&revision| revision >= EPOCH_REVISION))?;
+        Some(UNIX_EPOCH + Duration::from_secs(timestamp))
+    }
+
+    /// Check that the metadata is no older than `max_age`.
+    pub fn check_age(&self, max_age: Duration) -> Result<()> {
+        let generated = match self.generated() {
+            Some(generated) => generated,
+            None => {
+                debug!("'{}' has no timestamp to check the age of", self.location);
+                return Ok(());
+            }
+        };
+        let age = generated.elapsed().unwrap_or_default();
+        if age > max_age {
+            return Err(Stale {
+                url: self.location.clone(),
+                age,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     /// Compare the versions of two mirrors.
     pub fn same_version(&self, other: &Mirror) -> bool {
         self.repo == other.repo
     }
 
     /// Create a local cache of all metadata.
-    pub async fn into_cache(self, client: &Client) -> Result<Cache> {
-        Cache::new(client, self).await
+    ///
+    /// Metadata is cached in a temporary directory within `staging` unless a persistent directory
+    /// is given, in which case files that are still current are reused rather than downloaded
+    /// again. The bytes downloaded are counted in `stats`, as package downloads are.
+    pub async fn into_cache(
+        self,
+        client: &Client,
+        dir: Option<&Path>,
+        staging: &Path,
+        stats: &Stats,
+    ) -> Result<Cache> {
+        Cache::new(client, self, dir, staging, stats).await
     }
 
     /// Get the package listing for the cached repository.
     pub async fn metadata(&self, base_path: &Path) -> Result<Metadata> {
         let primary_path = base_path.join(self.repo.primary_path()?);
-        Ok(decode(&mut File::open(primary_path).await?).await?)
+        decode(&mut File::open(primary_path).await?).await
     }
 
     /// Get the listing of deltas.
@@ -97,14 +323,126 @@ impl Mirror {
         }
     }
 
-    /// Remove all extraneous files.
-    pub async fn clean(&self) -> Result<()> {
+    /// Every package and delta listed by the metadata, with its size and checksum.
+    pub async fn listed_files(&self) -> Result<HashMap<String, (u64, Checksum)>> {
+        let base_path = Path::new(self.location.path());
+        let mut listed = HashMap::new();
+        for (file, size, checksum) in self.metadata(base_path).await?.files() {
+            listed.insert(file.to_owned(), (size, checksum.clone()));
+        }
+        if let Some(deltas) = self.prestodelta(base_path).await? {
+            for (file, size, checksum) in deltas.files() {
+                listed.insert(file.to_owned(), (size, checksum.clone()));
+            }
+        }
+        Ok(listed)
+    }
+
+    /// Find the packages and deltas in the metadata that are missing or fail a check.
+    pub async fn verify(&self, check: CheckType) -> Result<Vec<String>> {
+        let base_path = Path::new(self.location.path());
+        let mut failed = Vec::new();
+        for (file, (size, checksum)) in self.listed_files().await? {
+            let path = href::local(base_path, &file)?;
+            if local_matches(&path, check.check(size, &checksum)).await? != Some(true) {
+                failed.push(file);
+            }
+        }
+        failed.sort();
+        Ok(failed)
+    }
+
+    /// Describe every package in the metadata, for an inventory of the mirror.
+    pub async fn listed(&self, repo: &str) -> Result<Vec<Listed>> {
         let base_path = Path::new(self.location.path());
         let metadata = self.metadata(base_path).await?;
-        let prestodelta = self.prestodelta(base_path).await?;
+        Ok(metadata.listed(repo, &base_path.to_string_lossy()))
+    }
+
+    /// Find the packages providing files that match a pattern, from the `filelists` metadata.
+    pub async fn providers(&self, pattern: &Pattern) -> Result<Vec<Provider>> {
+        let path = self
+            .repo
+            .subsection_path("filelists")
+            .ok_or(format_err!("No filelists metadata found"))?;
+        let path = Path::new(self.location.path()).join(path);
+        let pattern = pattern.clone();
+        spawn_blocking(move || filelists::search_file(&path, &pattern)).await?
+    }
+
+    /// Remove all extraneous files, other than those below an excluded path.
+    #[instrument(name = "clean", skip_all)]
+    pub async fn clean(&self, exclude: &[Pattern], stats: &Stats) -> Result<()> {
+        let base_path = Path::new(self.location.path());
         debug!("Removing extraneous files in '{:?}'", base_path);
+        remove_orphans(base_path, &self.orphans(exclude).await?, stats).await?;
+        prune_empty_dirs(base_path, exclude)
+    }
+
+    /// Describe what is published in the mirror, from its metadata.
+    pub async fn published(&self) -> Result<Published> {
+        let metadata = self.metadata(Path::new(self.location.path())).await?;
+        let files = metadata.files();
+        Ok(Published {
+            revision: self.repo.revision,
+            packages: files.len() as u64,
+            bytes: files.iter().map(|(_, size, _)| size).sum(),
+        })
+    }
+
+    /// List every file in the mirror with its SHA-256 digest, other than those below an excluded
+    /// path.
+    ///
+    /// Packages are only hashed if their metadata doesn't list a SHA-256 checksum for their size.
+    pub async fn manifest(&self, exclude: &[Pattern]) -> Result<Vec<manifest::Entry>> {
+        let base_path = Path::new(self.location.path());
+        let metadata = self.metadata(base_path).await?;
+        let known: HashMap<&str, (u64, &str)> = metadata
+            .files()
+            .into_iter()
+            .filter(|(_, _, checksum)| checksum.algorithm() == "sha256")
+            .map(|(file, size, checksum)| (file, (size, checksum.digest())))
+            .collect();
+
+        let mut entries = Vec::new();
+        for entry in walk(base_path, exclude) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let rel_path = entry.path().strip_prefix(base_path)?.to_string_lossy();
+            let size = entry.metadata()?.len();
+            let sha256 = match known.get(rel_path.as_ref()) {
+                Some((known_size, digest)) if *known_size == size => digest.to_string(),
+                _ => digest_file(entry.path(), "sha256").await?.1,
+            };
+            entries.push(manifest::Entry {
+                path: rel_path.into_owned(),
+                size,
+                sha256,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Find every file in the mirror that isn't referenced by its metadata, other than those
+    /// below an excluded path.
+    pub async fn orphans(&self, exclude: &[Pattern]) -> Result<Vec<Orphan>> {
+        let base_path = Path::new(self.location.path());
+        let metadata = self.metadata(base_path).await?;
+        let prestodelta = self.prestodelta(base_path).await?;
 
         let mut files: HashSet<_> = self.repo.meta_files().into_iter().map(Path::new).collect();
+        files.extend(EXTRA_FILES.iter().map(Path::new));
+
+        let retained = retained_metadata(base_path).await?;
+        files.extend(retained.iter().map(Path::new));
+
+        let tree = TreeInfo::local(base_path).await?;
+        if let Some((name, tree)) = &tree {
+            files.insert(Path::new(name));
+            files.extend(tree.files().map(Path::new));
+        }
 
         let package_files = metadata.files();
 
@@ -118,88 +456,697 @@ impl Mirror {
             }
         }
 
-        for entry in WalkDir::new(base_path) {
-            let file = entry?;
-            let rel_path = file.path().strip_prefix(base_path)?;
-            debug!("Found '{:?}'", rel_path);
-            if !file.file_type().is_dir() && !files.contains(&rel_path) {
-                let path = base_path.join(rel_path);
-                info!("Removing '{:?}'", path);
-                remove_file(&path).await?;
+        unreferenced(base_path, &files, exclude)
+    }
+}
+
+/// Find every file below a destination that isn't referenced, other than those below an excluded
+/// path.
+fn unreferenced(
+    base_path: &Path,
+    files: &HashSet<&Path>,
+    exclude: &[Pattern],
+) -> Result<Vec<Orphan>> {
+    let mut orphans = Vec::new();
+    for entry in walk(base_path, exclude) {
+        let file = entry?;
+        let rel_path = file.path().strip_prefix(base_path)?;
+        debug!("Found '{:?}'", rel_path);
+        if !file.file_type().is_dir() && !files.contains(&rel_path) {
+            orphans.push(Orphan {
+                path: rel_path.to_owned(),
+                size: file.metadata().map(|m| m.len()).unwrap_or(0),
+            });
+        }
+    }
+    Ok(orphans)
+}
+
+/// Remove orphaned files from a destination, counting them in `stats`.
+pub async fn remove_orphans(base_path: &Path, orphans: &[Orphan], stats: &Stats) -> Result<()> {
+    for orphan in orphans {
+        let path = base_path.join(&orphan.path);
+        Event::new("remove")
+            .file(orphan.path.display())
+            .log(|| info!("Removing '{:?}'", path));
+        audit::remove_file(&path).await?;
+        stats.removed(&orphan.path.to_string_lossy(), orphan.size);
+    }
+    Ok(())
+}
+
+/// A file in a mirror that isn't referenced by its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    /// The path of the file, relative to the mirror.
+    pub path: PathBuf,
+    /// The size of the file in bytes.
+    pub size: u64,
+}
+
+/// Walk every file and directory in a destination that may be cleaned.
+///
+/// The staging directory and paths matching an exclusion are skipped along with their contents.
+pub(crate) fn walk<'a>(
+    base_path: &'a Path,
+    exclude: &'a [Pattern],
+) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + 'a {
+    let staging = base_path.join(STAGING_DIR);
+    WalkDir::new(base_path)
+        .into_iter()
+        .filter_entry(move |entry| {
+            if entry.depth() == 0 {
+                return true;
             }
+            let rel_path = entry.path().strip_prefix(base_path).unwrap_or(entry.path());
+            entry.path() != staging && !exclude.iter().any(|pattern| pattern.matches_path(rel_path))
+        })
+}
+
+/// Make a destination a replica of another, linking files into place where possible.
+///
+/// Packages are put in place before the metadata that lists them and files that are no longer
+/// needed are removed last, so the replica can be used throughout. Metadata is always replaced,
+/// while other files are only replaced if their size differs.
+pub async fn replicate(src: &Path, dest: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in walk(src, &[]) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            files.push(entry.path().strip_prefix(src)?.to_owned());
         }
+    }
 
-        Ok(())
+    // Publish packages first, then metadata with the index last
+    let is_metadata =
+        |file: &Path| file.starts_with(MD_DIR) || EXTRA_FILES.iter().any(|f| file == Path::new(f));
+    files.sort_by_key(|file| (is_metadata(file), file == Path::new(MD_PATH)));
+    for file in &files {
+        let (from, to) = (src.join(file), dest.join(file));
+        if !is_metadata(file)
+            && to.exists()
+            && metadata(&from).await?.len() == metadata(&to).await?.len()
+        {
+            continue;
+        }
+        debug!("Replicating {:?}", file);
+        create_dir_all(to.parent().expect("Invalid repository structure")).await?;
+        publish(&from, &to).await?;
+    }
+
+    let files: HashSet<_> = files.iter().map(PathBuf::as_path).collect();
+    for entry in walk(dest, &[]) {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(dest)?;
+        if !entry.file_type().is_dir() && !files.contains(rel_path) {
+            debug!("Removing {:?} from replica", rel_path);
+            audit::remove_file(entry.path()).await?;
+        }
+    }
+    prune_empty_dirs(dest, &[])
+}
+
+/// Remove every directory below a destination that is left empty.
+pub fn prune_empty_dirs(base_path: &Path, exclude: &[Pattern]) -> Result<()> {
+    let mut dirs = Vec::new();
+    for entry in walk(base_path, exclude).skip(1) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            dirs.push(entry.into_path());
+        }
+    }
+
+    // Every directory is listed before its contents, so visit them in reverse to empty the
+    // innermost directories first.
+    for dir in dirs.iter().rev() {
+        if std::fs::read_dir(dir)?.next().is_none() {
+            debug!("Removing empty directory {:?}", dir);
+            std::fs::remove_dir(dir)?;
+        }
     }
+    Ok(())
+}
+
+/// Download the metadata index of a remote repository.
+async fn fetch_repomd(client: &Client, url: &str) -> Result<String> {
+    let md_url = href::base(url)?.join(MD_PATH)?;
+    debug!("Loading remote metadata from '{}'", md_url);
+    Ok(client
+        .get(md_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?)
 }
 
 pub struct Cache {
     mirror: Mirror,
-    dir: TempDir,
+    dir: CacheDir,
+}
+
+/// Where metadata is cached.
+enum CacheDir {
+    /// A directory removed once synchronisation finishes.
+    Temporary(TempDir),
+    /// A directory kept between runs.
+    Persistent(PathBuf),
+}
+
+impl CacheDir {
+    fn path(&self) -> &Path {
+        match self {
+            CacheDir::Temporary(dir) => dir.path(),
+            CacheDir::Persistent(path) => path,
+        }
+    }
 }
 
 impl Cache {
-    async fn new(client: &Client, mirror: Mirror) -> Result<Cache> {
-        let cache_dir = TempDir::new(env!("CARGO_PKG_NAME"))?;
-        debug!("Caching metadata in {}", cache_dir.path().to_str().unwrap());
+    #[instrument(name = "metadata", skip_all)]
+    async fn new(
+        client: &Client,
+        mirror: Mirror,
+        dir: Option<&Path>,
+        staging: &Path,
+        stats: &Stats,
+    ) -> Result<Cache> {
+        let cache_dir = match dir {
+            Some(path) => {
+                mirror.repo.prune_cache(path).await?;
+                CacheDir::Persistent(path.to_owned())
+            }
+            None => {
+                create_dir_all(staging).await?;
+                CacheDir::Temporary(TempDir::new_in(staging, "metadata")?)
+            }
+        };
+        debug!("Caching metadata in {:?}", cache_dir.path());
+        if let Some(minimum) = &mirror.minimum_digest {
+            let checksums = mirror
+                .repo
+                .data
+                .iter()
+                .flat_map(|datum| datum.checksum.iter().chain(&datum.open_checksum));
+            minimum.check("metadata files", checksums.map(Checksum::algorithm))?;
+        }
         mirror
             .repo
-            .download_meta(client, &mirror.location, cache_dir.path())
+            .download_meta(
+                client,
+                &mirror.location,
+                cache_dir.path(),
+                &Tracker::metadata(stats),
+            )
             .await?;
+        if let Some(verification) = &mirror.verification {
+            let md_path = cache_dir.path().join(MD_PATH);
+            let signature = cache_dir.path().join(MD_DIR).join("repomd.xml.asc");
+            verification.verify(client, &md_path, &signature).await?;
+        }
+
+        if !mirror.excluded.is_empty() {
+            let md_path = cache_dir.path().join(MD_PATH);
+            let repomd = read_to_string(&md_path).await?;
+            write(&md_path, without_metadata(&repomd, &mirror.excluded)).await?;
+            // The upstream signature of the index no longer matches it
+            let signature = cache_dir.path().join(MD_DIR).join("repomd.xml.asc");
+            if let Some(signing) = &mirror.signing {
+                signing.sign(&md_path).await?;
+            } else if signature.exists() {
+                remove_file(signature).await?;
+            }
+        }
 
         Ok(Cache {
-            mirror: mirror,
+            mirror,
             dir: cache_dir,
         })
     }
 
-    pub async fn clone(&self, client: &Client, dest: &Path, check: CheckType) -> Result<()> {
+    /// Compare the packages in the new metadata with those of the current mirror, if any.
+    ///
+    /// Updated packages are given their upstream changelog entries when the repository has
+    /// `other` metadata that can be read. The changelog entries are only informational, so the
+    /// changes are still described without them if it can't.
+    pub async fn changes_since(&self, local: Option<&Mirror>) -> Result<Changelog> {
+        let old = match local {
+            Some(local) => local.metadata(Path::new(local.location.path())).await?,
+            None => Metadata::default(),
+        };
+        let mut changelog = self.metadata(self.dir.path()).await?.changes_since(&old);
+        if changelog.updated.is_empty() {
+            return Ok(changelog);
+        }
+
+        if let Some(path) = self.repo.subsection_path("other") {
+            let new = match Other::load(&self.dir.path().join(path)).await {
+                Ok(new) => new,
+                Err(e) => {
+                    warn!(
+                        "Could not read the changelogs of '{}': {}",
+                        self.location, e
+                    );
+                    return Ok(changelog);
+                }
+            };
+            let old = match local.and_then(|local| {
+                let path = local.repo.subsection_path("other")?;
+                Some(Path::new(local.location.path()).join(path))
+            }) {
+                // The previous changelogs only limit which entries are new
+                Some(path) => Other::load(&path).await.ok(),
+                None => None,
+            };
+            changelog.annotate(&new, old.as_ref());
+        }
+        Ok(changelog)
+    }
+
+    /// Synchronise packages to a destination then publish the new metadata.
+    ///
+    /// The files of the last `retain` generations of metadata are kept.
+    pub async fn clone(
+        &self,
+        client: &Client,
+        dest: &Path,
+        check: CheckType,
+        retain: usize,
+        stats: &Stats,
+    ) -> Result<()> {
+        self.download(client, dest, check, stats).await?;
+        self.replace_metadata(dest, retain).await
+    }
+
+    /// Synchronise packages to a destination without publishing the new metadata.
+    pub async fn download(
+        &self,
+        client: &Client,
+        dest: &Path,
+        check: CheckType,
+        stats: &Stats,
+    ) -> Result<()> {
+        let src = &self.sources();
+        let vetting = &self.mirror.vetting;
+        let on_missing = self.mirror.on_missing;
+        let packages = self.metadata(self.dir.path()).await?;
+        let deltas = self.prestodelta(self.dir.path()).await?;
+        if let Some(minimum) = &self.mirror.minimum_digest {
+            let checksums = packages.files().into_iter().map(|(_, _, c)| c.algorithm());
+            minimum.check("packages", checksums)?;
+            if let Some(deltas) = &deltas {
+                let checksums = deltas.files().into_iter().map(|(_, _, c)| c.algorithm());
+                minimum.check("deltas", checksums)?;
+            }
+        }
+        let packages = match &self.mirror.wanted {
+            Some(wanted) => {
+                let (wanted, rest) = packages.partition(wanted);
+                info!("Downloading {} wanted packages first", wanted.files().len());
+                on_missing
+                    .tolerate(sync_all(client, &wanted, src, dest, check, stats, vetting).await)?;
+                rest
+            }
+            None => packages,
+        };
+        if self.mirror.restrict {
+            return Ok(());
+        }
+        on_missing.tolerate(sync_all(client, &packages, src, dest, check, stats, vetting).await)?;
+        if let Some(deltas) = deltas {
+            on_missing
+                .tolerate(sync_all(client, &deltas, src, dest, check, stats, vetting).await)?;
+        }
+        Ok(())
+    }
+
+    /// Download only the packages matching any of the patterns, then publish the new metadata.
+    ///
+    /// Returns the number of matching packages. The new metadata is only published if every
+    /// other package and delta it lists is already in the destination, as clients would otherwise
+    /// be pointed at files that aren't there. The packages downloaded are kept either way, for
+    /// the next synchronisation to publish.
+    pub async fn fetch(
+        &self,
+        client: &Client,
+        patterns: &[Pattern],
+        dest: &Path,
+        check: CheckType,
+        retain: usize,
+        stats: &Stats,
+    ) -> Result<usize> {
+        let matching = self.metadata(self.dir.path()).await?.matching(patterns);
+        let count = matching.files().len();
+        if count == 0 {
+            return Ok(0);
+        }
+        if let Some(minimum) = &self.mirror.minimum_digest {
+            let checksums = matching.files().into_iter().map(|(_, _, c)| c.algorithm());
+            minimum.check("packages", checksums)?;
+        }
+        let src = &self.sources();
+        let vetting = &self.mirror.vetting;
+        self.mirror
+            .on_missing
+            .tolerate(sync_all(client, &matching, src, dest, check, stats, vetting).await)?;
+
+        let packages = self.metadata(self.dir.path()).await?;
+        let deltas = self.prestodelta(self.dir.path()).await?;
+        let mut missing = 0;
+        for (file, size, _) in packages
+            .files()
+            .into_iter()
+            .chain(deltas.iter().flat_map(|deltas| deltas.files()))
+        {
+            let present = metadata(href::local(dest, file)?)
+                .await
+                .is_ok_and(|local| local.len() == size);
+            if !present {
+                debug!("'{}' is not in the destination yet", file);
+                missing += 1;
+            }
+        }
+        if missing > 0 {
+            bail!(
+                "Not publishing the new metadata of {:?}, as {} other files it lists haven't been \
+                 downloaded; synchronise the repository to publish it",
+                dest,
+                missing
+            );
+        }
+        self.replace_metadata(dest, retain).await?;
+        Ok(count)
+    }
+
+    /// Probe every package and delta upstream, returning those that can't be retrieved.
+    pub async fn check_links(&self, client: &Client) -> Result<Vec<Broken>> {
+        let base = &self.mirror.location;
         let packages = self.metadata(self.dir.path()).await?;
-        sync_all(client, &packages, &self.mirror.location, dest, check).await?;
+        let mut broken = links::check(client, &packages, base).await?;
         if let Some(deltas) = self.prestodelta(self.dir.path()).await? {
-            sync_all(client, &deltas, &self.mirror.location, dest, check).await?;
+            broken.extend(links::check(client, &deltas, base).await?);
         }
-        self.replace_metadata(dest).await
+        Ok(broken)
     }
 
-    async fn replace_metadata(&self, dest: &Path) -> Result<()> {
+    /// Measure how quickly the upstream and each of its fallbacks serve a sample of the
+    /// packages, fastest first.
+    pub async fn bench(
+        &self,
+        client: &Client,
+        files: usize,
+        chunk: Option<u64>,
+    ) -> Result<Vec<Measurement>> {
+        let packages = self.metadata(self.dir.path()).await?;
+        let sample = bench::sample(
+            packages
+                .files()
+                .into_iter()
+                .map(|(file, size, _)| (file, size)),
+            files,
+        );
+        let mut measurements = Vec::new();
+        for url in self.sources().get() {
+            info!("Measuring '{}'", url);
+            measurements.push(bench::measure(client, &url, &sample, chunk).await);
+        }
+        bench::rank(&mut measurements);
+        Ok(measurements)
+    }
+
+    /// The upstream and its fallbacks, to download packages from.
+    fn sources(&self) -> Sources {
+        let mut urls = vec![self.mirror.location.clone()];
+        urls.extend(self.mirror.fallbacks.iter().cloned());
+        match self.mirror.ranking {
+            Some(interval) => Sources::ranked(urls, interval),
+            None => Sources::from(urls),
+        }
+    }
+
+    /// Plan the changes synchronising to a destination would make, verifying its files.
+    ///
+    /// Returns the packages to download and the files to remove. The metadata isn't included, as
+    /// it is always published from the cache. If `lazy` is set, no packages are downloaded.
+    pub async fn plan(
+        &self,
+        dest: &Path,
+        check: CheckType,
+        lazy: bool,
+        exclude: &[Pattern],
+    ) -> Result<(Vec<Download>, Vec<Orphan>)> {
+        let metadata = self.metadata(self.dir.path()).await?;
+        let deltas = self.prestodelta(self.dir.path()).await?;
+
+        let mut files: HashSet<&Path> = self.repo.meta_files().into_iter().map(Path::new).collect();
+        files.extend(EXTRA_FILES.iter().map(Path::new));
+        let retained = retained_metadata(dest).await?;
+        files.extend(retained.iter().map(Path::new));
+        let tree = TreeInfo::local(dest).await?;
+        if let Some((name, tree)) = &tree {
+            files.insert(Path::new(name));
+            files.extend(tree.files().map(Path::new));
+        }
+        let packages = metadata.files();
+        let delta_files = deltas.as_ref().map(Fetch::files).unwrap_or_default();
+        files.extend(packages.iter().map(|(file, _, _)| Path::new(*file)));
+        files.extend(delta_files.iter().map(|(file, _, _)| Path::new(*file)));
+        // Old metadata is removed when the new metadata is published
+        let removals = unreferenced(dest, &files, exclude)?
+            .into_iter()
+            .filter(|orphan| !orphan.path.starts_with(MD_DIR))
+            .collect();
+
+        // Restricted mirrors only download the wanted packages and what they depend on
+        let restricted: Option<HashSet<String>> = match (&self.mirror.wanted, self.mirror.restrict)
+        {
+            (Some(wanted), true) => {
+                let (wanted, _) = self.metadata(self.dir.path()).await?.partition(wanted);
+                Some(
+                    wanted
+                        .files()
+                        .into_iter()
+                        .map(|(file, _, _)| file.to_owned())
+                        .collect(),
+                )
+            }
+            _ => None,
+        };
+        let mut downloads = Vec::new();
+        for (file, size, checksum) in packages.iter().chain(&delta_files) {
+            if lazy
+                || restricted
+                    .as_ref()
+                    .is_some_and(|wanted| !wanted.contains(*file))
+            {
+                continue;
+            }
+            let local_path = href::local(dest, file)?;
+            if local_matches(&local_path, check.check(*size, checksum)).await? != Some(true) {
+                downloads.push(Download {
+                    path: file.to_string(),
+                    size: *size,
+                    checksum: checksum.to_string(),
+                });
+            }
+        }
+        Ok((downloads, removals))
+    }
+
+    /// Download the planned packages to a destination, then publish the new metadata.
+    ///
+    /// The files of the last `retain` generations of metadata are kept.
+    pub async fn apply(
+        &self,
+        client: &Client,
+        planned: &Planned,
+        dest: &Path,
+        check: CheckType,
+        retain: usize,
+        stats: &Stats,
+    ) -> Result<()> {
+        let src = &self.sources();
+        let vetting = &self.mirror.vetting;
+        self.mirror
+            .on_missing
+            .tolerate(sync_all(client, planned, src, dest, check, stats, vetting).await)?;
+        self.replace_metadata(dest, retain).await
+    }
+
+    /// Publish the new metadata to a destination without synchronising any packages.
+    ///
+    /// The files of the last `retain` generations of metadata are kept.
+    #[instrument(name = "publish", skip_all)]
+    pub async fn replace_metadata(&self, dest: &Path, retain: usize) -> Result<()> {
         let target_meta_dir = dest.join(MD_DIR);
         let cache_meta_dir = self.dir.path().join(MD_DIR);
 
         if target_meta_dir.exists() {
             debug!("Replacing existing metadata in {:?}", target_meta_dir);
-            // Delete existing metadata
+            record_generation(dest, retain).await?;
+            let retained = retained_metadata(dest).await?;
+
+            // Delete existing metadata, other than that of retained generations
             let mut entries = read_dir(&target_meta_dir).await?;
             while let Some(entry) = entries.next_entry().await? {
                 let path = entry.path();
+                let rel_path = path.strip_prefix(dest)?;
+                if rel_path.to_str().is_some_and(|p| retained.contains(p)) {
+                    debug!("Retaining {:?}", path);
+                    continue;
+                }
                 debug!("Deleting {:?}", path);
-                remove_file(path).await?;
+                audit::remove_file(&path).await?;
             }
         } else {
             debug!("Copying metadata to {:?}", target_meta_dir);
             create_dir_all(&target_meta_dir).await?;
         }
 
-        // Copy new metadata
+        // Link new metadata into place, copying it if the cache is on another filesystem
         let mut entries = read_dir(&cache_meta_dir).await?;
         while let Some(entry) = entries.next_entry().await? {
             let src = entry.path();
-            let dest = target_meta_dir.join(src.file_name().unwrap());
-            debug!("Copying {:?} to {:?}", src, dest);
-            let mut src = File::open(src).await?;
-            let mut dest = OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(dest)
-                .await?;
-            copy(&mut src, &mut dest).await?;
+            publish(&src, &target_meta_dir.join(src.file_name().unwrap())).await?;
+        }
+
+        // Files outside of the metadata directory
+        for file in EXTRA_FILES.iter().filter(|f| !f.starts_with(MD_DIR)) {
+            let src = self.dir.path().join(file);
+            if src.exists() {
+                let dest = dest.join(file);
+                create_dir_all(dest.parent().expect("Invalid repository structure")).await?;
+                publish(&src, &dest).await?;
+            }
         }
 
         Ok(())
     }
 }
 
+/// Remove the entries for types of metadata from a metadata index.
+fn without_metadata(repomd: &str, types: &[String]) -> String {
+    let data = Regex::new(r#"(?s)[ \t]*<data\s+type="(?P<type>[^"]*)".*?</data>\s*?\n?"#).unwrap();
+    data.replace_all(repomd, |caps: &Captures| {
+        if types.iter().any(|t| *t == caps["type"]) {
+            String::new()
+        } else {
+            caps[0].to_owned()
+        }
+    })
+    .into_owned()
+}
+
+/// Put a cached file in place, linking it if possible and copying it otherwise.
+pub(crate) async fn publish(src: &Path, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        remove_file(dest).await?;
+    }
+    if hard_link(src, dest).await.is_ok() {
+        debug!("Linked {:?} to {:?}", src, dest);
+        return Ok(());
+    }
+    debug!("Copying {:?} to {:?}", src, dest);
+    let mut src = File::open(src).await?;
+    let mut dest = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(dest)
+        .await?;
+    copy(&mut src, &mut dest).await?;
+    Ok(())
+}
+
+/// Download a file the repository may not have, returning whether it exists.
+///
+/// Any copy from an earlier download is removed if the file no longer exists.
+async fn fetch_extra(
+    client: &Client,
+    relative: &str,
+    src: &Url,
+    dest: &Path,
+    tracker: &Tracker,
+) -> Result<bool> {
+    let local_path = href::local(dest, relative)?;
+    let remote_path = href::join(src, relative)?;
+    let response = client.get(remote_path.clone()).send().await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        if local_path.exists() {
+            audit::remove_file(&local_path).await?;
+        }
+        return Ok(false);
+    }
+
+    let record = audit::Record::new("download", &local_path).source(&remote_path);
+    let result: Result<u64> = async {
+        let contents = response.error_for_status()?.bytes().await?;
+        tracker.transferred(contents.len() as u64);
+        tracker.finish(0);
+        create_dir_all(local_path.parent().expect("Invalid repository structure")).await?;
+        tokio::fs::write(&local_path, &contents).await?;
+        Ok(contents.len() as u64)
+    }
+    .await;
+    match &result {
+        Ok(bytes) => record.bytes(*bytes).write(),
+        Err(e) => record.error(e).write(),
+    }
+    result.map(|_| true)
+}
+
+/// Keep the index of the metadata currently published in a destination as a generation, then
+/// forget all but the last `retain` generations.
+async fn record_generation(dest: &Path, retain: usize) -> Result<()> {
+    let generations_dir = dest.join(GENERATIONS_DIR);
+    let current = dest.join(MD_PATH);
+    if retain > 0 && current.exists() {
+        create_dir_all(&generations_dir).await?;
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let record = generations_dir.join(format!("{:020}.xml", since_epoch.as_nanos()));
+        debug!("Recording metadata generation {:?}", record);
+        copy(
+            &mut File::open(&current).await?,
+            &mut File::create(record).await?,
+        )
+        .await?;
+    }
+    if !generations_dir.exists() {
+        return Ok(());
+    }
+
+    let mut records = Vec::new();
+    let mut entries = read_dir(&generations_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        records.push(entry.path());
+    }
+    records.sort();
+    let expired = records.len().saturating_sub(retain);
+    for record in &records[..expired] {
+        debug!("Forgetting metadata generation {:?}", record);
+        remove_file(record).await?;
+    }
+    Ok(())
+}
+
+/// The metadata files of every retained generation in a destination, relative to it.
+async fn retained_metadata(dest: &Path) -> Result<HashSet<String>> {
+    let generations_dir = dest.join(GENERATIONS_DIR);
+    let mut retained = HashSet::new();
+    if !generations_dir.exists() {
+        return Ok(retained);
+    }
+
+    let mut entries = read_dir(&generations_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let repo = Repo::decode(&mut File::open(entry.path()).await?).await?;
+        for file in repo.meta_files().into_iter().filter(|f| *f != MD_PATH) {
+            retained.insert(file.to_owned());
+        }
+    }
+    Ok(retained)
+}
+
 impl Deref for Cache {
     type Target = Mirror;
 
@@ -218,11 +1165,15 @@ pub struct Repo {
 }
 
 impl PartialEq for Repo {
+    /// Repositories are the same version if they have the same revision.
+    ///
+    /// Without a revision on both sides, they are the same version if they list the same metadata
+    /// files, provided every file is identified by its checksum or timestamp.
     fn eq(&self, other: &Self) -> bool {
         if let (Some(this), Some(that)) = (self.revision, other.revision) {
             this == that
         } else {
-            false
+            self.identifiable() && other.identifiable() && self.entries() == other.entries()
         }
     }
 }
@@ -232,6 +1183,48 @@ struct Data {
     #[serde(rename = "type")]
     datum: String,
     location: Location,
+    #[serde(default)]
+    checksum: Option<Checksum>,
+    #[serde(rename = "open-checksum", default)]
+    open_checksum: Option<Checksum>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(rename = "open-size", default)]
+    open_size: Option<u64>,
+}
+
+impl Data {
+    /// Download a metadata file and verify it against the repository index.
+    async fn download(
+        &self,
+        client: &Client,
+        src: &Url,
+        dest: &Path,
+        tracker: &Tracker,
+    ) -> Result<()> {
+        let href = self.location.href.as_str();
+        let check = match (self.size, &self.checksum) {
+            (Some(size), Some(checksum)) => Check::Hash(size, checksum),
+            (Some(size), None) => Check::RemoteSize(size),
+            _ => Check::Metadata,
+        };
+        sync_file(client, href, src, dest, check, Some(tracker), None).await?;
+        tracker.finish(0);
+
+        if let Some(open_checksum) = &self.open_checksum {
+            let path = dest.join(href);
+            debug!("Verifying decompressed checksum of {:?}", path);
+            match open_checksum.check_open(&path, self.open_size).await? {
+                Some(true) => {}
+                Some(false) => bail!("Metadata failed decompressed checksum {:?}", path),
+                None => debug!("Can't decompress {:?} to verify it", path),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
@@ -273,12 +1266,28 @@ impl Repo {
         Ok(xml::from_str(&text)?)
     }
 
+    /// Whether every metadata file can be told apart from another version of itself.
+    fn identifiable(&self) -> bool {
+        !self.data.is_empty()
+            && self
+                .data
+                .iter()
+                .all(|datum| datum.checksum.is_some() || datum.timestamp.is_some())
+    }
+
+    /// The metadata files in a consistent order.
+    fn entries(&self) -> Vec<&Data> {
+        let mut entries: Vec<&Data> = self.data.iter().collect();
+        entries.sort_by(|a, b| (&a.datum, &a.location.href).cmp(&(&b.datum, &b.location.href)));
+        entries
+    }
+
     /// Returns a list of paths for metadata files to sync.
     pub fn meta_files(&self) -> Vec<&str> {
         let mut files = vec![MD_PATH];
         let mut decoded = self.data.iter().map(|d| d.location.href.as_str()).collect();
         files.append(&mut decoded);
-        return files;
+        files
     }
 
     /// Returns the relative path of the primary data file.
@@ -288,8 +1297,11 @@ impl Repo {
     }
 
     /// Returns the relative path of the prestodelta data file.
+    ///
+    /// SUSE repositories call this `deltainfo`.
     pub fn prestodelta_path(&self) -> Option<PathBuf> {
         self.subsection_path("prestodelta")
+            .or_else(|| self.subsection_path("deltainfo"))
     }
 
     /// Get the path of a repository subsection.
@@ -305,10 +1317,66 @@ impl Repo {
         None
     }
 
+    /// Remove every file from a persistent metadata cache that can't be reused for this version.
+    ///
+    /// Only files listed with a checksum are kept, as they are verified before they are reused.
+    /// The index itself is always removed so that it is downloaded again.
+    async fn prune_cache(&self, dir: &Path) -> Result<()> {
+        let cache_meta_dir = dir.join(MD_DIR);
+        if !cache_meta_dir.exists() {
+            return Ok(());
+        }
+
+        let reusable: HashSet<PathBuf> = self
+            .data
+            .iter()
+            .filter(|datum| datum.checksum.is_some())
+            .map(|datum| dir.join(&datum.location.href))
+            .collect();
+        let mut entries = read_dir(&cache_meta_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            // Only cached files are pruned; anything nested is left alone
+            if entry.file_type().await?.is_dir() {
+                continue;
+            }
+            if !reusable.contains(&path) {
+                debug!("Removing stale cached metadata {:?}", path);
+                remove_file(path).await?;
+            }
+        }
+        Ok(())
+    }
+
     /// Download the contents of a repo to a given path.
-    async fn download_meta(&self, client: &Client, src: &Url, dest: &Path) -> Result<()> {
-        for file in self.meta_files() {
-            sync_file(client, file, src, dest, Check::Metadata).await?;
+    async fn download_meta(
+        &self,
+        client: &Client,
+        src: &Url,
+        dest: &Path,
+        tracker: &Tracker,
+    ) -> Result<()> {
+        sync_file(
+            client,
+            MD_PATH,
+            src,
+            dest,
+            Check::Metadata,
+            Some(tracker),
+            None,
+        )
+        .await?;
+        tracker.finish(0);
+        for datum in &self.data {
+            datum.download(client, src, dest, tracker).await?;
+        }
+        // The extra files are optional, so failing to fetch one doesn't fail the repository
+        for file in EXTRA_FILES {
+            match fetch_extra(client, file, src, dest, tracker).await {
+                Ok(true) => debug!("Downloaded optional file '{}'", file),
+                Ok(false) => {}
+                Err(e) => warn!("Could not download optional file '{}': {}", file, e),
+            }
         }
         Ok(())
     }
@@ -323,17 +1391,320 @@ mod test {
 
     #[tokio::test]
     async fn check_dissimilar() {
-        let local = Repo::decode(&mut LOCAL_REPOMD).await.unwrap();
-        let remote = Repo::decode(&mut REMOTE_REPOMD).await.unwrap();
+        let local = Repo::decode(&mut &LOCAL_REPOMD[..]).await.unwrap();
+        let remote = Repo::decode(&mut &REMOTE_REPOMD[..]).await.unwrap();
 
         println!("{:?}\n{:?}", local, remote);
 
         assert_ne!(local, remote);
     }
 
+    #[tokio::test]
+    async fn compare_without_revision() {
+        let repomd = |revision: &str, entries: &[(&str, &str)]| {
+            let mut xml = format!("<repomd>{}", revision);
+            for (datum, sum) in entries {
+                xml += &format!(
+                    "<data type=\"{0}\"><checksum type=\"sha256\">{1}</checksum>\
+                     <location href=\"repodata/{1}-{0}.xml.gz\"/></data>",
+                    datum, sum
+                );
+            }
+            xml + "</repomd>"
+        };
+        let decode = |xml: String| async move { Repo::decode(&mut xml.as_bytes()).await.unwrap() };
+
+        let old = decode(repomd("", &[("primary", "aa"), ("other", "bb")])).await;
+        let same = decode(repomd("", &[("other", "bb"), ("primary", "aa")])).await;
+        let new = decode(repomd("", &[("primary", "cc"), ("other", "bb")])).await;
+        let revised = decode(repomd(
+            "<revision>1</revision>",
+            &[("primary", "aa"), ("other", "bb")],
+        ))
+        .await;
+        let unsummed = "<repomd><data type=\"primary\">\
+                        <location href=\"repodata/primary.xml.gz\"/></data></repomd>";
+
+        assert_eq!(old, same);
+        assert_ne!(old, new);
+        assert_eq!(old, revised);
+
+        // Without anything identifying its files, an index isn't the same as even a copy of itself
+        let empty = decode(repomd("", &[])).await;
+        assert!(!empty.identifiable());
+        assert_ne!(empty, decode(repomd("", &[])).await);
+        let unidentified = decode(unsummed.to_owned()).await;
+        assert!(!unidentified.identifiable());
+        assert_ne!(unidentified, decode(unsummed.to_owned()).await);
+    }
+
+    #[tokio::test]
+    async fn retain_generations() {
+        let dir = TempDir::new("generations").unwrap();
+        std::fs::create_dir_all(dir.path().join(MD_DIR)).unwrap();
+        for repomd in &[LOCAL_REPOMD, REMOTE_REPOMD, LOCAL_REPOMD] {
+            std::fs::write(dir.path().join(MD_PATH), repomd).unwrap();
+            record_generation(dir.path(), 2).await.unwrap();
+        }
+        let records = std::fs::read_dir(dir.path().join(GENERATIONS_DIR)).unwrap();
+        assert_eq!(records.count(), 2);
+
+        let local = Repo::decode(&mut &LOCAL_REPOMD[..]).await.unwrap();
+        let remote = Repo::decode(&mut &REMOTE_REPOMD[..]).await.unwrap();
+        let retained = retained_metadata(dir.path()).await.unwrap();
+        assert!(retained.contains(local.primary_path().unwrap().to_str().unwrap()));
+        assert!(retained.contains(remote.primary_path().unwrap().to_str().unwrap()));
+        assert!(!retained.contains(MD_PATH));
+
+        record_generation(dir.path(), 0).await.unwrap();
+        assert!(retained_metadata(dir.path()).await.unwrap().is_empty());
+    }
+
+    /// Create a mirror with a single package and a stale metadata file.
+    fn local_mirror() -> TempDir {
+        let dir = TempDir::new("mirror").unwrap();
+        let write = |file: &str, contents: &str| {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(
+            MD_PATH,
+            "<?xml version=\"1.0\"?><repomd><data type=\"primary\">\
+             <location href=\"repodata/primary.xml\"/></data></repomd>",
+        );
+        write(
+            "repodata/primary.xml",
+            "<?xml version=\"1.0\"?><metadata><package><name>a</name>\
+             <version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+             <checksum type=\"sha256\">00</checksum>\
+             <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+             <location href=\"Packages/a/a-1-1.rpm\"/></package></metadata>",
+        );
+        write("Packages/a/a-1-1.rpm", "a");
+        write("repodata/old-primary.xml", "");
+        dir
+    }
+
+    #[tokio::test]
+    async fn replicate_mirror() {
+        let src = local_mirror();
+        let dest = TempDir::new("replica").unwrap();
+        for dir in &["Packages/a", "Packages/z"] {
+            std::fs::create_dir_all(dest.path().join(dir)).unwrap();
+        }
+        std::fs::write(dest.path().join("Packages/z/z-1-1.rpm"), "z").unwrap();
+        std::fs::write(dest.path().join("Packages/a/a-1-1.rpm"), "stale").unwrap();
+
+        replicate(src.path(), dest.path()).await.unwrap();
+        for file in &[MD_PATH, "repodata/primary.xml", "Packages/a/a-1-1.rpm"] {
+            assert_eq!(
+                std::fs::read(src.path().join(file)).unwrap(),
+                std::fs::read(dest.path().join(file)).unwrap()
+            );
+        }
+        assert!(!dest.path().join("Packages/z").exists());
+    }
+
+    #[tokio::test]
+    async fn exclude_from_clean() {
+        let dir = local_mirror();
+        for file in &[
+            "images/pxeboot/vmlinuz",
+            "LiveOS/squashfs.img",
+            "Packages/stray.rpm",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::create_dir_all(dir.path().join("EFI/BOOT")).unwrap();
+
+        let mirror = Mirror::local(dir.path().to_str().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let exclude = vec![
+            Pattern::new("images").unwrap(),
+            Pattern::new("LiveOS").unwrap(),
+            Pattern::new("EFI").unwrap(),
+        ];
+        mirror.clean(&exclude, &Stats::default()).await.unwrap();
+        assert!(dir.path().join("images/pxeboot/vmlinuz").exists());
+        assert!(dir.path().join("LiveOS/squashfs.img").exists());
+        assert!(dir.path().join("EFI/BOOT").exists());
+        assert!(!dir.path().join("Packages/stray.rpm").exists());
+        assert!(dir.path().join("Packages/a/a-1-1.rpm").exists());
+    }
+
+    #[tokio::test]
+    async fn find_orphans() {
+        let dir = local_mirror();
+        std::fs::create_dir_all(dir.path().join("Packages/s")).unwrap();
+        std::fs::write(dir.path().join("Packages/s/stray.rpm"), b"stray").unwrap();
+        std::fs::create_dir_all(dir.path().join(STAGING_DIR)).unwrap();
+        std::fs::write(dir.path().join(STAGING_DIR).join("partial"), b"").unwrap();
+        std::fs::create_dir_all(dir.path().join("images")).unwrap();
+        std::fs::write(dir.path().join("images/boot.iso"), b"").unwrap();
+        std::fs::create_dir_all(dir.path().join("media.1")).unwrap();
+        std::fs::write(dir.path().join("media.1/media"), b"").unwrap();
+        std::fs::write(dir.path().join("content"), b"").unwrap();
+        std::fs::write(
+            dir.path().join(".treeinfo"),
+            "[images-x86_64]\nboot.iso = images/boot.iso\n",
+        )
+        .unwrap();
+
+        let mirror = Mirror::local(dir.path().to_str().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut orphans = mirror.orphans(&[]).await.unwrap();
+        orphans.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<_> = orphans.iter().map(|o| o.path.to_str().unwrap()).collect();
+        assert_eq!(
+            paths,
+            vec!["Packages/s/stray.rpm", "repodata/old-primary.xml",]
+        );
+        assert_eq!(orphans[0].size, 5);
+    }
+
+    #[tokio::test]
+    async fn manifest_of_mirror() {
+        let dir = local_mirror();
+        std::fs::write(dir.path().join("MANIFEST.sha256"), b"").unwrap();
+        let mirror = Mirror::local(dir.path().to_str().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let exclude = [Pattern::new("MANIFEST.sha256").unwrap()];
+        let mut entries = mirror.manifest(&exclude).await.unwrap();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "Packages/a/a-1-1.rpm",
+                "repodata/old-primary.xml",
+                "repodata/primary.xml",
+                "repodata/repomd.xml"
+            ]
+        );
+        // The checksum in the metadata is trusted rather than hashing the package again
+        assert_eq!(entries[0].sha256, "00");
+        assert_eq!(
+            entries[1].sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn prune_empty() {
+        let dir = TempDir::new("prune").unwrap();
+        let base = dir.path();
+        for sub in &[
+            "Packages/a",
+            "Packages/b/c",
+            "Packages/d",
+            ".yumclone/generations",
+        ] {
+            std::fs::create_dir_all(base.join(sub)).unwrap();
+        }
+        std::fs::write(base.join("Packages/a/a.rpm"), b"").unwrap();
+
+        prune_empty_dirs(base, &[]).unwrap();
+        assert!(base.join("Packages/a/a.rpm").exists());
+        assert!(!base.join("Packages/b").exists());
+        assert!(!base.join("Packages/d").exists());
+        assert!(base.join(".yumclone/generations").exists());
+    }
+
+    #[tokio::test]
+    async fn suse_metadata() {
+        let repomd = r#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo" xmlns:rpm="http://linux.duke.edu/metadata/rpm" xmlns:suse="http://novell.com/package/metadata/suse/repo">
+  <revision>1700000000</revision>
+  <tags>
+    <repo>obsrepository://build.opensuse.org/openSUSE:Leap:15.5/standard</repo>
+    <content>binary</content>
+  </tags>
+  <data type="primary">
+    <checksum type="sha256">aa</checksum>
+    <location href="repodata/aa-primary.xml.gz"/>
+    <size>10</size>
+  </data>
+  <data type="susedata">
+    <checksum type="sha256">bb</checksum>
+    <location href="repodata/bb-susedata.xml.gz"/>
+    <size>20</size>
+  </data>
+  <data type="deltainfo">
+    <checksum type="sha256">cc</checksum>
+    <location href="repodata/cc-deltainfo.xml.gz"/>
+    <size>30</size>
+  </data>
+</repomd>"#;
+        let repo = Repo::decode(&mut repomd.as_bytes()).await.unwrap();
+
+        assert_eq!(repo.revision, Some(1700000000));
+        assert!(repo.meta_files().contains(&"repodata/bb-susedata.xml.gz"));
+        assert_eq!(
+            repo.prestodelta_path().unwrap(),
+            Path::new("repodata/cc-deltainfo.xml.gz")
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_metadata() {
+        let url = Url::parse("https://mirror.example.com/repo/").unwrap();
+        let mirror = |xml: &'static str| {
+            let url = url.clone();
+            async move { Mirror::new(Repo::decode(&mut xml.as_bytes()).await.unwrap(), url) }
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let dated = mirror(
+            "<repomd><revision>1</revision><data type=\"primary\">\
+             <timestamp>1600000000</timestamp>\
+             <location href=\"repodata/primary.xml.gz\"/></data></repomd>",
+        )
+        .await;
+        assert_eq!(
+            dated.generated(),
+            Some(UNIX_EPOCH + Duration::from_secs(1600000000))
+        );
+        let stale = dated.check_age(day).unwrap_err();
+        assert!(stale.downcast_ref::<Stale>().is_some());
+
+        let revised = mirror("<repomd><revision>1700000000</revision></repomd>").await;
+        assert_eq!(
+            revised.generated(),
+            Some(UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        let undated = mirror("<repomd><revision>1</revision></repomd>").await;
+        assert_eq!(undated.generated(), None);
+        assert!(undated.check_age(day).is_ok());
+    }
+
+    #[tokio::test]
+    async fn exclude_appstream() {
+        let repomd = "<repomd>\n  <revision>1</revision>\n  \
+            <data type=\"primary\">\n    <location href=\"repodata/primary.xml.gz\"/>\n  </data>\n  \
+            <data type=\"appstream\">\n    <location href=\"repodata/appstream.xml.gz\"/>\n  </data>\n  \
+            <data type=\"appstream-icons\">\n    <location href=\"repodata/icons.tar.gz\"/>\n  </data>\n\
+            </repomd>\n";
+        let types = vec!["appstream".to_owned(), "appstream-icons".to_owned()];
+        let filtered = without_metadata(repomd, &types);
+
+        assert!(!filtered.contains("appstream"));
+        let repo = Repo::decode(&mut filtered.as_bytes()).await.unwrap();
+        assert_eq!(repo.meta_files(), vec![MD_PATH, "repodata/primary.xml.gz"]);
+        assert_eq!(without_metadata(repomd, &[]), repomd);
+    }
+
     #[tokio::test]
     async fn metadata_list() {
-        let remote = Repo::decode(&mut LOCAL_REPOMD).await.unwrap();
+        let remote = Repo::decode(&mut &LOCAL_REPOMD[..]).await.unwrap();
         let expected = vec![
             MD_PATH,
             "repodata/84fe7bb9cf340186df02863647f41a4be32c86a21b80eaaeddaa97e99a24b7a6-primary.xml.gz",
@@ -353,9 +1724,58 @@ mod test {
 
     #[tokio::test]
     async fn primary_path() {
-        let remote = Repo::decode(&mut LOCAL_REPOMD).await.unwrap();
+        let remote = Repo::decode(&mut &LOCAL_REPOMD[..]).await.unwrap();
         let expected = Path::new("repodata/84fe7bb9cf340186df02863647f41a4be32c86a21b80eaaeddaa97e99a24b7a6-primary.xml.gz");
 
         assert_eq!(remote.primary_path().unwrap(), expected);
     }
+
+    #[tokio::test]
+    async fn prune_persistent_cache() {
+        let local = Repo::decode(&mut &LOCAL_REPOMD[..]).await.unwrap();
+        let dir = TempDir::new("cache").unwrap();
+        let primary = dir.path().join(local.primary_path().unwrap());
+        let stale = dir.path().join("repodata/0000-primary.xml.gz");
+        let nested = dir.path().join("repodata/nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        for path in &[dir.path().join(MD_PATH), primary.clone(), stale.clone()] {
+            std::fs::write(path, b"cached").unwrap();
+        }
+
+        local.prune_cache(dir.path()).await.unwrap();
+        assert!(primary.exists());
+        assert!(!stale.exists());
+        assert!(!dir.path().join(MD_PATH).exists());
+        assert!(nested.is_dir());
+    }
+
+    #[tokio::test]
+    async fn metadata_checksums() {
+        let local = Repo::decode(&mut &LOCAL_REPOMD[..]).await.unwrap();
+        let base = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test-data/local");
+        let primary = local.data.iter().find(|d| d.datum == "primary").unwrap();
+        let path = base.join(&primary.location.href);
+
+        assert_eq!(primary.size, Some(3143566));
+        assert!(primary
+            .checksum
+            .as_ref()
+            .unwrap()
+            .check(&path)
+            .await
+            .unwrap());
+
+        let open_checksum = primary.open_checksum.as_ref().unwrap();
+        assert_eq!(
+            open_checksum
+                .check_open(&path, primary.open_size)
+                .await
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            open_checksum.check_open(&path, Some(0)).await.unwrap(),
+            Some(false)
+        );
+    }
 }
@@ -0,0 +1,154 @@
+//! `.repo` files for clients of the mirror, so they can be pointed at it straight away.
+//!
+//! After a variant is published, a dnf-compatible `<id>.repo` file is written
+//! to its destination, pointing at the URL clients reach the mirror at. If the
+//! upstream index is verified against a key, the key is published alongside it
+//! as `RPM-GPG-KEY-<id>`.
+
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::path::Path;
+use tokio::fs::write;
+use tracing::info;
+
+use crate::repo::MD_DIR;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// How clients reach the mirror, from the configuration of a repository.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Clients {
+    /// The URL clients reach the destination at, using the same tags as the destination.
+    pub url: String,
+    /// The name shown to clients, which may use tags. Defaults to the name of the repository.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Whether clients check the signatures of packages.
+    #[serde(default = "default_true")]
+    pub gpgcheck: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A repository in a `.repo` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoFile {
+    /// The ID of the repository, which names the files.
+    pub id: String,
+    /// The name shown to clients.
+    pub name: String,
+    /// The URL of the mirrored repository.
+    pub baseurl: String,
+    /// Whether clients check the signatures of packages.
+    pub gpgcheck: bool,
+    /// Whether clients check the signature of the metadata index.
+    pub repo_gpgcheck: bool,
+    /// Whether the upstream key is published alongside the file.
+    pub key: bool,
+}
+
+impl RepoFile {
+    /// The name of the `.repo` file.
+    pub fn file_name(&self) -> String {
+        format!("{}.repo", self.id)
+    }
+
+    /// The name of the published upstream key.
+    pub fn key_name(&self) -> String {
+        format!("RPM-GPG-KEY-{}", self.id)
+    }
+
+    /// The URLs of the keys clients check signatures with.
+    fn keys(&self) -> Vec<String> {
+        let base = self.baseurl.trim_end_matches('/');
+        let mut keys = Vec::new();
+        if self.key {
+            keys.push(format!("{}/{}", base, self.key_name()));
+        }
+        if self.repo_gpgcheck {
+            keys.push(format!("{}/{}/repomd.xml.key", base, MD_DIR));
+        }
+        keys
+    }
+
+    /// Write the file, and the upstream key if there is one, to a destination.
+    pub async fn write(&self, dest: &Path, key: Option<&[u8]>) -> Result<()> {
+        if let Some(key) = key {
+            write(dest.join(self.key_name()), key).await?;
+        }
+        let path = dest.join(self.file_name());
+        info!("Writing client configuration to {:?}", path);
+        write(path, self.to_string()).await?;
+        Ok(())
+    }
+}
+
+impl Display for RepoFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{}]", self.id)?;
+        writeln!(f, "name={}", self.name)?;
+        writeln!(f, "baseurl={}", self.baseurl)?;
+        writeln!(f, "enabled=1")?;
+        writeln!(f, "gpgcheck={}", u8::from(self.gpgcheck))?;
+        writeln!(f, "repo_gpgcheck={}", u8::from(self.repo_gpgcheck))?;
+        let keys = self.keys();
+        if !keys.is_empty() {
+            writeln!(f, "gpgkey={}", keys.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Make a repository ID from a name and tag values, keeping only characters dnf allows.
+pub fn id<'a>(name: &'a str, values: impl IntoIterator<Item = &'a str>) -> String {
+    std::iter::once(name)
+        .chain(values)
+        .collect::<Vec<_>>()
+        .join("-")
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | ':' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_repo_file() {
+        let repo = RepoFile {
+            id: id("fedora", vec!["39", "x86_64"]),
+            name: "Fedora 39 x86_64".to_owned(),
+            baseurl: "https://mirror.example.com/fedora/39/x86_64/".to_owned(),
+            gpgcheck: true,
+            repo_gpgcheck: true,
+            key: true,
+        };
+        assert_eq!(repo.file_name(), "fedora-39-x86_64.repo");
+        assert_eq!(
+            repo.to_string(),
+            "[fedora-39-x86_64]\n\
+             name=Fedora 39 x86_64\n\
+             baseurl=https://mirror.example.com/fedora/39/x86_64/\n\
+             enabled=1\n\
+             gpgcheck=1\n\
+             repo_gpgcheck=1\n\
+             gpgkey=https://mirror.example.com/fedora/39/x86_64/RPM-GPG-KEY-fedora-39-x86_64 \
+             https://mirror.example.com/fedora/39/x86_64/repodata/repomd.xml.key\n"
+        );
+
+        let plain = RepoFile {
+            gpgcheck: false,
+            repo_gpgcheck: false,
+            key: false,
+            ..repo
+        };
+        assert!(!plain.to_string().contains("gpgkey"));
+        assert_eq!(id("my repo/extras", None), "my_repo_extras");
+    }
+}
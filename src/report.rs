@@ -0,0 +1,407 @@
+//! A machine readable report of a synchronisation run.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::progress::{format_bytes, format_duration};
+use crate::serve::escape;
+use crate::state::State;
+use crate::stats::Summary;
+
+/// Styles for the HTML report.
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+td.number { text-align: right; }
+.synced { background: #c8e6c9; }
+.failed { background: #ffcdd2; }
+.unavailable, .over_quota, .over_budget { background: #fff9c4; }
+.disabled { background: #eeeeee; }
+.run { display: inline-block; width: 0.6em; height: 1em; margin-right: 1px; }
+.trend { font-family: monospace; }
+";
+
+/// The result of synchronising every repository.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// The result for each repository, in the order they were synchronised.
+    pub repos: Vec<RepoReport>,
+}
+
+/// The result of synchronising a single repository.
+#[derive(Debug, Serialize)]
+pub struct RepoReport {
+    /// The name or source of the repository.
+    pub repo: String,
+    /// What happened to the repository.
+    pub status: Status,
+    /// The error that stopped the repository from being synchronised.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The changes made to the repository.
+    pub stats: Summary,
+}
+
+/// What happened to a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Every variant was synchronised.
+    Synced,
+    /// The source could not be reached and was skipped.
+    Unavailable,
+    /// The repository is disabled.
+    Disabled,
+    /// Synchronising failed.
+    Failed,
+    /// The transfer quota was reached.
+    #[serde(rename = "over_quota")]
+    OverQuota,
+    /// The download budget for the run was spent, so the rest is left for the next run.
+    #[serde(rename = "over_budget")]
+    OverBudget,
+}
+
+impl Status {
+    /// The name of the status, as it appears in the JSON report.
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Synced => "synced",
+            Status::Unavailable => "unavailable",
+            Status::Disabled => "disabled",
+            Status::Failed => "failed",
+            Status::OverQuota => "over_quota",
+            Status::OverBudget => "over_budget",
+        }
+    }
+}
+
+impl Report {
+    /// Whether no repository failed.
+    pub fn succeeded(&self) -> bool {
+        self.repos.iter().all(|repo| repo.status != Status::Failed)
+    }
+
+    /// The status of a repository, if it was part of the run.
+    pub fn status(&self, repo: &str) -> Option<Status> {
+        self.repos
+            .iter()
+            .find(|report| report.repo == repo)
+            .map(|report| report.status)
+    }
+
+    /// Write the report as JSON.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, json + "\n")
+    }
+
+    /// Write the report as a standalone HTML page, replacing the file atomically so that it can
+    /// be served from the web root of the mirror.
+    pub fn write_html(&self, path: &Path, state: &State) -> io::Result<()> {
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, self.html(state))?;
+        fs::rename(&temp, path)
+    }
+
+    /// Render the report as HTML, with the recent history of each repository from the state.
+    pub fn html(&self, state: &State) -> String {
+        let generated = humantime::format_rfc3339_seconds(SystemTime::now());
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <title>yumclone report</title>\n<style>\n{}</style></head><body>\n\
+             <h1>yumclone report</h1>\n<p>Generated {}. {} of {} repositories failed.</p>\n",
+            STYLE,
+            generated,
+            self.repos
+                .iter()
+                .filter(|repo| repo.status == Status::Failed)
+                .count(),
+            self.repos.len()
+        );
+
+        html += "<table>\n<tr><th>Repository</th><th>Status</th><th>Added</th><th>Removed</th>\
+                 <th>Downloaded</th><th>Deleted</th><th>Quarantined</th><th>Duration</th>\
+                 <th>Recent runs</th><th>Downloads</th></tr>\n";
+        for repo in &self.repos {
+            let stats = &repo.stats;
+            let history = state
+                .repos
+                .get(&repo.repo)
+                .map(|state| &state.history[..])
+                .unwrap_or_default();
+            let runs: String = history
+                .iter()
+                .map(|run| {
+                    format!(
+                        "<span class=\"run {0}\" title=\"{1}: {0}, {2} added, {3} downloaded\">\
+                         </span>",
+                        run.status.name(),
+                        escape(&run.finished),
+                        run.added,
+                        format_bytes(run.bytes_downloaded)
+                    )
+                })
+                .collect();
+            let downloads: Vec<u64> = history.iter().map(|run| run.bytes_downloaded).collect();
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"{status}\">{status}</td><td class=\"number\">{}</td>\
+                 <td class=\"number\">{}</td><td class=\"number\">{}</td>\
+                 <td class=\"number\">{}</td><td class=\"number\">{}</td>\
+                 <td class=\"number\">{}</td><td>{}</td><td class=\"trend\">{}</td></tr>",
+                escape(&repo.repo),
+                stats.added,
+                stats.removed,
+                format_bytes(stats.bytes_downloaded),
+                format_bytes(stats.bytes_deleted),
+                stats.quarantined,
+                format_duration(Duration::from_secs_f64(stats.duration)),
+                runs,
+                sparkline(&downloads),
+                status = repo.status.name(),
+            );
+        }
+        html += "</table>\n";
+
+        let failures: Vec<_> = self
+            .repos
+            .iter()
+            .filter_map(|repo| repo.error.as_ref().map(|error| (&repo.repo, error)))
+            .collect();
+        if !failures.is_empty() {
+            html += "<h2>Failures</h2>\n<ul>\n";
+            for (repo, error) in failures {
+                let _ = writeln!(
+                    html,
+                    "<li><strong>{}</strong>: {}</li>",
+                    escape(repo),
+                    escape(error)
+                );
+            }
+            html += "</ul>\n";
+        }
+
+        let inconsistent: Vec<_> = self
+            .repos
+            .iter()
+            .filter(|repo| !repo.stats.inconsistencies.is_empty())
+            .collect();
+        if !inconsistent.is_empty() {
+            html += "<h2>Upstream inconsistencies</h2>\n";
+            for repo in inconsistent {
+                let _ = writeln!(
+                    html,
+                    "<details open><summary>{}: {} files</summary><ul>",
+                    escape(&repo.repo),
+                    repo.stats.inconsistencies.len()
+                );
+                for inconsistency in &repo.stats.inconsistencies {
+                    let _ = writeln!(
+                        html,
+                        "<li>{}: {}</li>",
+                        escape(&inconsistency.path),
+                        escape(&inconsistency.problem)
+                    );
+                }
+                html += "</ul></details>\n";
+            }
+        }
+
+        let updated: Vec<_> = self
+            .repos
+            .iter()
+            .flat_map(|repo| repo.stats.changes.iter().map(move |c| (&repo.repo, c)))
+            .filter(|(_, (_, changelog))| !changelog.is_empty())
+            .collect();
+        if !updated.is_empty() {
+            html += "<h2>Package changes</h2>\n";
+            for (repo, (dest, changelog)) in updated {
+                let _ = writeln!(
+                    html,
+                    "<details><summary>{} ({}): {}</summary><ul>",
+                    escape(repo),
+                    escape(dest),
+                    changelog
+                );
+                for package in &changelog.added {
+                    let _ = writeln!(html, "<li>+ {}</li>", escape(package));
+                }
+                for update in &changelog.updated {
+                    let _ = write!(
+                        html,
+                        "<li>{} &rarr; {}",
+                        escape(&update.from),
+                        escape(&update.to)
+                    );
+                    for entry in &update.changelog {
+                        let _ = write!(
+                            html,
+                            "<pre>{} {}\n{}</pre>",
+                            entry.day(),
+                            escape(&entry.author),
+                            escape(&entry.text)
+                        );
+                    }
+                    html += "</li>\n";
+                }
+                for package in &changelog.removed {
+                    let _ = writeln!(html, "<li>&minus; {}</li>", escape(package));
+                }
+                html += "</ul></details>\n";
+            }
+        }
+
+        let changed: Vec<_> = self
+            .repos
+            .iter()
+            .filter(|repo| {
+                !repo.stats.added_files.is_empty() || !repo.stats.removed_files.is_empty()
+            })
+            .collect();
+        if !changed.is_empty() {
+            html += "<h2>Files</h2>\n";
+            for repo in changed {
+                let _ = writeln!(
+                    html,
+                    "<details><summary>{}: {} added, {} removed</summary><ul>",
+                    escape(&repo.repo),
+                    repo.stats.added_files.len(),
+                    repo.stats.removed_files.len()
+                );
+                for file in &repo.stats.added_files {
+                    let _ = writeln!(html, "<li>+ {}</li>", escape(file));
+                }
+                for file in &repo.stats.removed_files {
+                    let _ = writeln!(html, "<li>&minus; {}</li>", escape(file));
+                }
+                html += "</ul></details>\n";
+            }
+        }
+
+        html += "</body></html>\n";
+        html
+    }
+}
+
+/// Draw values as a line of bars scaled to the largest.
+fn sparkline(values: &[u64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or_default().max(1);
+    values
+        .iter()
+        .map(|value| BARS[(value * (BARS.len() as u64 - 1) / max) as usize])
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::other::Entry;
+    use crate::package::{Changelog, Update};
+    use crate::stats::Inconsistency;
+
+    #[test]
+    fn report_json() {
+        let report = Report {
+            repos: vec![RepoReport {
+                repo: "fedora".to_owned(),
+                status: Status::Failed,
+                error: Some("Connection refused".to_owned()),
+                stats: Summary {
+                    added: 2,
+                    ..Summary::default()
+                },
+            }],
+        };
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repos"][0]["status"], "failed");
+        assert_eq!(json["repos"][0]["stats"]["added"], 2);
+        assert_eq!(json["repos"][0]["error"], "Connection refused");
+        assert!(!report.succeeded());
+        assert_eq!(report.status("fedora"), Some(Status::Failed));
+        assert_eq!(report.status("epel"), None);
+    }
+
+    #[test]
+    fn report_html() {
+        let report = Report {
+            repos: vec![
+                RepoReport {
+                    repo: "fedora".to_owned(),
+                    status: Status::Synced,
+                    error: None,
+                    stats: Summary {
+                        added: 1,
+                        added_files: vec!["Packages/a/a-1-1.rpm".to_owned()],
+                        changes: vec![(
+                            "mirror/fedora".to_owned(),
+                            Changelog {
+                                updated: vec![Update {
+                                    from: "a-0-1.noarch".to_owned(),
+                                    to: "a-1-1.noarch".to_owned(),
+                                    changelog: vec![Entry {
+                                        author: "Packager <packager@example.com> - 1-1".to_owned(),
+                                        date: 1700000000,
+                                        text: "- Fix <everything>".to_owned(),
+                                    }],
+                                }],
+                                ..Changelog::default()
+                            },
+                        )]
+                        .into_iter()
+                        .collect(),
+                        ..Summary::default()
+                    },
+                },
+                RepoReport {
+                    repo: "epel".to_owned(),
+                    status: Status::Failed,
+                    error: Some("Invalid <repomd>".to_owned()),
+                    stats: Summary {
+                        inconsistencies: vec![Inconsistency {
+                            path: "Packages/b/b-1-1.rpm".to_owned(),
+                            problem: "failed checksum".to_owned(),
+                            missing: false,
+                        }],
+                        ..Summary::default()
+                    },
+                },
+            ],
+        };
+        let mut state = State::default();
+        for bytes in &[0, 700] {
+            let summary = Summary {
+                bytes_downloaded: *bytes,
+                ..Summary::default()
+            };
+            state
+                .repo("fedora")
+                .record_run(crate::state::Run::finished_now(
+                    Status::Synced,
+                    &summary,
+                    &summary.published,
+                ));
+        }
+
+        let html = report.html(&state);
+        assert!(html.contains("1 of 2 repositories failed"));
+        assert!(html.contains("<td class=\"failed\">failed</td>"));
+        assert!(html.contains("<strong>epel</strong>: Invalid &lt;repomd&gt;"));
+        assert!(html.contains("<li>+ Packages/a/a-1-1.rpm</li>"));
+        assert!(html.contains("<summary>epel: 1 files</summary>"));
+        assert!(html.contains("<li>Packages/b/b-1-1.rpm: failed checksum</li>"));
+        assert!(html.contains("<li>a-0-1.noarch &rarr; a-1-1.noarch"));
+        assert!(html.contains(
+            "2023-11-14 Packager &lt;packager@example.com&gt; - 1-1\n- Fix &lt;everything&gt;"
+        ));
+        assert!(html.contains("<td class=\"trend\">▁█</td>"));
+        assert_eq!(sparkline(&[]), "");
+    }
+}
@@ -0,0 +1,639 @@
+//! Name resolution for upstream mirrors.
+//!
+//! Hosts can be resolved to fixed addresses, in the `host:port:address` form
+//! of curl's `--resolve`, or by querying a particular DNS server rather than
+//! the system resolver. The HTTP client can't be given a resolver of its own,
+//! so it connects through a proxy on the loopback interface which resolves
+//! names itself and tunnels each connection to the upstream server.
+//!
+//! Other local users can reach the loopback interface too, so the proxy only
+//! serves clients with the random credentials it was started with. It connects
+//! to any host, as upstreams commonly redirect to mirrors that aren't known in
+//! advance. Each proxy is started once and shared for the life of the process.
+
+use failure::{bail, format_err};
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Uri};
+use reqwest::Url;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// How long to wait for a DNS server to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS record types for IPv4 and IPv6 addresses.
+const A: u16 = 1;
+const AAAA: u16 = 28;
+
+/// The IP versions used to connect to upstream hosts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// Only IPv4
+    V4,
+    /// Only IPv6
+    V6,
+    /// Whichever the host resolves to
+    #[default]
+    Auto,
+}
+
+impl IpFamily {
+    /// Whether an address may be used.
+    pub fn allows(self, addr: &IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+            IpFamily::Auto => true,
+        }
+    }
+
+    /// The local address to bind to, restricting connections to the family.
+    pub fn local_address(self) -> Option<IpAddr> {
+        match self {
+            IpFamily::V4 => Some(IpAddr::from([0u8; 4])),
+            IpFamily::V6 => Some(IpAddr::from([0u16; 8])),
+            IpFamily::Auto => None,
+        }
+    }
+}
+
+impl Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6"),
+            IpFamily::Auto => write!(f, "IP"),
+        }
+    }
+}
+
+/// Resolves the hosts of upstream mirrors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Resolver {
+    overrides: Vec<Override>,
+    nameserver: Option<SocketAddr>,
+    family: IpFamily,
+}
+
+/// The proxies started by the process, with the resolvers they use.
+static PROXIES: Mutex<Vec<(Resolver, Url)>> = Mutex::new(Vec::new());
+
+/// The fixed addresses of a host and port.
+#[derive(Debug, Clone, PartialEq)]
+struct Override {
+    host: String,
+    port: u16,
+    addrs: Vec<IpAddr>,
+}
+
+impl Resolver {
+    /// Create a resolver from `host:port:address[,address...]` overrides and the
+    /// address of a DNS server.
+    pub fn new(overrides: &[String], nameserver: Option<&str>) -> Result<Resolver> {
+        let overrides = overrides
+            .iter()
+            .map(|o| parse_override(o))
+            .collect::<Result<_>>()?;
+        let nameserver = match nameserver {
+            Some(nameserver) => Some(parse_nameserver(nameserver)?),
+            None => None,
+        };
+        Ok(Resolver {
+            overrides,
+            nameserver,
+            family: IpFamily::Auto,
+        })
+    }
+
+    /// Only connect to addresses of one IP version.
+    pub fn restrict_to(&mut self, family: IpFamily) {
+        self.family = family;
+    }
+
+    /// Find the addresses of a host.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(addr, port)]);
+        }
+
+        let fixed = self
+            .overrides
+            .iter()
+            .find(|o| o.port == port && o.host.eq_ignore_ascii_case(host));
+        let addrs: Vec<SocketAddr> = match (fixed, self.nameserver) {
+            (Some(fixed), _) => fixed
+                .addrs
+                .iter()
+                .map(|&addr| SocketAddr::new(addr, port))
+                .collect(),
+            (None, Some(nameserver)) => {
+                let mut addrs = Vec::new();
+                for &record in &[AAAA, A] {
+                    let kind = if record == A {
+                        IpFamily::V4
+                    } else {
+                        IpFamily::V6
+                    };
+                    if self.family != IpFamily::Auto && self.family != kind {
+                        continue;
+                    }
+                    match query(nameserver, host, record).await {
+                        Ok(found) => addrs.extend(found),
+                        Err(e) => debug!("Failed to look up {} at {}: {}", host, nameserver, e),
+                    }
+                }
+                addrs
+                    .into_iter()
+                    .map(|addr| SocketAddr::new(addr, port))
+                    .collect()
+            }
+            (None, None) => lookup_host((host, port)).await?.collect(),
+        };
+
+        let addrs: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| self.family.allows(&addr.ip()))
+            .collect();
+        if addrs.is_empty() {
+            bail!("No {} addresses found for {}", self.family, host);
+        }
+        Ok(addrs)
+    }
+
+    /// Connect to the first reachable address of a host.
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.resolve(host, port).await? {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+            Some(e) => format_err!("Failed to connect to {}:{}: {}", host, port, e),
+            None => format_err!("No addresses found for {}", host),
+        })
+    }
+
+    /// Find or start a proxy on the loopback interface that connects using this resolver.
+    ///
+    /// Clients with the same resolver share a proxy. Must be called from within the runtime.
+    /// Returns the URL of the proxy, with the credentials it requires.
+    pub fn proxy(self) -> Result<Url> {
+        let mut proxies = PROXIES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, url)) = proxies.iter().find(|(resolver, _)| *resolver == self) {
+            return Ok(url.clone());
+        }
+        let url = self.clone().start()?;
+        proxies.push((self, url.clone()));
+        Ok(url)
+    }
+
+    /// Start a proxy on the loopback interface that connects using this resolver.
+    fn start(self) -> Result<Url> {
+        let mut token = [0; 16];
+        random(&mut token)?;
+        let token = hex::encode(token);
+        let credentials = format!("{}:{}", env!("CARGO_PKG_NAME"), token);
+        let authorization = Arc::new(HeaderValue::from_str(&format!(
+            "Basic {}",
+            base64::encode(&credentials)
+        ))?);
+        let resolver = Arc::new(self);
+        let server =
+            Server::try_bind(&([127, 0, 0, 1], 0).into())?.serve(make_service_fn(move |_| {
+                let resolver = resolver.clone();
+                let authorization = authorization.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        let resolver = resolver.clone();
+                        let authorization = authorization.clone();
+                        async move {
+                            Ok::<_, Infallible>(forward(&resolver, &authorization, request).await)
+                        }
+                    }))
+                }
+            }));
+        let url = Url::parse(&format!("http://{}@{}/", credentials, server.local_addr()))?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("Resolving proxy failed: {}", e);
+            }
+        });
+        Ok(url)
+    }
+}
+
+/// Parse an override of the form `host:port:address[,address...]`.
+fn parse_override(spec: &str) -> Result<Override> {
+    let invalid = || format_err!("'{}' isn't of the form host:port:address", spec);
+    let mut parts = spec.splitn(3, ':');
+    let host = parts.next().filter(|h| !h.is_empty()).ok_or_else(invalid)?;
+    let port = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    let addrs = parts
+        .next()
+        .ok_or_else(invalid)?
+        .split(',')
+        .map(|addr| {
+            addr.trim()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_| format_err!("Invalid address '{}' in '{}'", addr, spec))
+        })
+        .collect::<Result<Vec<IpAddr>>>()?;
+    Ok(Override {
+        host: host.to_owned(),
+        port,
+        addrs,
+    })
+}
+
+/// Parse the address of a DNS server, with an optional port.
+fn parse_nameserver(nameserver: &str) -> Result<SocketAddr> {
+    nameserver
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            let addr = nameserver.trim_start_matches('[').trim_end_matches(']');
+            addr.parse::<IpAddr>().map(|addr| SocketAddr::new(addr, 53))
+        })
+        .map_err(|_| format_err!("Invalid nameserver address '{}'", nameserver))
+}
+
+/// Fill a buffer with random bytes from the kernel.
+fn random(buffer: &mut [u8]) -> Result<()> {
+    File::open("/dev/urandom")?.read_exact(buffer)?;
+    Ok(())
+}
+
+/// Handle a request to the proxy.
+///
+/// Only requests with the proxy's credentials are forwarded.
+async fn forward(
+    resolver: &Resolver,
+    authorization: &HeaderValue,
+    request: Request<Body>,
+) -> Response<Body> {
+    let refuse = |status, message: String| {
+        let mut response = Response::new(Body::from(message));
+        *response.status_mut() = status;
+        response
+    };
+    if request.headers().get(PROXY_AUTHORIZATION) != Some(authorization) {
+        return refuse(
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            "Missing or invalid proxy credentials".to_owned(),
+        );
+    }
+    let target = request.uri().clone();
+    if let Err(e) = authority(&target) {
+        return refuse(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    let result = if request.method() == Method::CONNECT {
+        tunnel(resolver, request).await
+    } else {
+        relay(resolver, request).await
+    };
+    result.unwrap_or_else(|e| {
+        warn!("Failed to proxy {}: {}", target, e);
+        refuse(StatusCode::BAD_GATEWAY, e.to_string())
+    })
+}
+
+/// The host and port that a request is for.
+fn authority(uri: &Uri) -> Result<(&str, u16)> {
+    let host = uri
+        .host()
+        .ok_or_else(|| format_err!("No host in '{}'", uri))?;
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        });
+    Ok((host, port))
+}
+
+/// Tunnel a CONNECT request to the upstream server.
+async fn tunnel(resolver: &Resolver, request: Request<Body>) -> Result<Response<Body>> {
+    let (host, port) = authority(request.uri())?;
+    let mut upstream = resolver.connect(host, port).await?;
+
+    tokio::spawn(async move {
+        let result: Result<()> = async {
+            let client = request.into_body().on_upgrade().await?;
+            let (mut client_read, mut client_write) = tokio::io::split(client);
+            let (mut upstream_read, mut upstream_write) = upstream.split();
+            let sent = async {
+                tokio::io::copy(&mut client_read, &mut upstream_write).await?;
+                upstream_write.shutdown().await
+            };
+            let received = async {
+                tokio::io::copy(&mut upstream_read, &mut client_write).await?;
+                client_write.shutdown().await
+            };
+            tokio::try_join!(sent, received)?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            debug!("Tunnel closed: {}", e);
+        }
+    });
+
+    Ok(Response::new(Body::empty()))
+}
+
+/// Relay a plain HTTP request to the upstream server.
+async fn relay(resolver: &Resolver, mut request: Request<Body>) -> Result<Response<Body>> {
+    let (host, port) = authority(request.uri())?;
+    let upstream = resolver.connect(host, port).await?;
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .parse()?;
+    *request.uri_mut() = path;
+    request.headers_mut().remove(PROXY_AUTHORIZATION);
+
+    let (mut sender, connection) = hyper::client::conn::handshake(upstream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Upstream connection closed: {}", e);
+        }
+    });
+    Ok(sender.send_request(request).await?)
+}
+
+/// Look up the addresses of one type for a host from a DNS server.
+async fn query(nameserver: SocketAddr, host: &str, record: u16) -> Result<Vec<IpAddr>> {
+    let local: SocketAddr = if nameserver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let mut socket = UdpSocket::bind(local).await?;
+    socket.connect(nameserver).await?;
+
+    // A random ID, so that answers can't be forged by guessing it
+    let mut id = [0; 2];
+    random(&mut id)?;
+    let id = u16::from_be_bytes(id);
+    socket.send(&encode_query(id, host, record)?).await?;
+
+    let mut buffer = [0; 4096];
+    loop {
+        let length = timeout(QUERY_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .map_err(|_| format_err!("Timed out waiting for {}", nameserver))??;
+        // Answers to earlier queries are ignored.
+        if let Some(addrs) = decode_answer(id, record, &buffer[..length])? {
+            return Ok(addrs);
+        }
+    }
+}
+
+/// Encode a recursive query for one type of record.
+fn encode_query(id: u16, host: &str, record: u16) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(host.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, with a single question.
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid host name '{}'", host);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record.to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes());
+    Ok(message)
+}
+
+/// Decode the addresses in the answer to a query.
+///
+/// Returns `None` if the message doesn't answer the query with the given ID.
+fn decode_answer(id: u16, record: u16, message: &[u8]) -> Result<Option<Vec<IpAddr>>> {
+    let truncated = || format_err!("Truncated DNS answer");
+    let u16_at = |pos: usize| -> Result<u16> {
+        let bytes = message.get(pos..pos + 2).ok_or_else(truncated)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    if message.len() < 12 || u16_at(0)? != id || message[2] & 0x80 == 0 {
+        return Ok(None);
+    }
+    // The rest of the answer would only be sent over TCP, which isn't supported.
+    if message[2] & 0x02 != 0 {
+        bail!("DNS answer was truncated, and looking it up over TCP isn't supported");
+    }
+    match message[3] & 0x0f {
+        0 => {}
+        // The name doesn't exist.
+        3 => return Ok(Some(Vec::new())),
+        code => bail!("DNS query failed with response code {}", code),
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos).ok_or_else(truncated)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos).ok_or_else(truncated)?;
+        let kind = u16_at(pos)?;
+        let length = u16_at(pos + 8)? as usize;
+        pos += 10;
+        let data = message.get(pos..pos + length).ok_or_else(truncated)?;
+        pos += length;
+
+        match (kind, data.len()) {
+            (A, 4) if record == A => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::from(octets));
+            }
+            (AAAA, 16) if record == AAAA => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::from(octets));
+            }
+            // Aliases are followed by the server.
+            _ => {}
+        }
+    }
+    Ok(Some(addrs))
+}
+
+/// Find the end of a possibly compressed name.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(pos)?;
+        match length {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serve::serve_dir;
+    use reqwest::{Client, Proxy};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn parse_overrides() {
+        let resolver = Resolver::new(
+            &[
+                "mirror.example.com:443:10.0.0.5".to_owned(),
+                "mirror.example.com:80:[2001:db8::5],10.0.0.6".to_owned(),
+            ],
+            Some("10.0.0.53"),
+        )
+        .unwrap();
+        assert_eq!(
+            resolver.overrides[0].addrs,
+            vec![IpAddr::from([10, 0, 0, 5])]
+        );
+        assert_eq!(resolver.overrides[1].port, 80);
+        assert_eq!(resolver.overrides[1].addrs.len(), 2);
+        assert_eq!(resolver.nameserver, Some(([10, 0, 0, 53], 53).into()));
+
+        assert!(Resolver::new(&["mirror.example.com:10.0.0.5".to_owned()], None).is_err());
+        assert!(Resolver::new(&["mirror.example.com:443:mirror".to_owned()], None).is_err());
+        assert!(Resolver::new(&[], Some("dns.example.com")).is_err());
+    }
+
+    #[tokio::test]
+    async fn restrict_family() {
+        let overrides = ["mirror.example.com:443:[2001:db8::5],192.0.2.5".to_owned()];
+        let mut resolver = Resolver::new(&overrides, None).unwrap();
+        let resolved = resolver.resolve("mirror.example.com", 443).await.unwrap();
+        assert_eq!(resolved.len(), 2);
+
+        resolver.restrict_to(IpFamily::V4);
+        let resolved = resolver.resolve("mirror.example.com", 443).await.unwrap();
+        assert_eq!(resolved, vec![SocketAddr::from(([192, 0, 2, 5], 443))]);
+
+        let v6_only = ["mirror.example.com:443:2001:db8::5".to_owned()];
+        let mut resolver = Resolver::new(&v6_only, None).unwrap();
+        resolver.restrict_to(IpFamily::V4);
+        let err = resolver
+            .resolve("mirror.example.com", 443)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No IPv4 addresses found for mirror.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn query_nameserver() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nameserver = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            loop {
+                let (length, client) = server.recv_from(&mut buffer).await.unwrap();
+                let mut answer = buffer[..length].to_vec();
+                let aaaa = answer[length - 3] == AAAA as u8;
+                answer[2] |= 0x80;
+                if !aaaa {
+                    answer[7] = 1;
+                    // A compressed name pointing at the question.
+                    answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    answer.extend_from_slice(&[192, 0, 2, 7]);
+                }
+                server.send_to(&answer, &client).await.unwrap();
+            }
+        });
+
+        let resolver = Resolver::new(&[], Some(&nameserver.to_string())).unwrap();
+        let addrs = resolver.resolve("mirror.example.com", 443).await.unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from(([192, 0, 2, 7], 443))]);
+    }
+
+    #[test]
+    fn reject_truncated_answers() {
+        let mut answer = encode_query(7, "mirror.example.com", A).unwrap();
+        answer[2] |= 0x80;
+        answer[7] = 1;
+        answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 7]);
+        assert_eq!(
+            decode_answer(7, A, &answer).unwrap(),
+            Some(vec![IpAddr::from([192, 0, 2, 7])])
+        );
+
+        // Only some of the addresses fitted, so the rest are missing
+        answer[2] |= 0x02;
+        let err = decode_answer(7, A, &answer).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn proxy_overridden_host() {
+        let root = TempDir::new("yumclone").unwrap();
+        fs::write(root.path().join("repomd.xml"), "<repomd/>").unwrap();
+        let served = serve_dir(root.path());
+        let port = served.port().unwrap();
+
+        let resolver =
+            Resolver::new(&[format!("mirror.invalid:{}:127.0.0.1", port)], None).unwrap();
+        let proxy = resolver.clone().proxy().unwrap();
+        // Clients with the same resolver share the proxy
+        assert_eq!(resolver.proxy().unwrap(), proxy);
+        let client = |proxy: &Url| {
+            Client::builder()
+                .proxy(Proxy::all(proxy.as_str()).unwrap())
+                .build()
+                .unwrap()
+        };
+        let get = |client: Client, host: &str| {
+            let url = format!("http://{}:{}/repomd.xml", host, port);
+            async move { client.get(&url).send().await.unwrap() }
+        };
+
+        let response = get(client(&proxy), "mirror.invalid").await;
+        assert_eq!(response.text().await.unwrap(), "<repomd/>");
+        let response = get(client(&proxy), "unresolvable.invalid").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        // Nothing is relayed without the credentials
+        let mut anonymous = proxy.clone();
+        anonymous.set_username("").unwrap();
+        anonymous.set_password(None).unwrap();
+        let response = get(client(&anonymous), "mirror.invalid").await;
+        assert_eq!(response.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    }
+}
@@ -0,0 +1,171 @@
+//! Secrets kept outside of the configuration.
+//!
+//! Instead of writing a password into the configuration, it can be read from
+//! a file, printed by a command such as a password manager, or looked up in
+//! the keyring of the operating system. Trailing newlines are removed.
+
+use serde::Deserialize;
+use std::fmt::{self, Debug, Display};
+use std::fs;
+use std::process::{Command, Stdio};
+
+use failure::{bail, format_err};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The service that yumclone's secrets are stored under in the keyring.
+const KEYRING_SERVICE: &str = env!("CARGO_PKG_NAME");
+
+/// A secret value, which is never shown in logs.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The secret itself.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Apply a function to the secret.
+    pub fn map<F: FnOnce(&str) -> Result<String>>(&self, f: F) -> Result<Secret> {
+        Ok(Secret(f(&self.0)?))
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// Where a secret is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A shell command that prints the secret.
+    Command(String),
+    /// A file containing the secret.
+    File(String),
+    /// An entry in the keyring, by account name.
+    Keyring(String),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Command(command) => write!(f, "command '{}'", command),
+            Source::File(path) => write!(f, "file '{}'", path),
+            Source::Keyring(account) => write!(f, "keyring entry '{}'", account),
+        }
+    }
+}
+
+impl Source {
+    /// The source given by at most one of a command, a file or a keyring entry.
+    pub fn from_options(
+        name: &str,
+        command: Option<&str>,
+        file: Option<&str>,
+        keyring: Option<&str>,
+    ) -> Result<Option<Source>> {
+        let sources: Vec<Source> = [
+            command.map(|c| Source::Command(c.to_owned())),
+            file.map(|f| Source::File(f.to_owned())),
+            keyring.map(|k| Source::Keyring(k.to_owned())),
+        ]
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+        match sources.len() {
+            0 | 1 => Ok(sources.into_iter().next()),
+            _ => bail!(
+                "Only one of {0}_cmd, {0}_file and {0}_keyring can be set",
+                name
+            ),
+        }
+    }
+
+    /// Read the secret.
+    pub fn read(&self) -> Result<Secret> {
+        let secret = match self {
+            Source::Command(command) => run(Command::new("sh").arg("-c").arg(command))?,
+            Source::File(path) => fs::read_to_string(path)
+                .map_err(|e| format_err!("Could not read '{}': {}", path, e))?,
+            Source::Keyring(account) => run(&mut keyring_lookup(account))?,
+        };
+        let secret = secret.trim_end_matches(['\r', '\n']);
+        if secret.is_empty() {
+            bail!("The secret from {} is empty", self);
+        }
+        Ok(Secret(secret.to_owned()))
+    }
+}
+
+/// The command that prints a secret from the keyring.
+#[cfg(target_os = "macos")]
+fn keyring_lookup(account: &str) -> Command {
+    let mut command = Command::new("security");
+    command.args([
+        "find-generic-password",
+        "-w",
+        "-s",
+        KEYRING_SERVICE,
+        "-a",
+        account,
+    ]);
+    command
+}
+
+/// The command that prints a secret from the keyring.
+#[cfg(not(target_os = "macos"))]
+fn keyring_lookup(account: &str) -> Command {
+    let mut command = Command::new("secret-tool");
+    command.args(["lookup", "service", KEYRING_SERVICE, "account", account]);
+    command
+}
+
+/// Run a command, returning what it printed.
+fn run(command: &mut Command) -> Result<String> {
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format_err!("Could not run {:?}: {}", command, e))?;
+    if !output.status.success() {
+        bail!("{:?} failed ({})", command, output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn read_secrets() {
+        let command = Source::Command("printf 'hunter2\\n'".to_owned());
+        let password = command.read().unwrap();
+        assert_eq!(password.expose(), "hunter2");
+        assert_eq!(format!("{:?}", password), "<redacted>");
+        assert!(Source::Command("exit 1".to_owned()).read().is_err());
+        assert!(Source::Command("true".to_owned()).read().is_err());
+
+        let dir = TempDir::new("secret").unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, "s3cret\n").unwrap();
+        let file = Source::File(path.display().to_string());
+        assert_eq!(file.read().unwrap().expose(), "s3cret");
+
+        assert_eq!(
+            Source::from_options("password", None, Some("/run/password"), None).unwrap(),
+            Some(Source::File("/run/password".to_owned()))
+        );
+        assert_eq!(
+            Source::from_options("password", None, None, None).unwrap(),
+            None
+        );
+        assert!(Source::from_options("password", Some("pass"), None, Some("mirror")).is_err());
+    }
+}
@@ -0,0 +1,659 @@
+//! A simple HTTP server for mirrored repositories.
+//!
+//! Packages missing from lazily mirrored repositories are fetched from upstream when they are
+//! first requested, and kept for later requests.
+
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::{metadata, read_dir, File};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use failure::{bail, format_err};
+
+use crate::package::{sync_file, Check, Checksum, Vetting};
+use crate::repo::{Mirror, MD_PATH, STAGING_DIR};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The number of bytes read from a file at a time while sending it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size and checksum of each file listed by the metadata of a repository.
+type Listing = HashMap<String, (u64, Checksum)>;
+
+/// What to serve and how.
+#[derive(Debug)]
+pub struct Options {
+    /// The directory served at `/`.
+    pub root: PathBuf,
+    /// Whether to list the contents of directories.
+    pub listing: bool,
+    /// Lazily mirrored repositories below the root.
+    pub upstreams: Vec<Upstream>,
+}
+
+/// A lazily mirrored repository, whose packages are fetched from upstream on demand.
+#[derive(Debug)]
+pub struct Upstream {
+    /// The source of the repository.
+    src: Url,
+    /// Where the metadata of the repository is published.
+    dest: PathBuf,
+    client: Client,
+    /// Checks made on fetched files.
+    vetting: Vetting,
+    /// The files listed by the published metadata, as of the time the index was modified.
+    listed: Mutex<Option<(SystemTime, Arc<Listing>)>>,
+    /// A lock for each file being fetched, so that each is only downloaded once.
+    fetching: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl Upstream {
+    /// Fetch packages of the repository published in `dest` from `src`.
+    pub fn new(src: Url, dest: PathBuf, client: Client, vetting: Vetting) -> Upstream {
+        Upstream {
+            src,
+            dest,
+            client,
+            vetting,
+            listed: Mutex::new(None),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The files listed by the published metadata, reloaded whenever it changes.
+    async fn listed(&self) -> Result<Arc<Listing>> {
+        let modified = metadata(self.dest.join(MD_PATH)).await?.modified()?;
+        let mut listed = self.listed.lock().await;
+        match &*listed {
+            Some((loaded, files)) if *loaded == modified => Ok(files.clone()),
+            _ => {
+                let dest = self
+                    .dest
+                    .to_str()
+                    .ok_or_else(|| format_err!("Couldn't decode directory: {:?}", self.dest))?;
+                let mirror = match Mirror::local(dest).await? {
+                    Some(mirror) => mirror,
+                    None => bail!("No metadata published in {:?}", self.dest),
+                };
+                let files = Arc::new(mirror.listed_files().await?);
+                *listed = Some((modified, files.clone()));
+                Ok(files)
+            }
+        }
+    }
+
+    /// Fetch a file listed by the metadata, returning whether it is now available.
+    ///
+    /// Files that aren't listed are never fetched, and fetched files are verified against their
+    /// checksums before they are served.
+    async fn fetch(&self, path: &Path) -> Result<bool> {
+        let relative = match path.strip_prefix(&self.dest).ok().and_then(Path::to_str) {
+            Some(relative) => relative.to_owned(),
+            None => return Ok(false),
+        };
+        let files = self.listed().await?;
+        let (size, checksum) = match files.get(&relative) {
+            Some(listed) => listed,
+            None => return Ok(false),
+        };
+
+        let lock = self
+            .fetching
+            .lock()
+            .await
+            .entry(relative.clone())
+            .or_default()
+            .clone();
+        let _fetching = lock.lock().await;
+        let mut result = Ok(());
+        if !path.exists() {
+            info!("Fetching '{}' from '{}'", relative, self.src);
+            let check = Check::Hash(*size, checksum);
+            let vetting = Some(&self.vetting);
+            result = sync_file(
+                &self.client,
+                &relative,
+                &self.src,
+                &self.dest,
+                check,
+                None,
+                vetting,
+            )
+            .await;
+        }
+        // The lock is dropped whether or not the fetch succeeded, so failures don't pile up
+        self.fetching.lock().await.remove(&relative);
+        result.map(|()| true)
+    }
+}
+
+/// Serve files until the process is stopped.
+///
+/// Relative paths are taken from the current directory.
+pub async fn serve(addr: SocketAddr, mut options: Options) -> Result<()> {
+    let current = std::env::current_dir()?;
+    options.root = current.join(&options.root);
+    for upstream in &mut options.upstreams {
+        upstream.dest = current.join(&upstream.dest);
+        upstream.vetting.quarantine = current.join(&upstream.vetting.quarantine);
+    }
+    let options = Arc::new(options);
+    let make_service = make_service_fn(move |_| {
+        let options = options.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let options = options.clone();
+                async move { Ok::<_, Infallible>(handle(&options, request).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("Serving on http://{}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+/// Respond to a single request, logging the outcome.
+pub async fn handle(options: &Options, request: Request<Body>) -> Response<Body> {
+    let response = match respond(options, &request).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Error serving '{}': {}", request.uri().path(), e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+    debug!(
+        "{} {} {}",
+        request.method(),
+        request.uri().path(),
+        response.status().as_u16()
+    );
+    response
+}
+
+async fn respond(options: &Options, request: &Request<Body>) -> Result<Response<Body>> {
+    let head = match *request.method() {
+        Method::GET => false,
+        Method::HEAD => true,
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let uri_path = request.uri().path();
+    let path = match resolve(&options.root, uri_path) {
+        Some(path) => path,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    let info = match metadata(&path).await {
+        Ok(info) => info,
+        Err(_) if fetch(options, &path).await? => metadata(&path).await?,
+        Err(_) => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    if info.is_dir() {
+        if !uri_path.ends_with('/') {
+            let mut response = status(StatusCode::MOVED_PERMANENTLY);
+            response
+                .headers_mut()
+                .insert(LOCATION, HeaderValue::from_str(&format!("{}/", uri_path))?);
+            return Ok(response);
+        }
+        if !options.listing {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+        let html = listing(&path, uri_path).await?;
+        let mut response = Response::new(if head {
+            Body::empty()
+        } else {
+            Body::from(html.clone())
+        });
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(html.len() as u64));
+        return Ok(response);
+    }
+
+    let size = info.len();
+    let range = request
+        .headers()
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| parse_range(range, size));
+    let (start, end, code) = match range {
+        None => (0, size, StatusCode::OK),
+        Some(Some((start, end))) => (start, end, StatusCode::PARTIAL_CONTENT),
+        Some(None) => {
+            let mut response = status(StatusCode::RANGE_NOT_SATISFIABLE);
+            response.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", size))?,
+            );
+            return Ok(response);
+        }
+    };
+
+    let body = if head {
+        Body::empty()
+    } else {
+        send_file(path.clone(), start, end - start).await?
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = code;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(&path)));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if code == StatusCode::PARTIAL_CONTENT {
+        headers.insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end - 1, size))?,
+        );
+    }
+    Ok(response)
+}
+
+/// Fetch a missing file from the upstream of the lazily mirrored repository containing it,
+/// returning whether it is now available.
+async fn fetch(options: &Options, path: &Path) -> Result<bool> {
+    for upstream in &options.upstreams {
+        if path.starts_with(&upstream.dest) {
+            return upstream.fetch(path).await;
+        }
+    }
+    Ok(false)
+}
+
+/// An empty response with a status.
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+/// Stream part of a file as a response body.
+async fn send_file(path: PathBuf, start: u64, length: u64) -> Result<Body> {
+    let mut file = File::open(&path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut block = vec![0; CHUNK_SIZE];
+        let mut remaining = length;
+        while remaining > 0 {
+            let wanted = remaining.min(CHUNK_SIZE as u64) as usize;
+            let read = match file.read(&mut block[..wanted]).await {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("Error reading {:?}: {}", path, e);
+                    sender.abort();
+                    return;
+                }
+            };
+            if sender
+                .send_data(Bytes::copy_from_slice(&block[..read]))
+                .await
+                .is_err()
+            {
+                // The client went away
+                return;
+            }
+            remaining -= read as u64;
+        }
+    });
+
+    Ok(body)
+}
+
+/// Map a request path onto a file below the root.
+///
+/// Returns `None` for paths that can't be decoded, that would leave the root, or that are within
+/// the staging directory of a repository, which holds quarantined and partial files.
+fn resolve(root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(uri_path)?;
+    let mut path = root.to_owned();
+    for component in Path::new(&decoded).components() {
+        match component {
+            Component::Normal(part) if part == STAGING_DIR => return None,
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Decode `%XX` escapes in a request path.
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = text.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Parse a `Range` header for a file of the given size.
+///
+/// Returns `None` if the header should be ignored, `Some(None)` if the range can't be satisfied,
+/// and otherwise the start and (exclusive) end of the range. Only single byte ranges are
+/// supported; anything else is answered with the whole file.
+fn parse_range(header: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let mut parts = spec.splitn(2, '-');
+    let (first, last) = (parts.next()?.trim(), parts.next()?.trim());
+
+    let range = if first.is_empty() {
+        // The last `n` bytes
+        let suffix: u64 = last.parse().ok()?;
+        Some((size.saturating_sub(suffix), size)).filter(|_| suffix > 0 && size > 0)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        let end = if last.is_empty() {
+            size
+        } else {
+            let last: u64 = last.parse().ok()?;
+            if last < start {
+                return None;
+            }
+            (last + 1).min(size)
+        };
+        Some((start, end)).filter(|_| start < size)
+    };
+    Some(range)
+}
+
+/// The content type of a file, by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let extension = name.rsplit('.').next().unwrap_or_default();
+    match extension {
+        "rpm" | "drpm" => "application/x-rpm",
+        "xml" => "application/xml",
+        "gz" => "application/gzip",
+        "bz2" => "application/x-bzip2",
+        "xz" => "application/x-xz",
+        "zst" => "application/zstd",
+        "zck" => "application/zchunk",
+        "asc" => "application/pgp-signature",
+        "key" => "application/pgp-keys",
+        "iso" => "application/x-iso9660-image",
+        "img" => "application/octet-stream",
+        "json" => "application/json",
+        "html" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        _ if name == ".treeinfo" || name == "treeinfo" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Render an HTML listing of a directory.
+async fn listing(dir: &Path, uri_path: &str) -> Result<String> {
+    let mut names = Vec::new();
+    let mut entries = read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name() == STAGING_DIR {
+            continue;
+        }
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let title = escape(uri_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {0}</title></head><body>\n\
+         <h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if uri_path != "/" {
+        html += "<li><a href=\"../\">../</a></li>\n";
+    }
+    for name in names {
+        let name = escape(&name);
+        html += &format!("<li><a href=\"{0}\">{0}</a></li>\n", name);
+    }
+    html += "</ul>\n</body></html>\n";
+    Ok(html)
+}
+
+/// Escape text for use in HTML.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Serve a directory on an unused local port, returning its URL.
+#[cfg(test)]
+pub(crate) fn serve_dir(root: &Path) -> Url {
+    let options = Arc::new(Options {
+        root: root.to_owned(),
+        listing: false,
+        upstreams: Vec::new(),
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+        let options = options.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let options = options.clone();
+                async move { Ok::<_, Infallible>(handle(&options, request).await) }
+            }))
+        }
+    }));
+    let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+    tokio::spawn(server);
+    url
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Some((0, 100))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Some((900, 1000))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Some((900, 1000))));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some(Some((990, 1000))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("lines=1-2", 1000), None);
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let root = Path::new("/srv/mirror");
+        assert_eq!(
+            resolve(root, "/fedora/Packages/a%20b.rpm"),
+            Some(root.join("fedora/Packages/a b.rpm"))
+        );
+        assert_eq!(resolve(root, "/"), Some(root.to_owned()));
+        assert_eq!(resolve(root, "/fedora/../../etc/passwd"), None);
+        assert_eq!(resolve(root, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve(root, "/bad%zz"), None);
+        assert_eq!(resolve(root, "/fedora/.yumclone/quarantine/a.rpm"), None);
+        assert_eq!(resolve(root, "/fedora/%2Eyumclone/"), None);
+    }
+
+    #[tokio::test]
+    async fn hide_staging() {
+        let dir = TempDir::new("serve").unwrap();
+        let quarantined = dir.path().join(STAGING_DIR).join("quarantine/a-1-1.rpm");
+        std::fs::create_dir_all(quarantined.parent().unwrap()).unwrap();
+        std::fs::write(&quarantined, "infected").unwrap();
+        std::fs::write(dir.path().join("b-1-1.rpm"), "b").unwrap();
+        let options = Options {
+            root: dir.path().to_owned(),
+            listing: true,
+            upstreams: Vec::new(),
+        };
+        let get = |path: &str| handle(&options, Request::get(path).body(Body::empty()).unwrap());
+
+        assert_eq!(
+            get("/.yumclone/quarantine/a-1-1.rpm").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(get("/.yumclone/").await.status(), StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(get("/").await.into_body())
+            .await
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("b-1-1.rpm"));
+        assert!(!body.contains(STAGING_DIR));
+    }
+
+    #[tokio::test]
+    async fn serve_files() {
+        let dir = TempDir::new("serve").unwrap();
+        std::fs::create_dir(dir.path().join("repodata")).unwrap();
+        std::fs::write(dir.path().join("repodata/repomd.xml"), "0123456789").unwrap();
+        let options = Options {
+            root: dir.path().to_owned(),
+            listing: false,
+            upstreams: Vec::new(),
+        };
+        let get = |path: &str, range: Option<&str>| {
+            let mut request = Request::get(path);
+            if let Some(range) = range {
+                request = request.header(RANGE, range);
+            }
+            handle(&options, request.body(Body::empty()).unwrap())
+        };
+
+        let response = get("/repodata/repomd.xml", Some("bytes=2-4")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/xml");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"234");
+
+        let response = get("/repodata/repomd.xml", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"0123456789");
+
+        assert_eq!(
+            get("/repodata", None).await.status(),
+            StatusCode::MOVED_PERMANENTLY
+        );
+        assert_eq!(
+            get("/repodata/", None).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(get("/missing", None).await.status(), StatusCode::NOT_FOUND);
+
+        let options = Options {
+            listing: true,
+            ..options
+        };
+        let response = handle(
+            &options,
+            Request::get("/repodata/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("<a href=\"repomd.xml\">"));
+    }
+
+    #[tokio::test]
+    async fn fetch_lazily() {
+        let upstream = TempDir::new("upstream").unwrap();
+        let mirror = TempDir::new("lazy").unwrap();
+        let write = |dir: &Path, file: &str, contents: &str| {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(
+            mirror.path(),
+            MD_PATH,
+            "<?xml version=\"1.0\"?><repomd><data type=\"primary\">\
+             <location href=\"repodata/primary.xml\"/></data></repomd>",
+        );
+        write(
+            mirror.path(),
+            "repodata/primary.xml",
+            "<?xml version=\"1.0\"?><metadata><package><name>a</name>\
+             <version epoch=\"0\" ver=\"1\" rel=\"1\"/>\
+             <checksum type=\"sha256\">\
+             ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb</checksum>\
+             <size package=\"1\" installed=\"1\" archive=\"1\"/>\
+             <location href=\"Packages/a/a-1-1.rpm\"/></package></metadata>",
+        );
+        write(upstream.path(), "Packages/a/a-1-1.rpm", "a");
+        write(upstream.path(), "Packages/b/b-1-1.rpm", "b");
+
+        let src = serve_dir(upstream.path());
+        let options = Options {
+            root: mirror.path().to_owned(),
+            listing: false,
+            upstreams: vec![Upstream::new(
+                src,
+                mirror.path().to_owned(),
+                Client::new(),
+                Vetting::default(),
+            )],
+        };
+        let get = |path: &str| handle(&options, Request::get(path).body(Body::empty()).unwrap());
+
+        let response = get("/Packages/a/a-1-1.rpm").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"a");
+        assert!(mirror.path().join("Packages/a/a-1-1.rpm").exists());
+
+        // Files that aren't listed in the metadata aren't fetched
+        assert_eq!(
+            get("/Packages/b/b-1-1.rpm").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert!(!mirror.path().join("Packages/b/b-1-1.rpm").exists());
+
+        // A failed fetch is reported and doesn't leave its lock behind
+        std::fs::remove_file(mirror.path().join("Packages/a/a-1-1.rpm")).unwrap();
+        std::fs::remove_file(upstream.path().join("Packages/a/a-1-1.rpm")).unwrap();
+        assert_eq!(
+            get("/Packages/a/a-1-1.rpm").await.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(options.upstreams[0].fetching.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+//! Sharing the download of a repository between several hosts.
+//!
+//! With `--shard i/N`, each of N hosts only downloads the packages whose
+//! paths hash to its shard, so together they seed shared storage without
+//! downloading anything twice. Sharded runs leave the metadata and any
+//! other files alone, as each host only has part of the repository; a final
+//! run without `--shard` publishes the metadata once every shard is done.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// The shard of packages downloaded by this host, if the work is shared.
+static SHARD: OnceLock<Shard> = OnceLock::new();
+
+/// One of several equal parts of the packages of a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// The shard, counting from 1.
+    index: u64,
+    /// The number of shards.
+    count: u64,
+}
+
+impl Shard {
+    /// Check whether a file belongs to the shard.
+    pub fn contains(&self, path: &str) -> bool {
+        fnv1a(path.as_bytes()) % self.count == self.index - 1
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Shard, String> {
+        let invalid = || format!("Expected a shard as i/N but found '{}'", s);
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index: u64 = index.trim().parse().map_err(|_| invalid())?;
+        let count: u64 = count.trim().parse().map_err(|_| invalid())?;
+        if index == 0 || index > count {
+            return Err(format!("Shard {} must be between 1 and {}", index, count));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Only download the packages of a shard.
+pub fn install(shard: Shard) {
+    let _ = SHARD.set(shard);
+}
+
+/// The shard of packages downloaded by this host, if the work is shared.
+pub fn current() -> Option<Shard> {
+    SHARD.get().copied()
+}
+
+/// Hash data with 64-bit FNV-1a, which is the same on every host.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partition_packages() {
+        let shards: Vec<Shard> = (1..=3)
+            .map(|i| format!("{}/3", i).parse().unwrap())
+            .collect();
+        let paths: Vec<String> = (0..300)
+            .map(|i| format!("Packages/p/package-{}-1.noarch.rpm", i))
+            .collect();
+        for path in &paths {
+            let owners = shards.iter().filter(|shard| shard.contains(path)).count();
+            assert_eq!(owners, 1);
+        }
+        for shard in &shards {
+            let share = paths.iter().filter(|path| shard.contains(path)).count();
+            assert!(share > 50, "shard {} only has {} packages", shard, share);
+        }
+
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert!("0/3".parse::<Shard>().is_err());
+        assert!("4/3".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+        assert_eq!("2/3".parse::<Shard>().unwrap().to_string(), "2/3");
+    }
+}
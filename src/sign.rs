@@ -0,0 +1,347 @@
+//! Signing of rewritten metadata with a local GPG key, and verification of upstream signatures.
+
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tempdir::TempDir;
+use tokio::fs::{read, write};
+use tokio::process::Command;
+use tracing::{debug, info};
+
+use failure::{bail, format_err};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// A key used to sign metadata, held by `gpg` (and its agent).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Signing {
+    /// The ID or fingerprint of the secret key.
+    pub key: String,
+    /// The GnuPG home directory holding the key, if not the default.
+    #[serde(default)]
+    pub homedir: Option<PathBuf>,
+}
+
+impl Signing {
+    /// Sign a file, writing a detached armored signature to `<file>.asc` and the public key to
+    /// `<file>.key`.
+    pub async fn sign(&self, path: &Path) -> Result<()> {
+        let signature = append_extension(path, "asc");
+        debug!("Signing {:?} with key '{}'", path, self.key);
+        self.gpg(&[
+            "--local-user".as_ref(),
+            self.key.as_ref(),
+            "--armor".as_ref(),
+            "--detach-sign".as_ref(),
+            "--output".as_ref(),
+            signature.as_os_str(),
+            path.as_os_str(),
+        ])
+        .await?;
+
+        let public_key = append_extension(path, "key");
+        self.gpg(&[
+            "--armor".as_ref(),
+            "--output".as_ref(),
+            public_key.as_os_str(),
+            "--export".as_ref(),
+            self.key.as_ref(),
+        ])
+        .await?;
+        Ok(())
+    }
+
+    /// Run `gpg` non-interactively with the configured home directory.
+    async fn gpg(&self, args: &[&OsStr]) -> Result<()> {
+        gpg(self.homedir.as_deref(), args).await.map(|_| ())
+    }
+}
+
+/// The key that must have signed the upstream metadata index.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// The URL or path of the public key.
+    pub key: String,
+    /// The fingerprints the key is pinned to. If any are given, every key in the file must have
+    /// one of them.
+    pub fingerprints: Vec<String>,
+    /// The key once it has been fetched, shared with every clone.
+    fetched: Arc<OnceLock<Vec<u8>>>,
+}
+
+impl Verification {
+    /// Verify with the key at a URL or path, pinned to the fingerprints if any are given.
+    pub fn new(key: String, fingerprints: Vec<String>) -> Verification {
+        Verification {
+            key,
+            fingerprints,
+            fetched: Arc::default(),
+        }
+    }
+
+    /// Check that the index was signed by the key, downloading the key first.
+    pub async fn verify(&self, client: &Client, index: &Path, signature: &Path) -> Result<()> {
+        if !signature.exists() {
+            bail!("The upstream metadata index is not signed");
+        }
+
+        // Use a keyring of only this key, so no other key can satisfy the check
+        let homedir = TempDir::new("gpgkey")?;
+        let key_path = homedir.path().join("key");
+        write(&key_path, self.fetch_key(client).await?).await?;
+        gpg(
+            Some(homedir.path()),
+            &["--import".as_ref(), key_path.as_os_str()],
+        )
+        .await
+        .map_err(|e| format_err!("Could not import the key from '{}': {}", self.key, e))?;
+
+        let fingerprints = fingerprints(homedir.path()).await?;
+        if fingerprints.is_empty() {
+            bail!("No keys were found in '{}'", self.key);
+        }
+        let pinned: Vec<String> = self.fingerprints.iter().map(|f| normalise(f)).collect();
+        if !pinned.is_empty() {
+            for fingerprint in &fingerprints {
+                if !pinned.contains(fingerprint) {
+                    bail!(
+                        "The key from '{}' has fingerprint {}, which is not pinned",
+                        self.key,
+                        fingerprint
+                    );
+                }
+            }
+        }
+
+        gpg(
+            Some(homedir.path()),
+            &[
+                "--verify".as_ref(),
+                signature.as_os_str(),
+                index.as_os_str(),
+            ],
+        )
+        .await
+        .map_err(|e| format_err!("The upstream metadata index has a bad signature: {}", e))?;
+        info!(
+            "Verified the upstream metadata index with {}",
+            fingerprints.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Read the key from a local path or `file:` URL, or download it.
+    ///
+    /// The key is only fetched once, so that the key published with the metadata is the one it
+    /// was verified with.
+    pub async fn fetch_key(&self, client: &Client) -> Result<Vec<u8>> {
+        if let Some(key) = self.fetched.get() {
+            return Ok(key.clone());
+        }
+        let key = match Url::parse(&self.key) {
+            Ok(url) if url.scheme() == "file" => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| format_err!("Invalid key path '{}'", self.key))?;
+                read(path).await?
+            }
+            Ok(url) => {
+                debug!("Downloading key from '{}'", url);
+                let response = client.get(url).send().await?.error_for_status()?;
+                response.bytes().await?.to_vec()
+            }
+            Err(_) => read(&self.key).await?,
+        };
+        Ok(self.fetched.get_or_init(|| key).clone())
+    }
+}
+
+/// Write a fingerprint as upper case hexadecimal without spaces.
+pub fn normalise(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// The fingerprints of the primary keys in a keyring.
+async fn fingerprints(homedir: &Path) -> Result<Vec<String>> {
+    let output = gpg(
+        Some(homedir),
+        &["--with-colons".as_ref(), "--list-keys".as_ref()],
+    )
+    .await?;
+    let mut fingerprints = Vec::new();
+    let mut primary = false;
+    for line in String::from_utf8_lossy(&output).lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "pub" => primary = true,
+            "fpr" if primary => {
+                fingerprints.extend(fields.get(9).map(|f| normalise(f)));
+                primary = false;
+            }
+            "sub" => primary = false,
+            _ => {}
+        }
+    }
+    Ok(fingerprints)
+}
+
+/// Run `gpg` non-interactively, returning what it writes to standard output.
+async fn gpg(homedir: Option<&Path>, args: &[&OsStr]) -> Result<Vec<u8>> {
+    let mut command = Command::new("gpg");
+    command.args(["--batch", "--yes"]);
+    if let Some(homedir) = homedir {
+        command.arg("--homedir").arg(homedir);
+    }
+    let output = command
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format_err!("Could not run gpg: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "gpg failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Add an extension to a path, keeping any it already has.
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    /// Generate a signing key in a new home directory, returning false if gpg isn't available.
+    fn generate_key(homedir: &Path) -> bool {
+        std::fs::create_dir(homedir).unwrap();
+        let generated = Command::new("gpg")
+            .args(["--batch", "--passphrase", "", "--homedir"])
+            .arg(homedir)
+            .args([
+                "--quick-gen-key",
+                "yumclone@example.com",
+                "default",
+                "sign",
+                "never",
+            ])
+            .output();
+        match generated {
+            Ok(output) if output.status.success() => true,
+            _ => {
+                eprintln!("Skipping signing test as gpg is not available");
+                false
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_file() {
+        let dir = TempDir::new("sign").unwrap();
+        let homedir = dir.path().join("gnupg");
+        if !generate_key(&homedir) {
+            return;
+        }
+
+        let repomd = dir.path().join("repomd.xml");
+        std::fs::write(&repomd, "<repomd/>").unwrap();
+        let signing = Signing {
+            key: "yumclone@example.com".to_owned(),
+            homedir: Some(homedir.clone()),
+        };
+        signing.sign(&repomd).await.unwrap();
+
+        let key = std::fs::read_to_string(dir.path().join("repomd.xml.key")).unwrap();
+        assert!(key.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+        let verified = Command::new("gpg")
+            .args(["--batch", "--homedir"])
+            .arg(&homedir)
+            .arg("--verify")
+            .arg(dir.path().join("repomd.xml.asc"))
+            .arg(&repomd)
+            .output()
+            .unwrap();
+        assert!(verified.status.success());
+
+        let missing = Signing {
+            key: "missing@example.com".to_owned(),
+            homedir: Some(homedir),
+        };
+        assert!(missing.sign(&repomd).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_signature() {
+        let dir = TempDir::new("verify").unwrap();
+        let homedir = dir.path().join("gnupg");
+        if !generate_key(&homedir) {
+            return;
+        }
+        let repomd = dir.path().join("repomd.xml");
+        let signature = dir.path().join("repomd.xml.asc");
+        std::fs::write(&repomd, "<repomd/>").unwrap();
+        let signing = Signing {
+            key: "yumclone@example.com".to_owned(),
+            homedir: Some(homedir.clone()),
+        };
+        signing.sign(&repomd).await.unwrap();
+        let fingerprint = fingerprints(&homedir).await.unwrap().remove(0);
+
+        let client = Client::new();
+        let key = dir.path().join("repomd.xml.key");
+        let mut verification = Verification::new(key.to_string_lossy().into_owned(), Vec::new());
+        verification
+            .verify(&client, &repomd, &signature)
+            .await
+            .unwrap();
+
+        // Fingerprints can be written in groups, as gpg shows them
+        verification = Verification::new(
+            Url::from_file_path(&key).unwrap().to_string(),
+            vec![fingerprint
+                .as_bytes()
+                .chunks(4)
+                .map(|c| String::from_utf8_lossy(c).to_lowercase())
+                .collect::<Vec<_>>()
+                .join(" ")],
+        );
+        verification
+            .verify(&client, &repomd, &signature)
+            .await
+            .unwrap();
+
+        verification.fingerprints = vec!["0".repeat(40)];
+        let error = verification
+            .verify(&client, &repomd, &signature)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("not pinned"));
+
+        verification.fingerprints = vec![fingerprint];
+        std::fs::write(&repomd, "<repomd>tampered</repomd>").unwrap();
+        assert!(verification
+            .verify(&client, &repomd, &signature)
+            .await
+            .is_err());
+        std::fs::remove_file(&signature).unwrap();
+        assert!(verification
+            .verify(&client, &repomd, &signature)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Incremental updates between two snapshots of a destination.
+//!
+//! Only the files added or changed in the newer snapshot are exported, along
+//! with `DELTA.json` listing them and the files removed since the older one,
+//! so an offline mirror can be brought up to date without shipping the whole
+//! repository each time.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs::{create_dir_all, write};
+
+use failure::bail;
+
+use crate::manifest::Entry;
+use crate::package::digest_file;
+use crate::repo::{publish, walk};
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The name of the description of the changes, in the exported directory.
+pub const DELTA_FILE: &str = "DELTA.json";
+
+/// The changes between two snapshots.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta {
+    /// When the changes were found, in RFC 3339 format.
+    pub created: String,
+    /// Files only in the newer snapshot.
+    pub added: Vec<Entry>,
+    /// Files in both snapshots whose contents differ.
+    pub changed: Vec<Entry>,
+    /// Files only in the older snapshot, relative to it.
+    pub removed: Vec<String>,
+}
+
+impl Delta {
+    /// Compare the files of two snapshots.
+    ///
+    /// Files of the same size are only hashed if they aren't links to the same file.
+    pub async fn between(old: &Path, new: &Path) -> Result<Delta> {
+        let mut old_files = files(old)?;
+        let mut delta = Delta {
+            created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            ..Delta::default()
+        };
+        for (path, new_meta) in files(new)? {
+            let new_path = new.join(&path);
+            match old_files.remove(&path) {
+                None => delta.added.push(entry(&new_path, path).await?),
+                Some(old_meta) => {
+                    // Snapshots often link unchanged files to each other
+                    if old_meta.dev() == new_meta.dev() && old_meta.ino() == new_meta.ino() {
+                        continue;
+                    }
+                    if old_meta.len() == new_meta.len() {
+                        let (_, old_sum) = digest_file(&old.join(&path), "sha256").await?;
+                        let (size, new_sum) = digest_file(&new_path, "sha256").await?;
+                        if old_sum != new_sum {
+                            delta.changed.push(Entry {
+                                path,
+                                size,
+                                sha256: new_sum,
+                            });
+                        }
+                    } else {
+                        delta.changed.push(entry(&new_path, path).await?);
+                    }
+                }
+            }
+        }
+        delta.removed = old_files.into_keys().collect();
+        Ok(delta)
+    }
+
+    /// The total size of the files added and changed.
+    pub fn size(&self) -> u64 {
+        self.added.iter().chain(&self.changed).map(|e| e.size).sum()
+    }
+
+    /// Export the added and changed files of the newer snapshot to an empty directory, along
+    /// with the description of the changes.
+    pub async fn export(&self, new: &Path, output: &Path) -> Result<()> {
+        if output.exists() && output.read_dir()?.next().is_some() {
+            bail!("{:?} is not empty", output);
+        }
+        for entry in self.added.iter().chain(&self.changed) {
+            let to = output.join(&entry.path);
+            create_dir_all(to.parent().unwrap_or(output)).await?;
+            publish(&new.join(&entry.path), &to).await?;
+        }
+        create_dir_all(output).await?;
+        write(
+            output.join(DELTA_FILE),
+            serde_json::to_string_pretty(self)? + "\n",
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Every file in a snapshot, by its path relative to the snapshot.
+fn files(base: &Path) -> Result<BTreeMap<String, std::fs::Metadata>> {
+    let mut files = BTreeMap::new();
+    for entry in walk(base, &[]) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            let path = entry
+                .path()
+                .strip_prefix(base)?
+                .to_string_lossy()
+                .into_owned();
+            files.insert(path, entry.metadata()?);
+        }
+    }
+    Ok(files)
+}
+
+/// Describe a file in a snapshot, hashing it.
+async fn entry(file: &Path, path: String) -> Result<Entry> {
+    let (size, sha256) = digest_file(file, "sha256").await?;
+    Ok(Entry { path, size, sha256 })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn export_changes() {
+        let dir = TempDir::new("snapshot").unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        let write = |base: &Path, file: &str, contents: &str| {
+            let path = base.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(&old, "Packages/a-1-1.rpm", "a");
+        write(&old, "Packages/b-1-1.rpm", "b");
+        write(&old, "repodata/repomd.xml", "old");
+        write(&new, "Packages/c-1-1.rpm", "c");
+        write(&new, "repodata/repomd.xml", "new");
+        std::fs::hard_link(
+            old.join("Packages/a-1-1.rpm"),
+            new.join("Packages/a-1-1.rpm"),
+        )
+        .unwrap();
+
+        let delta = Delta::between(&old, &new).await.unwrap();
+        let paths =
+            |entries: &[Entry]| -> Vec<String> { entries.iter().map(|e| e.path.clone()).collect() };
+        assert_eq!(paths(&delta.added), vec!["Packages/c-1-1.rpm"]);
+        assert_eq!(paths(&delta.changed), vec!["repodata/repomd.xml"]);
+        assert_eq!(delta.removed, vec!["Packages/b-1-1.rpm"]);
+        assert_eq!(delta.size(), 4);
+
+        let output = dir.path().join("delta");
+        delta.export(&new, &output).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output.join("repodata/repomd.xml")).unwrap(),
+            "new"
+        );
+        assert!(!output.join("Packages/a-1-1.rpm").exists());
+        let exported: Delta =
+            serde_json::from_str(&std::fs::read_to_string(output.join(DELTA_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(exported, delta);
+        assert!(delta.export(&new, &output).await.is_err());
+    }
+}
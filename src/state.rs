@@ -0,0 +1,421 @@
+//! State kept between runs.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use failure::format_err;
+
+use crate::report::Status;
+#[cfg(test)]
+use crate::stats::Inconsistency;
+use crate::stats::Summary;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The number of runs remembered for each repository.
+const HISTORY: usize = 30;
+
+/// Everything remembered about previous runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// The state of each repository, by label.
+    #[serde(default)]
+    pub repos: BTreeMap<String, RepoState>,
+}
+
+/// Everything remembered about a single repository.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RepoState {
+    /// Bytes transferred in the current month.
+    #[serde(default)]
+    pub transfer: Transfer,
+    /// The fingerprint of the upstream metadata of each variant when it was last synchronised,
+    /// by source URL.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstream: BTreeMap<String, String>,
+    /// The most recent runs, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Run>,
+    /// Packages that were missing upstream in the last run, to be downloaded in a later one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    /// When the repository was last synchronised successfully, in RFC 3339 format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_synced: Option<String>,
+    /// What was last published to each destination, by destination.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub published: BTreeMap<String, Published>,
+}
+
+/// What was last published to a destination.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Published {
+    /// The revision of the metadata, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+    /// The number of packages the metadata lists.
+    pub packages: u64,
+    /// The total size of the packages the metadata lists.
+    pub bytes: u64,
+}
+
+/// The outcome of synchronising a repository in a previous run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    /// When the run finished, in RFC 3339 format.
+    pub finished: String,
+    /// What happened to the repository.
+    pub status: Status,
+    /// Packages downloaded.
+    pub added: u64,
+    /// Files removed.
+    pub removed: u64,
+    /// Bytes downloaded.
+    pub bytes_downloaded: u64,
+    /// Wall-clock time in seconds.
+    pub duration: f64,
+    /// The packages listed by the metadata of every destination, if the run published to any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<Published>,
+}
+
+impl Run {
+    /// Describe a run that just finished, given what is now published to every destination.
+    ///
+    /// Destinations that were already up to date are counted along with those published during
+    /// the run, so that the totals of different runs can be compared.
+    pub fn finished_now(
+        status: Status,
+        summary: &Summary,
+        published: &BTreeMap<String, Published>,
+    ) -> Run {
+        Run {
+            finished: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            status,
+            added: summary.added,
+            removed: summary.removed,
+            bytes_downloaded: summary.bytes_downloaded,
+            duration: summary.duration,
+            published: if summary.published.is_empty() {
+                None
+            } else {
+                Some(Published {
+                    revision: None,
+                    packages: published.values().map(|p| p.packages).sum(),
+                    bytes: published.values().map(|p| p.bytes).sum(),
+                })
+            },
+        }
+    }
+}
+
+/// Bytes transferred during a calendar month.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+    /// The month, as `YYYY-MM` in UTC.
+    pub month: String,
+    /// Bytes downloaded during the month.
+    pub bytes: u64,
+}
+
+impl State {
+    /// Load the state, starting afresh if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<State> {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| format_err!("Could not parse state {:?}: {}", path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(format_err!("Could not read state {:?}: {}", path, e)),
+        }
+    }
+
+    /// Save the state, replacing the file atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_string_pretty(self)? + "\n")?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Get the state of a repository.
+    pub fn repo(&mut self, label: &str) -> &mut RepoState {
+        self.repos.entry(label.to_owned()).or_default()
+    }
+}
+
+impl RepoState {
+    /// Remember the packages that were missing upstream, to download them in a later run.
+    ///
+    /// Packages that were missing before are only forgotten once they are downloaded, or once a
+    /// run that synchronised the whole repository didn't find them missing again, as they were
+    /// then already present or no longer listed.
+    pub fn record_missing(&mut self, summary: &Summary, synced: bool) {
+        let downloaded: BTreeSet<&str> = summary.added_files.iter().map(String::as_str).collect();
+        let mut missing: BTreeSet<String> = if synced {
+            BTreeSet::new()
+        } else {
+            self.missing
+                .iter()
+                .filter(|path| !downloaded.contains(path.as_str()))
+                .cloned()
+                .collect()
+        };
+        missing.extend(
+            summary
+                .inconsistencies
+                .iter()
+                .filter(|inconsistency| inconsistency.missing)
+                .map(|inconsistency| inconsistency.path.clone()),
+        );
+        self.missing = missing.into_iter().collect();
+    }
+
+    /// The bytes transferred so far during a month.
+    pub fn month_to_date(&self, month: &str) -> u64 {
+        if self.transfer.month == month {
+            self.transfer.bytes
+        } else {
+            0
+        }
+    }
+
+    /// Add to the bytes transferred during a month.
+    pub fn record_transfer(&mut self, month: &str, bytes: u64) {
+        if self.transfer.month != month {
+            self.transfer = Transfer {
+                month: month.to_owned(),
+                bytes: 0,
+            };
+        }
+        self.transfer.bytes += bytes;
+    }
+
+    /// Remember a run, forgetting the oldest once there are too many.
+    pub fn record_run(&mut self, run: Run) {
+        if run.status == Status::Synced {
+            self.last_synced = Some(run.finished.clone());
+        }
+        self.history.push(run);
+        if self.history.len() > HISTORY {
+            self.history.drain(..self.history.len() - HISTORY);
+        }
+    }
+
+    /// The number of runs that have failed since the last that didn't.
+    pub fn failures(&self) -> usize {
+        self.history
+            .iter()
+            .rev()
+            .take_while(|run| run.status != Status::Synced)
+            .filter(|run| matches!(run.status, Status::Failed | Status::Unavailable))
+            .count()
+    }
+
+    /// The run before the most recent one that published metadata, to measure growth against.
+    pub fn previous_sync(&self) -> Option<&Run> {
+        self.history
+            .iter()
+            .rev()
+            .filter(|run| run.published.is_some())
+            .nth(1)
+    }
+}
+
+/// The current month, as `YYYY-MM` in UTC.
+pub fn current_month() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()[..7].to_owned()
+}
+
+/// The default location of the state file.
+///
+/// This follows the XDG base directory specification, falling back to the working directory.
+pub fn default_path() -> PathBuf {
+    let name = env!("CARGO_PKG_NAME");
+    if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        PathBuf::from(dir).join(name).join("state.json")
+    } else if let Some(home) = env::var_os("HOME") {
+        PathBuf::from(home)
+            .join(".local/state")
+            .join(name)
+            .join("state.json")
+    } else {
+        PathBuf::from(format!("{}-state.json", name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn monthly_transfer() {
+        let mut repo = RepoState::default();
+        repo.record_transfer("2024-01", 100);
+        repo.record_transfer("2024-01", 50);
+        assert_eq!(repo.month_to_date("2024-01"), 150);
+        assert_eq!(repo.month_to_date("2024-02"), 0);
+
+        repo.record_transfer("2024-02", 10);
+        assert_eq!(repo.month_to_date("2024-02"), 10);
+        assert_eq!(repo.month_to_date("2024-01"), 0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new("state").unwrap();
+        let path = dir.path().join("nested/state.json");
+        assert!(State::load(&path).unwrap().repos.is_empty());
+
+        let mut state = State::default();
+        state.repo("fedora").record_transfer("2024-01", 42);
+        for added in 0..40 {
+            let summary = Summary {
+                added,
+                ..Summary::default()
+            };
+            state.repo("fedora").record_run(Run::finished_now(
+                Status::Synced,
+                &summary,
+                &summary.published,
+            ));
+        }
+        state.save(&path).unwrap();
+
+        let mut loaded = State::load(&path).unwrap();
+        assert_eq!(loaded.repo("fedora").month_to_date("2024-01"), 42);
+        let history = &loaded.repo("fedora").history;
+        assert_eq!(history.len(), HISTORY);
+        assert_eq!(history[0].added, 10);
+        assert_eq!(history[HISTORY - 1].status, Status::Synced);
+        assert_eq!(current_month().len(), 7);
+    }
+
+    #[test]
+    fn retry_missing() {
+        let missing = |path: &str| Inconsistency {
+            path: path.to_owned(),
+            problem: "404 Not Found".to_owned(),
+            missing: true,
+        };
+        let mut repo = RepoState::default();
+        let summary = Summary {
+            inconsistencies: vec![missing("a.rpm"), missing("b.rpm")],
+            ..Summary::default()
+        };
+        repo.record_missing(&summary, true);
+        assert_eq!(repo.missing, vec!["a.rpm", "b.rpm"]);
+
+        // A run that fails part way only forgets the packages it downloaded
+        let summary = Summary {
+            added_files: vec!["a.rpm".to_owned()],
+            inconsistencies: vec![missing("c.rpm")],
+            ..Summary::default()
+        };
+        repo.record_missing(&summary, false);
+        assert_eq!(repo.missing, vec!["b.rpm", "c.rpm"]);
+
+        let summary = Summary {
+            inconsistencies: vec![missing("c.rpm")],
+            ..Summary::default()
+        };
+        repo.record_missing(&summary, true);
+        assert_eq!(repo.missing, vec!["c.rpm"]);
+    }
+
+    #[test]
+    fn failures_since_success() {
+        let mut repo = RepoState::default();
+        let summary = Summary::default();
+        for status in [
+            Status::Failed,
+            Status::Synced,
+            Status::Failed,
+            Status::OverQuota,
+            Status::Unavailable,
+        ] {
+            repo.record_run(Run::finished_now(status, &summary, &summary.published));
+        }
+        assert_eq!(repo.failures(), 2);
+        assert!(repo.previous_sync().is_none());
+        assert_eq!(repo.last_synced.as_ref(), Some(&repo.history[1].finished));
+    }
+
+    #[test]
+    fn previous_sync() {
+        let mut repo = RepoState::default();
+        for packages in [10, 12] {
+            let mut summary = Summary::default();
+            summary.published.insert(
+                "/srv/a".to_owned(),
+                Published {
+                    revision: None,
+                    packages,
+                    bytes: packages * 100,
+                },
+            );
+            summary.published.insert(
+                "/srv/b".to_owned(),
+                Published {
+                    revision: Some(1),
+                    packages: 1,
+                    bytes: 1,
+                },
+            );
+            repo.record_run(Run::finished_now(
+                Status::Synced,
+                &summary,
+                &summary.published,
+            ));
+        }
+        repo.record_run(Run::finished_now(
+            Status::Failed,
+            &Summary::default(),
+            &BTreeMap::new(),
+        ));
+
+        let previous = repo.previous_sync().unwrap().published.as_ref().unwrap();
+        assert_eq!(previous.packages, 11);
+        assert_eq!(previous.bytes, 1001);
+        assert_eq!(previous.revision, None);
+    }
+
+    #[test]
+    fn previous_sync_with_unchanged_variant() {
+        let published = |packages| Published {
+            revision: None,
+            packages,
+            bytes: packages * 100,
+        };
+        let mut repo = RepoState::default();
+        for (dest, packages) in [("/srv/a", 10), ("/srv/b", 20)] {
+            let mut summary = Summary::default();
+            summary
+                .published
+                .insert(dest.to_owned(), published(packages));
+            repo.published.extend(summary.published.clone());
+            repo.record_run(Run::finished_now(Status::Synced, &summary, &repo.published));
+        }
+
+        // Only the first variant changed, but the second is still published
+        let mut summary = Summary::default();
+        summary.published.insert("/srv/a".to_owned(), published(12));
+        repo.published.extend(summary.published.clone());
+        repo.record_run(Run::finished_now(Status::Synced, &summary, &repo.published));
+
+        assert_eq!(repo.history[2].published.as_ref().unwrap().packages, 32);
+        let previous = repo.previous_sync().unwrap().published.as_ref().unwrap();
+        assert_eq!(previous.packages, 30);
+        assert_eq!(previous.bytes, 3000);
+    }
+}
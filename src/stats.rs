@@ -0,0 +1,431 @@
+//! Counts of the changes made while synchronising a repository.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::package::Changelog;
+use crate::progress::{format_bytes, format_duration};
+use crate::state::Published;
+
+/// Shared counters for a single repository.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// The most bytes that may be downloaded.
+    limit: Option<u64>,
+    /// Bytes downloaded or set aside for downloads in progress.
+    reserved: AtomicU64,
+    /// The download budget shared with the other repositories in the run.
+    budget: Option<Stats>,
+    /// Whether the shared budget refused a download.
+    over_budget: AtomicBool,
+    added: AtomicU64,
+    removed: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    bytes_deleted: AtomicU64,
+    checksum_failures: AtomicU64,
+    quarantined: AtomicU64,
+    /// The paths of the packages downloaded.
+    added_files: Mutex<Vec<String>>,
+    /// The paths of the files removed.
+    removed_files: Mutex<Vec<String>>,
+    /// The packages that changed in each destination.
+    changes: Mutex<BTreeMap<String, Changelog>>,
+    /// Files the upstream metadata references but the upstream doesn't serve.
+    inconsistencies: Mutex<Vec<Inconsistency>>,
+    /// What was published to each destination.
+    published: Mutex<BTreeMap<String, Published>>,
+}
+
+impl Stats {
+    /// Count changes, stopping once `limit` bytes have been downloaded.
+    pub fn with_limit(limit: u64) -> Stats {
+        Stats {
+            counters: Arc::new(Counters {
+                limit: Some(limit),
+                ..Counters::default()
+            }),
+        }
+    }
+
+    /// Count changes, stopping once `limit` bytes have been downloaded, if given, or the
+    /// `budget` shared with other repositories is spent.
+    pub fn within(limit: Option<u64>, budget: &Stats) -> Stats {
+        Stats {
+            counters: Arc::new(Counters {
+                limit,
+                budget: Some(budget.clone()),
+                ..Counters::default()
+            }),
+        }
+    }
+
+    /// Check that neither the download limit nor the shared budget has been reached.
+    pub fn check_limit(&self) -> Result<(), LimitReached> {
+        if let Some(budget) = &self.counters.budget {
+            budget
+                .check_limit()
+                .map_err(|e| LimitReached { shared: true, ..e })?;
+        }
+        match self.counters.limit {
+            Some(limit) if self.counters.bytes_downloaded.load(Ordering::Relaxed) >= limit => {
+                Err(LimitReached {
+                    limit,
+                    shared: false,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The limit that stopped a download from being reserved, or else the tightest limit.
+    pub fn limit_reached(&self) -> LimitReached {
+        let budget = self.counters.budget.as_ref().and_then(Stats::limit);
+        match (self.counters.limit, budget) {
+            (_, Some(budget)) if self.counters.over_budget.load(Ordering::Relaxed) => {
+                LimitReached {
+                    limit: budget,
+                    shared: true,
+                }
+            }
+            (Some(limit), _) => LimitReached {
+                limit,
+                shared: false,
+            },
+            (None, budget) => LimitReached {
+                limit: budget.unwrap_or_default(),
+                shared: true,
+            },
+        }
+    }
+
+    /// The most bytes that may be downloaded, if limited.
+    pub fn limit(&self) -> Option<u64> {
+        self.counters.limit
+    }
+
+    /// Whether downloads are limited, by a limit of their own or a shared budget.
+    pub fn is_limited(&self) -> bool {
+        self.counters.limit.is_some() || self.counters.budget.is_some()
+    }
+
+    /// Set aside bytes for a download, returning false if they would exceed the limit or the
+    /// shared budget.
+    pub fn reserve(&self, bytes: u64) -> bool {
+        if let Some(limit) = self.counters.limit {
+            let reserved = self
+                .counters
+                .reserved
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+                    Some(reserved + bytes).filter(|total| *total <= limit)
+                })
+                .is_ok();
+            if !reserved {
+                self.counters.over_budget.store(false, Ordering::Relaxed);
+                return false;
+            }
+        }
+        if let Some(budget) = &self.counters.budget {
+            if !budget.reserve(bytes) {
+                self.release_own(bytes);
+                self.counters.over_budget.store(true, Ordering::Relaxed);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return bytes that were set aside but not downloaded.
+    pub fn release(&self, bytes: u64) {
+        self.release_own(bytes);
+        if let Some(budget) = &self.counters.budget {
+            budget.release(bytes);
+        }
+    }
+
+    /// Return bytes that were set aside against the limit of these counters alone.
+    fn release_own(&self, bytes: u64) {
+        let _ =
+            self.counters
+                .reserved
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+                    Some(reserved.saturating_sub(bytes))
+                });
+    }
+
+    /// Record a package that was downloaded.
+    pub fn added(&self, path: &str) {
+        self.counters.added.fetch_add(1, Ordering::Relaxed);
+        let mut files = self
+            .counters
+            .added_files
+            .lock()
+            .expect("Poisoned file list");
+        files.push(path.to_owned());
+    }
+
+    /// Record a file that was removed.
+    pub fn removed(&self, path: &str, bytes: u64) {
+        self.counters.removed.fetch_add(1, Ordering::Relaxed);
+        let mut files = self
+            .counters
+            .removed_files
+            .lock()
+            .expect("Poisoned file list");
+        files.push(path.to_owned());
+        self.counters
+            .bytes_deleted
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record bytes that were downloaded.
+    pub fn downloaded(&self, bytes: u64) {
+        self.counters
+            .bytes_downloaded
+            .fetch_add(bytes, Ordering::Relaxed);
+        if let Some(budget) = &self.counters.budget {
+            budget.downloaded(bytes);
+        }
+    }
+
+    /// Forget bytes that were recorded as downloaded, as the download they were part of was
+    /// abandoned to start again.
+    pub fn discarded(&self, bytes: u64) {
+        let _ = self.counters.bytes_downloaded.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |downloaded| Some(downloaded.saturating_sub(bytes)),
+        );
+        if let Some(budget) = &self.counters.budget {
+            budget.discarded(bytes);
+        }
+    }
+
+    /// Record a file that did not match its size or checksum.
+    pub fn checksum_failed(&self) {
+        self.counters
+            .checksum_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the packages that changed in a destination.
+    pub fn changed(&self, dest: &str, changelog: Changelog) {
+        let mut changes = self.counters.changes.lock().expect("Poisoned changelog");
+        changes.insert(dest.to_owned(), changelog);
+    }
+
+    /// Record what was published to a destination.
+    pub fn published(&self, dest: &str, published: Published) {
+        let mut all = self
+            .counters
+            .published
+            .lock()
+            .expect("Poisoned publications");
+        all.insert(dest.to_owned(), published);
+    }
+
+    /// Record a file that the upstream metadata references but the upstream doesn't serve.
+    pub fn inconsistent(&self, path: &str, problem: &str, missing: bool) {
+        let mut inconsistencies = self
+            .counters
+            .inconsistencies
+            .lock()
+            .expect("Poisoned inconsistencies");
+        inconsistencies.push(Inconsistency {
+            path: path.to_owned(),
+            problem: problem.to_owned(),
+            missing,
+        });
+    }
+
+    /// Forget the inconsistencies found so far, before synchronising again.
+    pub fn clear_inconsistencies(&self) {
+        let mut inconsistencies = self
+            .counters
+            .inconsistencies
+            .lock()
+            .expect("Poisoned inconsistencies");
+        inconsistencies.clear();
+    }
+
+    /// Record a downloaded file that was moved into quarantine.
+    pub fn quarantined(&self) {
+        self.counters.quarantined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Summarise the counts so far.
+    pub fn summary(&self, duration: Duration) -> Summary {
+        let counters = &self.counters;
+        let sorted = |files: &Mutex<Vec<String>>| {
+            let mut files = files.lock().expect("Poisoned file list").clone();
+            files.sort();
+            files
+        };
+        Summary {
+            added: counters.added.load(Ordering::Relaxed),
+            removed: counters.removed.load(Ordering::Relaxed),
+            bytes_downloaded: counters.bytes_downloaded.load(Ordering::Relaxed),
+            bytes_deleted: counters.bytes_deleted.load(Ordering::Relaxed),
+            checksum_failures: counters.checksum_failures.load(Ordering::Relaxed),
+            quarantined: counters.quarantined.load(Ordering::Relaxed),
+            duration: duration.as_secs_f64(),
+            added_files: sorted(&counters.added_files),
+            removed_files: sorted(&counters.removed_files),
+            changes: counters.changes.lock().expect("Poisoned changelog").clone(),
+            inconsistencies: {
+                let mut inconsistencies = counters
+                    .inconsistencies
+                    .lock()
+                    .expect("Poisoned inconsistencies")
+                    .clone();
+                inconsistencies.sort_by(|a, b| a.path.cmp(&b.path));
+                inconsistencies
+            },
+            published: counters
+                .published
+                .lock()
+                .expect("Poisoned publications")
+                .clone(),
+        }
+    }
+}
+
+/// The download limit was reached.
+#[derive(Debug)]
+pub struct LimitReached {
+    /// The limit in bytes.
+    pub limit: u64,
+    /// Whether the limit is the budget shared by every repository in the run.
+    pub shared: bool,
+}
+
+impl std::error::Error for LimitReached {}
+
+impl Display for LimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer limit of {} reached", format_bytes(self.limit))
+    }
+}
+
+/// A file that the upstream metadata references but the upstream doesn't serve, as happens while
+/// the upstream is being updated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Inconsistency {
+    /// The path of the file within the repository.
+    pub path: String,
+    /// What was wrong with the file.
+    pub problem: String,
+    /// Whether the file was missing, rather than not matching its checksum.
+    pub missing: bool,
+}
+
+/// The changes made to a repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Summary {
+    /// Packages downloaded.
+    pub added: u64,
+    /// Files removed.
+    pub removed: u64,
+    /// Bytes downloaded.
+    pub bytes_downloaded: u64,
+    /// Bytes removed.
+    pub bytes_deleted: u64,
+    /// Files that failed their size or checksum.
+    pub checksum_failures: u64,
+    /// Downloaded files that were moved into quarantine.
+    pub quarantined: u64,
+    /// Wall-clock time in seconds.
+    pub duration: f64,
+    /// The paths of the packages downloaded.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added_files: Vec<String>,
+    /// The paths of the files removed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_files: Vec<String>,
+    /// The packages that changed in each destination.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub changes: BTreeMap<String, Changelog>,
+    /// Files the upstream metadata references but the upstream doesn't serve.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inconsistencies: Vec<Inconsistency>,
+    /// What was published to each destination.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub published: BTreeMap<String, Published>,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} downloaded, {} deleted, {} checksum failures, {} quarantined \
+             in {}",
+            self.added,
+            self.removed,
+            format_bytes(self.bytes_downloaded),
+            format_bytes(self.bytes_deleted),
+            self.checksum_failures,
+            self.quarantined,
+            format_duration(Duration::from_secs_f64(self.duration))
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn download_limit() {
+        let stats = Stats::with_limit(100);
+        stats.downloaded(60);
+        assert!(stats.check_limit().is_ok());
+        stats.downloaded(40);
+        assert_eq!(stats.check_limit().unwrap_err().limit, 100);
+        assert!(Stats::default().check_limit().is_ok());
+    }
+
+    #[test]
+    fn reserve_within_limit() {
+        let stats = Stats::with_limit(100);
+        assert!(stats.reserve(70));
+        assert!(!stats.reserve(40));
+        assert!(stats.reserve(30));
+        stats.release(50);
+        assert!(stats.reserve(40));
+        assert!(Stats::default().reserve(u64::MAX));
+    }
+
+    #[test]
+    fn shared_budget() {
+        let budget = Stats::with_limit(100);
+        let (a, b) = (
+            Stats::within(None, &budget),
+            Stats::within(Some(50), &budget),
+        );
+        assert!(a.reserve(60));
+        assert!(b.reserve(30));
+        // Both fit their own limits, but not the budget they share
+        assert!(!a.reserve(20));
+        assert!(!b.reserve(20));
+        assert!(b.limit_reached().shared);
+
+        a.release(60);
+        assert!(b.reserve(20));
+        assert!(!b.reserve(1));
+        assert!(!b.limit_reached().shared);
+
+        a.downloaded(50);
+        b.downloaded(50);
+        assert!(budget.check_limit().is_err());
+        assert!(a.check_limit().unwrap_err().shared);
+        assert!(b.check_limit().unwrap_err().shared);
+    }
+}
@@ -0,0 +1,460 @@
+//! Export of spans and metrics to an OpenTelemetry collector.
+//!
+//! Spans are sent as OTLP over HTTP with JSON bodies, so that each repository
+//! that is synchronised appears as a trace. Every request to an upstream is a
+//! `request` span, from which the response latency and bytes received are
+//! also aggregated into metrics for each mirror.
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// How often collected spans and metrics are sent.
+const INTERVAL: Duration = Duration::from_secs(10);
+/// The upper bounds of the latency histogram buckets, in milliseconds.
+const LATENCY_BOUNDS: &[f64] = &[
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// The exporter, once it has been started.
+static EXPORTER: OnceLock<Arc<Exporter>> = OnceLock::new();
+
+/// Sends collected spans and metrics to a collector.
+struct Exporter {
+    client: Client,
+    endpoint: Url,
+    started: SystemTime,
+    spans: Mutex<Vec<Value>>,
+    mirrors: Mutex<BTreeMap<String, Mirror>>,
+}
+
+/// The requests made to one mirror so far.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Mirror {
+    requests: u64,
+    latency_sum: f64,
+    buckets: Vec<u64>,
+    bytes: u64,
+}
+
+impl Mirror {
+    /// Count a request that got a response after `latency` milliseconds.
+    fn record(&mut self, latency: Option<f64>, bytes: Option<u64>) {
+        if let Some(latency) = latency {
+            if self.buckets.is_empty() {
+                self.buckets = vec![0; LATENCY_BOUNDS.len() + 1];
+            }
+            let bucket = LATENCY_BOUNDS
+                .iter()
+                .position(|bound| latency <= *bound)
+                .unwrap_or(LATENCY_BOUNDS.len());
+            self.buckets[bucket] += 1;
+            self.requests += 1;
+            self.latency_sum += latency;
+        }
+        self.bytes += bytes.unwrap_or(0);
+    }
+}
+
+/// Start exporting to an OTLP/HTTP endpoint, returning the layer that collects spans.
+///
+/// Collected spans and metrics are sent periodically, so this must be called from within the
+/// runtime.
+pub fn start(endpoint: Url) -> Telemetry {
+    let exporter = Arc::new(Exporter::new(endpoint));
+    if EXPORTER.set(exporter.clone()).is_ok() {
+        tokio::spawn(async {
+            loop {
+                tokio::time::delay_for(INTERVAL).await;
+                flush().await;
+            }
+        });
+    }
+    Telemetry { exporter }
+}
+
+/// Send any spans and metrics that have been collected.
+pub async fn flush() {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let spans = std::mem::take(&mut *exporter.spans.lock().unwrap());
+    if !spans.is_empty() {
+        exporter.send("v1/traces", traces(spans)).await;
+    }
+    let mirrors = exporter.mirrors.lock().unwrap().clone();
+    if !mirrors.is_empty() {
+        let body = metrics(&mirrors, exporter.started, SystemTime::now());
+        exporter.send("v1/metrics", body).await;
+    }
+}
+
+impl Exporter {
+    /// Export to a collector, whose URL must end in a slash.
+    fn new(endpoint: Url) -> Exporter {
+        Exporter {
+            client: Client::new(),
+            endpoint,
+            started: SystemTime::now(),
+            spans: Mutex::new(Vec::new()),
+            mirrors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Post a request to the collector.
+    ///
+    /// Failures go straight to standard error, as logging them would create more spans.
+    async fn send(&self, path: &str, body: Value) {
+        let url = match self.endpoint.join(path) {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Invalid OTLP endpoint {}: {}", self.endpoint, e);
+                return;
+            }
+        };
+        let response = self
+            .client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => eprintln!("{} responded with {}", url, response.status()),
+            Err(e) => eprintln!("Could not export to {}: {}", url, e),
+        }
+    }
+}
+
+/// Collects the spans of yumclone for export.
+pub struct Telemetry {
+    exporter: Arc<Exporter>,
+}
+
+/// A span that is being collected.
+struct Collected {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+    attributes: Fields,
+}
+
+/// The fields recorded on a span.
+#[derive(Debug, Default)]
+struct Fields(BTreeMap<&'static str, Value>);
+
+impl Fields {
+    /// The value of a field, if it is a string.
+    fn str(&self, name: &str) -> Option<&str> {
+        self.0.get(name).and_then(Value::as_str)
+    }
+}
+
+impl Visit for Fields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), json!(format!("{:?}", value)));
+    }
+}
+
+/// A random identifier for a trace or span.
+fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    // Zero is not a valid identifier
+    hasher.finish().max(1)
+}
+
+impl<S> Layer<S> for Telemetry
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        if !span.metadata().target().starts_with(env!("CARGO_PKG_NAME")) {
+            return;
+        }
+        let parent = span.scope().skip(1).find_map(|parent| {
+            parent
+                .extensions()
+                .get::<Collected>()
+                .map(|collected| (collected.trace_id, collected.span_id))
+        });
+        let mut attributes = Fields::default();
+        attrs.record(&mut attributes);
+        let trace_id = parent.map_or_else(
+            || (u128::from(random_id()) << 64) | u128::from(random_id()),
+            |(trace_id, _)| trace_id,
+        );
+        span.extensions_mut().insert(Collected {
+            trace_id,
+            span_id: random_id(),
+            parent_id: parent.map(|(_, span_id)| span_id),
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(collected) = span.extensions_mut().get_mut::<Collected>() {
+                values.record(&mut collected.attributes);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let exporter = &self.exporter;
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let extensions = span.extensions();
+        let collected = match extensions.get::<Collected>() {
+            Some(collected) => collected,
+            None => return,
+        };
+        if span.name() == "request" {
+            if let Some(mirror) = collected.attributes.str("mirror") {
+                let attributes = &collected.attributes.0;
+                exporter
+                    .mirrors
+                    .lock()
+                    .unwrap()
+                    .entry(mirror.to_owned())
+                    .or_default()
+                    .record(
+                        attributes.get("latency_ms").and_then(Value::as_f64),
+                        attributes.get("bytes").and_then(Value::as_u64),
+                    );
+            }
+        }
+        let otlp = span_json(span.name(), collected, SystemTime::now());
+        exporter.spans.lock().unwrap().push(otlp);
+    }
+}
+
+/// Nanoseconds since the epoch, as OTLP encodes them in JSON.
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// An OTLP attribute.
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// The resource and scope that every span and metric is reported from.
+fn resource() -> (Value, Value) {
+    (
+        json!({
+            "attributes": [attribute("service.name", &json!(env!("CARGO_PKG_NAME")))]
+        }),
+        json!({ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") }),
+    )
+}
+
+/// An OTLP span.
+fn span_json(name: &str, collected: &Collected, end: SystemTime) -> Value {
+    let attributes: Vec<Value> = collected
+        .attributes
+        .0
+        .iter()
+        .filter(|(key, _)| **key != "error")
+        .map(|(key, value)| attribute(key, value))
+        .collect();
+    // Requests are made to upstreams, everything else is internal
+    let kind = if name == "request" { 3 } else { 1 };
+    let status = match collected.attributes.str("error") {
+        Some(error) => json!({ "code": 2, "message": error }),
+        None => json!({}),
+    };
+    let mut span = json!({
+        "traceId": format!("{:032x}", collected.trace_id),
+        "spanId": format!("{:016x}", collected.span_id),
+        "name": name,
+        "kind": kind,
+        "startTimeUnixNano": nanos(collected.start),
+        "endTimeUnixNano": nanos(end),
+        "attributes": attributes,
+        "status": status,
+    });
+    if let Some(parent_id) = collected.parent_id {
+        span["parentSpanId"] = json!(format!("{:016x}", parent_id));
+    }
+    span
+}
+
+/// A request exporting spans.
+fn traces(spans: Vec<Value>) -> Value {
+    let (resource, scope) = resource();
+    json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{ "scope": scope, "spans": spans }],
+        }]
+    })
+}
+
+/// A request exporting the latency and bytes received for each mirror since `start`.
+fn metrics(mirrors: &BTreeMap<String, Mirror>, start: SystemTime, now: SystemTime) -> Value {
+    let (resource, scope) = resource();
+    let point = |mirror: &str| {
+        json!({
+            "attributes": [attribute("mirror", &json!(mirror))],
+            "startTimeUnixNano": nanos(start),
+            "timeUnixNano": nanos(now),
+        })
+    };
+    let latency: Vec<Value> = mirrors
+        .iter()
+        .filter(|(_, stats)| stats.requests > 0)
+        .map(|(mirror, stats)| {
+            let mut point = point(mirror);
+            point["count"] = json!(stats.requests.to_string());
+            point["sum"] = json!(stats.latency_sum);
+            point["bucketCounts"] =
+                json!(stats.buckets.iter().map(u64::to_string).collect::<Vec<_>>());
+            point["explicitBounds"] = json!(LATENCY_BOUNDS);
+            point
+        })
+        .collect();
+    let bytes: Vec<Value> = mirrors
+        .iter()
+        .map(|(mirror, stats)| {
+            let mut point = point(mirror);
+            point["asInt"] = json!(stats.bytes.to_string());
+            point
+        })
+        .collect();
+    // Cumulative temporality
+    json!({
+        "resourceMetrics": [{
+            "resource": resource,
+            "scopeMetrics": [{
+                "scope": scope,
+                "metrics": [
+                    {
+                        "name": "yumclone.upstream.latency",
+                        "description": "Time for an upstream to respond to a request",
+                        "unit": "ms",
+                        "histogram": { "aggregationTemporality": 2, "dataPoints": latency },
+                    },
+                    {
+                        "name": "yumclone.upstream.bytes",
+                        "description": "Bytes received from an upstream",
+                        "unit": "By",
+                        "sum": {
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true,
+                            "dataPoints": bytes,
+                        },
+                    },
+                ],
+            }],
+        }]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::registry::Registry;
+
+    #[test]
+    fn collect_spans() {
+        let exporter = Arc::new(Exporter::new(Url::parse("http://localhost:4318/").unwrap()));
+        let subscriber = Registry::default().with(Telemetry {
+            exporter: exporter.clone(),
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            let _repo = info_span!("repo", repo = "fedora").entered();
+            let request = info_span!(
+                "request",
+                mirror = "https://dl.example.com",
+                latency_ms = 30.0,
+                bytes = tracing::field::Empty,
+                error = tracing::field::Empty,
+            );
+            request.record("bytes", 10);
+            request.record("error", "connection reset");
+            drop(request);
+        });
+
+        let spans = exporter.spans.lock().unwrap().clone();
+        assert_eq!(spans.len(), 2);
+        let (request, repo) = (&spans[0], &spans[1]);
+        assert_eq!(request["name"], "request");
+        assert_eq!(request["kind"], 3);
+        assert_eq!(request["traceId"], repo["traceId"]);
+        assert_eq!(request["parentSpanId"], repo["spanId"]);
+        assert!(repo.get("parentSpanId").is_none());
+        assert_eq!(request["status"]["code"], 2);
+        assert_eq!(
+            repo["attributes"][0],
+            json!({ "key": "repo", "value": { "stringValue": "fedora" } })
+        );
+
+        let mirrors = exporter.mirrors.lock().unwrap().clone();
+        let body = metrics(&mirrors, UNIX_EPOCH, UNIX_EPOCH);
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        let latency = &metrics[0]["histogram"]["dataPoints"][0];
+        assert_eq!(
+            latency["attributes"][0]["value"]["stringValue"],
+            "https://dl.example.com"
+        );
+        assert_eq!(latency["count"], "1");
+        assert_eq!(latency["bucketCounts"][3], "1");
+        assert_eq!(metrics[1]["sum"]["dataPoints"][0]["asInt"], "10");
+    }
+}
@@ -0,0 +1,385 @@
+//! Limiting the rate of downloads according to the time of day.
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::units::deserialize_rate;
+
+/// How often a preempted transfer checks whether it may continue.
+const PREEMPT_POLL: Duration = Duration::from_millis(100);
+
+/// The bandwidth available to downloads.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Bandwidth {
+    /// The rate in bytes per second used outside of any scheduled period, unlimited if unset.
+    #[serde(default, deserialize_with = "deserialize_bandwidth")]
+    pub default: Option<u64>,
+    /// Periods of the day with a different rate.
+    #[serde(default)]
+    pub schedule: Vec<Period>,
+}
+
+/// A period of each day with its own rate.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Period {
+    /// The local time the period starts.
+    pub from: TimeOfDay,
+    /// The local time the period ends, which may be earlier than the start to span midnight.
+    pub to: TimeOfDay,
+    /// The rate in bytes per second during the period, unlimited if unset.
+    #[serde(default, deserialize_with = "deserialize_bandwidth")]
+    pub rate: Option<u64>,
+}
+
+/// Parse an optional rate, rejecting a rate of zero rather than treating it as unlimited.
+fn deserialize_bandwidth<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match deserialize_rate(deserializer)? {
+        Some(0) => Err(de::Error::custom(
+            "A bandwidth of 0 would stop every download; leave the rate out for no limit",
+        )),
+        rate => Ok(rate),
+    }
+}
+
+/// A time of day, in minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    /// Parse a time written as `HH:MM`.
+    pub fn parse(time: &str) -> Option<TimeOfDay> {
+        let mut parts = time.trim().splitn(2, ':');
+        let hours: u32 = parts.next()?.parse().ok()?;
+        let minutes: u32 = parts.next()?.parse().ok()?;
+        if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+            return None;
+        }
+        Some(TimeOfDay(hours * 60 + minutes))
+    }
+
+    /// The current local time of day.
+    pub fn now() -> TimeOfDay {
+        // SAFETY: `localtime_r` only writes to the `tm` it is given.
+        unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&now, &mut tm).is_null() {
+                return TimeOfDay(0);
+            }
+            TimeOfDay(tm.tm_hour as u32 * 60 + tm.tm_min as u32)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TimeOfDay, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        TimeOfDay::parse(&time)
+            .ok_or_else(|| de::Error::custom(format!("Invalid time '{}' (expected HH:MM)", time)))
+    }
+}
+
+impl Period {
+    /// Check whether a time falls within the period.
+    fn contains(&self, time: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+impl Bandwidth {
+    /// The rate in bytes per second at a time of day, or `None` if unlimited.
+    pub fn rate_at(&self, time: TimeOfDay) -> Option<u64> {
+        match self.schedule.iter().find(|period| period.contains(time)) {
+            Some(period) => period.rate,
+            None => self.default,
+        }
+    }
+
+    /// Whether any limit applies at all.
+    pub fn is_limited(&self) -> bool {
+        self.default.is_some() || self.schedule.iter().any(|period| period.rate.is_some())
+    }
+}
+
+/// A token bucket that refills at the scheduled rate.
+#[derive(Debug)]
+pub struct Throttle {
+    bandwidth: Bandwidth,
+    /// Whether transfers are preempted even while the bandwidth is unlimited.
+    preemptive: bool,
+    bucket: Mutex<Bucket>,
+    /// The number of transfers in progress at each priority.
+    active: Mutex<BTreeMap<i32, usize>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be sent without waiting, negative when in debt.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    /// Create a throttle for a bandwidth schedule.
+    ///
+    /// Transfers are preempted by those of higher priorities while the bandwidth is limited, or
+    /// always if `preemptive`.
+    pub fn new(bandwidth: Bandwidth, preemptive: bool) -> Throttle {
+        Throttle {
+            bandwidth,
+            preemptive,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+            active: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a transfer at a priority until the returned guard is dropped.
+    pub fn start(&self, priority: i32) -> Transfer<'_> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        *active.entry(priority).or_default() += 1;
+        Transfer {
+            throttle: self,
+            priority,
+        }
+    }
+
+    /// Check whether a transfer with a higher priority is in progress.
+    fn preempted(&self, priority: i32) -> bool {
+        let active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        active
+            .range(priority.saturating_add(1)..)
+            .any(|(_, count)| *count > 0)
+    }
+
+    /// Take bytes from the bucket, returning how long to wait before using them.
+    ///
+    /// The rate is looked up every time so that a change of period takes effect mid-transfer.
+    fn take(&self, bytes: u64, time: TimeOfDay, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let rate = match self.bandwidth.rate_at(time) {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => {
+                bucket.tokens = 0.0;
+                bucket.updated = now;
+                return Duration::default();
+            }
+        };
+
+        // Allow at most a second of burst.
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::default()
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until bytes may be transferred.
+    ///
+    /// While the bandwidth is limited, or always if the throttle is preemptive, transfers wait for
+    /// every transfer of a higher priority to finish.
+    pub async fn acquire(&self, bytes: u64, priority: i32) {
+        while (self.preemptive || self.bandwidth.rate_at(TimeOfDay::now()).is_some())
+            && self.preempted(priority)
+        {
+            tokio::time::delay_for(PREEMPT_POLL).await;
+        }
+        let wait = self.take(bytes, TimeOfDay::now(), Instant::now());
+        if wait > Duration::default() {
+            tokio::time::delay_for(wait).await;
+        }
+    }
+}
+
+/// A transfer in progress, which stops preempting others when dropped.
+pub struct Transfer<'t> {
+    throttle: &'t Throttle,
+    priority: i32,
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        let mut active = self
+            .throttle
+            .active
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = active.get_mut(&self.priority) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The priority of the repository being downloaded by the current task.
+    static PRIORITY: i32;
+}
+
+/// Run a future with downloads at a priority.
+pub async fn with_priority<F: Future>(priority: i32, future: F) -> F::Output {
+    PRIORITY.scope(priority, future).await
+}
+
+/// The priority of downloads made by the current task.
+pub fn current_priority() -> i32 {
+    PRIORITY.try_with(|priority| *priority).unwrap_or(0)
+}
+
+/// The throttle shared by every download.
+static THROTTLE: OnceLock<Throttle> = OnceLock::new();
+
+/// Limit every download to a bandwidth schedule.
+///
+/// In daemon mode, downloads of higher priorities preempt the rest even if the bandwidth isn't
+/// limited, so that repositories such as security updates get all of it.
+pub fn install(bandwidth: Bandwidth, daemon: bool) {
+    if daemon || bandwidth.is_limited() {
+        let _ = THROTTLE.set(Throttle::new(bandwidth, daemon));
+    }
+}
+
+/// Record a transfer at a priority, if downloads are limited or preemptive.
+pub fn start(priority: i32) -> Option<Transfer<'static>> {
+    THROTTLE.get().map(|throttle| throttle.start(priority))
+}
+
+/// Wait until bytes may be downloaded at a priority under the installed schedule.
+pub async fn acquire(bytes: u64, priority: i32) {
+    if let Some(throttle) = THROTTLE.get() {
+        throttle.acquire(bytes, priority).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn office_hours() -> Bandwidth {
+        Bandwidth {
+            default: Some(2 * 1024 * 1024),
+            schedule: vec![Period {
+                from: TimeOfDay::parse("22:00").unwrap(),
+                to: TimeOfDay::parse("06:00").unwrap(),
+                rate: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn parse_time() {
+        assert_eq!(TimeOfDay::parse("06:30"), Some(TimeOfDay(390)));
+        assert_eq!(TimeOfDay::parse("24:00"), Some(TimeOfDay(1440)));
+        assert!(TimeOfDay::parse("25:00").is_none());
+        assert!(TimeOfDay::parse("noon").is_none());
+    }
+
+    #[test]
+    fn scheduled_rate() {
+        let bandwidth = office_hours();
+        let at = |time| bandwidth.rate_at(TimeOfDay::parse(time).unwrap());
+        assert_eq!(at("23:15"), None);
+        assert_eq!(at("02:00"), None);
+        assert_eq!(at("06:00"), Some(2 * 1024 * 1024));
+        assert_eq!(at("12:00"), Some(2 * 1024 * 1024));
+    }
+
+    #[test]
+    fn reject_zero_rate() {
+        let bandwidth: Bandwidth =
+            toml::from_str("default = \"2MiB/s\"\n[[schedule]]\nfrom = \"22:00\"\nto = \"06:00\"")
+                .unwrap();
+        assert_eq!(bandwidth.rate_at(TimeOfDay::parse("23:00").unwrap()), None);
+
+        for invalid in [
+            "default = 0",
+            "default = \"0MiB/s\"",
+            "[[schedule]]\nfrom = \"09:00\"\nto = \"17:00\"\nrate = 0",
+        ] {
+            assert!(
+                toml::from_str::<Bandwidth>(invalid).is_err(),
+                "'{}' was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn throttle_waits() {
+        let start = Instant::now();
+        let throttle = Throttle::new(
+            Bandwidth {
+                default: Some(1000),
+                schedule: vec![],
+            },
+            false,
+        );
+        let noon = TimeOfDay::parse("12:00").unwrap();
+
+        assert_eq!(throttle.take(500, noon, start), Duration::from_millis(500));
+        let later = start + Duration::from_secs(2);
+        assert_eq!(throttle.take(500, noon, later), Duration::default());
+
+        let night = TimeOfDay::parse("23:00").unwrap();
+        let throttle = Throttle::new(office_hours(), false);
+        assert_eq!(throttle.take(1 << 30, night, start), Duration::default());
+    }
+
+    #[test]
+    fn preempt_lower_priority() {
+        let throttle = Throttle::new(office_hours(), false);
+        let low = throttle.start(0);
+        assert!(!throttle.preempted(0));
+
+        let high = throttle.start(10);
+        assert!(throttle.preempted(0));
+        assert!(!throttle.preempted(10));
+
+        drop(high);
+        assert!(!throttle.preempted(0));
+        drop(low);
+    }
+
+    #[tokio::test]
+    async fn acquire_preempted() {
+        let wait = PREEMPT_POLL * 3;
+        let throttle = Throttle::new(Bandwidth::default(), true);
+        let high = throttle.start(10);
+
+        // Unlimited, but the lower priority still waits for the higher one to finish
+        assert!(tokio::time::timeout(wait, throttle.acquire(1, 0))
+            .await
+            .is_err());
+        tokio::time::timeout(wait, throttle.acquire(1, 10))
+            .await
+            .unwrap();
+        drop(high);
+        tokio::time::timeout(wait, throttle.acquire(1, 0))
+            .await
+            .unwrap();
+
+        // Outside of daemon mode, nothing is preempted while the bandwidth is unlimited
+        let throttle = Throttle::new(Bandwidth::default(), false);
+        let _high = throttle.start(10);
+        tokio::time::timeout(wait, throttle.acquire(1, 0))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! Limits on how long connections and transfers may take.
+//!
+//! Connecting and waiting for data fail quickly by default, while whole files
+//! may take as long as they need unless a limit is configured. Transfers that
+//! stay below a minimum speed for a while can also be aborted, like curl's
+//! `--speed-limit`. Limits can be set for every repository in the main
+//! configuration, and overridden for a single repository.
+
+use reqwest::Url;
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::progress::format_bytes;
+use crate::units::{deserialize_duration, deserialize_rate};
+
+/// How long to wait to connect, unless configured.
+const CONNECT: Duration = Duration::from_secs(30);
+/// How long to wait for data, unless configured.
+const READ: Duration = Duration::from_secs(60);
+/// How long a transfer must stay below the minimum speed, unless configured.
+const LOW_SPEED_TIME: Duration = Duration::from_secs(60);
+
+/// The time limits of transfers from a repository.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct Timeouts {
+    /// How long to wait to connect to a server.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    connect: Option<Duration>,
+    /// How long a request may wait without receiving any data.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    read: Option<Duration>,
+    /// How long a whole file may take to download.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    file: Option<Duration>,
+    /// The speed in bytes per second below which a transfer is aborted.
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    low_speed_limit: Option<u64>,
+    /// How long a transfer may stay below the minimum speed.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    low_speed_time: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Fill in the limits that aren't set from defaults.
+    pub fn or(self, defaults: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(defaults.connect),
+            read: self.read.or(defaults.read),
+            file: self.file.or(defaults.file),
+            low_speed_limit: self.low_speed_limit.or(defaults.low_speed_limit),
+            low_speed_time: self.low_speed_time.or(defaults.low_speed_time),
+        }
+    }
+
+    /// Whether any limit is set.
+    pub fn is_set(&self) -> bool {
+        *self != Timeouts::default()
+    }
+
+    /// How long to wait to connect to a server.
+    pub fn connect(&self) -> Duration {
+        self.connect.unwrap_or(CONNECT)
+    }
+
+    /// How long a request may wait without receiving any data.
+    pub fn read(&self) -> Duration {
+        self.read.unwrap_or(READ)
+    }
+
+    /// How long a whole file may take to download, if it is limited.
+    pub fn file(&self) -> Option<Duration> {
+        self.file
+    }
+
+    /// Start monitoring the speed of a transfer, if a minimum speed is set.
+    pub fn low_speed(&self) -> Option<LowSpeed> {
+        let limit = self.low_speed_limit.filter(|&limit| limit > 0)?;
+        Some(LowSpeed {
+            limit,
+            time: self.low_speed_time.unwrap_or(LOW_SPEED_TIME),
+            start: Instant::now(),
+            bytes: 0,
+            paused: Duration::default(),
+        })
+    }
+}
+
+/// Watches the average speed of a transfer over successive periods.
+#[derive(Debug)]
+pub struct LowSpeed {
+    limit: u64,
+    time: Duration,
+    start: Instant,
+    bytes: u64,
+    paused: Duration,
+}
+
+impl LowSpeed {
+    /// The length of each period over which the speed is averaged.
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Exclude time spent waiting on anything other than the server.
+    pub fn pause(&mut self, paused: Duration) {
+        self.paused += paused;
+    }
+
+    /// Record received bytes, returning the average speed if it fell below the limit over a
+    /// whole period.
+    pub fn record(&mut self, bytes: u64, now: Instant) -> Option<u64> {
+        self.bytes += bytes;
+        let elapsed = now
+            .duration_since(self.start)
+            .checked_sub(self.paused)
+            .unwrap_or_default();
+        if elapsed < self.time {
+            return None;
+        }
+        let speed = (self.bytes as f64 / elapsed.as_secs_f64()) as u64;
+        self.start = now;
+        self.bytes = 0;
+        self.paused = Duration::default();
+        Some(speed).filter(|&speed| speed < self.limit)
+    }
+
+    /// Describe a transfer that was too slow.
+    pub fn too_slow(&self, url: &Url, speed: u64) -> TooSlow {
+        TooSlow {
+            url: url.clone(),
+            speed,
+            limit: self.limit,
+            time: self.time,
+        }
+    }
+}
+
+/// A transfer that stayed below the minimum speed.
+#[derive(Debug)]
+pub struct TooSlow {
+    url: Url,
+    speed: u64,
+    limit: u64,
+    time: Duration,
+}
+
+impl std::error::Error for TooSlow {}
+
+impl Display for TooSlow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transfer from {} averaged {}/s over {}, below the minimum of {}/s",
+            self.url,
+            format_bytes(self.speed),
+            humantime::format_duration(self.time),
+            format_bytes(self.limit)
+        )
+    }
+}
+
+tokio::task_local! {
+    /// The limits of the repository being downloaded by the current task.
+    static TIMEOUTS: Timeouts;
+}
+
+/// Run a future with transfers limited by a repository's timeouts.
+pub async fn scope<F: Future>(timeouts: Timeouts, future: F) -> F::Output {
+    TIMEOUTS.scope(timeouts, future).await
+}
+
+/// The limits of transfers made by the current task.
+pub fn current() -> Timeouts {
+    TIMEOUTS.try_with(|timeouts| *timeouts).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repo_overrides_defaults() {
+        let defaults: Timeouts = toml::from_str("connect = \"10s\"\nfile = \"2h\"").unwrap();
+        let repo: Timeouts = toml::from_str("read = \"5m\"\nfile = \"30m\"").unwrap();
+        let timeouts = repo.or(defaults);
+        assert_eq!(timeouts.connect(), Duration::from_secs(10));
+        assert_eq!(timeouts.read(), Duration::from_secs(300));
+        assert_eq!(timeouts.file(), Some(Duration::from_secs(1800)));
+
+        assert_eq!(Timeouts::default().connect(), CONNECT);
+        assert_eq!(Timeouts::default().file(), None);
+        assert!(toml::from_str::<Timeouts>("read = \"soon\"").is_err());
+    }
+
+    #[test]
+    fn low_speed_periods() {
+        assert!(Timeouts::default().low_speed().is_none());
+        let timeouts: Timeouts = toml::from_str("low_speed_limit = \"10KB\"").unwrap();
+        let mut low_speed = timeouts.low_speed().unwrap();
+        assert_eq!(low_speed.time(), LOW_SPEED_TIME);
+
+        let start = low_speed.start;
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(low_speed.record(100_000, at(30)), None);
+        // 1MB over the first minute is fast enough
+        assert_eq!(low_speed.record(900_000, at(60)), None);
+        // Only 60KB over the next is too slow
+        assert_eq!(low_speed.record(60_000, at(120)), Some(1000));
+        // Time spent waiting elsewhere isn't counted
+        low_speed.pause(Duration::from_secs(50));
+        assert_eq!(low_speed.record(500_000, at(180)), None);
+        assert_eq!(low_speed.record(1_000_000, at(190)), None);
+    }
+}
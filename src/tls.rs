@@ -0,0 +1,217 @@
+//! TLS configuration for connections to upstream mirrors.
+//!
+//! Certificates are verified against the usual web roots. A repository can
+//! also pin the SHA-256 hashes of the certificates or public keys that its
+//! mirrors may present, in the `sha256//<base64>` form used by curl's
+//! `--pinnedpubkey`, in which case the handshake is refused unless the
+//! server's certificate matches one of them.
+//!
+//! The TLS backend is selected at build time. The `native-tls` feature uses
+//! the platform's TLS library (OpenSSL on Linux), while the `rustls` feature
+//! uses rustls, which is also needed to check pins. If both are enabled,
+//! connections without pins use the platform's library.
+
+// Pins are only checked with rustls
+#![cfg_attr(not(feature = "rustls"), allow(dead_code))]
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either the `native-tls` or `rustls` feature must be enabled");
+
+use failure::bail;
+use reqwest::ClientBuilder;
+#[cfg(feature = "rustls")]
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+#[cfg(feature = "rustls")]
+use webpki::DNSNameRef;
+
+use crate::hash::Hasher;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The TLS backends yumclone was built with, the first being used for connections without pins.
+pub const BACKENDS: &[&str] = &[
+    #[cfg(feature = "native-tls")]
+    "native-tls",
+    #[cfg(feature = "rustls")]
+    "rustls",
+];
+
+/// The hashes that the certificate of a mirror must match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pins(Vec<Vec<u8>>);
+
+impl Pins {
+    /// Parse pins written as `sha256//<base64>`.
+    pub fn parse(pins: &[String]) -> Result<Pins> {
+        if !pins.is_empty() && !cfg!(feature = "rustls") {
+            bail!("TLS pins can only be checked when yumclone is built with the rustls feature");
+        }
+        let mut hashes = Vec::new();
+        for pin in pins {
+            let encoded = match pin.trim().strip_prefix("sha256//") {
+                Some(encoded) => encoded,
+                None => bail!("TLS pin '{}' isn't of the form sha256//<base64>", pin),
+            };
+            match base64::decode(encoded) {
+                Ok(hash) if hash.len() == 32 => hashes.push(hash),
+                _ => bail!("TLS pin '{}' isn't a base64 SHA-256 hash", pin),
+            }
+        }
+        Ok(Pins(hashes))
+    }
+
+    /// Check whether the leaf certificate, or its public key, is pinned.
+    fn matches(&self, certificate: &[u8]) -> bool {
+        let spki = subject_public_key_info(certificate);
+        [Some(certificate), spki]
+            .iter()
+            .flatten()
+            .filter_map(|der| sha256(der))
+            .any(|hash| self.0.contains(&hash))
+    }
+}
+
+/// Only accept certificates matching the pins on connections made by a client.
+#[cfg(feature = "rustls")]
+pub fn pin(builder: ClientBuilder, pins: Pins) -> ClientBuilder {
+    builder.use_preconfigured_tls(client_config(pins))
+}
+
+/// Only accept certificates matching the pins on connections made by a client.
+#[cfg(not(feature = "rustls"))]
+pub fn pin(builder: ClientBuilder, _pins: Pins) -> ClientBuilder {
+    // Pins can't be parsed without rustls, so there are none to check
+    builder
+}
+
+/// Create a TLS configuration that only accepts certificates matching the pins.
+#[cfg(feature = "rustls")]
+fn client_config(pins: Pins) -> ClientConfig {
+    let mut tls = ClientConfig::new();
+    tls.set_protocols(&["h2".into(), "http/1.1".into()]);
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    tls.dangerous().set_certificate_verifier(Arc::new(Pinned {
+        pins,
+        inner: WebPKIVerifier::new(),
+    }));
+    tls
+}
+
+/// Verifies certificates as usual, then checks them against the pins.
+#[cfg(feature = "rustls")]
+struct Pinned {
+    pins: Pins,
+    inner: WebPKIVerifier,
+}
+
+#[cfg(feature = "rustls")]
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> ::std::result::Result<ServerCertVerified, TLSError> {
+        let verified =
+            self.inner
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        match presented_certs.first() {
+            Some(leaf) if self.pins.matches(&leaf.0) => Ok(verified),
+            _ => {
+                let name: &str = dns_name.into();
+                Err(TLSError::General(format!(
+                    "The certificate presented by {} is not pinned",
+                    name
+                )))
+            }
+        }
+    }
+}
+
+/// Hash data with SHA-256.
+fn sha256(data: &[u8]) -> Option<Vec<u8>> {
+    let mut hasher = Hasher::new("sha256").ok()??;
+    hasher.update(data).ok()?;
+    hex::decode(hasher.finish().ok()?).ok()
+}
+
+/// Find the encoded SubjectPublicKeyInfo within a DER encoded certificate.
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = der_element(certificate)?;
+    let (tbs, _) = der_element(der_contents(certificate)?)?;
+    let mut fields = der_contents(tbs)?;
+
+    // The version is optional and explicitly tagged.
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.1;
+    }
+    // The serial number, signature algorithm, issuer, validity and subject
+    // precede the public key.
+    for _ in 0..5 {
+        fields = der_element(fields)?.1;
+    }
+    der_element(fields).map(|(spki, _)| spki)
+}
+
+/// Split the first DER element, including its header, from the data after it.
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (header, length) = der_length(data)?;
+    let end = header.checked_add(length)?;
+    if end > data.len() {
+        return None;
+    }
+    Some(data.split_at(end))
+}
+
+/// The contents of a DER element.
+fn der_contents(element: &[u8]) -> Option<&[u8]> {
+    let (header, _) = der_length(element)?;
+    element.get(header..)
+}
+
+/// Decode the header and content lengths of a DER element.
+fn der_length(data: &[u8]) -> Option<(usize, usize)> {
+    let first = *data.get(1)?;
+    if first < 0x80 {
+        return Some((2, first as usize));
+    }
+    let bytes = (first & 0x7f) as usize;
+    if bytes == 0 || bytes > 4 {
+        return None;
+    }
+    let length = data
+        .get(2..2 + bytes)?
+        .iter()
+        .fold(0, |length, &b| (length << 8) | b as usize);
+    Some((2 + bytes, length))
+}
+
+#[cfg(all(test, feature = "rustls"))]
+mod test {
+    use super::*;
+
+    const CERTIFICATE: &[u8] = include_bytes!("test-data/mirror.example.com.der");
+    // Produced with `openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+    const SPKI_PIN: &str = "sha256//NuArpS7NcGvdCJoHNUEIyELK/qoeJV+8lFCjD2dva3A=";
+    const CERTIFICATE_PIN: &str = "sha256//F5O40vtW7wcoE7TDwJtvZb0mwPZH0amXsTNv7SNYInc=";
+
+    #[test]
+    fn pinned_certificates() {
+        let pins = |pin: &str| Pins::parse(&[pin.to_owned()]).unwrap();
+        assert!(pins(SPKI_PIN).matches(CERTIFICATE));
+        assert!(pins(CERTIFICATE_PIN).matches(CERTIFICATE));
+        let other = "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        assert!(!pins(other).matches(CERTIFICATE));
+        assert!(!pins(SPKI_PIN).matches(&CERTIFICATE[..100]));
+
+        assert!(Pins::parse(&["NuArpS7NcGvdCJoHNUEIyELK".to_owned()]).is_err());
+        assert!(Pins::parse(&["sha256//NuArpS7NcGvdCJoHNUEIyELK".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Installer trees described by a `.treeinfo` file.
+
+use reqwest::{Client, StatusCode, Url};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs::{read_to_string, write};
+use tracing::{debug, info, warn};
+
+use failure::bail;
+
+use crate::audit;
+use crate::href;
+use crate::init::parse_ini;
+use crate::package::{sync_file, Check, Checksum, Vetting};
+use crate::progress::Tracker;
+use crate::stats::Stats;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
+/// The names of the tree description, in the order they are looked for.
+pub const TREEINFO_NAMES: &[&str] = &[".treeinfo", "treeinfo"];
+
+/// The files of an installer tree, such as the kernel, initrd and install images.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TreeInfo {
+    /// Each file relative to the tree, with its checksum if one is listed.
+    files: BTreeMap<String, Option<Checksum>>,
+}
+
+impl TreeInfo {
+    /// Read the files listed in a tree description.
+    ///
+    /// Files are listed in the `images-<arch>` and `stage2` sections, with their checksums in
+    /// the `checksums` section as `<algorithm>:<digest>`.
+    pub fn parse(source: &str) -> TreeInfo {
+        let mut files = BTreeMap::new();
+        let sections = parse_ini(source);
+
+        for (name, section) in &sections {
+            if name.starts_with("images-") || name == "stage2" {
+                for file in section.values() {
+                    files.insert(file.clone(), None);
+                }
+            }
+        }
+
+        for (name, section) in &sections {
+            if name == "checksums" {
+                for (file, value) in section {
+                    let mut parts = value.splitn(2, ':');
+                    if let (Some(algorithm), Some(sum)) = (parts.next(), parts.next()) {
+                        files.insert(file.clone(), Some(Checksum::new(algorithm, sum)));
+                    }
+                }
+            }
+        }
+
+        TreeInfo { files }
+    }
+
+    /// Load the tree description kept in a destination, if there is one.
+    pub async fn local(dest: &Path) -> Result<Option<(&'static str, TreeInfo)>> {
+        for name in TREEINFO_NAMES {
+            let path = dest.join(name);
+            if path.exists() {
+                return Ok(Some((name, TreeInfo::parse(&read_to_string(path).await?))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The path of every file in the tree.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+}
+
+/// Fetch the tree description of a remote repository, if it has one.
+async fn fetch(client: &Client, src: &Url) -> Result<Option<(&'static str, String)>> {
+    for name in TREEINFO_NAMES {
+        let url = src.join(name)?;
+        debug!("Loading tree description from '{}'", url);
+        let response = client.get(url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+        return Ok(Some((name, response.error_for_status()?.text().await?)));
+    }
+    Ok(None)
+}
+
+/// Mirror the installer tree of a repository.
+///
+/// Files that already match their checksums are kept. The tree description is written last, so
+/// that it is only published once every file it lists is in place. The bytes downloaded are
+/// counted in `stats`.
+pub async fn sync(
+    client: &Client,
+    src: &str,
+    dest: &Path,
+    vetting: &Vetting,
+    stats: &Stats,
+) -> Result<()> {
+    let tracker = Tracker::metadata(stats);
+    let src = href::base(src)?;
+    let (name, source) = match fetch(client, &src).await? {
+        Some(treeinfo) => treeinfo,
+        None => {
+            warn!("No tree description found in '{}'", src);
+            return Ok(());
+        }
+    };
+    tracker.transferred(source.len() as u64);
+    tracker.finish(0);
+
+    let tree = TreeInfo::parse(&source);
+    for (file, checksum) in &tree.files {
+        let path = href::local(dest, file)?;
+        if path.exists() {
+            match checksum {
+                Some(checksum) if checksum.check(&path).await? => continue,
+                Some(_) => audit::remove_file(&path).await?,
+                None => continue,
+            }
+        }
+
+        info!("Downloading installer file '{}'", file);
+        sync_file(
+            client,
+            file,
+            &src,
+            dest,
+            Check::Metadata,
+            Some(&tracker),
+            Some(vetting),
+        )
+        .await?;
+        tracker.finish(0);
+        if let Some(checksum) = checksum {
+            if !checksum.check(&path).await? {
+                audit::remove_file(&path).await?;
+                bail!("Installer file failed checksum {:?}", path);
+            }
+        }
+    }
+
+    let path = dest.join(name);
+    let record = audit::Record::new("download", &path).source(href::join(&src, name)?);
+    match write(&path, &source).await {
+        Ok(()) => record.bytes(source.len() as u64).write(),
+        Err(e) => {
+            record.error(&e).write();
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TREEINFO: &str = "\
+[general]
+family = Fedora
+version = 39
+
+[checksums]
+images/boot.iso = sha256:AB12
+images/pxeboot/vmlinuz = sha256:cd34
+
+[images-x86_64]
+boot.iso = images/boot.iso
+kernel = images/pxeboot/vmlinuz
+initrd = images/pxeboot/initrd.img
+
+[stage2]
+mainimage = images/install.img
+";
+
+    #[test]
+    fn parse_treeinfo() {
+        let tree = TreeInfo::parse(TREEINFO);
+        let files: Vec<_> = tree.files().collect();
+        assert_eq!(
+            files,
+            vec![
+                "images/boot.iso",
+                "images/install.img",
+                "images/pxeboot/initrd.img",
+                "images/pxeboot/vmlinuz",
+            ]
+        );
+        assert_eq!(
+            tree.files["images/boot.iso"],
+            Some(Checksum::new("sha256", "ab12"))
+        );
+        assert_eq!(tree.files["images/install.img"], None);
+    }
+}
@@ -0,0 +1,194 @@
+//! Sizes, rates and durations in the configuration, written with their units.
+//!
+//! Sizes are written like `"1.5TiB"` or `"500MB"`, rates like `"5MiB/s"`, and
+//! durations like `"10m"` or `"1h 30m"`. Plain integers are still accepted as
+//! bytes or bytes per second, as earlier configurations used them, but any
+//! other value that doesn't parse is an error rather than a guess.
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+/// Parse a rate in bytes per second, such as `5MiB/s`.
+///
+/// The `/s` may be left out, as rates used to be plain sizes.
+pub fn parse_rate(text: &str) -> Result<u64, String> {
+    let size = text.trim();
+    let size = size.strip_suffix("/s").unwrap_or(size);
+    parse_bytes(size).map_err(|e| {
+        format!(
+            "Invalid rate '{}': {} (expected a size per second, such as \"5MiB/s\")",
+            text, e
+        )
+    })
+}
+
+/// Parse a size, such as `1.5TiB`.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    parse_bytes(text).map_err(|e| format!("{} (expected a size, such as \"1.5TiB\")", e))
+}
+
+/// Parse a duration, such as `10m` or `1h 30m`.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    humantime::parse_duration(text).map_err(|e| {
+        format!(
+            "Invalid duration '{}': {} (expected a duration, such as \"10m\")",
+            text, e
+        )
+    })
+}
+
+/// Parse a number of bytes with an optional unit, such as `50GiB` or `2.5 MB`.
+fn parse_bytes(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", text))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1 << 30,
+        "t" | "tb" => 1000 * 1000 * 1000 * 1000,
+        "tib" => 1 << 40,
+        unit => return Err(format!("Unknown unit '{}' in size '{}'", unit, text)),
+    };
+    let bytes = number * multiplier as f64;
+    // Casting would saturate, silently turning a typo into the largest possible size
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("Size '{}' is too large", text));
+    }
+    Ok(bytes as u64)
+}
+
+/// Accepts a number of bytes, either with its unit or as a plain integer.
+struct Bytes {
+    parse: fn(&str) -> Result<u64, String>,
+    expecting: &'static str,
+}
+
+impl<'de> Visitor<'de> for Bytes {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+        u64::try_from(value).map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        (self.parse)(value).map_err(E::custom)
+    }
+}
+
+/// Parse an optional size such as `"1.5TiB"`, or a number of bytes.
+pub(crate) fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer
+        .deserialize_any(Bytes {
+            parse: parse_size,
+            expecting: "a size such as \"1.5TiB\", or a number of bytes",
+        })
+        .map(Some)
+}
+
+/// Parse an optional rate such as `"5MiB/s"`, or a number of bytes per second.
+pub(crate) fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer
+        .deserialize_any(Bytes {
+            parse: parse_rate,
+            expecting: "a rate such as \"5MiB/s\", or a number of bytes per second",
+        })
+        .map(Some)
+}
+
+/// Parse an optional duration such as `"30s"` or `"1h 30m"`.
+pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Tunables {
+        #[serde(default, deserialize_with = "deserialize_size")]
+        size: Option<u64>,
+        #[serde(default, deserialize_with = "deserialize_rate")]
+        rate: Option<u64>,
+        #[serde(default, deserialize_with = "deserialize_duration")]
+        timeout: Option<Duration>,
+    }
+
+    #[test]
+    fn parse_units() {
+        let tunables: Tunables =
+            toml::from_str("size = \"1.5TiB\"\nrate = \"5MiB/s\"\ntimeout = \"10m\"").unwrap();
+        assert_eq!(tunables.size, Some(3 << 39));
+        assert_eq!(tunables.rate, Some(5 << 20));
+        assert_eq!(tunables.timeout, Some(Duration::from_secs(600)));
+
+        // Plain integers are bytes, as they always were
+        let tunables: Tunables = toml::from_str("size = 1024\nrate = 2048").unwrap();
+        assert_eq!(tunables.size, Some(1024));
+        assert_eq!(tunables.rate, Some(2048));
+        assert_eq!(parse_rate("10KB").unwrap(), 10_000);
+        // Sizes and durations on the command line, such as --max-bytes, are written the same way
+        assert_eq!(parse_size("100MiB").unwrap(), 100 << 20);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("100 lines").is_err());
+        assert_eq!(parse_bytes("50GiB"), Ok(50 << 30));
+        assert_eq!(parse_bytes("2.5 MB"), Ok(2_500_000));
+        assert_eq!(parse_bytes("1024"), Ok(1024));
+        assert!(parse_bytes("10 parsecs").is_err());
+        assert!(parse_duration("15 fortnights").is_err());
+
+        for invalid in [
+            "size = \"1.5TiBs\"",
+            "size = \"5MiB/s\"",
+            "size = -1",
+            "size = 1.5",
+            "size = \"\"",
+            "size = \"99999999999TiB\"",
+            "size = \"1e30\"",
+            "rate = \"5MiB/min\"",
+            "timeout = \"10 mins later\"",
+            "timeout = 600",
+        ] {
+            assert!(
+                toml::from_str::<Tunables>(invalid).is_err(),
+                "'{}' was accepted",
+                invalid
+            );
+        }
+        let error = toml::from_str::<Tunables>("rate = \"fast\"").unwrap_err();
+        assert!(error.to_string().contains("5MiB/s"), "{}", error);
+    }
+}
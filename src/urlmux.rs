@@ -1,14 +1,19 @@
 //! Creates URLs based on a combination of patterns from a set of inputs.
 
 use regex::{Captures, Regex, Replacer};
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{From, Into};
+use std::fmt;
 
 /// A Generator of URL pairs for a given set of tags.
 pub struct UrlMux<'a, 'b, 's> {
     src: &'a str,
     dst: &'b str,
     fields: TagFieldIter<'s>,
+    exclusions: &'s [TagRule],
+    dst_values: Option<&'s ValueMap>,
+    dst_transforms: Option<&'s HashMap<String, Transform>>,
     tag_search: Regex,
 }
 
@@ -24,26 +29,116 @@ impl<'a, 'b, 's> UrlMux<'a, 'b, 's> {
         T: Into<TagFieldIter<'s>>,
     {
         UrlMux {
-            src: src,
-            dst: dst,
+            src,
+            dst,
             fields: fields.into(),
+            exclusions: &[],
+            dst_values: None,
+            dst_transforms: None,
             tag_search: tag_finder(),
         }
     }
+
+    /// Skip every combination of tags that matches one of the rules.
+    pub fn exclude(mut self, rules: &'s [TagRule]) -> UrlMux<'a, 'b, 's> {
+        self.exclusions = rules;
+        self
+    }
+
+    /// Rewrite tag values when they are substituted into the destination.
+    ///
+    /// A value listed for a tag in `values` is replaced as written, otherwise the tag's transform
+    /// is applied to it.
+    pub fn rewrite_dst(
+        mut self,
+        values: &'s ValueMap,
+        transforms: &'s HashMap<String, Transform>,
+    ) -> UrlMux<'a, 'b, 's> {
+        self.dst_values = Some(values);
+        self.dst_transforms = Some(transforms);
+        self
+    }
+
+    /// Advance each group of tags together rather than taking every combination.
+    pub fn zip(mut self, groups: &[Vec<String>]) -> UrlMux<'a, 'b, 's> {
+        self.fields = self.fields.zip(groups);
+        self
+    }
 }
 
 impl<'a, 'b, 's> Iterator for UrlMux<'a, 'b, 's> {
     type Item = (String, String);
 
     fn next(&mut self) -> Option<(String, String)> {
-        if let Some(ref replacer) = self.fields.next() {
-            Some((
-                self.tag_search.replace_all(&self.src, replacer).into_owned(),
-                self.tag_search.replace_all(&self.dst, replacer).into_owned()
-            ))
-        } else {
-            None
+        self.next_variant().map(|variant| (variant.src, variant.dst))
+    }
+}
+
+/// A single expansion of the source and destination along with the tags used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    /// The expanded source URL.
+    pub src: String,
+    /// The expanded destination.
+    pub dst: String,
+    /// The value of each tag.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (tag, value) in &self.tags {
+            if !first {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", tag, value)?;
+            first = false;
         }
+        Ok(())
+    }
+}
+
+/// An iterator over the variants of a URL Mux.
+pub struct Variants<'a, 'b, 's>(UrlMux<'a, 'b, 's>);
+
+impl<'a, 'b, 's> Iterator for Variants<'a, 'b, 's> {
+    type Item = Variant;
+
+    fn next(&mut self) -> Option<Variant> {
+        self.0.next_variant()
+    }
+}
+
+impl<'a, 'b, 's> UrlMux<'a, 'b, 's> {
+    /// Iterate over each variant along with its tags rather than just the URL pairs.
+    pub fn variants(self) -> Variants<'a, 'b, 's> {
+        Variants(self)
+    }
+
+    /// Find the next combination of tags that isn't excluded.
+    fn next_variant(&mut self) -> Option<Variant> {
+        for replacer in self.fields.by_ref() {
+            if self.exclusions.iter().any(|rule| replacer.matches(rule)) {
+                continue;
+            }
+
+            let rewrite = Rewrite {
+                tags: &replacer,
+                values: self.dst_values,
+                transforms: self.dst_transforms,
+            };
+            return Some(Variant {
+                src: self.tag_search.replace_all(self.src, &replacer).into_owned(),
+                dst: self.tag_search.replace_all(self.dst, rewrite).into_owned(),
+                tags: replacer
+                    .map
+                    .iter()
+                    .map(|(tag, value)| (tag.to_string(), value.to_string()))
+                    .collect(),
+            });
+        }
+        None
     }
 }
 
@@ -51,39 +146,60 @@ impl<'a, 'b, 's> Iterator for UrlMux<'a, 'b, 's> {
 /// A list of tags with each possible variant of a given tag.
 type TagField = HashMap<String, Vec<String>>;
 
+/// A partial set of tag values, matching every combination that has all of them.
+pub type TagRule = HashMap<String, String>;
+
+/// Replacements for the values of each tag.
+pub type ValueMap = HashMap<String, HashMap<String, String>>;
+
+/// A simple transform applied to a tag value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transform {
+    /// Convert the value to lower case
+    Lowercase,
+    /// Convert the value to upper case
+    Uppercase,
+}
+
+impl Transform {
+    /// Apply the transform to a value.
+    pub fn apply(self, value: &str) -> String {
+        match self {
+            Transform::Lowercase => value.to_lowercase(),
+            Transform::Uppercase => value.to_uppercase(),
+        }
+    }
+}
+
 /// An iterator over all of the combinations in a tag field.
+///
+/// Each entry of the field is a group of tags along with the rows of values they take together.
+/// Ungrouped tags are groups of one.
 #[derive(Debug)]
 pub struct TagFieldIter<'s> {
-    field: Vec<(&'s str, Vec<&'s str>)>,
+    field: Vec<(Vec<&'s str>, Vec<Vec<&'s str>>)>,
     index: Option<Vec<usize>>,
 }
 
 impl<'s> From<&'s TagField> for TagFieldIter<'s> {
     /// Create an iterator over the tag field.
     fn from(field: &'s TagField) -> TagFieldIter<'s> {
-        let index = vec![0; field.len()];
         let field = field
             .iter()
             .map(|(k, v)| (k.as_ref(), v.iter().map(String::as_ref).collect()))
             .collect();
-        TagFieldIter {
-            field: field,
-            index: Some(index),
-        }
+        TagFieldIter::with_field(field)
     }
 }
 
 impl<'s> From<HashMap<&'s str, Vec<&'s str>>> for TagFieldIter<'s> {
     /// Create an iterator over the tag field.
-    fn from(mut field: HashMap<&'s str, Vec<&'s str>>) -> TagFieldIter<'_> {
-        let index = vec![0; field.len()];
+    fn from(mut field: HashMap<&'s str, Vec<&'s str>>) -> TagFieldIter<'s> {
         let field = field
             .drain()
             .collect();
-        TagFieldIter {
-            field: field,
-            index: Some(index),
-        }
+        TagFieldIter::with_field(field)
     }
 }
 
@@ -98,6 +214,52 @@ impl<'s> Iterator for TagFieldIter<'s> {
 }
 
 impl<'s> TagFieldIter<'s> {
+    /// Create an iterator over a list of tags and their values.
+    ///
+    /// A tag without any values produces no combinations.
+    fn with_field(field: Vec<(&'s str, Vec<&'s str>)>) -> TagFieldIter<'s> {
+        let field = field
+            .into_iter()
+            .map(|(tag, values)| (vec![tag], values.into_iter().map(|v| vec![v]).collect()))
+            .collect();
+        TagFieldIter::with_groups(field)
+    }
+
+    /// Create an iterator over groups of tags and the rows of values they take.
+    fn with_groups(field: Vec<(Vec<&'s str>, Vec<Vec<&'s str>>)>) -> TagFieldIter<'s> {
+        let index = if field.iter().any(|(_, rows)| rows.is_empty()) {
+            None
+        } else {
+            Some(vec![0; field.len()])
+        };
+        TagFieldIter { field, index }
+    }
+
+    /// Merge each group of tags so that their values advance together.
+    ///
+    /// The n-th value of every tag in a group is used with the n-th value of the others, so a
+    /// group produces as many combinations as its shortest tag has values.
+    pub fn zip(self, groups: &[Vec<String>]) -> TagFieldIter<'s> {
+        let mut field = self.field;
+        for group in groups {
+            let (members, rest): (Vec<_>, Vec<_>) = field
+                .into_iter()
+                .partition(|(tags, _)| tags.iter().any(|t| group.iter().any(|g| g == t)));
+            field = rest;
+            if members.is_empty() {
+                continue;
+            }
+
+            let len = members.iter().map(|(_, rows)| rows.len()).min().unwrap_or(0);
+            let tags = members.iter().flat_map(|(tags, _)| tags.iter().cloned()).collect();
+            let rows = (0..len)
+                .map(|i| members.iter().flat_map(|(_, rows)| rows[i].iter().cloned()).collect())
+                .collect();
+            field.push((tags, rows));
+        }
+        TagFieldIter::with_groups(field)
+    }
+
     /// Increment the index.
     fn next_index(&self) -> Option<Vec<usize>> {
         if let Some(mut next) = self.index.clone() {
@@ -121,7 +283,10 @@ impl<'s> TagFieldIter<'s> {
                 .iter()
                 .cloned()
                 .enumerate()
-                .map(|(t, v)| (self.field[t].0.as_ref(), self.field[t].1[v].as_ref()))
+                .flat_map(|(t, v)| {
+                    let (ref tags, ref rows) = self.field[t];
+                    tags.iter().cloned().zip(rows[v].iter().cloned())
+                })
                 .collect();
             Some(tagset.into())
         } else {
@@ -135,6 +300,14 @@ pub struct TagSet<'s> {
     map: HashMap<&'s str, &'s str>,
 }
 
+impl<'s> TagSet<'s> {
+    /// Check whether every tag in a rule has the value given by the rule.
+    pub fn matches(&self, rule: &TagRule) -> bool {
+        rule.iter()
+            .all(|(tag, value)| self.map.get(tag.as_str()) == Some(&value.as_str()))
+    }
+}
+
 impl<'s> From<HashMap<&'s str, &'s str>> for TagSet<'s> {
     fn from(map: HashMap<&'s str, &'s str>) -> TagSet<'s> {
         TagSet { map }
@@ -143,17 +316,46 @@ impl<'s> From<HashMap<&'s str, &'s str>> for TagSet<'s> {
 
 impl<'t, 's> Replacer for &'t TagSet<'s> {
     fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
-        if let Some(ref val) = self.map.get(&caps["tag"]) {
+        if let Some(val) = self.map.get(&caps["tag"]) {
             dst.push_str(val);
         } else {
-            panic!("Invalid tag name specified in url");
+            // Unresolved tags are reported by config validation, so leave them as written.
+            dst.push_str(&caps[0]);
+        }
+    }
+}
+
+/// Replaces tags with rewritten values from a tag set.
+struct Rewrite<'t, 's> {
+    tags: &'t TagSet<'s>,
+    values: Option<&'s ValueMap>,
+    transforms: Option<&'s HashMap<String, Transform>>,
+}
+
+impl<'t, 's> Replacer for Rewrite<'t, 's> {
+    fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+        let tag = &caps["tag"];
+        let val = match self.tags.map.get(tag) {
+            Some(val) => *val,
+            None => {
+                dst.push_str(&caps[0]);
+                return;
+            }
+        };
+
+        if let Some(mapped) = self.values.and_then(|v| v.get(tag)).and_then(|v| v.get(val)) {
+            dst.push_str(mapped);
+        } else if let Some(transform) = self.transforms.and_then(|t| t.get(tag)) {
+            dst.push_str(&transform.apply(val));
+        } else {
+            dst.push_str(val);
         }
     }
 }
 
 impl<'s> Replacer for TagSet<'s> {
     fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
-        if let Some(ref val) = self.map.get(&caps["tag"]) {
+        if let Some(val) = self.map.get(&caps["tag"]) {
             dst.push_str(val);
         } else {
             // Push the string if not found.
@@ -167,6 +369,74 @@ fn tag_finder() -> Regex {
     Regex::new(r"\$(?P<tag>[-a-zA-Z0-9_]+)").unwrap()
 }
 
+/// Find the destinations shared by more than one source in a list of URL pairs.
+///
+/// Returns each colliding destination with every source that maps to it.
+pub fn collisions<I>(pairs: I) -> Vec<(String, Vec<String>)>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut sources: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (src, dst) in pairs {
+        sources.entry(dst).or_default().push(src);
+    }
+    sources.into_iter().filter(|(_, srcs)| srcs.len() > 1).collect()
+}
+
+/// The most values a single range of tag values may expand to.
+pub const MAX_RANGE: u64 = 1000;
+
+/// Expand a tag value of the form `start..end` or `start..=end` into every integer in the range.
+///
+/// Returns `None` if the value is not a range. Leading zeros on the start of the range are kept
+/// as the width of every value. Ranges that are empty or have more than `MAX_RANGE` values are
+/// rejected, as they are almost certainly typos.
+pub fn expand_range(value: &str) -> Result<Option<Vec<String>>, String> {
+    let (start, end, inclusive) = if let Some(split) = value.find("..=") {
+        (&value[..split], &value[split + 3..], true)
+    } else if let Some(split) = value.find("..") {
+        (&value[..split], &value[split + 2..], false)
+    } else {
+        return Ok(None);
+    };
+
+    let (first, last): (u64, u64) = match (start.trim().parse(), end.trim().parse()) {
+        (Ok(first), Ok(last)) => (first, last),
+        _ => return Ok(None),
+    };
+    let count = if inclusive {
+        last.checked_sub(first).map(|count| count.saturating_add(1))
+    } else {
+        last.checked_sub(first)
+    };
+    match count {
+        None | Some(0) => return Err(format!("The range '{}' is empty", value)),
+        Some(count) if count > MAX_RANGE => {
+            return Err(format!(
+                "The range '{}' has {} values, more than the limit of {}",
+                value, count, MAX_RANGE
+            ))
+        }
+        Some(_) => {}
+    }
+    let width = if start.trim().starts_with('0') { start.trim().len() } else { 0 };
+    let values = if inclusive {
+        (first..=last).map(|v| format!("{:01$}", v, width)).collect()
+    } else {
+        (first..last).map(|v| format!("{:01$}", v, width)).collect()
+    };
+    Ok(Some(values))
+}
+
+/// List the names of all tags referenced in a URL.
+pub fn tag_names(url: &str) -> Vec<&str> {
+    tag_finder()
+        .captures_iter(url)
+        .filter_map(|caps| caps.name("tag"))
+        .map(|tag| tag.as_str())
+        .collect()
+}
+
 
 #[cfg(test)]
 mod test {
@@ -186,6 +456,14 @@ mod test {
         assert_eq!(sets.len(), 6);
     }
 
+    #[test]
+    fn empty_tag_field() {
+        let mut tags = tags();
+        tags.insert("release", vec![]);
+        let fields: TagFieldIter<'_> = tags.into();
+        assert_eq!(fields.count(), 0);
+    }
+
     #[test]
     fn url_tag_replace() {
         use std::collections::BTreeSet;
@@ -204,6 +482,104 @@ mod test {
         assert!(variants.contains("epel/i686/$other"));
     }
 
+    #[test]
+    fn url_tag_names() {
+        assert_eq!(tag_names("https://host/$os/$arch/os/"), vec!["os", "arch"]);
+        assert!(tag_names("https://host/fedora/").is_empty());
+    }
+
+    #[test]
+    fn tag_ranges() {
+        assert_eq!(expand_range("38..41").unwrap().unwrap(), vec!["38", "39", "40"]);
+        assert_eq!(expand_range("38..=41").unwrap().unwrap(), vec!["38", "39", "40", "41"]);
+        assert_eq!(expand_range("08..=10").unwrap().unwrap(), vec!["08", "09", "10"]);
+        assert_eq!(expand_range("41..=41").unwrap().unwrap(), vec!["41"]);
+        assert!(expand_range("x86_64").unwrap().is_none());
+        assert!(expand_range("1.2").unwrap().is_none());
+
+        // Reversed and empty ranges would silently skip the repository
+        assert!(expand_range("41..38").unwrap_err().contains("empty"));
+        assert!(expand_range("41..41").unwrap_err().contains("empty"));
+        assert!(expand_range("41..=38").unwrap_err().contains("empty"));
+
+        // Typos would expand to billions of values
+        assert_eq!(expand_range("0..1000").unwrap().unwrap().len(), 1000);
+        assert!(expand_range("0..=1000").unwrap_err().contains("limit"));
+        assert!(expand_range("38..4100000000").unwrap_err().contains("limit"));
+    }
+
+    #[test]
+    fn url_mux_collisions() {
+        let mux = UrlMux::new("src/$os/$arch", "dst/$os", tags());
+        let found = collisions(mux);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, "dst/epel");
+        assert_eq!(found[0].1.len(), 3);
+
+        let mux = UrlMux::new("src/$os/$arch", "dst/$os/$arch", tags());
+        assert!(collisions(mux).is_empty());
+    }
+
+    #[test]
+    fn url_mux_rewrite_dst() {
+        let values: ValueMap = vec![(
+            "os".to_owned(),
+            vec![("epel".to_owned(), "epel9".to_owned())].into_iter().collect(),
+        )].into_iter().collect();
+        let transforms = vec![("arch".to_owned(), Transform::Lowercase)].into_iter().collect();
+        let mux = UrlMux::new("src/$os/$arch", "dst/$os/$arch", tags())
+            .rewrite_dst(&values, &transforms);
+        let variants: Vec<(String, String)> = mux.collect();
+
+        assert!(variants.contains(&("src/epel/SRPMS".to_owned(), "dst/epel9/srpms".to_owned())));
+        assert!(variants.contains(&("src/fedora/x86_64".to_owned(), "dst/fedora/x86_64".to_owned())));
+    }
+
+    #[test]
+    fn url_mux_variants() {
+        let mut tagset = tags();
+        tagset.remove("os");
+        let variants: Vec<Variant> = UrlMux::new("src/$arch", "dst/$arch", tagset)
+            .variants()
+            .collect();
+
+        assert_eq!(variants.len(), 3);
+        let x86 = variants.iter().find(|v| v.src == "src/x86_64").unwrap();
+        assert_eq!(x86.dst, "dst/x86_64");
+        assert_eq!(x86.to_string(), "arch=x86_64");
+    }
+
+    #[test]
+    fn url_mux_exclude() {
+        let tagset = tags();
+        let rules: Vec<TagRule> = vec![
+            vec![("os".to_owned(), "epel".to_owned()), ("arch".to_owned(), "i686".to_owned())]
+                .into_iter()
+                .collect(),
+            vec![("arch".to_owned(), "SRPMS".to_owned())].into_iter().collect(),
+        ];
+        let mux = UrlMux::new("src/$os/$arch", "dst/$os/$arch", tagset).exclude(&rules);
+        let mut variants: Vec<String> = mux.map(|(src, _)| src).collect();
+        variants.sort();
+
+        assert_eq!(variants, vec!["src/epel/x86_64", "src/fedora/i686", "src/fedora/x86_64"]);
+    }
+
+    #[test]
+    fn url_mux_zip() {
+        let mut tagset = tags();
+        tagset.insert("release", vec!["39", "40"]);
+        tagset.insert("name", vec!["thirty-nine", "forty"]);
+        let groups = vec![vec!["release".to_owned(), "name".to_owned()]];
+        let mux = UrlMux::new("src/$release/$arch", "dst/$name/$arch", tagset).zip(&groups);
+        let variants: Vec<(String, String)> = mux.collect();
+
+        assert_eq!(variants.len(), 12);
+        assert!(variants.contains(&("src/39/x86_64".to_owned(), "dst/thirty-nine/x86_64".to_owned())));
+        assert!(variants.contains(&("src/40/SRPMS".to_owned(), "dst/forty/SRPMS".to_owned())));
+        assert!(!variants.iter().any(|(src, dst)| src.contains("39") && dst.contains("forty")));
+    }
+
     #[test]
     fn url_mux() {
         use std::collections::BTreeSet;
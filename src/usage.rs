@@ -0,0 +1,102 @@
+//! Summaries of the packages mirrored, from the metadata of each destination.
+
+use std::collections::BTreeMap;
+
+use crate::list::Listed;
+
+/// The number and total size of a group of packages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Total {
+    /// The number of packages.
+    pub packages: u64,
+    /// The total size of the package files in bytes.
+    pub bytes: u64,
+}
+
+impl Total {
+    fn add(&mut self, package: &Listed) {
+        self.packages += 1;
+        self.bytes += package.size;
+    }
+}
+
+/// The packages mirrored, broken down by architecture and source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Every package.
+    pub total: Total,
+    /// The packages of each architecture.
+    pub by_arch: BTreeMap<String, Total>,
+    /// The packages mirrored by each repository, by label.
+    pub by_repo: BTreeMap<String, Total>,
+    /// The largest packages, largest first.
+    pub largest: Vec<Listed>,
+}
+
+impl Usage {
+    /// Summarise packages, keeping the given number of the largest.
+    pub fn new(packages: &[Listed], largest: usize) -> Usage {
+        let mut usage = Usage::default();
+        for package in packages {
+            usage.total.add(package);
+            usage
+                .by_arch
+                .entry(package.arch.clone())
+                .or_default()
+                .add(package);
+            usage
+                .by_repo
+                .entry(package.repo.clone())
+                .or_default()
+                .add(package);
+        }
+        let mut sorted: Vec<&Listed> = packages.iter().collect();
+        sorted.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.nevra.cmp(&b.nevra)));
+        usage.largest = sorted.into_iter().take(largest).cloned().collect();
+        usage
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarise_packages() {
+        let package = |repo: &str, name: &str, arch: &str, size: u64| Listed {
+            repo: repo.to_owned(),
+            dest: format!("/srv/{}", repo),
+            name: name.to_owned(),
+            arch: arch.to_owned(),
+            nevra: format!("{}-1-1.{}", name, arch),
+            size,
+            checksum: "sha256:00".to_owned(),
+            location: format!("Packages/{}-1-1.{}.rpm", name, arch),
+        };
+        let packages = vec![
+            package("base", "bash", "x86_64", 300),
+            package("base", "vim", "x86_64", 500),
+            package("base", "docs", "noarch", 100),
+            package("updates", "vim", "x86_64", 500),
+        ];
+
+        let usage = Usage::new(&packages, 2);
+        assert_eq!(
+            usage.total,
+            Total {
+                packages: 4,
+                bytes: 1400
+            }
+        );
+        assert_eq!(usage.by_arch["x86_64"].packages, 3);
+        assert_eq!(usage.by_arch["noarch"].bytes, 100);
+        assert_eq!(usage.by_repo["base"].bytes, 900);
+        assert_eq!(usage.by_repo["updates"].packages, 1);
+        let largest: Vec<(&str, &str)> = usage
+            .largest
+            .iter()
+            .map(|p| (p.repo.as_str(), p.name.as_str()))
+            .collect();
+        assert_eq!(largest, vec![("base", "vim"), ("updates", "vim")]);
+    }
+}